@@ -42,7 +42,7 @@ impl ReadBuffer for &[u8] {
     }
 
     fn fill_all(&mut self) -> &[u8] {
-        mem::replace(self, &[])
+        mem::take(self)
     }
 }
 
@@ -37,7 +37,7 @@ impl WriteBuffer for &mut [u8] {
             return Err(BufferOverflow);
         }
 
-        let (current, left) = mem::replace(self, &mut []).split_at_mut(buffer.len());
+        let (current, left) = mem::take(self).split_at_mut(buffer.len());
         current.copy_from_slice(buffer);
         *self = left;
         Ok(())
@@ -51,7 +51,7 @@ impl WriteBuffer for &mut [u8] {
             return Err(BufferOverflow);
         }
 
-        let (current, mut left) = mem::replace(self, &mut []).split_at_mut(len);
+        let (current, mut left) = mem::take(self).split_at_mut(len);
         callback(&mut left)?;
         *self = left;
         Ok(current)
@@ -169,4 +169,4 @@ fn enums_simple() -> Result<(), DecodeError> {
         }
     );
     Ok(())
-}
\ No newline at end of file
+}
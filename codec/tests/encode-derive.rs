@@ -111,7 +111,7 @@ fn encode_in_slice() {
     assert_eq!(0x0203u16.encode(&mut view), Ok(2));
     assert_eq!(0x0405_0607u32.encode(&mut view), Ok(4));
     assert_eq!(0x08u8.encode(&mut view), Err(BufferOverflow));
-    assert_eq!((&[][..]).encode(&mut view), Ok(0));
+    assert_eq!([][..].encode(&mut view), Ok(0));
     assert_eq!(slice, [1, 2, 3, 4, 5, 6, 7]);
 
     let mut slice = [0; 2];
@@ -180,4 +180,4 @@ fn enums_simple() {
         buffer,
         vec![0, 0, 0, 10, 0xaa, 0xbb, 0xcc, 0xdd, 0x01, 0x02]
     );
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+//! Writing large ForwardPackets with the payload copied into the frame compared to vectored
+//! writes of the head and the payload, run with `cargo bench`
+
+use dersp::{
+    crypto::SecretKey,
+    proto::{
+        data::{ForwardPacket, PROTOCOL_VERSION},
+        write_forward_packet,
+    },
+};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+
+/// Frames written per run
+const FRAMES: usize = 20_000;
+/// Payload of each frame, about the largest a client sends
+const PAYLOAD_SIZE: usize = 64 * 1024 - 100;
+
+/// Time writing `FRAMES` frames to a loopback connection takes, the other end drains it
+async fn write_frames(vectored: bool) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut writer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut reader, _) = listener.accept().await.unwrap();
+    let drain = spawn(async move {
+        let mut buf = vec![0; 1 << 20];
+        while reader.read(&mut buf).await.unwrap() > 0 {}
+    });
+
+    let packet = ForwardPacket::new(
+        SecretKey::gen().public(),
+        SecretKey::gen().public(),
+        1,
+        vec![0; PAYLOAD_SIZE],
+    );
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        if vectored {
+            write_forward_packet(&mut writer, &packet, PROTOCOL_VERSION)
+                .await
+                .unwrap();
+        } else {
+            // What encoding the whole frame amounts to
+            let mut frame = packet.head_for(PROTOCOL_VERSION).unwrap();
+            frame.extend_from_slice(&packet.payload);
+            writer.write_all(&frame).await.unwrap();
+        }
+    }
+    writer.shutdown().await.unwrap();
+    drain.await.unwrap();
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, vectored) in [("concatenated", false), ("vectored", true)] {
+        let elapsed = write_frames(vectored).await;
+        let rate = FRAMES as f64 / elapsed.as_secs_f64();
+        println!("{name}: {rate:.0} frames/s ({elapsed:?})");
+    }
+}
@@ -0,0 +1,61 @@
+//! Handshake throughput with a single acceptor compared to several, run with `cargo bench`
+
+use clap::Parser;
+use dersp::{
+    client::DerpClient,
+    crypto::SecretKey,
+    service::{DerpService, Service},
+    Config,
+};
+use std::time::Instant;
+use tokio::{net::TcpListener, spawn, task::JoinSet};
+
+/// Handshakes per run
+const CONNECTIONS: usize = 2000;
+/// Clients handshaking at the same time
+const CONCURRENCY: usize = 64;
+
+async fn handshakes_per_second(acceptors: usize) -> f64 {
+    let acceptors = acceptors.to_string();
+    let config = Config::parse_from([
+        "dersp",
+        "--listen-on",
+        "127.0.0.1:0",
+        "--acceptors",
+        acceptors.as_str(),
+    ]);
+    let listener = TcpListener::bind(config.listen_on.as_deref().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let service = DerpService::new(config).await.unwrap();
+    let runner = service.clone();
+    let server = spawn(async move { runner.run(listener).await });
+
+    let start = Instant::now();
+    let mut clients = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let addr = addr.clone();
+        clients.spawn(async move {
+            for _ in 0..CONNECTIONS / CONCURRENCY {
+                DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+            }
+        });
+    }
+    while let Some(result) = clients.join_next().await {
+        result.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    server.abort();
+    (CONNECTIONS / CONCURRENCY * CONCURRENCY) as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+    for acceptors in [1, cores] {
+        let rate = handshakes_per_second(acceptors).await;
+        println!("{acceptors} acceptor(s): {rate:.0} handshakes/s");
+    }
+}
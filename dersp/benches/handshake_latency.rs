@@ -0,0 +1,74 @@
+//! Ping round trips of a connected client while others handshake, with the ClientInfos
+//! decrypted on the connection threads compared to `--crypto-threads`, run with `cargo bench`
+
+use clap::Parser;
+use dersp::{
+    client::DerpClient,
+    crypto::SecretKey,
+    service::{DerpService, Service},
+    Config,
+};
+use std::time::Duration;
+use tokio::{net::TcpListener, spawn, task::JoinSet};
+
+/// Handshakes per run
+const CONNECTIONS: usize = 2000;
+/// Clients handshaking at the same time
+const CONCURRENCY: usize = 64;
+
+/// Returns the median and worst ping round trip seen during the handshakes
+async fn ping_under_handshakes(crypto_threads: Option<usize>) -> (Duration, Duration) {
+    let mut args = vec![
+        "dersp".to_string(),
+        "--listen-on".to_string(),
+        "127.0.0.1:0".to_string(),
+    ];
+    if let Some(threads) = crypto_threads {
+        args.extend(["--crypto-threads".to_string(), threads.to_string()]);
+    }
+    let config = Config::parse_from(args);
+    let listener = TcpListener::bind(config.listen_on.as_deref().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let service = DerpService::new(config).await.unwrap();
+    let runner = service.clone();
+    let server = spawn(async move { runner.run(listener).await });
+
+    let pinger = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+    let mut clients = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let addr = addr.clone();
+        clients.spawn(async move {
+            for _ in 0..CONNECTIONS / CONCURRENCY {
+                DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+            }
+        });
+    }
+    let mut rtts = Vec::new();
+    while !clients.is_empty() {
+        tokio::select! {
+            result = clients.join_next() => result.unwrap().unwrap(),
+            rtt = pinger.ping() => rtts.push(rtt.unwrap()),
+        }
+    }
+
+    server.abort();
+    rtts.sort();
+    let median = rtts.get(rtts.len() / 2).copied().unwrap_or_default();
+    let worst = rtts.last().copied().unwrap_or_default();
+    (median, worst)
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+    for crypto_threads in [None, Some(cores)] {
+        let (median, worst) = ping_under_handshakes(crypto_threads).await;
+        let label = match crypto_threads {
+            Some(threads) => format!("{threads} crypto thread(s)"),
+            None => "no crypto threads".to_string(),
+        };
+        println!("{label}: median ping {median:?}, worst {worst:?}");
+    }
+}
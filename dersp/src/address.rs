@@ -0,0 +1,101 @@
+//! Resolving the addresses given on the command line, including link-local IPv6 addresses
+//! with a zone, e.g. `[fe80::1%eth0]:8765`, which the system resolver doesn't take.
+
+use anyhow::{ensure, Context};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::lookup_host;
+
+/// Every address `addr` resolves to, like [`lookup_host`]. A scoped IPv6 address resolves to
+/// itself with the zone as its scope id.
+pub async fn resolve(addr: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Some(scoped) = parse_scoped(addr)? {
+        return Ok(vec![scoped]);
+    }
+    Ok(lookup_host(addr).await?.collect())
+}
+
+/// Parses `[ip%zone]:port`, where the zone is an interface name or index. `None` when `addr`
+/// has no zone.
+pub fn parse_scoped(addr: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let Some((host, port)) = addr
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+    else {
+        return Ok(None);
+    };
+    let Some((ip, zone)) = host.split_once('%') else {
+        return Ok(None);
+    };
+    let ip: Ipv6Addr = ip
+        .parse()
+        .with_context(|| format!("{ip} in {addr} isn't an IPv6 address"))?;
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("{port} in {addr} isn't a port"))?;
+    let scope_id = zone_index(zone).with_context(|| format!("Zone of {addr}"))?;
+    Ok(Some(SocketAddrV6::new(ip, port, 0, scope_id).into()))
+}
+
+/// Index of the interface a zone names, zones may also be the index itself
+fn zone_index(zone: &str) -> anyhow::Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    ensure!(
+        !zone.is_empty() && !zone.contains('/'),
+        "{zone:?} isn't an interface name"
+    );
+    interface_index(zone)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> anyhow::Result<u32> {
+    let index = std::fs::read_to_string(format!("/sys/class/net/{name}/ifindex"))
+        .with_context(|| format!("No interface named {name}"))?;
+    Ok(index.trim().parse()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(name: &str) -> anyhow::Result<u32> {
+    anyhow::bail!("Interface names can't be zones on this platform, use the index of {name}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zone_index_becomes_the_scope_id() {
+        let addr = parse_scoped("[fe80::1%3]:8765").unwrap().unwrap();
+        let SocketAddr::V6(addr) = addr else {
+            panic!("{addr} should be IPv6");
+        };
+        assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(addr.port(), 8765);
+        assert_eq!(addr.scope_id(), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn interface_names_are_looked_up() {
+        let addr = parse_scoped("[fe80::1%lo]:8765").unwrap().unwrap();
+        let SocketAddr::V6(addr) = addr else {
+            panic!("{addr} should be IPv6");
+        };
+        assert_ne!(addr.scope_id(), 0);
+
+        let err = parse_scoped("[fe80::1%nonexistent0]:8765").unwrap_err();
+        assert!(format!("{err:#}").contains("No interface named nonexistent0"));
+    }
+
+    #[tokio::test]
+    async fn addresses_without_a_zone_are_resolved_as_usual() {
+        assert_eq!(parse_scoped("[::1]:8765").unwrap(), None);
+        assert_eq!(parse_scoped("127.0.0.1:8765").unwrap(), None);
+        assert_eq!(
+            resolve("127.0.0.1:8765").await.unwrap(),
+            ["127.0.0.1:8765".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(parse_scoped("[fe80::1%]:8765").is_err());
+    }
+}
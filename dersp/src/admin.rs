@@ -0,0 +1,676 @@
+//! Admin HTTP API for operators, served on `--admin-listen`. `GET /metrics` answers in the
+//! Prometheus text format, the other endpoints in JSON. There's no authentication, the API is
+//! meant for private addresses.
+//!
+//! - `GET /top-talkers?n=10`: the local peers most bytes were forwarded to
+//! - `GET /peers/{key}`: whether the peer with the hex key is connected, and where
+//! - `GET /handshakes`: the connections that haven't completed their handshake, oldest first
+//! - `DELETE /handshakes/{addr}`: closes the connection from `addr` if it's still in its
+//!   handshake
+//! - `GET /asymmetric-peers?min_bytes=1048576&ratio=10`: the local peers whose traffic mostly
+//!   goes one way, most lopsided first
+
+use crate::{
+    crypto::PublicKey,
+    service::{DerpService, BUILD_INFO},
+};
+use anyhow::{bail, ensure};
+use httparse::Status;
+use log::{debug, info};
+use serde_json::{json, Value};
+use std::{
+    fmt::{Display, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Weak,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::RwLock,
+    task::JoinHandle,
+    time::timeout,
+};
+
+/// Largest request read, the API takes no request bodies
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Time a connection has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Top talkers listed by `/top-talkers` without `n`
+const DEFAULT_TOP_TALKERS: usize = 10;
+/// Top talkers with a `dersp_top_talker_bytes` gauge, more would bloat every scrape
+const TOP_TALKER_GAUGES: usize = 10;
+/// Bytes a peer must have moved one way to be listed by `/asymmetric-peers` without `min_bytes`
+const DEFAULT_ASYMMETRY_MIN_BYTES: u64 = 1 << 20;
+/// How lopsided a peer's traffic must be to be listed by `/asymmetric-peers` without `ratio`
+const DEFAULT_ASYMMETRY_RATIO: u64 = 10;
+
+/// The admin API served in the background, stopped when dropped
+#[derive(Debug)]
+pub struct AdminServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl AdminServer {
+    /// Serves the API of `service` on `listener` until it's dropped or the service is gone
+    pub fn start(
+        listener: TcpListener,
+        service: Weak<RwLock<DerpService>>,
+    ) -> anyhow::Result<Self> {
+        let addr = listener.local_addr()?;
+        info!("Serving the admin API on {addr}");
+        let task = spawn(async move {
+            loop {
+                let Ok((stream, peer_addr)) = listener.accept().await else {
+                    continue;
+                };
+                let service = service.clone();
+                spawn(async move {
+                    if let Err(e) = serve(stream, service).await {
+                        debug!("Admin request from {peer_addr} failed: {e}");
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// Address the API is served on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answers one request, the connection is closed after it
+async fn serve(mut stream: TcpStream, service: Weak<RwLock<DerpService>>) -> anyhow::Result<()> {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    let (method, target) = loop {
+        let read = timeout(REQUEST_TIMEOUT, stream.read(&mut buf[len..])).await??;
+        ensure!(read > 0, "Closed before the request was complete");
+        len += read;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        if let Status::Complete(_) = request.parse(&buf[..len])? {
+            let (Some(method), Some(target)) = (request.method, request.path) else {
+                bail!("Request without a method or path");
+            };
+            break (method.to_owned(), target.to_owned());
+        }
+        ensure!(len < buf.len(), "Request over {MAX_REQUEST_SIZE} bytes");
+    };
+    let response = match service.upgrade() {
+        Some(service) => route(&*service.read().await, &method, &target),
+        None => Response::text("503 Service Unavailable", "The service stopped\n"),
+    };
+    stream.write_all(&response.encode()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn route(service: &DerpService, method: &str, target: &str) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let result = match (method, segments.as_slice()) {
+        ("GET", ["metrics"]) => Ok(Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: metrics(service),
+        }),
+        ("GET", ["top-talkers"]) => top_talkers(service, query),
+        ("GET", ["peers", key]) => peer(service, key),
+        ("GET", ["handshakes"]) => Ok(handshakes(service)),
+        ("DELETE", ["handshakes", addr]) => cancel_handshake(service, addr),
+        ("GET", ["asymmetric-peers"]) => asymmetric_peers(service, query),
+        _ => Err(Response::text("404 Not Found", "No such endpoint\n")),
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+fn top_talkers(service: &DerpService, query: &str) -> Result<Response, Response> {
+    let n = param(query, "n", DEFAULT_TOP_TALKERS)?;
+    let talkers: Vec<Value> = service
+        .top_talkers(n)
+        .into_iter()
+        .map(|(pk, bytes)| json!({ "key": pk, "bytes": bytes }))
+        .collect();
+    Ok(Response::json(&Value::from(talkers)))
+}
+
+fn peer(service: &DerpService, key: &str) -> Result<Response, Response> {
+    let pk: PublicKey = key
+        .parse()
+        .map_err(|e| Response::text("400 Bad Request", &format!("Bad key {key}: {e}\n")))?;
+    let presence = service.presence(&pk);
+    Ok(Response::json(&json!({
+        "connected": presence.is_some(),
+        "via": presence.and_then(|presence| presence.via),
+        "since": presence.map(|presence| unix_seconds(presence.since)),
+        "preferred": presence.is_some_and(|presence| presence.preferred),
+    })))
+}
+
+fn handshakes(service: &DerpService) -> Response {
+    let handshakes: Vec<Value> = service
+        .handshakes()
+        .into_iter()
+        .map(|handshake| {
+            json!({
+                "peer_addr": handshake.peer_addr.to_string(),
+                "started": unix_seconds(handshake.started),
+            })
+        })
+        .collect();
+    Response::json(&Value::from(handshakes))
+}
+
+fn cancel_handshake(service: &DerpService, addr: &str) -> Result<Response, Response> {
+    let peer_addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| Response::text("400 Bad Request", &format!("Bad address {addr}\n")))?;
+    if !service.cancel_handshake(peer_addr) {
+        return Err(Response::text(
+            "404 Not Found",
+            &format!("No handshake with {peer_addr}\n"),
+        ));
+    }
+    Ok(Response::json(
+        &json!({ "cancelled": peer_addr.to_string() }),
+    ))
+}
+
+fn asymmetric_peers(service: &DerpService, query: &str) -> Result<Response, Response> {
+    let min_bytes = param(query, "min_bytes", DEFAULT_ASYMMETRY_MIN_BYTES)?;
+    let ratio = param(query, "ratio", DEFAULT_ASYMMETRY_RATIO)?;
+    let peers: Vec<Value> = service
+        .asymmetric_peers(min_bytes, ratio)
+        .into_iter()
+        .map(|traffic| {
+            json!({
+                "peer": traffic.peer,
+                "sent": traffic.sent,
+                "received": traffic.received,
+            })
+        })
+        .collect();
+    Ok(Response::json(&Value::from(peers)))
+}
+
+/// Seconds since the Unix epoch, times before it are 0
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The query parameter `name`, `default` without it. Bad values are a 400.
+fn param<T: FromStr>(query: &str, name: &str, default: T) -> Result<T, Response> {
+    let value = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value));
+    match value {
+        Some(value) => value.parse().map_err(|_| {
+            Response::text(
+                "400 Bad Request",
+                &format!("Bad value for {name}: {value}\n"),
+            )
+        }),
+        None => Ok(default),
+    }
+}
+
+/// The metrics of `service` in the Prometheus text format
+fn metrics(service: &DerpService) -> String {
+    let mut metrics = Metrics::default();
+
+    metrics.family(
+        "dersp_build_info",
+        "gauge",
+        "Build of the running server, always 1",
+    );
+    metrics.sample(
+        "dersp_build_info",
+        &[
+            ("version", BUILD_INFO.version),
+            ("git_hash", BUILD_INFO.git_hash.unwrap_or_default()),
+        ],
+        1,
+    );
+
+    metrics.family(
+        "dersp_uptime_seconds",
+        "gauge",
+        "Time since the service started",
+    );
+    metrics.sample("dersp_uptime_seconds", &[], service.uptime().as_secs_f64());
+
+    metrics.family(
+        "dersp_clients",
+        "gauge",
+        "Local clients by the protocol version they connected with",
+    );
+    for (version, clients) in service.protocol_versions() {
+        let version = version.to_string();
+        metrics.sample("dersp_clients", &[("version", &version)], clients);
+    }
+
+    metrics.family(
+        "dersp_bytes_forwarded_total",
+        "counter",
+        "Payload bytes forwarded since the service started",
+    );
+    metrics.sample(
+        "dersp_bytes_forwarded_total",
+        &[],
+        service.bytes_forwarded(),
+    );
+    metrics.family(
+        "dersp_bytes_forwarded_per_second",
+        "gauge",
+        "Payload bytes forwarded per second, averaged over the last second",
+    );
+    metrics.sample(
+        "dersp_bytes_forwarded_per_second",
+        &[],
+        service.forwarding_rate(),
+    );
+
+    let drops = service.packets_dropped();
+    metrics.family(
+        "dersp_packets_dropped_total",
+        "counter",
+        "Packets that weren't forwarded, by reason",
+    );
+    for (reason, count) in [
+        ("unknown_destination", drops.unknown_destination),
+        ("queue_overflow", drops.queue_overflow),
+        ("ttl_expired", drops.ttl_expired),
+        ("oversize", drops.oversize),
+        ("slow_destination", drops.slow_destination),
+        ("forwarding_disabled", drops.forwarding_disabled),
+    ] {
+        metrics.sample("dersp_packets_dropped_total", &[("reason", reason)], count);
+    }
+
+    metrics.family(
+        "dersp_top_talker_bytes",
+        "gauge",
+        "Bytes forwarded to the local peers most was forwarded to, by key prefix",
+    );
+    for (pk, bytes) in service.top_talkers(TOP_TALKER_GAUGES) {
+        let key = pk.log_display().to_string();
+        metrics.sample("dersp_top_talker_bytes", &[("key", &key)], bytes);
+    }
+
+    metrics.0
+}
+
+/// Writes metrics in the Prometheus text format
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    /// Starts the metric `name`, its samples follow
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.to_owned(),
+        }
+    }
+
+    fn json(value: &Value) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body: value.to_string(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::DerpClient,
+        crypto::SecretKey,
+        proto::data::PROTOCOL_VERSION,
+        test_utils::{start_service, wait_for_peer},
+    };
+    use tokio::time::sleep;
+
+    /// Sends a request to the admin API of `service`, returns the status line and the body
+    async fn request(
+        service: &RwLock<DerpService>,
+        method: &str,
+        target: &str,
+    ) -> (String, String) {
+        let addr = service.read().await.admin_addr().unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{method} {target} HTTP/1.1\r\nHost: dersp\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_owned();
+        (status, body.to_owned())
+    }
+
+    /// Scrapes the metrics until `line` is among them
+    async fn wait_for_metric(service: &RwLock<DerpService>, line: &str) {
+        for _ in 0..500 {
+            let (_, metrics) = request(service, "GET", "/metrics").await;
+            if metrics.lines().any(|sample| sample == line) {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{line:?} was never among the metrics");
+    }
+
+    #[tokio::test]
+    async fn drops_are_counted_by_reason() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+
+        client
+            .send_packet(SecretKey::gen().public(), b"nobody".to_vec())
+            .await
+            .unwrap();
+        wait_for_metric(
+            &service,
+            "dersp_packets_dropped_total{reason=\"unknown_destination\"} 1",
+        )
+        .await;
+        let (status, metrics) = request(&service, "GET", "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(metrics.contains("# TYPE dersp_packets_dropped_total counter"));
+        assert!(metrics
+            .lines()
+            .any(|line| line == "dersp_packets_dropped_total{reason=\"oversize\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn top_talkers_are_listed_by_bytes() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let busy = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let quiet = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&sender, &busy, &quiet] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+
+        for (receiver, packets) in [(&quiet, 1), (&busy, 3)] {
+            for _ in 0..packets {
+                sender
+                    .send_packet(receiver.public_key(), vec![0; 100])
+                    .await
+                    .unwrap();
+                timeout(Duration::from_secs(5), receiver.recv_packet())
+                    .await
+                    .expect("packet should be relayed")
+                    .unwrap();
+            }
+        }
+
+        let (status, body) = request(&service, "GET", "/top-talkers?n=2").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let talkers: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            talkers,
+            json!([
+                { "key": busy.public_key(), "bytes": 300 },
+                { "key": quiet.public_key(), "bytes": 100 },
+            ])
+        );
+        let busy_key = busy.public_key().log_display();
+        wait_for_metric(
+            &service,
+            &format!("dersp_top_talker_bytes{{key=\"{busy_key}\"}} 300"),
+        )
+        .await;
+
+        let (status, _) = request(&service, "GET", "/top-talkers?n=many").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn build_info_and_uptime_are_scraped() {
+        let (service, _addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        sleep(Duration::from_millis(10)).await;
+
+        let (_, metrics) = request(&service, "GET", "/metrics").await;
+        let build_info = format!(
+            "dersp_build_info{{version=\"{}\",git_hash=\"{}\"}} 1",
+            BUILD_INFO.version,
+            BUILD_INFO.git_hash.unwrap_or_default()
+        );
+        assert!(metrics.lines().any(|line| line == build_info));
+        let uptime: f64 = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("dersp_uptime_seconds "))
+            .expect("uptime should be scraped")
+            .parse()
+            .unwrap();
+        assert!(uptime >= 0.01);
+    }
+
+    #[tokio::test]
+    async fn presence_of_a_peer_is_looked_up_by_hex_key() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+
+        let target = format!("/peers/{:x}", client.public_key());
+        let (status, body) = request(&service, "GET", &target).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let presence: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(presence["connected"], true);
+        assert_eq!(presence["via"], Value::Null);
+        assert_eq!(presence["preferred"], false);
+        let since = presence["since"].as_u64().unwrap();
+        assert!(since <= unix_seconds(SystemTime::now()));
+
+        let target = format!("/peers/{:x}", SecretKey::gen().public());
+        let (status, body) = request(&service, "GET", &target).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let presence: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(presence["connected"], false);
+        assert_eq!(presence["since"], Value::Null);
+
+        let (status, _) = request(&service, "GET", "/peers/nokey").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn clients_are_counted_by_protocol_version() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+
+        wait_for_metric(
+            &service,
+            &format!("dersp_clients{{version=\"{PROTOCOL_VERSION}\"}} 1"),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stuck_handshakes_are_listed_and_cancelled() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        // Never sends its upgrade request
+        let mut stuck = TcpStream::connect(addr).await.unwrap();
+        let peer_addr = stuck.local_addr().unwrap().to_string();
+        let mut listed = false;
+        for _ in 0..500 {
+            let (status, body) = request(&service, "GET", "/handshakes").await;
+            assert_eq!(status, "HTTP/1.1 200 OK");
+            let handshakes: Value = serde_json::from_str(&body).unwrap();
+            listed = handshakes
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|handshake| handshake["peer_addr"] == peer_addr.as_str());
+            if listed {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(listed, "the stuck handshake should be listed");
+
+        let target = format!("/handshakes/{peer_addr}");
+        let (status, _) = request(&service, "DELETE", &target).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), stuck.read(&mut buf))
+            .await
+            .expect("cancelling should close the connection");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        let (status, _) = request(&service, "DELETE", &target).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = request(&service, "DELETE", "/handshakes/nowhere").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn one_way_traffic_is_listed_as_asymmetric() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&sender, &receiver] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+        for _ in 0..4 {
+            sender
+                .send_packet(receiver.public_key(), vec![0; 100])
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("packet should be relayed")
+                .unwrap();
+        }
+
+        let (status, body) =
+            request(&service, "GET", "/asymmetric-peers?min_bytes=100&ratio=10").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let peers: Value = serde_json::from_str(&body).unwrap();
+        let peers = peers.as_array().unwrap();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&json!({
+            "peer": sender.public_key(),
+            "sent": 400,
+            "received": 0,
+        })));
+
+        // Under the default threshold
+        let (_, body) = request(&service, "GET", "/asymmetric-peers").await;
+        assert_eq!(body, "[]");
+        let (status, _) = request(&service, "GET", "/asymmetric-peers?ratio=even").await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn forwarded_bytes_are_scraped() {
+        let (service, addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&sender, &receiver] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+
+        sender
+            .send_packet(receiver.public_key(), vec![0; 100])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        wait_for_metric(&service, "dersp_bytes_forwarded_total 100").await;
+        let (_, metrics) = request(&service, "GET", "/metrics").await;
+        assert!(metrics.contains("# TYPE dersp_bytes_forwarded_per_second gauge"));
+        let rate: f64 = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("dersp_bytes_forwarded_per_second "))
+            .expect("the forwarding rate should be scraped")
+            .parse()
+            .unwrap();
+        assert!(rate >= 0.0);
+    }
+
+    #[tokio::test]
+    async fn unknown_endpoints_are_not_found() {
+        let (service, _addr) = start_service(&["--admin-listen", "127.0.0.1:0"]).await;
+        let (status, _) = request(&service, "GET", "/nothing").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = request(&service, "POST", "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
@@ -1,208 +1,3182 @@
 use crate::{
-    crypto::PublicKey,
-    inout::DerpReader,
-    proto::data::{ForwardPacket, Frame, FrameType, PeerPresent, RecvPacket, SendPacket},
-    proto::{write_forward_packet, write_peer_present},
+    config::{DestinationLimitAction, FrameRateAction},
+    crypto::{PublicKey, SecretKey},
+    inout::{BufferPool, ConnectionClosed, DerpReader},
+    proto::data::{
+        ForwardPacket, Frame, FrameType, MirrorPackets, NotePreferred, PeerGone, PeerGoneReason,
+        PeerPresent, Ping, Pong, RecvPacket, Restarting, ResumeToken, RosterComplete, SendAck,
+        SendPacket, SendPackets, SendStatus, MESH_TTL,
+    },
+    proto::{
+        connect_http, exchange_keys, read_server_info, trace_frame, websocket::Role,
+        write_note_preferred, write_ping, write_send_packet, write_send_packets, write_watch_conns,
+        ProtoError, Transport, Upgraded,
+    },
     service::ServiceCommand,
+    DestinationLimit, FrameRateLimit, FrameTrace, Timeouts,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
 use codec::{Decode, Encode, SizeWrapper};
-use log::{debug, trace, warn};
-use std::net::SocketAddr;
+use log::{debug, info, trace, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::pending,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    pin, select, spawn,
+    sync::{
+        mpsc::{
+            channel,
+            error::{SendError, TrySendError},
+            Receiver, Sender, WeakSender,
+        },
+        oneshot, Mutex, Notify, RwLock,
     },
-    spawn,
-    sync::mpsc::{channel, Receiver, Sender},
+    task::JoinHandle,
+    time::{error::Elapsed, sleep, sleep_until, timeout, timeout_at},
 };
 
+/// How many received packets are buffered until `recv_packet` is called
+const INBOUND_QUEUE_SIZE: usize = 64;
+
+/// How many control frames may wait for a client on top of its queued packets
+const CONTROL_LANE_SIZE: usize = 16;
+
+/// How many packets may wait for a connection, the service drops the ones that don't fit
+/// rather than waiting for a slow connection
+pub(crate) const DATA_LANE_SIZE: usize = 32;
+
+/// How long a client recycled after `--max-connection-bytes` is told to keep trying to
+/// reconnect, in milliseconds
+const RECYCLED_TRY_FOR_MS: u32 = 10_000;
+
+/// A batch of frames is written once it grows past this, even before the flush interval
+const MAX_WRITE_BATCH_SIZE: usize = 64 * 1024;
+
+/// Time a [`DerpClient`] waits for the TCP connection unless told otherwise
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a [`DerpClient`] waits for the server's side of the handshake unless told otherwise
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before each attempt of a [`ReconnectingClient`] to reconnect
+const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
 pub struct Client {
-    _peer: SocketAddr,
-    r: OwnedReadHalf,
-    w: OwnedWriteHalf,
+    r: BoxedReader,
+    w: BoxedWriter,
     pk: PublicKey,
     can_mesh: bool,
+    /// Protocol version from the ClientInfo, decides how ForwardPackets are framed
+    protocol_version: u32,
+    timeouts: Timeouts,
+    frame_trace: FrameTrace,
+    /// Read buffers shared with the other connections
+    buffer_pool: Arc<BufferPool>,
+    /// Whether recoverable protocol errors end the connection too
+    strict_protocol: bool,
+    destination_limit: DestinationLimit,
+    frame_rate_limit: FrameRateLimit,
+    /// Whether the client asked for a SendAck after each SendPacket
+    send_acks: bool,
+    /// Payload bytes the client may send before it's asked to reconnect
+    max_connection_bytes: Option<u64>,
+    /// Most destinations one SendPackets may name
+    max_fanout: u8,
 }
 
 impl Client {
-    pub fn new(socket: TcpStream, pk: PublicKey, can_mesh: bool) -> Result<Self> {
-        let _peer = socket.peer_addr()?;
-        let (r, w) = socket.into_split();
-        Ok(Self {
-            _peer,
-            r,
-            w,
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        pk: PublicKey,
+        can_mesh: bool,
+        protocol_version: u32,
+        timeouts: Timeouts,
+        frame_trace: FrameTrace,
+        buffer_pool: Arc<BufferPool>,
+        strict_protocol: bool,
+        destination_limit: DestinationLimit,
+        frame_rate_limit: FrameRateLimit,
+        send_acks: bool,
+        max_connection_bytes: Option<u64>,
+        max_fanout: u8,
+    ) -> Self {
+        let (r, w) = split(stream);
+        Self {
+            r: Box::new(r),
+            w: Box::new(w),
             pk,
             can_mesh,
-        })
+            protocol_version,
+            timeouts,
+            frame_trace,
+            buffer_pool,
+            strict_protocol,
+            destination_limit,
+            frame_rate_limit,
+            send_acks,
+            max_connection_bytes,
+            max_fanout,
+        }
     }
 
-    pub async fn run(
-        self,
-        command_sender: Sender<ServiceCommand>,
-    ) -> Result<Sender<WriteLoopCommands>> {
+    pub async fn run(self, command_sender: Sender<ServiceCommand>) -> Result<ClientSink> {
+        let liveness = Liveness::new(self.timeouts.liveness_timeout);
         let w = self.w;
-        let sink = Self::start_write_loop(w, self.pk, self.can_mesh);
-        let r = self.r;
-        Self::start_read_loop(r, self.pk, command_sender, self.can_mesh, sink.clone());
+        let (sink, write_stopped) = Self::start_write_loop(
+            w,
+            self.pk,
+            self.can_mesh,
+            self.protocol_version,
+            self.timeouts.write_timeout,
+            self.timeouts.write_flush_interval,
+            self.frame_trace,
+            liveness.clone(),
+            command_sender.clone(),
+        );
+        let r = ProgressReader {
+            inner: self.r,
+            liveness,
+        };
+        Self::start_read_loop(
+            r,
+            self.pk,
+            command_sender,
+            self.can_mesh,
+            self.protocol_version,
+            sink.clone(),
+            // Without --idle-timeout quiet clients stay connected
+            self.timeouts.idle_timeout.unwrap_or(Duration::MAX),
+            self.frame_trace,
+            self.buffer_pool,
+            self.strict_protocol,
+            self.destination_limit,
+            self.frame_rate_limit,
+            self.send_acks,
+            self.max_connection_bytes,
+            self.max_fanout,
+            write_stopped,
+        );
 
         Ok(sink)
     }
 
-    pub fn start_read_loop(
-        r: OwnedReadHalf,
+    #[allow(clippy::too_many_arguments)]
+    pub fn start_read_loop<R: AsyncRead + Send + Unpin + 'static>(
+        r: R,
         pk: PublicKey,
         command_sender: Sender<ServiceCommand>,
         can_mesh: bool,
-        our_sink: Sender<WriteLoopCommands>,
+        protocol_version: u32,
+        our_sink: ClientSink,
+        idle_timeout: Duration,
+        frame_trace: FrameTrace,
+        buffer_pool: Arc<BufferPool>,
+        strict_protocol: bool,
+        destination_limit: DestinationLimit,
+        frame_rate_limit: FrameRateLimit,
+        send_acks: bool,
+        max_connection_bytes: Option<u64>,
+        max_fanout: u8,
+        write_stopped: oneshot::Receiver<()>,
     ) {
         spawn(async move {
-            if let Err(e) = Self::read_loop(r, pk, command_sender, can_mesh, our_sink).await {
-                warn!("[{pk:?}] Read loop failed: {e}");
-                // TODO: close whole client?
+            let key = pk.log_display();
+            let read_loop = Self::read_loop(
+                r,
+                pk,
+                command_sender.clone(),
+                can_mesh,
+                protocol_version,
+                our_sink.clone(),
+                idle_timeout,
+                frame_trace,
+                buffer_pool,
+                strict_protocol,
+                destination_limit,
+                frame_rate_limit,
+                send_acks,
+                max_connection_bytes,
+                max_fanout,
+            );
+            let reason = select! {
+                result = read_loop => match result {
+                    Ok(reason) => reason,
+                    Err(e) => {
+                        warn!("[{key}] Read loop failed: {e}");
+                        PeerGoneReason::Disconnected
+                    }
+                },
+                _ = write_stopped => {
+                    // The write loop already reported why the client is gone
+                    debug!("[{key}] write loop stopped, stopping read loop");
+                    return;
+                }
+            };
+            if let Err(e) = command_sender
+                .send(ServiceCommand::PeerGone(pk, reason, our_sink.clone()))
+                .await
+            {
+                warn!("[{key}] Failed to report peer gone: {e}");
             }
+            // Others may still hold our sink, don't leave the write half waiting for them
+            let _ = our_sink.send(WriteLoopCommands::Stop).await;
         });
     }
 
-    pub async fn read_loop(
-        r: OwnedReadHalf,
+    #[allow(clippy::too_many_arguments)]
+    pub async fn read_loop<R: AsyncRead + Unpin>(
+        r: R,
         pk: PublicKey,
         command_sender: Sender<ServiceCommand>,
         can_mesh: bool,
-        our_sink: Sender<WriteLoopCommands>,
-    ) -> anyhow::Result<()> {
-        trace!("[{pk:?}] starting read loop");
-        let mut derp_reader = DerpReader::new(r);
+        protocol_version: u32,
+        our_sink: ClientSink,
+        idle_timeout: Duration,
+        frame_trace: FrameTrace,
+        buffer_pool: Arc<BufferPool>,
+        strict_protocol: bool,
+        destination_limit: DestinationLimit,
+        frame_rate_limit: FrameRateLimit,
+        send_acks: bool,
+        max_connection_bytes: Option<u64>,
+        max_fanout: u8,
+    ) -> anyhow::Result<PeerGoneReason> {
+        let key = pk.log_display();
+        trace!("[{key}] starting read loop");
+        let mut derp_reader = DerpReader::with_pool(r, buffer_pool);
+        derp_reader.track_buffered(our_sink.memory().input.clone());
+        let mut destinations = DestinationTracker::new(destination_limit);
+        let mut frame_rate = FrameRateLimiter::new(frame_rate_limit);
+        let mut skipped = 0;
+        let mut sent_bytes = 0;
 
         loop {
-            let message = derp_reader.get_next_message().await?;
-            trace!("[{pk:?}] next frame: {:?}", message.ty);
+            let message = match timeout(idle_timeout, derp_reader.get_next_message()).await {
+                Ok(Ok(message)) => message,
+                Ok(Err(e)) if e.is::<ConnectionClosed>() => {
+                    debug!("[{key}] connection closed");
+                    return Ok(PeerGoneReason::Disconnected);
+                }
+                Ok(Err(e)) => {
+                    // The reader skips the frames with a wrong length itself
+                    Self::skip_frame(&pk, e, strict_protocol, &mut skipped)?;
+                    continue;
+                }
+                Err(_) => {
+                    debug!("[{key}] idle for {idle_timeout:?}, disconnecting");
+                    return Ok(PeerGoneReason::IdleTimeout);
+                }
+            };
+            trace!("[{key}] next frame: {:?}", message.ty);
+            trace_frame(frame_trace, &pk, "received", &message.buffer);
+            if let Some(wait) = frame_rate.take() {
+                if frame_rate_limit.action == FrameRateAction::Disconnect {
+                    warn!("[{key}] over the frame rate limit, disconnecting");
+                    return Ok(PeerGoneReason::Disconnected);
+                }
+                trace!("[{key}] over the frame rate limit, throttling for {wait:?}");
+                sleep(wait).await;
+            }
 
-            match message.ty {
-                FrameType::SendPacket => {
-                    let send_packet = Frame::<SendPacket>::decode(&mut message.buffer.as_slice())
-                        .map_err(|_| anyhow!("Decode error"))?
-                        .inner
-                        .into_inner();
-                    let is_forward = send_packet.target != pk;
-                    debug!("[{pk:?}] send_packet: {send_packet:?}, can mesh: {can_mesh}, is forward: {is_forward}");
-                    command_sender
-                        .send(ServiceCommand::SendPacket {
-                            source: pk,
-                            target: send_packet.target,
-                            payload: send_packet.payload,
-                        })
-                        .await?;
-                }
-
-                FrameType::WatchConns => {
-                    if !can_mesh {
-                        // TODO: close this connection
-                    } else {
+            // Errors about this frame alone may be skipped, see `ProtoError::is_recoverable`
+            let handled = async {
+                match message.ty {
+                    FrameType::SendPacket | FrameType::SendPackets => {
+                        let (targets, payload) = if message.ty == FrameType::SendPacket {
+                            let send_packet =
+                                Frame::<SendPacket>::decode(&mut message.buffer.as_slice())
+                                    .map_err(|_| ProtoError::Malformed(FrameType::SendPacket))?
+                                    .inner
+                                    .into_inner();
+                            (vec![send_packet.target], send_packet.payload)
+                        } else {
+                            let send_packets = SendPackets::decode_from(&message.buffer)
+                                .map_err(|_| ProtoError::Malformed(FrameType::SendPackets))?;
+                            (send_packets.targets, send_packets.payload)
+                        };
+                        // Shared by the destinations instead of copied for each
+                        let payload = Bytes::from(payload);
+                        let ack = |target, status| {
+                            if send_acks {
+                                // Acks are for debugging, losing one is fine
+                                let _ =
+                                    our_sink.try_send(WriteLoopCommands::SendAck(target, status));
+                            }
+                        };
+                        if targets.len() > usize::from(max_fanout) {
+                            warn!(
+                                "[{key}] dropping packet to {} destinations, over the limit of \
+                                 {max_fanout}",
+                                targets.len()
+                            );
+                            for target in targets {
+                                ack(target, SendStatus::Dropped);
+                            }
+                            return Ok(());
+                        }
+                        let mut allowed = Vec::with_capacity(targets.len());
+                        for target in targets {
+                            let is_forward = target != pk;
+                            debug!(
+                                "[{key}] send_packet to {}, {} bytes, can mesh: \
+                                 {can_mesh}, is forward: {is_forward}",
+                                target.log_display(),
+                                payload.len()
+                            );
+                            if destinations.allow(pk, target) {
+                                allowed.push(target);
+                            } else {
+                                trace!("[{key}] dropping packet to new destination over the limit");
+                                ack(target, SendStatus::Dropped);
+                            }
+                        }
+                        sent_bytes += (payload.len() * allowed.len()) as u64;
+                        // The service fans out, so it can apply the overflow policy to all of them
+                        let command = match allowed.as_slice() {
+                            [] => return Ok(()),
+                            [target] => ServiceCommand::SendPacket {
+                                source: pk,
+                                target: *target,
+                                ttl: MESH_TTL,
+                                payload,
+                            },
+                            _ => ServiceCommand::SendPackets {
+                                source: pk,
+                                targets: allowed,
+                                payload,
+                            },
+                        };
+                        command_sender.send(command).await?;
+                    }
+
+                    FrameType::ForwardPacket => {
+                        if !can_mesh {
+                            warn!("[{key}] Refusing ForwardPacket without mesh privileges");
+                        } else {
+                            let forward_packet =
+                                ForwardPacket::decode_from(protocol_version, &message.buffer)
+                                    .map_err(|_| ProtoError::Malformed(FrameType::ForwardPacket))?;
+                            trace!(
+                                "[{key}] forwarded packet from {} to {}",
+                                forward_packet.source.log_display(),
+                                forward_packet.target.log_display()
+                            );
+                            command_sender
+                                .send(ServiceCommand::SendPacket {
+                                    source: forward_packet.source,
+                                    target: forward_packet.target,
+                                    ttl: forward_packet.ttl,
+                                    payload: forward_packet.payload.into(),
+                                })
+                                .await?;
+                        }
+                    }
+
+                    FrameType::WatchConns => {
+                        if !can_mesh {
+                            // TODO: close this connection
+                            warn!("[{key}] Refusing WatchConns without mesh privileges");
+                        } else {
+                            command_sender
+                                .send(ServiceCommand::SubscribeForPeerChanges(
+                                    pk,
+                                    our_sink.clone(),
+                                ))
+                                .await?;
+                        }
+                    }
+
+                    FrameType::MirrorPackets => {
+                        if !can_mesh {
+                            warn!("[{key}] Refusing MirrorPackets without mesh privileges");
+                        } else {
+                            let mirrored =
+                                Frame::<MirrorPackets>::decode(&mut message.buffer.as_slice())
+                                    .map_err(|_| ProtoError::Malformed(FrameType::MirrorPackets))?
+                                    .inner
+                                    .into_inner()
+                                    .public_key;
+                            command_sender
+                                .send(ServiceCommand::MirrorPackets(
+                                    pk,
+                                    mirrored,
+                                    our_sink.clone(),
+                                ))
+                                .await?;
+                        }
+                    }
+
+                    FrameType::PeerPresent => {
+                        let peer_present =
+                            Frame::<PeerPresent>::decode(&mut message.buffer.as_slice())
+                                .map_err(|_| ProtoError::Malformed(FrameType::PeerPresent))?
+                                .inner
+                                .into_inner();
+                        debug!(
+                            "[{key}] will handle messages for {} (can mesh: {can_mesh})",
+                            peer_present.public_key.log_display(),
+                        );
                         command_sender
-                            .send(ServiceCommand::SubscribeForPeerChanges(
+                            .send(ServiceCommand::PeerPresent(
+                                peer_present.public_key,
                                 pk,
                                 our_sink.clone(),
                             ))
+                            .await
+                            .unwrap();
+                    }
+
+                    FrameType::PeerGone => {
+                        let peer_gone = Frame::<PeerGone>::decode(&mut message.buffer.as_slice())
+                            .map_err(|_| ProtoError::Malformed(FrameType::PeerGone))?
+                            .inner
+                            .into_inner();
+                        let reason = peer_gone.reason.unwrap_or(PeerGoneReason::Disconnected);
+                        debug!(
+                            "[{key}] {} is gone ({reason:?}, can mesh: {can_mesh})",
+                            peer_gone.public_key.log_display(),
+                        );
+                        command_sender
+                            .send(ServiceCommand::PeerGone(
+                                peer_gone.public_key,
+                                reason,
+                                our_sink.clone(),
+                            ))
                             .await?;
                     }
-                }
 
-                FrameType::PeerPresent => {
-                    let peer_present = Frame::<PeerPresent>::decode(&mut message.buffer.as_slice())
-                        .map_err(|_| anyhow!("Decode error"))?
-                        .inner
-                        .into_inner();
-                    debug!(
-                        "[{pk:?}] will handle messages for {:?} (can mesh: {can_mesh})",
-                        peer_present.public_key,
-                    );
-                    command_sender
-                        .send(ServiceCommand::PeerPresent(
-                            peer_present.public_key,
-                            our_sink.clone(),
-                        ))
-                        .await
-                        .unwrap();
+                    FrameType::RosterComplete => {
+                        if !can_mesh {
+                            warn!("[{key}] Refusing RosterComplete without mesh privileges");
+                        } else {
+                            debug!("[{key}] sent its whole roster");
+                            command_sender
+                                .send(ServiceCommand::RosterComplete(pk, our_sink.clone()))
+                                .await?;
+                        }
+                    }
+
+                    FrameType::NotePreferred => {
+                        let preferred =
+                            Frame::<NotePreferred>::decode(&mut message.buffer.as_slice())
+                                .map_err(|_| ProtoError::Malformed(FrameType::NotePreferred))?
+                                .inner
+                                .into_inner()
+                                .is_preferred();
+                        debug!("[{key}] preferred: {preferred}");
+                        command_sender
+                            .send(ServiceCommand::NotePreferred(
+                                pk,
+                                preferred,
+                                our_sink.clone(),
+                            ))
+                            .await?;
+                    }
+
+                    FrameType::Ping => {
+                        let ping = Frame::<Ping>::decode(&mut message.buffer.as_slice())
+                            .map_err(|_| ProtoError::Malformed(FrameType::Ping))?
+                            .inner
+                            .into_inner();
+                        trace!("[{key}] ping");
+                        our_sink.send(WriteLoopCommands::Pong(ping.data)).await?;
+                    }
+
+                    // Every frame resets the idle timeout, there's nothing else to do
+                    FrameType::KeepAlive => {}
+
+                    frame_type => return Err(ProtoError::UnexpectedFrame(frame_type).into()),
                 }
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = handled {
+                Self::skip_frame(&pk, e, strict_protocol, &mut skipped)?;
+            }
+            if max_connection_bytes.is_some_and(|max| sent_bytes >= max) {
+                info!("[{key}] sent {sent_bytes} bytes, asking it to reconnect");
+                let restarting = Restarting {
+                    reconnect_in: 0,
+                    try_for: RECYCLED_TRY_FOR_MS,
+                };
+                let mut frame = Vec::new();
+                restarting.frame().encode(&mut frame)?;
+                // Queued ahead of the stop that closes the connection
+                our_sink.send(WriteLoopCommands::Frame(frame)).await?;
+                return Ok(PeerGoneReason::Recycled);
+            }
+        }
+    }
 
-                frame_type => todo!("frame type: {frame_type:?}"),
+    /// Returns `error` unless the frame it's about can be skipped, counting it in `skipped`
+    fn skip_frame(
+        pk: &PublicKey,
+        error: anyhow::Error,
+        strict_protocol: bool,
+        skipped: &mut u64,
+    ) -> anyhow::Result<()> {
+        match error.downcast_ref::<ProtoError>() {
+            Some(proto_error) if !strict_protocol && proto_error.is_recoverable() => {
+                *skipped += 1;
+                debug!(
+                    "[{}] skipping frame ({skipped} so far): {proto_error}",
+                    pk.log_display()
+                );
+                Ok(())
             }
+            _ => Err(error),
         }
     }
 
-    pub fn start_write_loop(
-        w: OwnedWriteHalf,
+    #[allow(clippy::too_many_arguments)]
+    pub fn start_write_loop<W: AsyncWrite + Send + Unpin + 'static>(
+        w: W,
         pk: PublicKey,
         can_mesh: bool,
-    ) -> Sender<WriteLoopCommands> {
-        let (s, r) = channel(1);
+        protocol_version: u32,
+        write_timeout: Duration,
+        flush_interval: Duration,
+        frame_trace: FrameTrace,
+        liveness: Arc<Liveness>,
+        command_sender: Sender<ServiceCommand>,
+    ) -> (ClientSink, oneshot::Receiver<()>) {
+        let (s, r) = write_lanes(DATA_LANE_SIZE);
+        let our_sink = s.downgrade();
+        // Dropped when the write loop ends, which stops the read loop as well
+        let (write_stopped, write_stopped_receiver) = oneshot::channel::<()>();
+
+        spawn(async move {
+            let key = pk.log_display();
+            let _write_stopped = write_stopped;
+            if let Err(e) = Self::write_loop(
+                r,
+                w,
+                pk,
+                can_mesh,
+                protocol_version,
+                write_timeout,
+                flush_interval,
+                frame_trace,
+                &liveness,
+            )
+            .await
+            {
+                warn!("[{key}] Write loop failed: {e}");
+                let reason = if e.is::<Elapsed>() {
+                    PeerGoneReason::WriteTimeout
+                } else if e.is::<Evicted>() {
+                    PeerGoneReason::Evicted
+                } else if let Some(NoProgress(reason)) = e.downcast_ref() {
+                    *reason
+                } else {
+                    PeerGoneReason::Disconnected
+                };
+                Self::report_write_failure(pk, reason, our_sink, command_sender).await;
+            }
+        });
 
-        spawn(Self::write_loop(r, w, pk, can_mesh));
+        (s, write_stopped_receiver)
+    }
 
-        s
+    async fn report_write_failure(
+        pk: PublicKey,
+        reason: PeerGoneReason,
+        our_sink: WeakClientSink,
+        command_sender: Sender<ServiceCommand>,
+    ) {
+        // Nobody can reach this client anymore, nothing to clean up
+        let Some(our_sink) = our_sink.upgrade() else {
+            return;
+        };
+        let key = pk.log_display();
+        if let Err(e) = command_sender
+            .send(ServiceCommand::PeerGone(pk, reason, our_sink))
+            .await
+        {
+            warn!("[{key}] Failed to report peer gone: {e}");
+        }
     }
-    pub async fn write_loop(
-        mut r: Receiver<WriteLoopCommands>,
-        mut w: OwnedWriteHalf,
+
+    /// Writes the commands of `r` to `w`. Commands arriving within `flush_interval` of the
+    /// first one go out in a single write.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn write_loop<W: AsyncWrite + Unpin>(
+        mut r: WriteLanes,
+        mut w: W,
         pk: PublicKey,
         can_mesh: bool,
+        protocol_version: u32,
+        write_timeout: Duration,
+        flush_interval: Duration,
+        frame_trace: FrameTrace,
+        liveness: &Liveness,
     ) -> anyhow::Result<()> {
+        let key = pk.log_display();
+        let memory = r.memory.clone();
         loop {
-            match r.recv().await {
-                Some(WriteLoopCommands::SendPacket {
-                    source,
-                    target,
-                    payload,
-                }) => match (can_mesh, target != pk) {
-                    (true, true) => {
-                        trace!("[{pk:?}] Will forward packet from {source:?} to {target:?}");
-                        let forward_packet = ForwardPacket::new(source, target, payload);
-                        write_forward_packet(&mut w, forward_packet).await?;
-                    }
-
-                    (_, false) => {
-                        let mut writing_buffer = Vec::new();
-                        trace!("[{pk:?}] Will send {} bytes to {target}", payload.len());
-                        let frame = Frame {
-                            frame_type: FrameType::RecvPacket,
-                            inner: SizeWrapper::new(RecvPacket { target, payload }),
-                        };
-                        frame.encode(&mut writing_buffer)?;
-                        w.write_all(&writing_buffer)
-                            .await
-                            .map_err(|e| anyhow!("{e}"))?;
-                    }
-
-                    (false, true) => todo!(),
-                },
-                Some(WriteLoopCommands::_Stop) => {
-                    debug!("[{pk:?}] write loop stopping");
+            let command = select! {
+                command = r.recv() => command,
+                reason = liveness.expired() => return Err(NoProgress(reason).into()),
+                _ = memory.evicted() => return Err(Evicted.into()),
+            };
+            let mut batch = Vec::new();
+            let stopping = match command {
+                Some(WriteLoopCommands::Stop) => {
+                    debug!("[{key}] write loop stopping");
                     return Ok(());
                 }
-                Some(WriteLoopCommands::PeerPresent(pk)) => {
-                    trace!("[{pk:?}] Sending peer present with {pk}");
-                    write_peer_present(&mut w, &pk).await?;
+                Some(command) => {
+                    Self::encode_command(
+                        &mut batch,
+                        pk,
+                        can_mesh,
+                        protocol_version,
+                        frame_trace,
+                        command,
+                    )?;
+                    let mut stopping = false;
+                    if !flush_interval.is_zero() {
+                        let deadline = sleep(flush_interval);
+                        pin!(deadline);
+                        while batch.len() < MAX_WRITE_BATCH_SIZE {
+                            let command = select! {
+                                command = r.recv() => command,
+                                _ = &mut deadline => break,
+                            };
+                            match command {
+                                Some(WriteLoopCommands::Stop) | None => {
+                                    stopping = true;
+                                    break;
+                                }
+                                Some(command) => Self::encode_command(
+                                    &mut batch,
+                                    pk,
+                                    can_mesh,
+                                    protocol_version,
+                                    frame_trace,
+                                    command,
+                                )?,
+                            }
+                        }
+                    }
+                    stopping
                 }
                 None => {
-                    debug!("[{pk:?}] write loop stopping (no more commands)");
+                    debug!("[{key}] write loop stopping (no more commands)");
                     return Ok(());
                 }
+            };
+            if !batch.is_empty() {
+                liveness.write_started();
+                memory.add(batch.len());
+                let write = timeout(write_timeout, w.write_all(&batch));
+                select! {
+                    result = write => result
+                        .with_context(|| format!("Write timed out after {write_timeout:?}"))?
+                        .map_err(|e| anyhow!("{e}"))?,
+                    reason = liveness.expired() => return Err(NoProgress(reason).into()),
+                    _ = memory.evicted() => return Err(Evicted.into()),
+                }
+                memory.sub(batch.len());
+                liveness.write_done();
             }
+            if stopping {
+                debug!("[{key}] write loop stopping after the last batch");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Appends the frame of `command` to `batch`, unless the client is too old to know it
+    fn encode_command(
+        batch: &mut Vec<u8>,
+        pk: PublicKey,
+        can_mesh: bool,
+        protocol_version: u32,
+        frame_trace: FrameTrace,
+        command: WriteLoopCommands,
+    ) -> anyhow::Result<()> {
+        let key = pk.log_display();
+        let mut writing_buffer = Vec::new();
+        match command {
+            WriteLoopCommands::SendPacket {
+                source,
+                target,
+                ttl,
+                payload,
+            } => match (can_mesh, target != pk) {
+                (true, true) => {
+                    trace!(
+                        "[{key}] Will forward packet from {} to {}",
+                        source.log_display(),
+                        target.log_display()
+                    );
+                    ForwardPacket::new(source, target, ttl, payload.into())
+                        .encode_for(protocol_version, &mut writing_buffer)?;
+                }
+
+                (_, false) => {
+                    trace!("[{key}] Will send {} bytes to {target}", payload.len());
+                    let frame = Frame {
+                        frame_type: FrameType::RecvPacket,
+                        inner: SizeWrapper::new(RecvPacket {
+                            source,
+                            payload: payload.into(),
+                        }),
+                    };
+                    frame.encode(&mut writing_buffer)?;
+                }
+
+                (false, true) => todo!(),
+            },
+            WriteLoopCommands::PeerPresent(peer) => {
+                trace!("[{key}] Sending peer present with {peer}");
+                Frame {
+                    frame_type: FrameType::PeerPresent,
+                    inner: SizeWrapper::new(PeerPresent { public_key: peer }),
+                }
+                .encode(&mut writing_buffer)?;
+            }
+            WriteLoopCommands::PeerGone(peer, reason) => {
+                trace!("[{key}] Sending peer gone with {peer} ({reason:?})");
+                PeerGone::new(peer, reason.for_version(protocol_version))
+                    .frame()
+                    .encode(&mut writing_buffer)?;
+            }
+            WriteLoopCommands::Pong(data) => {
+                trace!("[{key}] Sending pong");
+                Pong { data }.frame().encode(&mut writing_buffer)?;
+            }
+            WriteLoopCommands::Frame(frame) => writing_buffer = frame,
+            WriteLoopCommands::RosterComplete => {
+                trace!("[{key}] Sending roster complete");
+                RosterComplete::default()
+                    .frame()
+                    .encode(&mut writing_buffer)?;
+            }
+            WriteLoopCommands::SendAck(target, status) => {
+                trace!("[{key}] Sending ack for {target} ({status:?})");
+                SendAck { target, status }
+                    .frame()
+                    .encode(&mut writing_buffer)?;
+            }
+            WriteLoopCommands::Stop => return Ok(()),
         }
+        let ty = FrameType::get_frame_type(&writing_buffer);
+        if ty.min_version() > protocol_version {
+            trace!("[{key}] Not sending {ty:?} to a client speaking version {protocol_version}");
+            return Ok(());
+        }
+        trace_frame(frame_trace, &pk, "sent", &writing_buffer);
+        batch.extend_from_slice(&writing_buffer);
+        Ok(())
     }
 }
 
+/// Forward progress of a connection in either direction, for `--liveness-timeout`
 #[derive(Debug)]
+pub struct Liveness {
+    timeout: Option<Duration>,
+    started: Instant,
+    /// Milliseconds from `started` to the last progress
+    last_progress: AtomicU64,
+    /// Reads don't count as progress while a write is stuck
+    writing: AtomicBool,
+}
+
+impl Liveness {
+    /// Never expires without a `timeout`
+    pub fn new(timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            timeout,
+            started: Instant::now(),
+            last_progress: AtomicU64::new(0),
+            writing: AtomicBool::new(false),
+        })
+    }
+
+    fn progressed(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_progress.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn read(&self) {
+        if !self.writing.load(Ordering::Relaxed) {
+            self.progressed();
+        }
+    }
+
+    pub fn write_started(&self) {
+        self.writing.store(true, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    pub fn write_done(&self) {
+        self.writing.store(false, Ordering::Relaxed);
+        self.progressed();
+    }
+
+    /// Resolves once there was no progress for the timeout, with the reason to report
+    pub async fn expired(&self) -> PeerGoneReason {
+        let Some(timeout) = self.timeout else {
+            return pending().await;
+        };
+        loop {
+            let last_progress =
+                self.started + Duration::from_millis(self.last_progress.load(Ordering::Relaxed));
+            if last_progress.elapsed() >= timeout {
+                return if self.writing.load(Ordering::Relaxed) {
+                    PeerGoneReason::WriteTimeout
+                } else {
+                    PeerGoneReason::IdleTimeout
+                };
+            }
+            sleep_until((last_progress + timeout).into()).await;
+        }
+    }
+}
+
+/// Distinct destinations a client sent to in the current window, see [`DestinationLimit`]
+struct DestinationTracker {
+    limit: DestinationLimit,
+    started: Instant,
+    seen: HashSet<PublicKey>,
+    /// Whether the client was logged in the current window
+    flagged: bool,
+}
+
+impl DestinationTracker {
+    fn new(limit: DestinationLimit) -> Self {
+        Self {
+            limit,
+            started: Instant::now(),
+            seen: HashSet::new(),
+            flagged: false,
+        }
+    }
+
+    /// Whether the packet of `source` to `target` may go through
+    fn allow(&mut self, source: PublicKey, target: PublicKey) -> bool {
+        let Some(max) = self.limit.max else {
+            return true;
+        };
+        if self.started.elapsed() >= self.limit.window {
+            self.started = Instant::now();
+            self.seen.clear();
+            self.flagged = false;
+        }
+        if self.seen.contains(&target) {
+            return true;
+        }
+        if self.seen.len() < max {
+            self.seen.insert(target);
+            return true;
+        }
+        if !self.flagged {
+            warn!(
+                "[{}] sent to over {max} destinations within {:?}, possibly scanning",
+                source.log_display(),
+                self.limit.window
+            );
+            self.flagged = true;
+        }
+        self.limit.action == DestinationLimitAction::Flag
+    }
+}
+
+/// Token bucket of the frames a client may send, see [`FrameRateLimit`]
+struct FrameRateLimiter {
+    limit: FrameRateLimit,
+    /// Negative once the client is throttled, until it waited long enough
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl FrameRateLimiter {
+    fn new(limit: FrameRateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.max.map_or(0.0, |max| max.get().into()),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a received frame, returns how long until the client is back under
+    /// the limit if it's over
+    fn take(&mut self) -> Option<Duration> {
+        let rate = f64::from(self.limit.max?.get());
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - 1.0;
+        self.refilled = now;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / rate))
+    }
+}
+
+/// The connection made no progress for the liveness timeout
+#[derive(Debug, thiserror::Error)]
+#[error("No progress, disconnecting ({0:?})")]
+struct NoProgress(PeerGoneReason);
+
+/// The connection was evicted to bring the server back within `--memory-budget`
+#[derive(Debug, thiserror::Error)]
+#[error("Evicted, the server is over its memory budget")]
+struct Evicted;
+
+/// Approximate memory held for a connection: payloads waiting in its write lanes, the batch
+/// being written and the frame being read
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    bytes: AtomicUsize,
+    /// Kept up to date by the connection's [`DerpReader`]
+    input: Arc<AtomicUsize>,
+    evicted: AtomicBool,
+    eviction: Notify,
+}
+
+impl MemoryUsage {
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed) + self.input.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Disconnects the connection, returns false if it was evicted already
+    pub fn evict(&self) -> bool {
+        let first = !self.evicted.swap(true, Ordering::Relaxed);
+        self.eviction.notify_waiters();
+        first
+    }
+
+    /// Resolves once the connection was evicted
+    async fn evicted(&self) {
+        loop {
+            let eviction = self.eviction.notified();
+            pin!(eviction);
+            eviction.as_mut().enable();
+            if self.evicted.load(Ordering::Relaxed) {
+                return;
+            }
+            eviction.await;
+        }
+    }
+}
+
+/// Counts every successful read as progress of the connection
+struct ProgressReader<R> {
+    inner: R,
+    liveness: Arc<Liveness>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            self.liveness.read();
+        }
+        poll
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum WriteLoopCommands {
     SendPacket {
         source: PublicKey,
         target: PublicKey,
-        payload: Vec<u8>,
+        /// Mesh hops left, only used when forwarding to another relay
+        ttl: u8,
+        /// Shared with the other destinations of the packet
+        payload: Bytes,
     },
     PeerPresent(PublicKey),
-    _Stop,
+    PeerGone(PublicKey, PeerGoneReason),
+    /// Reply to a Ping, with its payload
+    Pong([u8; 8]),
+    /// An already encoded frame, sent as is
+    Frame(Vec<u8>),
+    /// The PeerPresents answering a WatchConns are all sent
+    RosterComplete,
+    /// What became of a packet the client sent to the given target, if it asked for acks
+    SendAck(PublicKey, SendStatus),
+    Stop,
+}
+
+impl WriteLoopCommands {
+    /// Bytes the command holds while it's queued, only payloads are counted
+    fn queued_size(&self) -> usize {
+        match self {
+            WriteLoopCommands::SendPacket { payload, .. } => payload.len(),
+            WriteLoopCommands::Frame(frame) => frame.len(),
+            _ => 0,
+        }
+    }
+
+    /// Control frames are small and time sensitive, they skip the queued packets
+    fn is_control(&self) -> bool {
+        matches!(
+            self,
+            WriteLoopCommands::PeerPresent(_)
+                | WriteLoopCommands::PeerGone(..)
+                | WriteLoopCommands::Pong(_)
+                | WriteLoopCommands::RosterComplete
+                | WriteLoopCommands::SendAck(..)
+        )
+    }
+}
+
+/// Creates the two lanes feeding a write loop, `capacity` commands fit in the data lane
+pub fn write_lanes(capacity: usize) -> (ClientSink, WriteLanes) {
+    let (control, control_receiver) = channel(CONTROL_LANE_SIZE);
+    let (data, data_receiver) = channel(capacity);
+    let memory = Arc::new(MemoryUsage::default());
+    (
+        ClientSink {
+            control,
+            data,
+            memory: memory.clone(),
+        },
+        WriteLanes {
+            control: control_receiver,
+            data: data_receiver,
+            memory,
+        },
+    )
+}
+
+/// Sending half of a write loop, picks the lane of each command
+#[derive(Debug, Clone)]
+pub struct ClientSink {
+    control: Sender<WriteLoopCommands>,
+    data: Sender<WriteLoopCommands>,
+    memory: Arc<MemoryUsage>,
+}
+
+impl ClientSink {
+    pub async fn send(
+        &self,
+        command: WriteLoopCommands,
+    ) -> Result<(), SendError<WriteLoopCommands>> {
+        // Counted before it's queued, the write loop may take it right away
+        let size = command.queued_size();
+        self.memory.add(size);
+        let sent = if command.is_control() {
+            self.control.send(command).await
+        } else {
+            self.data.send(command).await
+        };
+        if sent.is_err() {
+            self.memory.sub(size);
+        }
+        sent
+    }
+
+    /// Like [`send`](Self::send) without waiting for room, for traffic that may be lost
+    pub fn try_send(
+        &self,
+        command: WriteLoopCommands,
+    ) -> Result<(), TrySendError<WriteLoopCommands>> {
+        let size = command.queued_size();
+        self.memory.add(size);
+        let sent = if command.is_control() {
+            self.control.try_send(command)
+        } else {
+            self.data.try_send(command)
+        };
+        if sent.is_err() {
+            self.memory.sub(size);
+        }
+        sent
+    }
+
+    /// Memory held for the connection behind the sink
+    pub fn memory(&self) -> &Arc<MemoryUsage> {
+        &self.memory
+    }
+
+    /// Whether packets for the connection behind the sink can't be queued right now
+    pub fn is_full(&self) -> bool {
+        self.data.capacity() == 0
+    }
+
+    pub fn same_channel(&self, other: &ClientSink) -> bool {
+        self.data.same_channel(&other.data)
+    }
+
+    pub fn downgrade(&self) -> WeakClientSink {
+        WeakClientSink {
+            control: self.control.downgrade(),
+            data: self.data.downgrade(),
+            memory: self.memory.clone(),
+        }
+    }
+}
+
+pub struct WeakClientSink {
+    control: WeakSender<WriteLoopCommands>,
+    data: WeakSender<WriteLoopCommands>,
+    memory: Arc<MemoryUsage>,
+}
+
+impl WeakClientSink {
+    pub fn upgrade(&self) -> Option<ClientSink> {
+        Some(ClientSink {
+            control: self.control.upgrade()?,
+            data: self.data.upgrade()?,
+            memory: self.memory.clone(),
+        })
+    }
+}
+
+/// Receiving half of a write loop. Commands come in order within a lane, the control lane
+/// is drained first.
+pub struct WriteLanes {
+    control: Receiver<WriteLoopCommands>,
+    data: Receiver<WriteLoopCommands>,
+    memory: Arc<MemoryUsage>,
+}
+
+impl WriteLanes {
+    /// `None` once every [`ClientSink`] is gone and both lanes are empty
+    pub async fn recv(&mut self) -> Option<WriteLoopCommands> {
+        let command = select! {
+            biased;
+            Some(command) = self.control.recv() => Some(command),
+            command = self.data.recv() => command,
+        };
+        if let Some(command) = &command {
+            self.memory.sub(command.queued_size());
+        }
+        command
+    }
+}
+
+/// What a [`DerpClient`] does with received packets once its inbound queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InboundPolicy {
+    /// Stop reading from the server until `recv_packet` makes room
+    #[default]
+    Backpressure,
+    /// Drop the oldest queued packet to make room for the new one
+    DropOldest,
+}
+
+/// Received packets waiting for `recv_packet`, at most `INBOUND_QUEUE_SIZE` of them
+#[derive(Default)]
+struct InboundQueue {
+    state: std::sync::Mutex<InboundState>,
+    /// Wakes `recv_packet` when a packet arrives or the connection closes
+    pushed: Notify,
+    /// Wakes the read loop when `recv_packet` makes room
+    popped: Notify,
+    /// Wakes `recv_ack` when an ack arrives or the connection closes
+    acked: Notify,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct InboundState {
+    packets: VecDeque<(PublicKey, Vec<u8>)>,
+    policy: InboundPolicy,
+    closed: bool,
+    /// Where the roster changes go, see `watch_conns`
+    roster: Option<Sender<RosterChange>>,
+    /// Pings waiting for their Pong, by their data
+    pings: HashMap<[u8; 8], oneshot::Sender<()>>,
+    /// Acks waiting for `recv_ack`, at most `INBOUND_QUEUE_SIZE` of them, older ones are dropped
+    acks: VecDeque<SendAck>,
+}
+
+/// Changes of the roster of a server, see [`DerpClient::watch_conns`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RosterChange {
+    Present(PublicKey),
+    Gone(PublicKey),
+    /// The peers connected when watching started were all announced. Also marks the end of a
+    /// resync after a [`ReconnectingClient`] reconnected.
+    Complete,
+}
+
+impl InboundQueue {
+    async fn push(&self, packet: (PublicKey, Vec<u8>)) {
+        loop {
+            let popped = self.popped.notified();
+            pin!(popped);
+            popped.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("Inbound queue poisoned");
+                if state.packets.len() < INBOUND_QUEUE_SIZE {
+                    state.packets.push_back(packet);
+                    self.pushed.notify_one();
+                    return;
+                }
+                if state.policy == InboundPolicy::DropOldest {
+                    state.packets.pop_front();
+                    state.packets.push_back(packet);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            popped.await;
+        }
+    }
+
+    /// `None` once the connection closed and the queued packets are taken
+    async fn pop(&self) -> Option<(PublicKey, Vec<u8>)> {
+        loop {
+            let pushed = self.pushed.notified();
+            pin!(pushed);
+            pushed.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("Inbound queue poisoned");
+                if let Some(packet) = state.packets.pop_front() {
+                    self.popped.notify_one();
+                    return Some(packet);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            pushed.await;
+        }
+    }
+
+    /// Passes the change on to the watcher, nobody watching drops it
+    async fn roster_changed(&self, change: RosterChange) {
+        let roster = self
+            .state
+            .lock()
+            .expect("Inbound queue poisoned")
+            .roster
+            .clone();
+        if let Some(roster) = roster {
+            // Fails only when the watcher is gone
+            let _ = roster.send(change).await;
+        }
+    }
+
+    fn ponged(&self, data: [u8; 8]) {
+        let ping = self
+            .state
+            .lock()
+            .expect("Inbound queue poisoned")
+            .pings
+            .remove(&data);
+        match ping {
+            Some(ping) => {
+                // Fails only when `ping` gave up waiting
+                let _ = ping.send(());
+            }
+            None => trace!("ignoring unexpected pong"),
+        }
+    }
+
+    fn acked(&self, ack: SendAck) {
+        let mut state = self.state.lock().expect("Inbound queue poisoned");
+        if state.acks.len() == INBOUND_QUEUE_SIZE {
+            state.acks.pop_front();
+        }
+        state.acks.push_back(ack);
+        self.acked.notify_one();
+    }
+
+    /// `None` once the connection closed and the acks received are taken
+    async fn next_ack(&self) -> Option<SendAck> {
+        loop {
+            let acked = self.acked.notified();
+            pin!(acked);
+            acked.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("Inbound queue poisoned");
+                if let Some(ack) = state.acks.pop_front() {
+                    return Some(ack);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            acked.await;
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().expect("Inbound queue poisoned");
+        state.closed = true;
+        // Ends the roster changes and wakes pending `ping`s
+        state.roster = None;
+        state.pings.clear();
+        drop(state);
+        self.pushed.notify_waiters();
+        self.acked.notify_waiters();
+    }
+}
+
+/// Connects to `addr`, an unreachable address fails after `connect_timeout` instead of
+/// whenever the OS gives up
+async fn connect_tcp(addr: &str, connect_timeout: Duration) -> Result<TcpStream> {
+    let stream = timeout(connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow!("Connecting to {addr} timed out after {connect_timeout:?}"))??;
+    Ok(stream)
+}
+
+/// Client side of a connection to a derp server
+pub struct DerpClient {
+    public_key: PublicKey,
+    server_key: PublicKey,
+    resume_token: Option<ResumeToken>,
+    region: Option<String>,
+    max_packet_size: Option<usize>,
+    /// Whether the server agreed to send acks, see [`connect_with_acks`](Self::connect_with_acks)
+    send_acks: bool,
+    no_forwarding: bool,
+    max_fanout: Option<usize>,
+    writer: Mutex<BoxedWriter>,
+    inbound: Arc<InboundQueue>,
+    read_loop: JoinHandle<()>,
+}
+
+impl DerpClient {
+    pub async fn connect(addr: &str, secret_key: SecretKey) -> Result<Self> {
+        Self::connect_via(addr, secret_key, Transport::default()).await
+    }
+
+    /// Like [`connect`](Self::connect), giving up when the TCP connection isn't established
+    /// within `connect_timeout` instead of the default 10s
+    pub async fn connect_with_timeout(
+        addr: &str,
+        secret_key: SecretKey,
+        connect_timeout: Duration,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, connect_timeout).await?;
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            None,
+            None,
+            false,
+            Transport::default(),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        debug!("connected to {addr} ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Like [`connect`](Self::connect), giving up when the server doesn't complete its side of
+    /// the handshake within `handshake_timeout` instead of the default 10s
+    pub async fn connect_with_handshake_timeout(
+        addr: &str,
+        secret_key: SecretKey,
+        handshake_timeout: Duration,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let transport = Transport::default();
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            None,
+            None,
+            false,
+            transport,
+            handshake_timeout,
+        )
+        .await?;
+        debug!("connected to {addr} ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Like [`connect`](Self::connect), upgrading the connection with the given `transport`
+    pub async fn connect_via(
+        addr: &str,
+        secret_key: SecretKey,
+        transport: Transport,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            None,
+            None,
+            false,
+            transport,
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        debug!(
+            "connected to {addr} over {transport:?} ({})",
+            client.server_key
+        );
+        Ok(client)
+    }
+
+    /// Reconnects with the resume token of a previous connection, packets the server queued
+    /// for us in the meantime are delivered first
+    pub async fn resume(
+        addr: &str,
+        secret_key: SecretKey,
+        resume_token: ResumeToken,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let transport = Transport::default();
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            None,
+            Some(resume_token),
+            false,
+            transport,
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        debug!("resumed connection to {addr} ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Like [`connect`](Self::connect), presenting `meshkey` for mesh privileges
+    pub async fn connect_with_meshkey(
+        addr: &str,
+        secret_key: SecretKey,
+        meshkey: &str,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            Some(meshkey),
+            None,
+            false,
+            Transport::default(),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        debug!("connected to {addr} with meshkey ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Connects again for a [`ReconnectingClient`], resuming the previous connection if it
+    /// got a resume token
+    async fn reconnect(
+        addr: &str,
+        secret_key: SecretKey,
+        meshkey: Option<&str>,
+        resume_token: Option<ResumeToken>,
+    ) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            meshkey,
+            resume_token,
+            false,
+            Transport::default(),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        debug!("reconnected to {addr} ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Like [`connect`](Self::connect), asking the server to tell what became of each sent
+    /// packet, see [`recv_ack`](Self::recv_ack). Meant for debugging delivery.
+    pub async fn connect_with_acks(addr: &str, secret_key: SecretKey) -> Result<Self> {
+        let stream = connect_tcp(addr, DEFAULT_CONNECT_TIMEOUT).await?;
+        let client = Self::handshake(
+            stream,
+            secret_key,
+            None,
+            None,
+            true,
+            Transport::default(),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await?;
+        ensure!(
+            client.send_acks,
+            "{addr} ({}) doesn't send acks",
+            client.server_key
+        );
+        debug!("connected to {addr} with acks ({})", client.server_key);
+        Ok(client)
+    }
+
+    /// Runs the handshake over an already established stream
+    pub async fn connect_stream<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        stream: S,
+        secret_key: SecretKey,
+    ) -> Result<Self> {
+        Self::handshake(
+            stream,
+            secret_key,
+            None,
+            None,
+            false,
+            Transport::default(),
+            DEFAULT_HANDSHAKE_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Reads of a server that stops answering fail once `handshake_timeout` passed
+    async fn handshake<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+        stream: S,
+        secret_key: SecretKey,
+        meshkey: Option<&str>,
+        resume_token: Option<ResumeToken>,
+        send_acks: bool,
+        transport: Transport,
+        handshake_timeout: Duration,
+    ) -> Result<Self> {
+        let (mut r, mut w) = split(stream);
+        let deadline = Instant::now() + handshake_timeout;
+
+        let leftovers = timeout_at(deadline.into(), connect_http(&mut r, &mut w, transport))
+            .await
+            .map_err(|_| anyhow!("No upgrade response within {handshake_timeout:?}"))??;
+        let upgraded = Upgraded::new(r.unsplit(w), leftovers, transport, Role::Client).await;
+        let (r, mut w) = split(upgraded);
+        let mut reader = DerpReader::new(r);
+        let server_key = timeout_at(
+            deadline.into(),
+            exchange_keys(
+                &mut reader,
+                &mut w,
+                &secret_key,
+                meshkey,
+                resume_token,
+                send_acks,
+            ),
+        )
+        .await
+        .map_err(|_| anyhow!("No server key within {handshake_timeout:?}"))??;
+        let server_info = timeout_at(
+            deadline.into(),
+            read_server_info(&mut reader, &secret_key, server_key),
+        )
+        .await
+        .map_err(|_| anyhow!("No ServerInfo from {server_key} within {handshake_timeout:?}"))??;
+
+        let inbound = Arc::new(InboundQueue::default());
+        let read_loop = spawn({
+            let inbound = inbound.clone();
+            async move {
+                if let Err(e) = Self::read_loop(reader, &inbound).await {
+                    debug!("Read loop of client connected to {server_key} stopped: {e}");
+                }
+                inbound.close();
+            }
+        });
+
+        Ok(Self {
+            public_key: secret_key.public(),
+            server_key,
+            resume_token: server_info.resume_token,
+            region: server_info.region,
+            max_packet_size: server_info.max_packet_size,
+            send_acks: server_info.send_acks,
+            no_forwarding: server_info.no_forwarding,
+            max_fanout: server_info.max_fanout,
+            writer: Mutex::new(Box::new(w)),
+            inbound,
+            read_loop,
+        })
+    }
+
+    /// Token to [`resume`](Self::resume) this connection after it breaks, if the server issued one
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.resume_token
+    }
+
+    /// Region the server advertised, if any, a hint for picking the home relay
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    pub fn server_key(&self) -> PublicKey {
+        self.server_key
+    }
+
+    /// Largest payload the server forwards, if it advertised one
+    pub fn max_packet_size(&self) -> Option<usize> {
+        self.max_packet_size
+    }
+
+    /// Whether the server relays packets, presence-only servers drop all of them
+    pub fn forwards_packets(&self) -> bool {
+        !self.no_forwarding
+    }
+
+    /// Most destinations [`send_packets`](Self::send_packets) may name, `None` when the server
+    /// doesn't take multi-destination sends
+    pub fn max_fanout(&self) -> Option<usize> {
+        self.max_fanout
+    }
+
+    /// Sends the packet to `target` through the server. Payloads over the advertised
+    /// [`max_packet_size`](Self::max_packet_size) are refused without being sent.
+    pub async fn send_packet(&self, target: PublicKey, payload: Vec<u8>) -> Result<()> {
+        if let Some(max) = self.max_packet_size {
+            ensure!(
+                payload.len() <= max,
+                "Packet of {} bytes is over the server's {max} bytes limit",
+                payload.len()
+            );
+        }
+        let mut writer = self.writer.lock().await;
+        write_send_packet(&mut *writer, SendPacket { target, payload }).await
+    }
+
+    /// Sends one packet to each of `targets` in a single frame, the server makes the copies.
+    /// Refused without being sent when the server doesn't take that many destinations.
+    pub async fn send_packets(&self, targets: Vec<PublicKey>, payload: Vec<u8>) -> Result<()> {
+        let Some(max_fanout) = self.max_fanout else {
+            bail!("Server doesn't take packets for several destinations");
+        };
+        ensure!(
+            targets.len() <= max_fanout,
+            "{} destinations are over the server's limit of {max_fanout}",
+            targets.len()
+        );
+        if let Some(max) = self.max_packet_size {
+            ensure!(
+                payload.len() <= max,
+                "Packet of {} bytes is over the server's {max} bytes limit",
+                payload.len()
+            );
+        }
+        let mut writer = self.writer.lock().await;
+        write_send_packets(&mut *writer, SendPackets { targets, payload }).await
+    }
+
+    /// Tells the server whether it's our home node, the preference can change at any time
+    pub async fn set_preferred(&self, preferred: bool) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        write_note_preferred(&mut *writer, preferred).await
+    }
+
+    /// Flushes pending sends and closes the connection, so the server sees a clean disconnect
+    /// instead of an abrupt teardown
+    pub async fn close(self) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.flush().await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Waits for the next packet relayed to us, returns its source and payload
+    pub async fn recv_packet(&self) -> Result<(PublicKey, Vec<u8>)> {
+        self.inbound
+            .pop()
+            .await
+            .ok_or_else(|| anyhow!("Connection to {} closed", self.server_key))
+    }
+
+    /// Sets what happens to received packets while the inbound queue is full, by default
+    /// reading from the server pauses until `recv_packet` is called
+    pub fn set_inbound_policy(&self, policy: InboundPolicy) {
+        self.inbound
+            .state
+            .lock()
+            .expect("Inbound queue poisoned")
+            .policy = policy;
+    }
+
+    /// Received packets dropped because the inbound queue was full
+    pub fn dropped_inbound(&self) -> u64 {
+        self.inbound.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the next ack of a sent packet, acks come in the order the packets were sent.
+    /// Only for connections made with [`connect_with_acks`](Self::connect_with_acks).
+    pub async fn recv_ack(&self) -> Result<SendAck> {
+        ensure!(
+            self.send_acks,
+            "Acks weren't asked for when connecting to {}",
+            self.server_key
+        );
+        self.inbound
+            .next_ack()
+            .await
+            .ok_or_else(|| anyhow!("Connection to {} closed", self.server_key))
+    }
+
+    /// Round trip time to the server, from a Ping until its Pong. The server answers ahead of
+    /// the packets queued to us, so the time doesn't grow with that backlog.
+    pub async fn ping(&self) -> Result<Duration> {
+        let data: [u8; 8] = rand::random();
+        let (ponged, pong) = oneshot::channel();
+        {
+            let mut state = self.inbound.state.lock().expect("Inbound queue poisoned");
+            ensure!(!state.closed, "Connection to {} closed", self.server_key);
+            state.pings.insert(data, ponged);
+        }
+        let started = Instant::now();
+        let sent = write_ping(&mut *self.writer.lock().await, data).await;
+        if let Err(e) = sent {
+            self.inbound
+                .state
+                .lock()
+                .expect("Inbound queue poisoned")
+                .pings
+                .remove(&data);
+            return Err(e);
+        }
+        pong.await
+            .map_err(|_| anyhow!("Connection to {} closed", self.server_key))?;
+        Ok(started.elapsed())
+    }
+
+    /// Peers connected to the server right now, through WatchConns. Needs mesh privileges, see
+    /// [`connect_with_meshkey`](Self::connect_with_meshkey), the server ignores the request and
+    /// this never returns otherwise.
+    pub async fn list_peers(&self) -> Result<Vec<PublicKey>> {
+        let result = async {
+            let mut roster = self.watch_conns().await?;
+            // The server keeps announcing changes after the first WatchConns, a later roster
+            // can start with leftovers of them
+            let mut peers = HashSet::new();
+            loop {
+                match roster.recv().await {
+                    Some(RosterChange::Present(peer)) => {
+                        peers.insert(peer);
+                    }
+                    Some(RosterChange::Gone(peer)) => {
+                        peers.remove(&peer);
+                    }
+                    Some(RosterChange::Complete) => {
+                        return anyhow::Ok(peers.into_iter().collect::<Vec<_>>())
+                    }
+                    None => bail!("Connection to {} closed", self.server_key),
+                }
+            }
+        }
+        .await;
+        self.inbound
+            .state
+            .lock()
+            .expect("Inbound queue poisoned")
+            .roster = None;
+        result
+    }
+
+    /// Watches the roster of the server: the peers connected now, a [`RosterChange::Complete`],
+    /// then the peers coming and going until the connection closes. Needs mesh privileges like
+    /// [`list_peers`](Self::list_peers), which takes over the changes. Reading from the server
+    /// waits for changes that aren't taken.
+    pub async fn watch_conns(&self) -> Result<Receiver<RosterChange>> {
+        let (sender, roster) = channel(INBOUND_QUEUE_SIZE);
+        {
+            let mut state = self.inbound.state.lock().expect("Inbound queue poisoned");
+            ensure!(!state.closed, "Connection to {} closed", self.server_key);
+            state.roster = Some(sender);
+        }
+        write_watch_conns(&mut *self.writer.lock().await).await?;
+        Ok(roster)
+    }
+
+    async fn read_loop<R: AsyncRead + Unpin>(
+        mut reader: DerpReader<R>,
+        inbound: &InboundQueue,
+    ) -> Result<()> {
+        loop {
+            let message = reader.get_next_message().await?;
+            match message.ty {
+                FrameType::RecvPacket => {
+                    let recv_packet = Frame::<RecvPacket>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    inbound
+                        .push((recv_packet.source, recv_packet.payload))
+                        .await;
+                }
+                FrameType::PeerPresent => {
+                    let peer = Frame::<PeerPresent>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    inbound
+                        .roster_changed(RosterChange::Present(peer.public_key))
+                        .await;
+                }
+                FrameType::PeerGone => {
+                    let peer = Frame::<PeerGone>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    inbound
+                        .roster_changed(RosterChange::Gone(peer.public_key))
+                        .await;
+                }
+                FrameType::RosterComplete => inbound.roster_changed(RosterChange::Complete).await,
+                FrameType::Pong => {
+                    let pong = Frame::<Pong>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    inbound.ponged(pong.data);
+                }
+                FrameType::SendAck => {
+                    let ack = Frame::<SendAck>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    inbound.acked(ack);
+                }
+                ty => trace!("ignoring frame: {ty:?}"),
+            }
+        }
+    }
+}
+
+impl Drop for DerpClient {
+    fn drop(&mut self) {
+        // The read half keeps the connection open until the read loop is gone
+        self.read_loop.abort();
+    }
+}
+
+/// A packet received through one of the relays of a [`ClientPool`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct RelayedPacket {
+    pub relay: String,
+    pub source: PublicKey,
+    pub payload: Vec<u8>,
+}
+
+/// Connections to several derp servers at once, for redundancy
+pub struct ClientPool {
+    public_key: PublicKey,
+    relays: RwLock<Vec<(String, Arc<DerpClient>)>>,
+    inbound: Mutex<Receiver<RelayedPacket>>,
+}
+
+impl ClientPool {
+    /// Connects to every relay in `addrs`. Relays that can't be reached are left out, it's only
+    /// an error if none of them can.
+    pub async fn connect<A: AsRef<str>>(addrs: &[A], secret_key: SecretKey) -> Result<Self> {
+        let (inbound_sender, inbound) = channel(INBOUND_QUEUE_SIZE);
+        let mut relays = Vec::new();
+        for addr in addrs {
+            let addr = addr.as_ref();
+            match DerpClient::connect(addr, secret_key.clone()).await {
+                Ok(client) => {
+                    let client = Arc::new(client);
+                    spawn(Self::forward_inbound(
+                        addr.to_owned(),
+                        client.clone(),
+                        inbound_sender.clone(),
+                    ));
+                    relays.push((addr.to_owned(), client));
+                }
+                Err(e) => warn!("Failed to connect to relay {addr}: {e}"),
+            }
+        }
+        ensure!(!relays.is_empty(), "Failed to connect to any relay");
+
+        Ok(Self {
+            public_key: secret_key.public(),
+            relays: RwLock::new(relays),
+            inbound: Mutex::new(inbound),
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Addresses of the relays still in the pool
+    pub async fn relays(&self) -> Vec<String> {
+        self.relays
+            .read()
+            .await
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Sends the packet through every relay. Relays failing to send are dropped from the pool,
+    /// it's only an error if all of them fail.
+    pub async fn send_packet(&self, target: PublicKey, payload: Vec<u8>) -> Result<()> {
+        let relays = self.relays.read().await.clone();
+        let mut failed = Vec::new();
+        for (addr, client) in &relays {
+            if let Err(e) = client.send_packet(target, payload.clone()).await {
+                warn!("Failed to send through relay {addr}, dropping it: {e}");
+                failed.push(addr.clone());
+            }
+        }
+        self.drop_relays(&failed).await;
+        ensure!(
+            failed.len() < relays.len(),
+            "Failed to send through any relay"
+        );
+        Ok(())
+    }
+
+    /// Sends the packet through the relay with the given address only
+    pub async fn send_packet_via(
+        &self,
+        relay: &str,
+        target: PublicKey,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        let client = self
+            .relays
+            .read()
+            .await
+            .iter()
+            .find(|(addr, _)| addr == relay)
+            .map(|(_, client)| client.clone())
+            .ok_or_else(|| anyhow!("Relay {relay} is not in the pool"))?;
+        let result = client.send_packet(target, payload).await;
+        if result.is_err() {
+            self.drop_relays(&[relay.to_owned()]).await;
+        }
+        result
+    }
+
+    /// Waits for the next packet relayed to us through any of the relays
+    pub async fn recv_packet(&self) -> Result<RelayedPacket> {
+        self.inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Connections to all relays closed"))
+    }
+
+    async fn drop_relays(&self, failed: &[String]) {
+        if !failed.is_empty() {
+            self.relays
+                .write()
+                .await
+                .retain(|(addr, _)| !failed.contains(addr));
+        }
+    }
+
+    async fn forward_inbound(relay: String, client: Arc<DerpClient>, sink: Sender<RelayedPacket>) {
+        while let Ok((source, payload)) = client.recv_packet().await {
+            let packet = RelayedPacket {
+                relay: relay.clone(),
+                source,
+                payload,
+            };
+            if sink.send(packet).await.is_err() {
+                return;
+            }
+        }
+        debug!("Relay {relay} closed");
+    }
+}
+
+/// What a [`ReconnectingClient`] does with a packet it can't queue, because the queue is full
+/// or reconnecting failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Drop the packet, it's counted in [`dropped`](ReconnectingClient::dropped)
+    #[default]
+    Drop,
+    /// Fail the send
+    Error,
+}
+
+/// How a [`ReconnectingClient`] rides out a broken connection
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectOptions {
+    /// Packets sent while reconnecting that wait for the new connection
+    pub queue_size: usize,
+    pub overflow: QueueOverflow,
+    /// How long to try reconnecting before giving up, the queued packets are dropped then
+    pub reconnect_for: Duration,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            queue_size: INBOUND_QUEUE_SIZE,
+            overflow: QueueOverflow::default(),
+            reconnect_for: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A connection to a derp server that's made again when it breaks, resuming the previous one.
+/// Packets sent while reconnecting are queued and sent once reconnected, the roster is watched
+/// again.
+pub struct ReconnectingClient {
+    addr: String,
+    public_key: PublicKey,
+    options: ReconnectOptions,
+    state: Arc<std::sync::Mutex<ReconnectState>>,
+    inbound: Mutex<Receiver<(PublicKey, Vec<u8>)>>,
+    dropped: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+struct ReconnectState {
+    /// `None` while reconnecting
+    client: Option<Arc<DerpClient>>,
+    queue: VecDeque<(PublicKey, Vec<u8>)>,
+    gave_up: bool,
+    /// Where the roster changes go once `watch_conns` was called
+    watcher: Option<Sender<RosterChange>>,
+    /// Peers the watcher was told are connected
+    roster: HashSet<PublicKey>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(
+        addr: &str,
+        secret_key: SecretKey,
+        options: ReconnectOptions,
+    ) -> Result<Self> {
+        let client = DerpClient::connect(addr, secret_key.clone()).await?;
+        Ok(Self::start(addr, secret_key, None, client, options))
+    }
+
+    /// Like [`connect`](Self::connect), presenting `meshkey` for mesh privileges on every
+    /// connection, e.g. to [`watch_conns`](Self::watch_conns)
+    pub async fn connect_with_meshkey(
+        addr: &str,
+        secret_key: SecretKey,
+        meshkey: &str,
+        options: ReconnectOptions,
+    ) -> Result<Self> {
+        let client = DerpClient::connect_with_meshkey(addr, secret_key.clone(), meshkey).await?;
+        Ok(Self::start(
+            addr,
+            secret_key,
+            Some(meshkey.to_owned()),
+            client,
+            options,
+        ))
+    }
+
+    fn start(
+        addr: &str,
+        secret_key: SecretKey,
+        meshkey: Option<String>,
+        client: DerpClient,
+        options: ReconnectOptions,
+    ) -> Self {
+        let client = Arc::new(client);
+        let state = Arc::new(std::sync::Mutex::new(ReconnectState {
+            client: Some(client.clone()),
+            queue: VecDeque::new(),
+            gave_up: false,
+            watcher: None,
+            roster: HashSet::new(),
+        }));
+        let (inbound_sender, inbound) = channel(INBOUND_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let task = spawn(Self::keep_connected(
+            addr.to_owned(),
+            secret_key.clone(),
+            meshkey,
+            client,
+            state.clone(),
+            inbound_sender,
+            options.reconnect_for,
+            dropped.clone(),
+        ));
+        Self {
+            addr: addr.to_owned(),
+            public_key: secret_key.public(),
+            options,
+            state,
+            inbound: Mutex::new(inbound),
+            dropped,
+            task,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Whether the connection is up, it's being reconnected otherwise
+    pub fn is_connected(&self) -> bool {
+        self.state
+            .lock()
+            .expect("Reconnect state poisoned")
+            .client
+            .is_some()
+    }
+
+    /// Packets dropped because they couldn't be queued or reconnecting failed
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sends the packet to `target`, or queues it while reconnecting. Failures of the
+    /// connection itself are returned like those of [`DerpClient::send_packet`].
+    pub async fn send_packet(&self, target: PublicKey, payload: Vec<u8>) -> Result<()> {
+        let client = {
+            let mut state = self.state.lock().expect("Reconnect state poisoned");
+            match &state.client {
+                Some(client) => client.clone(),
+                None => return self.queue(&mut state, target, payload),
+            }
+        };
+        client.send_packet(target, payload).await
+    }
+
+    fn queue(&self, state: &mut ReconnectState, target: PublicKey, payload: Vec<u8>) -> Result<()> {
+        if !state.gave_up && state.queue.len() < self.options.queue_size {
+            state.queue.push_back((target, payload));
+            return Ok(());
+        }
+        match self.options.overflow {
+            QueueOverflow::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            QueueOverflow::Error if state.gave_up => {
+                bail!("Gave up reconnecting to {}", self.addr)
+            }
+            QueueOverflow::Error => bail!(
+                "{} packets are already waiting for the connection to {}",
+                state.queue.len(),
+                self.addr
+            ),
+        }
+    }
+
+    /// Waits for the next packet relayed to us, over whichever connection
+    pub async fn recv_packet(&self) -> Result<(PublicKey, Vec<u8>)> {
+        self.inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Gave up reconnecting to {}", self.addr))
+    }
+
+    /// Watches the roster of the server like [`DerpClient::watch_conns`], over whichever
+    /// connection. After reconnecting, the peers that left meanwhile are reported gone and
+    /// those connected now present, followed by a [`RosterChange::Complete`]. Needs mesh
+    /// privileges, see [`connect_with_meshkey`](Self::connect_with_meshkey).
+    pub fn watch_conns(&self) -> Receiver<RosterChange> {
+        let (watcher, roster) = channel(INBOUND_QUEUE_SIZE);
+        let client = {
+            let mut state = self.state.lock().expect("Reconnect state poisoned");
+            state.watcher = Some(watcher);
+            state.roster.clear();
+            state.client.clone()
+        };
+        // Otherwise it's watched once reconnected
+        if let Some(client) = client {
+            spawn(Self::sync_roster(
+                self.addr.clone(),
+                client,
+                Arc::downgrade(&self.state),
+            ));
+        }
+        roster
+    }
+
+    /// Watches the roster over `client`. Once its roster is complete, the watcher is told the
+    /// difference to the roster it knows, then the changes are passed on until the connection
+    /// closes.
+    async fn sync_roster(
+        addr: String,
+        client: Arc<DerpClient>,
+        state: Weak<std::sync::Mutex<ReconnectState>>,
+    ) {
+        let mut changes = match client.watch_conns().await {
+            Ok(changes) => changes,
+            Err(e) => {
+                debug!("Failed to watch the roster of {addr}: {e}");
+                return;
+            }
+        };
+        drop(client);
+        // The server keeps announcing changes after an earlier WatchConns, the roster can
+        // start with leftovers of them
+        let mut current = HashSet::new();
+        loop {
+            match changes.recv().await {
+                Some(RosterChange::Present(peer)) => {
+                    current.insert(peer);
+                }
+                Some(RosterChange::Gone(peer)) => {
+                    current.remove(&peer);
+                }
+                Some(RosterChange::Complete) => break,
+                // Watched again once reconnected
+                None => return,
+            }
+        }
+        let Some((watcher, resync)) = state.upgrade().and_then(|state| {
+            let mut state = state.lock().expect("Reconnect state poisoned");
+            let watcher = state.watcher.clone()?;
+            let gone: Vec<_> = state.roster.difference(&current).copied().collect();
+            let resync: Vec<_> = gone
+                .into_iter()
+                .map(RosterChange::Gone)
+                .chain(current.iter().copied().map(RosterChange::Present))
+                .chain([RosterChange::Complete])
+                .collect();
+            state.roster = current;
+            Some((watcher, resync))
+        }) else {
+            return;
+        };
+        for change in resync {
+            if watcher.send(change).await.is_err() {
+                return;
+            }
+        }
+
+        while let Some(change) = changes.recv().await {
+            let Some(watcher) = state.upgrade().and_then(|state| {
+                let mut state = state.lock().expect("Reconnect state poisoned");
+                match change {
+                    RosterChange::Present(peer) => {
+                        state.roster.insert(peer);
+                    }
+                    RosterChange::Gone(peer) => {
+                        state.roster.remove(&peer);
+                    }
+                    RosterChange::Complete => {}
+                }
+                state.watcher.clone()
+            }) else {
+                return;
+            };
+            if watcher.send(change).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Passes on the packets received over `client`, reconnecting when it breaks until
+    /// reconnecting fails for `reconnect_for`
+    #[allow(clippy::too_many_arguments)]
+    async fn keep_connected(
+        addr: String,
+        secret_key: SecretKey,
+        meshkey: Option<String>,
+        mut client: Arc<DerpClient>,
+        state: Arc<std::sync::Mutex<ReconnectState>>,
+        inbound: Sender<(PublicKey, Vec<u8>)>,
+        reconnect_for: Duration,
+        dropped: Arc<AtomicU64>,
+    ) {
+        loop {
+            while let Ok(packet) = client.recv_packet().await {
+                if inbound.send(packet).await.is_err() {
+                    return;
+                }
+            }
+            info!("Connection to {addr} broke, reconnecting");
+            state.lock().expect("Reconnect state poisoned").client = None;
+            let resume_token = client.resume_token();
+            let Some(reconnected) = Self::reconnect(
+                &addr,
+                &secret_key,
+                meshkey.as_deref(),
+                resume_token,
+                reconnect_for,
+            )
+            .await
+            else {
+                warn!("Gave up reconnecting to {addr} after {reconnect_for:?}");
+                let mut state = state.lock().expect("Reconnect state poisoned");
+                state.gave_up = true;
+                dropped.fetch_add(state.queue.len() as u64, Ordering::Relaxed);
+                state.queue.clear();
+                return;
+            };
+            client = Arc::new(reconnected);
+
+            // Packets sent meanwhile are queued behind these, until the queue is empty
+            let watching = loop {
+                let (target, payload) = {
+                    let mut state = state.lock().expect("Reconnect state poisoned");
+                    match state.queue.pop_front() {
+                        Some(packet) => packet,
+                        None => {
+                            state.client = Some(client.clone());
+                            break state.watcher.is_some();
+                        }
+                    }
+                };
+                if let Err(e) = client.send_packet(target, payload).await {
+                    debug!("Failed to send a queued packet to {addr}: {e}");
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            };
+            info!("Reconnected to {addr}");
+            if watching {
+                spawn(Self::sync_roster(
+                    addr.clone(),
+                    client.clone(),
+                    Arc::downgrade(&state),
+                ));
+            }
+        }
+    }
+
+    async fn reconnect(
+        addr: &str,
+        secret_key: &SecretKey,
+        meshkey: Option<&str>,
+        resume_token: Option<ResumeToken>,
+        reconnect_for: Duration,
+    ) -> Option<DerpClient> {
+        let deadline = Instant::now() + reconnect_for;
+        while Instant::now() < deadline {
+            sleep(RECONNECT_DELAY).await;
+            let reconnected =
+                DerpClient::reconnect(addr, secret_key.clone(), meshkey, resume_token).await;
+            match reconnected {
+                Ok(client) => return Some(client),
+                Err(e) => debug!("Reconnecting to {addr} failed: {e}"),
+            }
+        }
+        None
+    }
+}
+
+impl Drop for ReconnectingClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::data::{ServerInfo, ServerInfoPayload, ServerKey, PROTOCOL_VERSION};
+    use crate::test_utils::{start_service, wait_for_peer};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{copy_bidirectional, duplex},
+        net::TcpListener,
+        sync::watch,
+        time::sleep,
+    };
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn pool_relays_through_at_least_one_server() {
+        let (_service_a, addr_a) = start_service(&[]).await;
+        let (service_b, addr_b) = start_service(&[]).await;
+        let receiver = DerpClient::connect(&addr_b.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service_b, receiver.public_key()).await;
+
+        let pool = ClientPool::connect(&[addr_a.to_string(), addr_b.to_string()], SecretKey::gen())
+            .await
+            .unwrap();
+        assert_eq!(pool.relays().await.len(), 2);
+        pool.send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+
+        let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        assert_eq!(source, pool.public_key());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn pool_skips_unreachable_relays() {
+        let (service, addr) = start_service(&[]).await;
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let pool = ClientPool::connect(
+            &[unreachable.to_string(), addr.to_string()],
+            SecretKey::gen(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pool.relays().await, vec![addr.to_string()]);
+
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, pool.public_key()).await;
+        sender
+            .send_packet(pool.public_key(), b"hi".to_vec())
+            .await
+            .unwrap();
+        let packet = timeout(Duration::from_secs(5), pool.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        assert_eq!(
+            packet,
+            RelayedPacket {
+                relay: addr.to_string(),
+                source: sender.public_key(),
+                payload: b"hi".to_vec(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn pong_is_written_ahead_of_queued_packets() {
+        const QUEUED: u8 = 8;
+
+        let pk = PublicKey::new([1; 32]);
+        let (sink, lanes) = write_lanes(QUEUED.into());
+        for i in 0..QUEUED {
+            sink.send(WriteLoopCommands::SendPacket {
+                source: PublicKey::new([2; 32]),
+                target: pk,
+                ttl: 0,
+                payload: Bytes::from(vec![i]),
+            })
+            .await
+            .unwrap();
+        }
+        sink.send(WriteLoopCommands::Pong([7; 8])).await.unwrap();
+        drop(sink);
+
+        let (w, r) = duplex(u16::MAX as usize);
+        Client::write_loop(
+            lanes,
+            w,
+            pk,
+            false,
+            PROTOCOL_VERSION,
+            Duration::from_secs(5),
+            Duration::ZERO,
+            FrameTrace::default(),
+            &Liveness::new(None),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = DerpReader::new(r);
+        let pong = reader.get_next_message().await.unwrap();
+        assert_eq!(pong.ty, FrameType::Pong);
+        assert_eq!(pong.buffer[5..], [7; 8]);
+        for i in 0..QUEUED {
+            let message = reader.get_next_message().await.unwrap();
+            let packet = Frame::<RecvPacket>::decode(&mut message.buffer.as_slice())
+                .unwrap()
+                .inner
+                .into_inner();
+            assert_eq!(packet.payload, [i]);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_client_is_not_sent_newer_frame_types() {
+        let pk = PublicKey::new([1; 32]);
+        let (sink, lanes) = write_lanes(4);
+        sink.send(WriteLoopCommands::PeerGone(
+            PublicKey::new([2; 32]),
+            PeerGoneReason::Disconnected,
+        ))
+        .await
+        .unwrap();
+        sink.send(WriteLoopCommands::Pong([7; 8])).await.unwrap();
+        sink.send(WriteLoopCommands::SendPacket {
+            source: PublicKey::new([2; 32]),
+            target: pk,
+            ttl: 0,
+            payload: Bytes::from_static(&[1]),
+        })
+        .await
+        .unwrap();
+        drop(sink);
+
+        let (w, mut r) = duplex(u16::MAX as usize);
+        Client::write_loop(
+            lanes,
+            w,
+            pk,
+            false,
+            1,
+            Duration::from_secs(5),
+            Duration::ZERO,
+            FrameTrace::default(),
+            &Liveness::new(None),
+        )
+        .await
+        .unwrap();
+
+        // The write half is gone, so this reads everything that was sent
+        let mut sent = Vec::new();
+        r.read_to_end(&mut sent).await.unwrap();
+        let mut reader = DerpReader::new(sent.as_slice());
+        assert_eq!(
+            reader.get_next_message().await.unwrap().ty,
+            FrameType::RecvPacket
+        );
+        assert!(reader.get_next_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn restarting_is_not_sent_to_clients_that_predate_it() {
+        let pk = PublicKey::new([1; 32]);
+        let (sink, lanes) = write_lanes(4);
+        let mut restarting = Vec::new();
+        Restarting::default()
+            .frame()
+            .encode(&mut restarting)
+            .unwrap();
+        sink.send(WriteLoopCommands::Frame(restarting))
+            .await
+            .unwrap();
+        sink.send(WriteLoopCommands::Pong([7; 8])).await.unwrap();
+        drop(sink);
+
+        let (w, r) = duplex(u16::MAX as usize);
+        Client::write_loop(
+            lanes,
+            w,
+            pk,
+            false,
+            FrameType::Restarting.min_version() - 1,
+            Duration::from_secs(5),
+            Duration::ZERO,
+            FrameTrace::default(),
+            &Liveness::new(None),
+        )
+        .await
+        .unwrap();
+
+        let message = DerpReader::new(r).get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::Pong);
+    }
+
+    #[tokio::test]
+    async fn older_clients_are_told_evicted_peers_disconnected() {
+        let pk = PublicKey::new([1; 32]);
+        let (sink, lanes) = write_lanes(4);
+        sink.send(WriteLoopCommands::PeerGone(
+            PublicKey::new([2; 32]),
+            PeerGoneReason::Evicted,
+        ))
+        .await
+        .unwrap();
+        drop(sink);
+
+        let (w, r) = duplex(u16::MAX as usize);
+        Client::write_loop(
+            lanes,
+            w,
+            pk,
+            false,
+            PeerGoneReason::Evicted.min_version() - 1,
+            Duration::from_secs(5),
+            Duration::ZERO,
+            FrameTrace::default(),
+            &Liveness::new(None),
+        )
+        .await
+        .unwrap();
+
+        let message = DerpReader::new(r).get_next_message().await.unwrap();
+        let peer_gone = Frame::<PeerGone>::decode(&mut message.buffer.as_slice())
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(peer_gone.reason, Some(PeerGoneReason::Disconnected));
+    }
+
+    /// Records the size of every write
+    #[derive(Clone, Default)]
+    struct RecordingWriter(Arc<std::sync::Mutex<Vec<usize>>>);
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Sizes of the writes of three pongs queued right after another
+    async fn writes_of_three_pongs(flush_interval: Duration) -> Vec<usize> {
+        let (sink, lanes) = write_lanes(4);
+        let writer = RecordingWriter::default();
+        let writes = writer.0.clone();
+        let write_loop = spawn(async move {
+            Client::write_loop(
+                lanes,
+                writer,
+                PublicKey::new([1; 32]),
+                false,
+                PROTOCOL_VERSION,
+                Duration::from_secs(5),
+                flush_interval,
+                FrameTrace::default(),
+                &Liveness::new(None),
+            )
+            .await
+        });
+        for i in 0..3 {
+            sink.send(WriteLoopCommands::Pong([i; 8])).await.unwrap();
+            // Gives the write loop a chance to run in between
+            tokio::task::yield_now().await;
+        }
+        drop(sink);
+        write_loop.await.unwrap().unwrap();
+        // The writer went away with the write loop
+        Arc::try_unwrap(writes).unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_within_the_flush_interval_are_written_together() {
+        // A pong is 5 bytes of header and 8 of payload
+        assert_eq!(
+            writes_of_three_pongs(Duration::from_millis(100)).await,
+            [3 * 13]
+        );
+        assert_eq!(writes_of_three_pongs(Duration::ZERO).await, [13, 13, 13]);
+    }
+
+    #[tokio::test]
+    async fn write_failure_reports_peer_gone_without_read_side() {
+        let (server, client) = socket_pair().await;
+        // No read loop is started, so only the write loop can notice the reset
+        let (_r, w) = server.into_split();
+        let pk = PublicKey::new([1; 32]);
+        let (command_sender, mut commands) = channel(4);
+        let (sink, write_stopped) = Client::start_write_loop(
+            w,
+            pk,
+            false,
+            PROTOCOL_VERSION,
+            Duration::from_secs(5),
+            Duration::ZERO,
+            FrameTrace::default(),
+            Liveness::new(None),
+            command_sender,
+        );
+
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+
+        // The first writes may land in the socket buffer before the reset is noticed
+        let command = timeout(Duration::from_secs(5), async {
+            loop {
+                let _ = sink
+                    .send(WriteLoopCommands::SendPacket {
+                        source: PublicKey::new([2; 32]),
+                        target: pk,
+                        ttl: 0,
+                        payload: Bytes::from(vec![0; 1024]),
+                    })
+                    .await;
+                select! {
+                    command = commands.recv() => return command,
+                    _ = sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .expect("write failure should be reported");
+
+        assert!(matches!(
+            command,
+            Some(ServiceCommand::PeerGone(gone, PeerGoneReason::Disconnected, gone_sink))
+                if gone == pk && gone_sink.same_channel(&sink)
+        ));
+        assert!(write_stopped.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_send_and_receive_at_the_same_time() {
+        // More than fits in the inbound queues, so sending only completes if reading goes on
+        const PACKETS: usize = 4 * INBOUND_QUEUE_SIZE;
+
+        let (service, addr) = start_service(&[]).await;
+        let a = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let b = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, a.public_key()).await;
+        wait_for_peer(&service, b.public_key()).await;
+
+        async fn exchange(client: &DerpClient, peer: PublicKey) {
+            let send = async {
+                for i in 0..PACKETS {
+                    client
+                        .send_packet(peer, i.to_be_bytes().to_vec())
+                        .await
+                        .unwrap();
+                }
+            };
+            let recv = async {
+                for i in 0..PACKETS {
+                    let (source, payload) = client.recv_packet().await.unwrap();
+                    assert_eq!(source, peer);
+                    assert_eq!(payload, i.to_be_bytes());
+                }
+            };
+            tokio::join!(send, recv);
+        }
+
+        timeout(Duration::from_secs(10), async {
+            tokio::join!(exchange(&a, b.public_key()), exchange(&b, a.public_key()))
+        })
+        .await
+        .expect("clients should not deadlock");
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_drops_the_oldest_packets() {
+        const PACKETS: usize = 4 * INBOUND_QUEUE_SIZE;
+
+        let (service, addr) = start_service(&[]).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let receiver = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        receiver.set_inbound_policy(InboundPolicy::DropOldest);
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        for i in 0..PACKETS {
+            sender
+                .send_packet(receiver.public_key(), i.to_be_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        let dropped = (PACKETS - INBOUND_QUEUE_SIZE) as u64;
+        timeout(Duration::from_secs(5), async {
+            while receiver.dropped_inbound() < dropped {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packets over the queue size should be dropped");
+        assert_eq!(receiver.dropped_inbound(), dropped);
+        assert_eq!(
+            receiver.inbound.state.lock().unwrap().packets.len(),
+            INBOUND_QUEUE_SIZE
+        );
+
+        for i in PACKETS - INBOUND_QUEUE_SIZE..PACKETS {
+            let (_, payload) = receiver.recv_packet().await.unwrap();
+            assert_eq!(payload, i.to_be_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn client_refuses_packets_over_the_advertised_size() {
+        let (service, addr) = start_service(&["--max-packet-size", "100"]).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let receiver = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+        assert_eq!(sender.max_packet_size(), Some(100));
+
+        let err = sender
+            .send_packet(receiver.public_key(), vec![0; 101])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Packet of 101 bytes is over the server's 100 bytes limit"
+        );
+
+        sender
+            .send_packet(receiver.public_key(), vec![0; 100])
+            .await
+            .unwrap();
+        let (_, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        assert_eq!(payload.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn websocket_client_exchanges_packets() {
+        let (service, addr) = start_service(&[]).await;
+        let sender =
+            DerpClient::connect_via(&addr.to_string(), SecretKey::gen(), Transport::WebSocket)
+                .await
+                .unwrap();
+        let receiver = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        assert_eq!(source, sender.public_key());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn refused_upgrade_fails_clearly() {
+        // A server that only upgrades WebSocket connections
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 426 Upgrade Required\r\nUpgrade: websocket\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let err = DerpClient::connect_via(&addr.to_string(), SecretKey::gen(), Transport::Derp)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Server refused the Derp upgrade: 426 Upgrade Required"
+        );
+    }
+
+    #[tokio::test]
+    async fn server_info_sent_along_with_the_server_key_is_read() {
+        let secret_key = SecretKey::gen();
+        let client_key = secret_key.public();
+        let server_sk = SecretKey::gen();
+        let server_key = server_sk.public();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            // The ServerInfo doesn't wait for the ClientInfo, everything goes in one write
+            let mut handshake = b"HTTP/1.1 101 Switching Protocols\r\n\r\n".to_vec();
+            ServerKey::new(server_key)
+                .frame()
+                .encode(&mut handshake)
+                .unwrap();
+            let payload = ServerInfoPayload {
+                region: Some("pipelined".to_owned()),
+                ..Default::default()
+            };
+            ServerInfo::new(&server_sk, client_key, &payload)
+                .unwrap()
+                .frame()
+                .encode(&mut handshake)
+                .unwrap();
+            stream.write_all(&handshake).await.unwrap();
+            // Hold the connection open until the client is done with it
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let client = timeout(
+            Duration::from_secs(5),
+            DerpClient::connect(&addr.to_string(), secret_key),
+        )
+        .await
+        .expect("the handshake should complete")
+        .unwrap();
+        assert_eq!(client.server_key(), server_key);
+        assert_eq!(client.region(), Some("pipelined"));
+    }
+
+    #[tokio::test]
+    async fn silent_server_fails_the_handshake_in_time() {
+        // Upgrades the connection but never sends its key
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let err = timeout(
+            Duration::from_secs(5),
+            DerpClient::connect_with_handshake_timeout(
+                &addr.to_string(),
+                SecretKey::gen(),
+                Duration::from_millis(200),
+            ),
+        )
+        .await
+        .expect("the handshake should time out")
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "No server key within 200ms");
+    }
+
+    #[tokio::test]
+    async fn connect_gives_up_after_the_connect_timeout() {
+        // TEST-NET-1 is never routed, the attempt either hangs or fails right away
+        let result = timeout(
+            Duration::from_secs(5),
+            DerpClient::connect_with_timeout(
+                "192.0.2.1:9",
+                SecretKey::gen(),
+                Duration::from_millis(200),
+            ),
+        )
+        .await
+        .expect("connecting should give up after the connect timeout");
+        assert!(result.is_err());
+    }
+
+    /// Forwards connections to `target` while `up` is set, others are closed right away.
+    /// Clearing it breaks the connections forwarded so far.
+    async fn outage_proxy(target: SocketAddr) -> (SocketAddr, watch::Sender<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (up, up_receiver) = watch::channel(true);
+        spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let mut up = up_receiver.clone();
+                if !*up.borrow() {
+                    continue;
+                }
+                spawn(async move {
+                    let mut server = TcpStream::connect(target).await.unwrap();
+                    select! {
+                        _ = copy_bidirectional(&mut client, &mut server) => {}
+                        _ = up.wait_for(|up| !*up) => {}
+                    }
+                });
+            }
+        });
+        (addr, up)
+    }
+
+    async fn wait_for_outage(client: &ReconnectingClient) {
+        timeout(Duration::from_secs(5), async {
+            while client.is_connected() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the broken connection should be noticed");
+    }
+
+    #[tokio::test]
+    async fn packets_sent_during_an_outage_are_delivered_after_reconnecting() {
+        let (service, addr) = start_service(&[]).await;
+        let receiver = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+        let (proxy, up) = outage_proxy(addr).await;
+        let sender = ReconnectingClient::connect(
+            &proxy.to_string(),
+            SecretKey::gen(),
+            ReconnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        up.send_replace(false);
+        wait_for_outage(&sender).await;
+        for i in 0..3 {
+            sender
+                .send_packet(receiver.public_key(), vec![i])
+                .await
+                .unwrap();
+        }
+
+        up.send_replace(true);
+        for i in 0..3 {
+            let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("queued packets should be sent once reconnected")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(payload, vec![i]);
+        }
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn packets_over_the_reconnect_queue_fail_with_the_error_policy() {
+        let (_service, addr) = start_service(&[]).await;
+        let (proxy, up) = outage_proxy(addr).await;
+        let options = ReconnectOptions {
+            queue_size: 1,
+            overflow: QueueOverflow::Error,
+            ..Default::default()
+        };
+        let sender = ReconnectingClient::connect(&proxy.to_string(), SecretKey::gen(), options)
+            .await
+            .unwrap();
+
+        up.send_replace(false);
+        wait_for_outage(&sender).await;
+        let target = SecretKey::gen().public();
+        sender.send_packet(target, vec![1]).await.unwrap();
+        assert!(sender.send_packet(target, vec![2]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_packets_are_dropped_when_reconnecting_fails() {
+        let (_service, addr) = start_service(&[]).await;
+        let (proxy, up) = outage_proxy(addr).await;
+        let options = ReconnectOptions {
+            queue_size: 1,
+            reconnect_for: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let sender = ReconnectingClient::connect(&proxy.to_string(), SecretKey::gen(), options)
+            .await
+            .unwrap();
+
+        up.send_replace(false);
+        wait_for_outage(&sender).await;
+        let target = SecretKey::gen().public();
+        for payload in [vec![1], vec![2]] {
+            sender.send_packet(target, payload).await.unwrap();
+        }
+        assert_eq!(sender.dropped(), 1);
+
+        let err = timeout(Duration::from_secs(5), sender.recv_packet())
+            .await
+            .expect("reconnecting should be given up")
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("Gave up reconnecting to {proxy}"));
+        assert_eq!(sender.dropped(), 2);
+    }
+
+    /// Takes the roster changes up to the next [`RosterChange::Complete`]
+    #[cfg(feature = "mesh")]
+    async fn next_roster(roster: &mut Receiver<RosterChange>) -> HashSet<RosterChange> {
+        let mut changes = HashSet::new();
+        loop {
+            let change = timeout(Duration::from_secs(5), roster.recv())
+                .await
+                .expect("roster should complete")
+                .unwrap();
+            if change == RosterChange::Complete {
+                return changes;
+            }
+            changes.insert(change);
+        }
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn watcher_resyncs_its_roster_after_reconnecting() {
+        let (service, addr) = start_service(&["--meshkey", "secret"]).await;
+        let stays = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let leaves = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, stays.public_key()).await;
+        wait_for_peer(&service, leaves.public_key()).await;
+        let (proxy, up) = outage_proxy(addr).await;
+        let watcher = ReconnectingClient::connect_with_meshkey(
+            &proxy.to_string(),
+            SecretKey::gen(),
+            "secret",
+            ReconnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut roster = watcher.watch_conns();
+        assert_eq!(
+            next_roster(&mut roster).await,
+            HashSet::from([
+                RosterChange::Present(stays.public_key()),
+                RosterChange::Present(leaves.public_key()),
+            ])
+        );
+
+        up.send_replace(false);
+        wait_for_outage(&watcher).await;
+        let left = leaves.public_key();
+        drop(leaves);
+        timeout(Duration::from_secs(5), async {
+            while service.read().await.connected_peers().contains(&left) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the server should notice the peer left");
+
+        up.send_replace(true);
+        assert_eq!(
+            next_roster(&mut roster).await,
+            HashSet::from([
+                RosterChange::Gone(left),
+                RosterChange::Present(stays.public_key()),
+            ])
+        );
+    }
 }
@@ -0,0 +1,598 @@
+#[cfg(feature = "mesh")]
+use crate::crypto::PublicKey;
+use crate::{duration::parse_duration, proto::MAX_PACKET_SIZE};
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::{Args, CommandFactory, Parser, ValueEnum};
+use serde_json::Value;
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Config {
+    /// JSON file with options, keyed by their long names, e.g. `{"listen-on": "[::]:8765"}`.
+    /// Options given on the command line override the file.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Path to the mesh key used to authenticate with other derp servers
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub meshkey: Option<String>,
+
+    /// List of other derp servers with which we should create a mesh, `host:port`, or
+    /// `derps://host[:port]` for one reached over TLS whose certificate may be pinned by its
+    /// SHA-256 in hex, `derps://sha256@host[:port]`. An entry can carry the meshkey presented to
+    /// that server, `addr=meshkey`, instead of `--meshkey`.
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub mesh_peers: Vec<String>,
+
+    /// Most mesh peers connected to, the ones listed after are ignored. Duplicates only count
+    /// once.
+    #[cfg(feature = "mesh")]
+    #[arg(long, default_value = "64")]
+    pub max_mesh_peers: usize,
+
+    /// PEM file with the certificates of the CAs that mesh peers reached over TLS must chain
+    /// to, the webpki roots by default
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub mesh_ca: Option<PathBuf>,
+
+    /// Public keys of the relays allowed to mesh with us, with or without the meshkey. Unlike
+    /// the shared meshkey, a leaked relay key can be revoked by removing it here.
+    #[cfg(feature = "mesh")]
+    #[arg(long, value_delimiter = ',')]
+    pub mesh_trusted_keys: Vec<PublicKey>,
+
+    /// File with the secret key, hex or base64, this server dials its mesh peers with. Without
+    /// it a new key is generated on every start, which peers can't list in their trusted keys.
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub relay_key_file: Option<PathBuf>,
+
+    /// Let mesh peers ask for copies of the packets relayed from or to a client, for debugging.
+    /// Off by default, the copies are sent regardless of what the client agreed to.
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub allow_mirroring: bool,
+
+    /// Maximum number of clients subscribed for peer changes, unlimited by default
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub max_watchers: Option<usize>,
+
+    /// Number of peers read from the peer map at a time for the initial roster of a new watcher
+    #[cfg(feature = "mesh")]
+    #[arg(long, default_value = "256")]
+    pub roster_chunk_size: NonZeroUsize,
+
+    /// Initial rosters sent at once, watchers subscribing past it wait for their turn so a
+    /// burst of them, e.g. after a restart, doesn't dump the roster all together. Unlimited by
+    /// default.
+    #[cfg(feature = "mesh")]
+    #[arg(long)]
+    pub max_roster_dumps: Option<NonZeroUsize>,
+
+    /// Address to listen on, link-local IPv6 addresses take a zone, e.g. `[fe80::1%eth0]:8765`
+    #[arg(long, short, required_unless_present = "systemd_socket")]
+    pub listen_on: Option<String>,
+
+    /// Accept connections on the socket passed by systemd socket activation, see
+    /// sd_listen_fds(3), instead of binding `--listen-on`
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "listen_on",
+            "reuse_addr",
+            "no_reuse_addr",
+            "reuse_port",
+            "listen_backlog",
+        ]
+    )]
+    pub systemd_socket: bool,
+
+    /// Address to serve the admin API on, e.g. Prometheus metrics at `/metrics`. It has no
+    /// authentication, bind it to a private address.
+    #[arg(long)]
+    pub admin_listen: Option<String>,
+
+    /// On SIGHUP, start the binary again with the same arguments, hand it the listening socket
+    /// and exit once the clients connected to this process are gone or `--drain-timeout`
+    /// passed. New connections go to the new process in the meantime. Only on unix.
+    #[arg(long)]
+    pub restart_on_signal: bool,
+
+    /// Relay a packet between two in-process clients with this config and exit, nothing is
+    /// bound and no mesh peer is contacted. Exits with 0 if the packet made it.
+    #[arg(long)]
+    pub self_test: bool,
+
+    /// Set SO_REUSEADDR on the listener, lets a restarted server bind while old connections
+    /// linger in TIME_WAIT. Already the default on unix, see `--no-reuse-addr`.
+    #[arg(long)]
+    pub reuse_addr: bool,
+
+    /// Don't set SO_REUSEADDR on the listener, which is otherwise done on unix like the
+    /// standard library does
+    #[arg(long, conflicts_with = "reuse_addr")]
+    pub no_reuse_addr: bool,
+
+    /// Set SO_REUSEPORT on the listener, lets several processes bind the same address. Linux
+    /// balances connections between them, BSDs and macOS hand them all to the last one bound.
+    /// Not available on Windows, Solaris and illumos.
+    #[arg(long)]
+    pub reuse_port: bool,
+
+    /// Connections waiting to be accepted the listener queues, more are refused or retried by
+    /// their clients. The OS silently caps it, e.g. at net.core.somaxconn on Linux and
+    /// kern.ipc.somaxconn on BSDs and macOS.
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..), default_value = "1024")]
+    pub listen_backlog: i32,
+
+    /// Number of tasks accepting connections and running handshakes in parallel
+    #[arg(long, default_value = "1")]
+    pub acceptors: NonZeroUsize,
+
+    /// Decrypt ClientInfos on up to this many blocking threads instead of the threads running
+    /// connections, so a burst of handshakes doesn't hold up relaying. Off by default.
+    #[arg(long)]
+    pub crypto_threads: Option<NonZeroUsize>,
+
+    /// How far the ClientInfo timestamp may be from the server clock, in either direction
+    #[arg(long, value_parser = parse_duration, default_value = "5m")]
+    pub max_clock_skew: Duration,
+
+    /// How long a disconnected client can resume its session, packets sent to it are queued
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    pub resume_token_ttl: Duration,
+
+    /// Largest packet payload forwarded, advertised to clients. Larger packets are dropped.
+    #[arg(long, default_value_t = MAX_PACKET_SIZE)]
+    pub max_packet_size: usize,
+
+    /// Only relay presence, every packet sent through the server is dropped. Advertised to
+    /// clients.
+    #[arg(long)]
+    pub no_forwarding: bool,
+
+    /// Most destinations one SendPackets frame may name, advertised to clients. Frames naming
+    /// more are dropped whole.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..), default_value_t = 16)]
+    pub max_fanout: u8,
+
+    /// What happens to a SendPackets frame when one of its destinations can't take more
+    /// packets right now
+    #[arg(long, value_enum, default_value = "skip")]
+    pub fanout_overflow: FanoutOverflow,
+
+    /// Authentication failures, i.e. ClientInfos that don't decrypt or wrong meshkeys, one IP
+    /// may cause within the auth failure window. Its connections are refused for the rest of
+    /// that window. Unlimited if not set.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_auth_failures: Option<u64>,
+
+    /// Window of `--max-auth-failures`, starting at an IP's first authentication failure
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    pub auth_failure_window: Duration,
+
+    /// Region this server serves, advertised to clients to help them pick their home relay
+    #[arg(long)]
+    pub region: Option<String>,
+
+    /// Disconnect clients sending frames the server can't handle, of unknown types, with a
+    /// wrong length or contents that don't decode, instead of skipping them. Useful for
+    /// debugging clients.
+    #[arg(long)]
+    pub strict_protocol: bool,
+
+    /// Read buffers shared by the connections, no more are allocated. Connections only hold
+    /// one for a read that has data, so idle ones and those stalled in a frame cost none
+    /// however many there are.
+    #[arg(long, default_value = "64")]
+    pub read_buffer_pool: NonZeroUsize,
+
+    /// Bytes all connections together may hold in queued packets and write buffers. Past it
+    /// the connections holding the most are evicted until the rest fits.
+    #[arg(long)]
+    pub memory_budget: Option<usize>,
+
+    /// Payload bytes a client may send over one connection. Past it the client is told to
+    /// reconnect and the connection is closed, so long-lived clients get rebalanced.
+    #[arg(long)]
+    pub max_connection_bytes: Option<u64>,
+
+    /// Daily window, in UTC, in which new connections are refused with 503 while the clients
+    /// already connected stay, e.g. `02:00-04:00`. Can be given several times.
+    #[arg(long)]
+    pub maintenance_window: Vec<MaintenanceWindow>,
+
+    /// Show whole public keys in logs instead of their first 8 hex characters
+    #[arg(long)]
+    pub log_full_keys: bool,
+
+    #[command(flatten)]
+    pub timeouts: Timeouts,
+
+    #[command(flatten)]
+    pub frame_trace: FrameTrace,
+
+    #[command(flatten)]
+    pub destination_limit: DestinationLimit,
+
+    #[command(flatten)]
+    pub frame_rate_limit: FrameRateLimit,
+}
+
+/// Timeouts accept human readable durations, e.g. `500ms`, `30s` or `1m30s`
+#[derive(Args, Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Time a new connection has to complete the whole handshake, from the HTTP upgrade request
+    /// to the ServerInfo. Connections that didn't send the upgrade request by then get a 408.
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub handshake_timeout: Duration,
+
+    /// Time a client has to send its ClientInfo after the upgrade, within the above
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub client_info_timeout: Duration,
+
+    /// Handshakes taking longer than this are logged with the time each phase took, to tell a
+    /// slow network from a busy server. Off by default.
+    #[arg(long, value_parser = parse_duration)]
+    pub slow_handshake_threshold: Option<Duration>,
+
+    /// Time after which a client that sent nothing is disconnected. Off by default, the
+    /// server sends no keepalives and clients may have nothing to send for a long time.
+    #[arg(long, value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Time a single write to a client may take before the client is disconnected
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub write_timeout: Duration,
+
+    /// Time frames queued for a client are collected before they're written together, fewer
+    /// writes for a bit of latency. `0s` writes every frame right away.
+    #[arg(long, value_parser = parse_duration, default_value = "1ms")]
+    pub write_flush_interval: Duration,
+
+    /// Time after which a client making no progress is disconnected: nothing read and nothing
+    /// written, or a write stuck that long even though the client keeps sending. Off by default.
+    #[arg(long, value_parser = parse_duration)]
+    pub liveness_timeout: Option<Duration>,
+
+    /// Time connecting to a mesh peer may take, over all the addresses it resolved to
+    #[cfg(feature = "mesh")]
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub mesh_connect_timeout: Duration,
+
+    /// Time the clients have to disconnect after a restart, see `--restart-on-signal`. Those
+    /// still connected then are disconnected.
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    pub drain_timeout: Duration,
+
+    /// Time a mesh peer has to complete the key exchange after connecting
+    #[cfg(feature = "mesh")]
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub mesh_handshake_timeout: Duration,
+
+    /// Time between pings on our mesh links, a link can stay up at the TCP level while the
+    /// relay at the other end is stuck
+    #[cfg(feature = "mesh")]
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub mesh_ping_interval: Duration,
+
+    /// Time a mesh peer has to answer a ping before the link is reconnected
+    #[cfg(feature = "mesh")]
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub mesh_ping_timeout: Duration,
+}
+
+impl Config {
+    /// Parses the command line of the process, see [`load_from`](Self::load_from)
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(std::env::args())
+    }
+
+    /// Like [`Parser::parse_from`], with the options of the `--config` file put in front of the
+    /// command line. Options on the command line replace the file's rather than add to them.
+    pub fn load_from<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Self> {
+        let mut args: Vec<String> = args.into_iter().collect();
+        let Some(path) = config_path(&args) else {
+            return Ok(Self::parse_from(args));
+        };
+        let file = std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading config file {}", path.display()))?;
+        let file: serde_json::Map<String, Value> = serde_json::from_str(&file)
+            .with_context(|| format!("Parsing config file {}", path.display()))?;
+        let file_args = file_args(&file, &args[1..])
+            .with_context(|| format!("In config file {}", path.display()))?;
+        args.splice(1..1, file_args);
+        Ok(Self::parse_from(args))
+    }
+}
+
+/// Value of `--config` on the command line
+fn config_path(args: &[String]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Turns the options of a config file into command line arguments, leaving out the ones that
+/// are on the command line already
+fn file_args(file: &serde_json::Map<String, Value>, cli: &[String]) -> anyhow::Result<Vec<String>> {
+    let command = Config::command();
+    let mut args = Vec::new();
+    for (name, value) in file {
+        let long = name.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .filter(|_| long != "config")
+            .ok_or_else(|| anyhow!("Unknown option {name:?}"))?;
+        let on_cli = cli.iter().any(|cli_arg| {
+            let cli_arg = cli_arg.split('=').next().unwrap_or_default();
+            cli_arg.strip_prefix("--") == Some(long.as_str())
+                || arg
+                    .get_short()
+                    .is_some_and(|short| cli_arg == format!("-{short}"))
+        });
+        if on_cli {
+            continue;
+        }
+        let flag = format!("--{long}");
+        match value {
+            Value::Null | Value::Bool(false) => {}
+            Value::Bool(true) => args.push(flag),
+            Value::Array(values) => {
+                for value in values {
+                    args.push(flag.clone());
+                    args.push(scalar(name, value)?);
+                }
+            }
+            value => {
+                args.push(flag);
+                args.push(scalar(name, value)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn scalar(name: &str, value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        _ => bail!("Option {name:?} must be a string or a number, not {value}"),
+    }
+}
+
+/// Limit on the distinct peers a client sends to, clients going over it may be scanning for keys
+#[derive(Args, Debug, Clone, Copy)]
+pub struct DestinationLimit {
+    /// Distinct destinations a client may send to within the window, unlimited by default
+    #[arg(long = "max-destinations")]
+    pub max: Option<usize>,
+
+    /// Window in which the distinct destinations of a client are counted
+    #[arg(long = "max-destinations-window", value_parser = parse_duration, default_value = "1m")]
+    pub window: Duration,
+
+    /// What happens to a client over the limit
+    #[arg(long = "max-destinations-action", value_enum, default_value = "flag")]
+    pub action: DestinationLimitAction,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationLimitAction {
+    /// Log the client once per window
+    Flag,
+    /// Log the client and drop its packets to destinations it didn't send to in the window
+    Drop,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanoutOverflow {
+    /// Drop the packet only for the full destinations, the others still get it
+    Skip,
+    /// Drop the packet for every destination, so they all get it or none does
+    DropAll,
+}
+
+/// Limit on the frames a client sends, tiny frames cost CPU however few bytes they carry
+#[derive(Args, Debug, Clone, Copy)]
+pub struct FrameRateLimit {
+    /// Frames per second a client may send on average, in bursts of up to as many. Unlimited
+    /// by default.
+    #[arg(long = "max-frames-per-sec")]
+    pub max: Option<NonZeroU32>,
+
+    /// What happens to a client over the limit
+    #[arg(long = "frame-rate-action", value_enum, default_value = "throttle")]
+    pub action: FrameRateAction,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameRateAction {
+    /// Stop reading from the client until it's back under the limit
+    Throttle,
+    /// Disconnect the client
+    Disconnect,
+}
+
+/// A daily window given as `HH:MM-HH:MM` in UTC. Windows ending before they start span
+/// midnight, ones ending when they start last the whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// Seconds since midnight
+    start: u64,
+    end: u64,
+}
+
+impl MaintenanceWindow {
+    /// Time left in the window at `now`, `None` outside of it
+    pub fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % DAY_SECS;
+        let length = match (self.end + DAY_SECS - self.start) % DAY_SECS {
+            0 => DAY_SECS,
+            length => length,
+        };
+        let elapsed = (now + DAY_SECS - self.start) % DAY_SECS;
+        (elapsed < length).then(|| Duration::from_secs(length - elapsed))
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Expected a window like 02:00-04:00, got {s:?}"))?;
+        Ok(MaintenanceWindow {
+            start: time_of_day(start)?,
+            end: time_of_day(end)?,
+        })
+    }
+}
+
+/// Seconds since midnight of a `HH:MM` time
+fn time_of_day(s: &str) -> anyhow::Result<u64> {
+    let (hours, minutes) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected a time like 02:00, got {s:?}"))?;
+    let (hours, minutes): (u64, u64) = (hours.parse()?, minutes.parse()?);
+    ensure!(hours < 24 && minutes < 60, "{s:?} isn't a time of day");
+    Ok(hours * 60 * 60 + minutes * 60)
+}
+
+/// Debug logging of the frames going through the server, logged at trace level
+#[derive(Args, Debug, Clone, Copy, Default)]
+pub struct FrameTrace {
+    /// Log every sent and received frame with its type, length and public keys
+    #[arg(long = "trace-frames")]
+    pub frames: bool,
+
+    /// Include the frame payloads as hex in the frame trace
+    #[arg(long = "trace-payloads", requires = "frames")]
+    pub payloads: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a new file in the temp dir
+    fn config_file(contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dersp-config-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> anyhow::Result<Config> {
+        Config::load_from(["dersp"].iter().chain(args).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options_are_read_from_the_file() {
+        let path = config_file(
+            r#"{
+                "listen-on": "127.0.0.1:1234",
+                "reuse-addr": true,
+                "idle-timeout": "30s",
+                "max_packet_size": 1000
+            }"#,
+        );
+        let config = load(&["--config", path.to_str().unwrap()]).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.listen_on.as_deref(), Some("127.0.0.1:1234"));
+        assert!(config.reuse_addr);
+        assert_eq!(config.timeouts.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.max_packet_size, 1000);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[test]
+    fn command_line_overrides_the_file() {
+        let path = config_file(
+            r#"{"listen-on": "127.0.0.1:1234", "region": "eu", "mesh-peers": ["a:1", "b:2"]}"#,
+        );
+        let path_arg = format!("--config={}", path.display());
+        let config = load(&["-l", "127.0.0.1:5678", &path_arg, "--mesh-peers", "c:3"]).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.listen_on.as_deref(), Some("127.0.0.1:5678"));
+        assert_eq!(config.region.as_deref(), Some("eu"));
+        // Lists from the command line replace the file's instead of extending them
+        assert_eq!(config.mesh_peers, ["c:3"]);
+    }
+
+    #[test]
+    fn invalid_config_files_are_refused() {
+        assert!(load(&["--config", "/nonexistent/dersp.json"]).is_err());
+
+        let path = config_file("{\"listen-on\": ");
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{err:#}").starts_with("Parsing config file"));
+
+        let path = config_file(r#"{"listen-on": "127.0.0.1:0", "listen-of": "typo"}"#);
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{err:#}").ends_with("Unknown option \"listen-of\""));
+
+        let path = config_file(r#"{"listen-on": {"host": "127.0.0.1"}}"#);
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{err:#}").contains("must be a string or a number"));
+    }
+
+    #[test]
+    fn maintenance_windows_may_span_midnight() {
+        let at = |hours: u64, minutes: u64| {
+            UNIX_EPOCH + Duration::from_secs(3 * DAY_SECS + hours * 60 * 60 + minutes * 60)
+        };
+        let window: MaintenanceWindow = "02:00-04:00".parse().unwrap();
+        assert_eq!(window.remaining(at(1, 59)), None);
+        assert_eq!(
+            window.remaining(at(3, 30)),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(window.remaining(at(4, 0)), None);
+
+        let window: MaintenanceWindow = "23:00-01:00".parse().unwrap();
+        assert_eq!(
+            window.remaining(at(23, 0)),
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+        assert_eq!(
+            window.remaining(at(0, 30)),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(window.remaining(at(12, 0)), None);
+
+        let window: MaintenanceWindow = "06:00-06:00".parse().unwrap();
+        assert!(window.remaining(at(5, 59)).is_some());
+
+        assert!("02:00".parse::<MaintenanceWindow>().is_err());
+        assert!("24:00-01:00".parse::<MaintenanceWindow>().is_err());
+        assert!("02:60-03:00".parse::<MaintenanceWindow>().is_err());
+    }
+}
@@ -9,7 +9,7 @@
 //! [ec]: https://en.wikipedia.org/wiki/Curve25519
 //!
 //! ```
-//! # use telio_crypto::{KeyDecodeError, SecretKey};
+//! # use dersp::crypto::{KeyDecodeError, SecretKey};
 //! # fn main() -> Result<(), KeyDecodeError> {
 //! const HEX_INPUT: &str = "babababababababababababababababababababababababababababababababa";
 //! const BASE64_INPUT: &str = "urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6uro=";
@@ -20,19 +20,24 @@
 //! # }
 //! ```
 
-use std::{convert::TryInto, fmt};
+use std::{
+    convert::TryInto,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use rand::prelude::*;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
+use zeroize::Zeroize;
 
 use codec::{Decode, Encode};
 
 /// Secret, Public and Wireguard Preshared key size in bytes
 pub const KEY_SIZE: usize = 32;
 
-/// Secret key type
+/// Secret key type, its bytes are zeroed when it's dropped
 #[derive(
-    Default, PartialOrd, Ord, PartialEq, Eq, Hash, Copy, Clone, DeserializeFromStr, SerializeDisplay,
+    Default, PartialOrd, Ord, PartialEq, Eq, Hash, Clone, DeserializeFromStr, SerializeDisplay,
 )]
 pub struct SecretKey([u8; KEY_SIZE]);
 
@@ -53,6 +58,31 @@ pub struct SecretKey([u8; KEY_SIZE]);
 )]
 pub struct PublicKey(pub [u8; KEY_SIZE]);
 
+/// Number of hex characters of a public key shown in logs by default
+pub const LOG_KEY_PREFIX_LEN: usize = 8;
+
+static LOG_FULL_KEYS: AtomicBool = AtomicBool::new(false);
+
+/// Makes [`PublicKey::log_display`] show whole keys instead of their prefix, for the whole process
+pub fn set_log_full_keys(full: bool) {
+    LOG_FULL_KEYS.store(full, Ordering::Relaxed);
+}
+
+/// Public key as shown in logs, see [`PublicKey::log_display`]
+#[derive(Copy, Clone)]
+pub struct LogKey(PublicKey);
+
+impl fmt::Display for LogKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = format!("{:x}", self.0);
+        if LOG_FULL_KEYS.load(Ordering::Relaxed) {
+            f.write_str(&hex)
+        } else {
+            f.write_str(&hex[..LOG_KEY_PREFIX_LEN])
+        }
+    }
+}
+
 /// Preshared key type
 #[derive(
     Default, PartialOrd, Ord, PartialEq, Eq, Hash, Copy, Clone, DeserializeFromStr, SerializeDisplay,
@@ -88,7 +118,7 @@ impl SecretKey {
     /// # Examples
     ///
     /// ```
-    /// # use telio_crypto::SecretKey;
+    /// # use dersp::crypto::SecretKey;
     /// let secret_key_a = SecretKey::gen();
     /// let secret_key_b = SecretKey::gen();
     /// assert_ne!(secret_key_a, secret_key_b);
@@ -116,7 +146,7 @@ impl SecretKey {
     /// # Examples
     ///
     /// ```
-    /// # use telio_crypto::SecretKey;
+    /// # use dersp::crypto::SecretKey;
     /// # let secret_key_a = SecretKey::gen();
     /// # let secret_key_b = SecretKey::gen();
     /// let pub_key_a = secret_key_a.public();
@@ -143,6 +173,11 @@ impl PublicKey {
     pub const fn new(bytes: [u8; 32]) -> Self {
         Self(bytes)
     }
+
+    /// Short hex prefix of the key for log lines, the whole key once [`set_log_full_keys`] is set
+    pub fn log_display(&self) -> LogKey {
+        LogKey(*self)
+    }
 }
 
 impl PresharedKey {
@@ -152,6 +187,18 @@ impl PresharedKey {
     }
 }
 
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
 impl From<crypto_box::SecretKey> for SecretKey {
     fn from(sk: crypto_box::SecretKey) -> Self {
         Self(*sk.as_bytes())
@@ -292,13 +339,6 @@ macro_rules! gen_common {
             }
         }
 
-        impl fmt::Debug for $t {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                let buf = base64::encode(&self.0);
-                f.write_str(&format!("\"{:.*}...{}\"", 4, &buf, &buf.get((buf.len())-4..).ok_or(fmt::Error)?))
-            }
-        }
-
     };
     ($t:ty, $($tt:ty),+) => {
         gen_common!($t);
@@ -307,6 +347,40 @@ macro_rules! gen_common {
 }
 gen_common!(SecretKey, PublicKey, PresharedKey);
 
+macro_rules! gen_short_debug {
+    ($($t:ty),+) => {
+        $(
+            impl fmt::Debug for $t {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    let buf = base64::encode(&self.0);
+                    f.write_str(&format!("\"{:.*}...{}\"", 4, &buf, &buf.get((buf.len())-4..).ok_or(fmt::Error)?))
+                }
+            }
+        )+
+    };
+}
+gen_short_debug!(PublicKey, PresharedKey);
+
+/// Deterministic keys for tests. Every generator yields the same keys in the same order, so
+/// the peers a test connects are the same on every run.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct TestKeys {
+    issued: u64,
+}
+
+#[cfg(test)]
+impl TestKeys {
+    /// Key built from the number of keys issued before it
+    pub fn next_key(&mut self) -> SecretKey {
+        self.issued += 1;
+        let mut bytes = [0; KEY_SIZE];
+        // Clear of the bits clamping changes
+        bytes[1..9].copy_from_slice(&self.issued.to_le_bytes());
+        SecretKey::new(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -314,7 +388,6 @@ mod tests {
     const SK: SecretKey = SecretKey::new([0xBAu8; 32]);
     const SK_HEX: &str = "b8babababababababababababababababababababababababababababababa7a";
     const SK_B64: &str = "uLq6urq6urq6urq6urq6urq6urq6urq6urq6urq6uno=";
-    const SK_B64_SHORT: &str = "\"uLq6...uno=\"";
     const PK: PublicKey = PublicKey([
         124, 138, 97, 25, 210, 221, 193, 169, 240, 19, 235, 72, 147, 68, 8, 93, 67, 1, 26, 73, 54,
         36, 116, 129, 248, 12, 124, 44, 238, 225, 78, 53,
@@ -323,6 +396,23 @@ mod tests {
     const PK_B64: &str = "fIphGdLdwanwE+tIk0QIXUMBGkk2JHSB+Ax8LO7hTjU=";
     const PK_B64_SHORT: &str = "\"fIph...TjU=\"";
 
+    #[test]
+    fn test_keys_are_distinct_and_stable() {
+        let mut keys = TestKeys::default();
+        let issued: Vec<SecretKey> = (0..1000).map(|_| keys.next_key()).collect();
+        let public: std::collections::HashSet<PublicKey> =
+            issued.iter().map(SecretKey::public).collect();
+        assert_eq!(public.len(), issued.len());
+
+        let mut again = TestKeys::default();
+        assert!(issued.iter().all(|key| *key == again.next_key()));
+        // Not derived from anything that could change between runs
+        let mut first = [0; KEY_SIZE];
+        first[1] = 1;
+        first[31] = 64;
+        assert_eq!(issued[0], SecretKey::new(first));
+    }
+
     #[test]
     fn secret_key_is_clammped() {
         assert_eq!(SK.as_bytes()[0], 0xb8);
@@ -338,10 +428,26 @@ mod tests {
     fn convert_to_base64() {
         assert_eq!(SK_B64, &format!("{}", SK));
         assert_eq!(PK_B64, &format!("{}", PK));
-        assert_eq!(SK_B64_SHORT, &format!("{:?}", SK));
         assert_eq!(PK_B64_SHORT, &format!("{:?}", PK));
     }
 
+    #[test]
+    fn secret_key_debug_hides_the_key() {
+        let debug = format!("{:?}", SK);
+        assert_eq!(debug, "SecretKey(..)");
+        assert!(!debug.contains(&SK_B64[..4]));
+        assert!(!debug.contains(&SK_HEX[..4]));
+    }
+
+    #[test]
+    fn cloned_secret_key_still_works_after_original_is_dropped() {
+        let original = SecretKey::gen();
+        let public = original.public();
+        let clone = original.clone();
+        drop(original);
+        assert_eq!(clone.public(), public);
+    }
+
     #[test]
     fn convert_from_base64() {
         assert_eq!(SK, SK_B64.parse().unwrap());
@@ -354,6 +460,13 @@ mod tests {
         assert_eq!(PK_HEX, &format!("{:x}", PK));
     }
 
+    #[test]
+    fn log_display_is_a_hex_prefix() {
+        let short = PK.log_display().to_string();
+        assert_eq!(short.len(), LOG_KEY_PREFIX_LEN);
+        assert!(PK_HEX.starts_with(&short));
+    }
+
     #[test]
     fn convert_from_hex() {
         assert_eq!(SK, SK_HEX.parse().unwrap());
@@ -0,0 +1,111 @@
+//! Parsing of human readable durations used by the timeout options.
+//!
+//! A duration is one or more `<number><unit>` segments, e.g. `500ms`, `30s` or `1m30s`.
+//! Supported units are `ms`, `s`, `m` and `h`.
+
+use std::time::Duration;
+
+/// Error returned when a duration string can't be parsed.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ParseDurationError {
+    /// The input string was empty.
+    #[error("empty duration")]
+    Empty,
+    /// A unit was found where a number was expected.
+    #[error("expected a number at position {0}")]
+    MissingNumber(usize),
+    /// A number was not followed by a unit.
+    #[error("missing unit after {0} (expected one of: ms, s, m, h)")]
+    MissingUnit(u64),
+    /// The unit is not one of the supported ones.
+    #[error("unknown unit {0:?} (expected one of: ms, s, m, h)")]
+    UnknownUnit(String),
+    /// The duration doesn't fit into a `Duration`.
+    #[error("duration is too big")]
+    Overflow,
+}
+
+/// Parse a human readable duration like `30s` or `1m30s`.
+pub fn parse_duration(input: &str) -> Result<Duration, ParseDurationError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(ParseDurationError::Empty);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let position = input.len() - rest.len();
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(ParseDurationError::MissingNumber(position));
+        }
+        let value: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| ParseDurationError::Overflow)?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let segment = match &rest[..unit_len] {
+            "" => return Err(ParseDurationError::MissingUnit(value)),
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(60 * 60).map(Duration::from_secs),
+            unit => return Err(ParseDurationError::UnknownUnit(unit.to_owned())),
+        };
+        rest = &rest[unit_len..];
+
+        total = segment
+            .and_then(|segment| total.checked_add(segment))
+            .ok_or(ParseDurationError::Overflow)?;
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_millis() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parse_seconds() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn parse_minutes() {
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+    }
+
+    #[test]
+    fn parse_compound() {
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_duration(""), Err(ParseDurationError::Empty));
+        assert_eq!(
+            parse_duration("30"),
+            Err(ParseDurationError::MissingUnit(30))
+        );
+        assert_eq!(
+            parse_duration("30 seconds"),
+            Err(ParseDurationError::UnknownUnit(" seconds".to_owned()))
+        );
+        assert_eq!(
+            parse_duration("s"),
+            Err(ParseDurationError::MissingNumber(0))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Fault injection for transports, to test partial reads, stalls and broken connections
+//! deterministically.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// Wraps a stream and misbehaves on reads as configured, writes go through untouched
+pub struct FaultyStream<S> {
+    inner: S,
+    read_chunk: Option<usize>,
+    read_delay: Option<Duration>,
+    fail_reads_after: Option<usize>,
+    /// Delay of the read in progress, kept until the read completes
+    delay: Option<Pin<Box<Sleep>>>,
+    read: usize,
+}
+
+impl<S> FaultyStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read_chunk: None,
+            read_delay: None,
+            fail_reads_after: None,
+            delay: None,
+            read: 0,
+        }
+    }
+
+    /// Returns at most `max` bytes per read, so frames end up split at awkward boundaries
+    pub fn split_reads(mut self, max: usize) -> Self {
+        assert!(max > 0, "reads must return at least one byte");
+        self.read_chunk = Some(max);
+        self
+    }
+
+    /// Waits `delay` before every read
+    pub fn delay_reads(mut self, delay: Duration) -> Self {
+        self.read_delay = Some(delay);
+        self
+    }
+
+    /// Fails every read with `ConnectionReset` once `bytes` were read
+    pub fn fail_reads_after(mut self, bytes: usize) -> Self {
+        self.fail_reads_after = Some(bytes);
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(read_delay) = this.read_delay {
+            let delay = this
+                .delay
+                .get_or_insert_with(|| Box::pin(sleep(read_delay)));
+            ready!(delay.as_mut().poll(cx));
+        }
+
+        let mut limit = buf.remaining();
+        if let Some(read_chunk) = this.read_chunk {
+            limit = limit.min(read_chunk);
+        }
+        if let Some(fail_reads_after) = this.fail_reads_after {
+            let left = fail_reads_after.saturating_sub(this.read);
+            if left == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "injected fault",
+                )));
+            }
+            limit = limit.min(left);
+        }
+
+        let mut chunk = vec![0; limit];
+        let mut chunk = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+        buf.put_slice(chunk.filled());
+        this.read += chunk.filled().len();
+        this.delay = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time::Instant};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn reads_are_split() {
+        let mut stream = FaultyStream::new(Cursor::new(vec![1, 2, 3, 4, 5])).split_reads(2);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 1);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reads_fail_after_the_given_bytes() {
+        let mut stream = FaultyStream::new(Cursor::new(vec![1, 2, 3, 4, 5])).fail_reads_after(3);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 3);
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn reads_are_delayed() {
+        let mut stream =
+            FaultyStream::new(Cursor::new(vec![1])).delay_reads(Duration::from_millis(50));
+        let started = Instant::now();
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -1,12 +1,39 @@
-use crate::proto::data::{FrameType, Header};
+use crate::proto::{
+    data::{FrameType, Header},
+    ProtoError,
+};
 use anyhow::anyhow;
 use codec::Decode;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use std::{
+    future::poll_fn,
+    io,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::Poll,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 
-const HEADER_SIZE: usize = 5;
+pub const HEADER_SIZE: usize = 5;
 /// Max TCP packet size is 65535
 const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
+/// Buffers of the pool of readers created with [`DerpReader::new`]
+const SHARED_POOL_SIZE: usize = 16;
+/// Capacity an input buffer keeps once it's drained, more is freed so idle connections stay small
+const MAX_IDLE_INPUT_CAPACITY: usize = 4096;
 
+/// Returned when the other side closed the connection
+#[derive(Debug, thiserror::Error)]
+#[error("connection closed")]
+pub struct ConnectionClosed;
+
+#[derive(Debug)]
 pub struct Message {
     pub ty: FrameType,
     pub buffer: Vec<u8>,
@@ -20,11 +47,44 @@ enum PartMessage {
 #[derive(Default)]
 pub struct InputBuffer {
     data: Vec<u8>,
+    /// Bytes of a rejected frame that didn't arrive yet, dropped when they do
+    skip: usize,
 }
 
 impl InputBuffer {
     pub fn input_data(&mut self, data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.data.extend(&data[skipped..]);
+    }
+
+    /// Like [`input_data`](Self::input_data) followed by `next_message`, but a whole frame at
+    /// the start of `data` is taken from it directly instead of going through the buffer
+    fn input_and_next(&mut self, data: &[u8]) -> anyhow::Result<PartMessage> {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        let data = &data[skipped..];
+        if let Some((ty, len)) = whole_frame(data).filter(|_| self.data.is_empty()) {
+            self.data.extend(&data[len..]);
+            return Ok(PartMessage::Message(Message {
+                ty,
+                buffer: data[..len].to_vec(),
+            }));
+        }
         self.data.extend(data);
+        self.next_message()
+    }
+
+    /// Bytes still to come of the frame started in the buffer, if one is
+    fn missing(&self) -> Option<usize> {
+        match self.data.len() {
+            0 => None,
+            len if len < HEADER_SIZE => Some(HEADER_SIZE - len),
+            len => {
+                let header = Header::decode(&mut &self.data[..HEADER_SIZE]).ok()?;
+                Some((HEADER_SIZE + header.size as usize).saturating_sub(len))
+            }
+        }
     }
 
     fn next_message(&mut self) -> anyhow::Result<PartMessage> {
@@ -35,48 +95,517 @@ impl InputBuffer {
         let mut header = [0; HEADER_SIZE];
         header.copy_from_slice(&self.data[..HEADER_SIZE]);
         let header = Header::decode(&mut header.as_slice()).map_err(|_| anyhow!("Decode error"))?;
+        if header.size as usize > MAX_TCP_PACKET_SIZE {
+            return Err(ProtoError::FrameTooLarge {
+                frame_type: header.frame_type,
+                size: header.size,
+            }
+            .into());
+        }
+        if let Some(expected) = header.frame_type.expected_size() {
+            if !expected.contains(&header.size) {
+                // The length is still right about where the next frame starts
+                let frame_size = HEADER_SIZE.saturating_add(header.size as usize);
+                let dropped = frame_size.min(self.data.len());
+                self.data.drain(..dropped);
+                self.skip = frame_size - dropped;
+                return Err(ProtoError::FrameLengthMismatch {
+                    frame_type: header.frame_type,
+                    expected,
+                    actual: header.size,
+                }
+                .into());
+            }
+        }
 
         let message_size = HEADER_SIZE + (header.size as usize);
         if self.data.len() >= message_size {
             // We can extract a message
-            let buffer = self.data.drain(..message_size).collect();
-            return Ok(PartMessage::Message(Message {
+            let buffer = if self.data.len() == message_size {
+                std::mem::take(&mut self.data)
+            } else {
+                self.data.drain(..message_size).collect()
+            };
+            if self.data.is_empty() && self.data.capacity() > MAX_IDLE_INPUT_CAPACITY {
+                self.data = Vec::new();
+            }
+            Ok(PartMessage::Message(Message {
                 ty: header.frame_type,
                 buffer,
-            }));
+            }))
         } else {
             // Insufficient data
-            return Ok(PartMessage::InsufficientData);
+            Ok(PartMessage::InsufficientData)
+        }
+    }
+}
+
+/// Type and length of the frame at the start of `data`, if all of it is there and its length
+/// is the one expected of its type
+fn whole_frame(data: &[u8]) -> Option<(FrameType, usize)> {
+    let header = Header::decode(&mut data.get(..HEADER_SIZE)?).ok()?;
+    let len = HEADER_SIZE + header.size as usize;
+    let expected = header.frame_type.expected_size();
+    let valid = expected.map_or(true, |expected| expected.contains(&header.size));
+    (valid && data.len() >= len).then_some((header.frame_type, len))
+}
+
+/// Read buffers shared by the readers of many connections, at most `size` of them exist.
+/// Readers lease one only for a read that has data, so idle connections and those stalled in a
+/// frame don't hold any. Returned buffers are kept for reuse.
+#[derive(Debug)]
+pub struct BufferPool {
+    /// One for each buffer that may be leased
+    permits: Arc<Semaphore>,
+    free: Mutex<Vec<Box<[u8]>>>,
+    leased: AtomicUsize,
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            permits: Arc::new(Semaphore::new(size)),
+            free: Mutex::new(Vec::new()),
+            leased: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+        })
+    }
+
+    /// Pool of the readers created with [`DerpReader::new`]
+    pub fn shared() -> Arc<Self> {
+        static SHARED: OnceLock<Arc<BufferPool>> = OnceLock::new();
+        SHARED
+            .get_or_init(|| BufferPool::new(SHARED_POOL_SIZE))
+            .clone()
+    }
+
+    /// Takes a free buffer, or allocates one if there's none, it's returned when dropped. Waits
+    /// for one to be returned if all are leased.
+    pub async fn lease(self: &Arc<Self>) -> BufferLease {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the pool never closes its semaphore");
+        let buffer = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            vec![0; MAX_TCP_PACKET_SIZE].into_boxed_slice()
+        });
+        self.leased.fetch_add(1, Ordering::Relaxed);
+        BufferLease {
+            pool: self.clone(),
+            buffer: Some(buffer),
+            _permit: permit,
         }
     }
+
+    /// Buffers currently leased
+    pub fn leased(&self) -> usize {
+        self.leased.load(Ordering::Relaxed)
+    }
+
+    /// Buffers kept for reuse
+    pub fn free(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Buffers allocated since the pool was created
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    fn give_back(&self, buffer: Box<[u8]>) {
+        self.leased.fetch_sub(1, Ordering::Relaxed);
+        self.free.lock().unwrap().push(buffer);
+    }
 }
 
+/// A buffer of a [`BufferPool`], returned to it when dropped
+pub struct BufferLease {
+    pool: Arc<BufferPool>,
+    buffer: Option<Box<[u8]>>,
+    /// Released once the buffer is back in the pool
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for BufferLease {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer
+            .as_deref()
+            .expect("buffer is only taken on drop")
+    }
+}
+
+impl DerefMut for BufferLease {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer
+            .as_deref_mut()
+            .expect("buffer is only taken on drop")
+    }
+}
+
+impl Drop for BufferLease {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.give_back(buffer);
+        }
+    }
+}
+
+/// Reads frames from a stream. Bytes past the end of a frame are kept for the next call, so
+/// frames may be split across reads or several of them may arrive in one read.
 pub struct DerpReader<T: AsyncRead + Unpin> {
     reader: T,
-    read_buffer: [u8; MAX_TCP_PACKET_SIZE],
+    pool: Arc<BufferPool>,
     input_buffer: InputBuffer,
+    /// Kept at the capacity of `input_buffer`, see [`track_buffered`](Self::track_buffered)
+    buffered: Option<Arc<AtomicUsize>>,
 }
 
 impl<T: AsyncRead + Unpin> DerpReader<T> {
     pub fn new(reader: T) -> Self {
+        Self::with_pool(reader, BufferPool::shared())
+    }
+
+    /// Reads the frames with buffers leased from `pool`
+    pub fn with_pool(reader: T, pool: Arc<BufferPool>) -> Self {
         DerpReader {
             reader,
-            read_buffer: [0; MAX_TCP_PACKET_SIZE],
+            pool,
             input_buffer: InputBuffer::default(),
+            buffered: None,
+        }
+    }
+
+    /// Keeps `buffered` at the bytes held for frames that aren't complete yet, for the memory
+    /// accounting of the connection
+    pub fn track_buffered(&mut self, buffered: Arc<AtomicUsize>) {
+        self.buffered = Some(buffered);
+        self.report_buffered();
+    }
+
+    fn report_buffered(&self) {
+        if let Some(buffered) = &self.buffered {
+            buffered.store(self.input_buffer.data.capacity(), Ordering::Relaxed);
         }
     }
 
     pub async fn get_next_message(&mut self) -> anyhow::Result<Message> {
+        let message = self.read_message().await;
+        self.report_buffered();
+        message
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let PartMessage::Message(message) = self.input_buffer.next_message()? {
+                return Ok(message);
+            }
+            match self.input_buffer.missing() {
+                // The rest of a started frame is read right after its start
+                Some(missing) => {
+                    let data = &mut self.input_buffer.data;
+                    data.reserve_exact(missing.min(MAX_TCP_PACKET_SIZE));
+                    // Counted while the reader waits for the rest
+                    self.report_buffered();
+                    if self.reader.read_buf(&mut self.input_buffer.data).await? == 0 {
+                        return Err(ConnectionClosed.into());
+                    }
+                }
+                None => {
+                    let (buffer, size) = self.read_leased().await?;
+                    if size == 0 {
+                        return Err(ConnectionClosed.into());
+                    }
+                    let message = self.input_buffer.input_and_next(&buffer[..size])?;
+                    if let PartMessage::Message(message) = message {
+                        return Ok(message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads into a pooled buffer. It's leased only while the reader has data, a reader waiting
+    /// for more gives it back until it's woken.
+    async fn read_leased(&mut self) -> io::Result<(BufferLease, usize)> {
+        let reader = &mut self.reader;
         loop {
-            let message = self.input_buffer.next_message()?;
-            match message {
-                PartMessage::InsufficientData => {
-                    let size = self.reader.read(&mut self.read_buffer).await?;
-                    self.input_buffer.input_data(&self.read_buffer[..size]);
+            let mut buffer = self.pool.lease().await;
+            let read = poll_fn(|cx| {
+                let mut read_buf = ReadBuf::new(&mut buffer);
+                let read = Pin::new(&mut *reader).poll_read(cx, &mut read_buf);
+                Poll::Ready(read.map_ok(|()| read_buf.filled().len()))
+            })
+            .await;
+            match read {
+                Poll::Ready(size) => return Ok((buffer, size?)),
+                Poll::Pending => {
+                    drop(buffer);
+                    // The reader wakes this task once it has data
+                    let mut parked = false;
+                    poll_fn(|_| match parked {
+                        true => Poll::Ready(()),
+                        false => {
+                            parked = true;
+                            Poll::Pending
+                        }
+                    })
+                    .await;
                 }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::faulty::FaultyStream;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use tokio::{
+        io::{duplex, AsyncWriteExt},
+        spawn,
+        sync::mpsc::unbounded_channel,
+        time::sleep,
+    };
+
+    const PING: [u8; 13] = [0x12, 0, 0, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+    const KEEP_ALIVE: [u8; 5] = [0x06, 0, 0, 0, 0];
+
+    /// Every part is returned by a separate read
+    fn reads(parts: &[&[u8]]) -> impl AsyncRead + Unpin {
+        let mut reader: Box<dyn AsyncRead + Unpin> = Box::new(tokio::io::empty());
+        for part in parts {
+            reader = Box::new(reader.chain(Cursor::new(part.to_vec())));
+        }
+        reader
+    }
+
+    #[tokio::test]
+    async fn two_frames_in_one_read() {
+        let data = [&PING[..], &KEEP_ALIVE[..]].concat();
+        let mut reader = DerpReader::new(reads(&[&data]));
+
+        let first = reader.get_next_message().await.unwrap();
+        assert_eq!(first.ty, FrameType::Ping);
+        assert_eq!(first.buffer, PING);
+        let second = reader.get_next_message().await.unwrap();
+        assert_eq!(second.ty, FrameType::KeepAlive);
+        assert_eq!(second.buffer, KEEP_ALIVE);
+        assert!(reader
+            .get_next_message()
+            .await
+            .unwrap_err()
+            .is::<ConnectionClosed>());
+    }
+
+    #[tokio::test]
+    async fn frame_split_across_reads() {
+        let mut reader = DerpReader::new(reads(&[&PING[..9], &PING[9..]]));
+
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::Ping);
+        assert_eq!(message.buffer, PING);
+    }
+
+    #[tokio::test]
+    async fn partial_length_prefix() {
+        let mut reader = DerpReader::new(reads(&[&PING[..3], &PING[3..5], &PING[5..]]));
+
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::Ping);
+        assert_eq!(message.buffer, PING);
+    }
+
+    #[tokio::test]
+    async fn frames_read_byte_by_byte() {
+        let data = [&PING[..], &KEEP_ALIVE[..]].concat();
+        let mut reader = DerpReader::new(FaultyStream::new(Cursor::new(data)).split_reads(1));
+
+        assert_eq!(reader.get_next_message().await.unwrap().buffer, PING);
+        assert_eq!(reader.get_next_message().await.unwrap().buffer, KEEP_ALIVE);
+    }
+
+    #[tokio::test]
+    async fn frame_with_wrong_length_is_skipped() {
+        let mut bad_peer_present = vec![0x09, 0, 0, 0, 31];
+        bad_peer_present.extend([7; 31]);
+        let data = [&PING[..], &bad_peer_present, &KEEP_ALIVE[..]].concat();
+        let mut reader = DerpReader::new(FaultyStream::new(Cursor::new(data)).split_reads(3));
+
+        assert_eq!(reader.get_next_message().await.unwrap().buffer, PING);
+        let error = reader.get_next_message().await.unwrap_err();
+        assert!(error.downcast_ref::<ProtoError>().unwrap().is_recoverable());
+        assert_eq!(reader.get_next_message().await.unwrap().buffer, KEEP_ALIVE);
+    }
 
-                PartMessage::Message(message) => return Ok(message),
+    #[tokio::test]
+    async fn read_error_inside_a_frame_is_returned() {
+        let reader = FaultyStream::new(Cursor::new(PING.to_vec())).fail_reads_after(9);
+        let mut reader = DerpReader::new(reader);
+
+        let err = reader.get_next_message().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_readers_hold_no_pooled_buffers() {
+        const READERS: usize = 1000;
+        const POOL_SIZE: usize = 4;
+
+        let pool = BufferPool::new(POOL_SIZE);
+        let (read_sender, mut read) = unbounded_channel();
+        let mut writers = Vec::new();
+        for _ in 0..READERS {
+            let (mut writer, reader) = duplex(64);
+            writer.write_all(&PING).await.unwrap();
+            writers.push(writer);
+            let mut reader = DerpReader::with_pool(reader, pool.clone());
+            let read_sender = read_sender.clone();
+            spawn(async move {
+                assert_eq!(reader.get_next_message().await.unwrap().buffer, PING);
+                read_sender.send(()).unwrap();
+                // Waits for a frame that never comes
+                let _ = reader.get_next_message().await;
+            });
+        }
+        for _ in 0..READERS {
+            read.recv().await.unwrap();
+        }
+
+        assert_eq!(pool.leased(), 0);
+        assert!(pool.free() <= POOL_SIZE);
+        assert!(pool.allocated() <= POOL_SIZE);
+    }
+
+    #[tokio::test]
+    async fn readers_stalled_in_a_frame_hold_no_pooled_buffers() {
+        const READERS: usize = 1000;
+        const POOL_SIZE: usize = 4;
+
+        let pool = BufferPool::new(POOL_SIZE);
+        let (read_sender, mut read) = unbounded_channel();
+        let mut writers = Vec::new();
+        for _ in 0..READERS {
+            let (mut writer, reader) = duplex(64);
+            // The rest of the frame only comes once every reader started it
+            writer.write_all(&PING[..9]).await.unwrap();
+            writers.push(writer);
+            let mut reader = DerpReader::with_pool(reader, pool.clone());
+            let read_sender = read_sender.clone();
+            spawn(async move {
+                let message = reader.get_next_message().await.unwrap();
+                read_sender.send(message.buffer).unwrap();
+            });
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(pool.leased(), 0);
+
+        for writer in &mut writers {
+            writer.write_all(&PING[9..]).await.unwrap();
+        }
+        for _ in 0..READERS {
+            assert_eq!(read.recv().await.unwrap(), PING);
+        }
+        assert!(pool.allocated() <= POOL_SIZE);
+    }
+
+    #[tokio::test]
+    async fn buffered_bytes_of_a_started_frame_are_tracked() {
+        let (mut writer, reader) = duplex(64);
+        let mut reader = DerpReader::new(reader);
+        let buffered = Arc::new(AtomicUsize::new(0));
+        reader.track_buffered(buffered.clone());
+        let mut frame = vec![0x04, 0, 0, 0x03, 0xE8];
+        frame.extend([7; 1000]);
+
+        writer.write_all(&frame[..10]).await.unwrap();
+        let read = spawn(async move {
+            let message = reader.get_next_message().await.unwrap();
+            (message, reader)
+        });
+        sleep(Duration::from_millis(100)).await;
+        assert!(buffered.load(Ordering::Relaxed) >= frame.len());
+
+        writer.write_all(&frame[10..]).await.unwrap();
+        let (message, _reader) = read.await.unwrap();
+        assert_eq!(message.buffer, frame);
+        assert_eq!(buffered.load(Ordering::Relaxed), 0);
+    }
+
+    fn next_error(data: &[u8]) -> ProtoError {
+        let mut input = InputBuffer::default();
+        input.input_data(data);
+        match input.next_message() {
+            Err(e) => e.downcast().unwrap(),
+            Ok(_) => panic!("frame should be rejected"),
+        }
+    }
+
+    #[test]
+    fn peer_present_with_wrong_length_is_rejected() {
+        let mut data = vec![0x09, 0, 0, 0, 31];
+        data.extend([7; 31]);
+        assert_eq!(
+            next_error(&data),
+            ProtoError::FrameLengthMismatch {
+                frame_type: FrameType::PeerPresent,
+                expected: 32..=32,
+                actual: 31,
             }
+        );
+    }
+
+    #[test]
+    fn frame_over_the_largest_packet_is_rejected() {
+        assert_eq!(
+            next_error(&[0x04, 0, 0x01, 0, 0]),
+            ProtoError::FrameTooLarge {
+                frame_type: FrameType::SendPacket,
+                size: 0x10000,
+            }
+        );
+        let error = ProtoError::FrameTooLarge {
+            frame_type: FrameType::SendPacket,
+            size: 0x10000,
+        };
+        assert!(!error.is_recoverable());
+    }
+
+    #[test]
+    fn wrong_length_is_rejected_before_payload_arrives() {
+        assert_eq!(
+            next_error(&[0x12, 0, 0, 0, 9]),
+            ProtoError::FrameLengthMismatch {
+                frame_type: FrameType::Ping,
+                expected: 8..=8,
+                actual: 9,
+            }
+        );
+    }
+
+    #[test]
+    fn peer_gone_with_and_without_reason_is_accepted() {
+        for size in [32, 33] {
+            let mut input = InputBuffer::default();
+            input.input_data(&[0x08, 0, 0, 0, size]);
+            input.input_data(&vec![7; size as usize]);
+            assert!(matches!(
+                input.next_message(),
+                Ok(PartMessage::Message(Message {
+                    ty: FrameType::PeerGone,
+                    ..
+                }))
+            ));
         }
     }
 }
@@ -0,0 +1,20 @@
+pub mod address;
+pub mod admin;
+pub mod client;
+pub mod config;
+pub mod crypto;
+pub mod duration;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod faulty;
+pub mod inout;
+pub mod listener;
+#[cfg(feature = "mesh")]
+pub mod mesh_client;
+pub mod proto;
+pub mod service;
+#[cfg(test)]
+mod test_utils;
+
+pub use config::{
+    Config, DestinationLimit, FrameRateLimit, FrameTrace, MaintenanceWindow, Timeouts,
+};
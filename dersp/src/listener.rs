@@ -0,0 +1,253 @@
+//! Binding the server's listening socket
+
+use crate::{address::resolve, Config};
+use anyhow::{anyhow, Context};
+use socket2::{Domain, Socket, Type};
+use std::io::ErrorKind;
+use tokio::net::TcpListener;
+
+/// First fd passed by systemd, see sd_listen_fds(3)
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+/// Environment variable holding the fd of a listener handed over by [`hand_over`]
+#[cfg(unix)]
+const INHERITED_LISTENER_VAR: &str = "DERSP_LISTEN_FD";
+
+/// Binds `config.listen_on`, which may be a link-local address with a zone, with SO_REUSEADDR
+/// (on unix unless `--no-reuse-addr`) and SO_REUSEPORT set before binding when asked, and listens with `config.listen_backlog`. An address in use is explained along with the
+/// ways around it.
+pub async fn bind(config: &Config) -> anyhow::Result<TcpListener> {
+    let listen_on = config
+        .listen_on
+        .as_deref()
+        .ok_or_else(|| anyhow!("No address to listen on"))?;
+    let addr = resolve(listen_on)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{listen_on} resolves to no address"))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if config.reuse_addr || (cfg!(unix) && !config.no_reuse_addr) {
+        socket.set_reuse_address(true)?;
+    }
+    if config.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into()).map_err(|e| {
+        let reason = match e.kind() {
+            ErrorKind::AddrInUse => format!(
+                "{addr} is already in use, is another instance running? Stop it or listen \
+                 elsewhere with --listen-on. If a previous instance just stopped, --reuse-addr \
+                 (the default on unix) lets us bind while its connections linger, --reuse-port \
+                 on every instance lets them share the address"
+            ),
+            _ => format!("Binding {addr}"),
+        };
+        anyhow::Error::new(e).context(reason)
+    })?;
+    socket.listen(config.listen_backlog)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Takes the listening socket passed by systemd socket activation, only the first one is used
+/// when several are passed
+#[cfg(unix)]
+pub fn systemd_socket() -> anyhow::Result<std::net::TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        anyhow::ensure!(
+            pid.parse::<u32>().context("Parsing LISTEN_PID")? == std::process::id(),
+            "The sockets passed by systemd are meant for process {pid}"
+        );
+    }
+    let fds: usize = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS is not set, the service isn't socket activated")?
+        .parse()
+        .context("Parsing LISTEN_FDS")?;
+    anyhow::ensure!(fds > 0, "systemd passed no socket");
+    if fds > 1 {
+        log::warn!("systemd passed {fds} sockets, only using the first one");
+    }
+    // Safety: the fds from SD_LISTEN_FDS_START on are handed to us, nothing else owns them
+    Ok(unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+pub fn systemd_socket() -> anyhow::Result<std::net::TcpListener> {
+    anyhow::bail!("--systemd-socket is only supported on unix")
+}
+
+/// Spawns `command` with `listener` left open across the exec, the new process takes it over
+/// with [`inherited`]
+#[cfg(unix)]
+pub fn hand_over(
+    listener: &std::net::TcpListener,
+    mut command: std::process::Command,
+) -> anyhow::Result<std::process::Child> {
+    use std::os::{
+        fd::{AsRawFd, BorrowedFd},
+        unix::process::CommandExt,
+    };
+
+    let fd = listener.as_raw_fd();
+    command.env(INHERITED_LISTENER_VAR, fd.to_string());
+    // Cleared in the child only, so processes spawned at the same time by other threads don't
+    // get the listener as well
+    // Safety: the closure only makes an fcntl call, which is async-signal-safe, and the fd
+    // stays open until the child execs since `listener` is borrowed
+    unsafe {
+        command.pre_exec(move || {
+            socket2::SockRef::from(&BorrowedFd::borrow_raw(fd)).set_cloexec(false)
+        });
+    }
+    command
+        .spawn()
+        .context("Starting the process taking over the listener")
+}
+
+#[cfg(not(unix))]
+pub fn hand_over(
+    _: &std::net::TcpListener,
+    _: std::process::Command,
+) -> anyhow::Result<std::process::Child> {
+    anyhow::bail!("Handing over the listener is only supported on unix")
+}
+
+/// Takes the listener handed over by the process that started us with [`hand_over`], if any.
+/// It removes its environment variable, so it must run before any other thread is started,
+/// e.g. those of the tokio runtime.
+#[cfg(unix)]
+pub fn inherited() -> anyhow::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let Ok(fd) = std::env::var(INHERITED_LISTENER_VAR) else {
+        return Ok(None);
+    };
+    // Processes we start must not take it for theirs
+    std::env::remove_var(INHERITED_LISTENER_VAR);
+    let fd = fd
+        .parse()
+        .with_context(|| format!("Parsing {INHERITED_LISTENER_VAR}"))?;
+    // Safety: the process handing it over left the fd open for us and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    socket2::SockRef::from(&listener).set_cloexec(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+pub fn inherited() -> anyhow::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> anyhow::Result<()> {
+    Ok(socket.set_reuse_port(true)?)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_: &Socket) -> anyhow::Result<()> {
+    anyhow::bail!("--reuse-port is not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(listen_on: &str, args: &[&str]) -> Config {
+        Config::parse_from(
+            ["dersp", "--listen-on", listen_on]
+                .iter()
+                .chain(args.iter()),
+        )
+    }
+
+    #[tokio::test]
+    async fn binds_without_reuse_flags() {
+        let listener = bind(&config("127.0.0.1:0", &[])).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(bind(&config(&addr, &[])).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_addr_is_the_default_on_unix() {
+        let listener = bind(&config("127.0.0.1:0", &[])).await.unwrap();
+        assert!(socket2::SockRef::from(&listener).reuse_address().unwrap());
+
+        let listener = bind(&config("127.0.0.1:0", &["--no-reuse-addr"]))
+            .await
+            .unwrap();
+        assert!(!socket2::SockRef::from(&listener).reuse_address().unwrap());
+    }
+
+    #[tokio::test]
+    async fn address_in_use_suggests_what_to_do() {
+        let listener = bind(&config("127.0.0.1:0", &[])).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let err = bind(&config(&addr, &[])).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with(&format!("{addr} is already in use")));
+        assert!(message.contains("--reuse-addr"), "{message}");
+        assert_eq!(
+            err.root_cause()
+                .downcast_ref::<std::io::Error>()
+                .map(std::io::Error::kind),
+            Some(ErrorKind::AddrInUse)
+        );
+    }
+
+    #[tokio::test]
+    async fn binds_with_the_requested_backlog() {
+        let listener = bind(&config("127.0.0.1:0", &["--listen-backlog", "1"]))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        listener.accept().await.unwrap();
+
+        assert!(Config::try_parse_from(["dersp", "--listen-backlog", "0"]).is_err());
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn two_listeners_with_reuse_port_share_an_address() {
+        let first = bind(&config("127.0.0.1:0", &["--reuse-port"]))
+            .await
+            .unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(&config(&addr.to_string(), &["--reuse-port"]))
+            .await
+            .unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn handed_over_listener_stays_open_in_the_new_process() {
+        use std::os::fd::AsRawFd;
+
+        let listener = bind(&config("127.0.0.1:0", &[]))
+            .await
+            .unwrap()
+            .into_std()
+            .unwrap();
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "test -S /proc/self/fd/$DERSP_LISTEN_FD"]);
+
+        let status = hand_over(&listener, command).unwrap().wait().unwrap();
+        assert!(status.success());
+        // Not leaked into processes spawned afterwards
+        let status = std::process::Command::new("sh")
+            .args([
+                "-c",
+                &format!("test ! -e /proc/self/fd/{}", listener.as_raw_fd()),
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
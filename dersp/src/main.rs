@@ -1,42 +1,97 @@
-mod client;
-mod crypto;
-mod inout;
-mod mesh_client;
-mod proto;
-mod service;
-
-use crate::service::{DerpService, Service};
-use clap::Parser;
+use dersp::{
+    crypto::set_log_full_keys,
+    listener,
+    proto::data::check_wire_format,
+    service::{self_test, DerpService, Service, BUILD_INFO},
+    Config,
+};
 use log::info;
+use std::process::Command;
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::ctrl_c;
 use tokio::sync::RwLock;
 
-#[derive(Parser, Debug)]
-#[command(version)]
-pub struct Config {
-    /// Path to the mesh key used to authenticate with other derp servers
-    #[arg(long)]
-    meshkey: Option<String>,
-
-    /// List of other derp servers with which we should create a mesh
-    #[arg(long)]
-    mesh_peers: Vec<String>,
-
-    #[arg(long, short)]
-    listen_on: String,
+pub fn main() -> anyhow::Result<()> {
+    // Changing the environment is only sound while no other thread runs
+    let inherited = listener::inherited()?;
+    run(inherited)
 }
 
 #[tokio::main]
-pub async fn main() -> anyhow::Result<()> {
+async fn run(inherited: Option<std::net::TcpListener>) -> anyhow::Result<()> {
     env_logger::init();
-    let config = Config::parse();
+    info!("Starting {BUILD_INFO}");
+    check_wire_format()?;
+    let config = Config::load()?;
+    set_log_full_keys(config.log_full_keys);
     info!("Config: {config:?}");
 
-    let listener = TcpListener::bind(&config.listen_on).await?;
+    if config.self_test {
+        self_test(config).await?;
+        info!("Self-test passed");
+        return Ok(());
+    }
+
+    let listener = if let Some(listener) = inherited {
+        info!("Took over the listener of the previous process");
+        listener
+    } else if config.systemd_socket {
+        listener::systemd_socket()?
+    } else {
+        listener::bind(&config).await?.into_std()?
+    };
+    let restart_on_signal = config.restart_on_signal;
+    let drain_timeout = config.timeouts.drain_timeout;
     let service: Arc<RwLock<DerpService>> = DerpService::new(config).await?;
 
     info!("Listening on: {:?}", listener.local_addr());
 
-    service.run(listener).await
+    listener.set_nonblocking(true)?;
+    let mut handle = service
+        .run_with_listener(TcpListener::from_std(listener.try_clone()?)?)
+        .await;
+    let restart = select! {
+        result = handle.joined() => return result,
+        _ = ctrl_c() => false,
+        result = restart_requested(restart_on_signal) => {
+            result?;
+            true
+        }
+    };
+
+    if restart {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(std::env::args_os().skip(1));
+        // The new process binds the admin address again
+        service.write().await.stop_admin();
+        let child = listener::hand_over(&listener, command)?;
+        info!(
+            "Process {} took over the listener, draining for up to {drain_timeout:?}",
+            child.id()
+        );
+        handle.drain(drain_timeout).await;
+    } else {
+        handle.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Resolves on SIGHUP with `--restart-on-signal`, never without it
+#[cfg(unix)]
+async fn restart_requested(enabled: bool) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    if !enabled {
+        return std::future::pending().await;
+    }
+    signal(SignalKind::hangup())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn restart_requested(enabled: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(!enabled, "--restart-on-signal is only supported on unix");
+    std::future::pending().await
 }
@@ -1,86 +1,344 @@
-use std::{io::Cursor, net::SocketAddr};
+use std::{
+    future::pending,
+    io::Cursor,
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, bail};
-use codec::Decode;
-use httparse::Status;
+use anyhow::{anyhow, bail, ensure, Context};
+use codec::{Decode, Encode};
 use log::debug;
 use log::{trace, warn};
+use sha2::{Digest, Sha256};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{lookup_host, tcp::OwnedWriteHalf, TcpStream},
-    spawn,
-    sync::mpsc::{channel, Receiver, Sender},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    select, spawn,
+    sync::{mpsc::Sender, watch},
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{
+            ring::default_provider, verify_tls12_signature, verify_tls13_signature,
+            WebPkiSupportedAlgorithms,
+        },
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
 };
 
 use crate::{
-    client::WriteLoopCommands,
+    address::resolve,
+    client::{write_lanes, ClientSink, WriteLanes, WriteLoopCommands, DATA_LANE_SIZE},
     crypto::{PublicKey, SecretKey},
     inout::DerpReader,
-    proto::data::{ForwardPacket, Frame, FrameType, PeerPresent},
-    proto::{exchange_keys, read_server_info, write_peer_present, write_watch_conns},
+    proto::data::{
+        ForwardPacket, Frame, FrameType, PeerGone, PeerGoneReason, PeerPresent, Ping, Pong,
+        RosterComplete, FORWARD_TTL_VERSION,
+    },
+    proto::{
+        connect_http, exchange_keys, read_server_info, write_forward_packet, write_peer_gone,
+        write_peer_present, write_watch_conns, Transport,
+    },
     service::ServiceCommand,
 };
 
-/// Max TCP packet size is 65535
-const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
+/// How long to wait for a connection attempt before starting the next one in parallel, as
+/// suggested by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Port of `derps://` and `https://` mesh peers given without one
+const TLS_PORT: u16 = 443;
+
+/// Address of a mesh peer as listed in `--mesh-peers`: `host:port`, or `derps://host[:port]`
+/// (`https://` works too) for a peer reached over TLS. Instead of chaining to a CA, the
+/// certificate of a TLS peer can be pinned by its SHA-256, `derps://sha256@host[:port]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeshPeerAddr {
+    /// `host:port` connected to
+    pub host: String,
+    /// Name the certificate of the peer must be valid for, `None` without TLS
+    pub tls_name: Option<String>,
+    /// SHA-256 of the only certificate the peer may present
+    pub pinned_certificate: Option<[u8; 32]>,
+}
+
+impl FromStr for MeshPeerAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(addr: &str) -> anyhow::Result<Self> {
+        let Some(url) = addr
+            .strip_prefix("derps://")
+            .or_else(|| addr.strip_prefix("https://"))
+        else {
+            return Ok(Self {
+                host: addr.to_owned(),
+                tls_name: None,
+                pinned_certificate: None,
+            });
+        };
+        let url = url.trim_end_matches('/');
+        let (pinned_certificate, host) = match url.split_once('@') {
+            Some((pin, host)) => {
+                let mut sha256 = [0; 32];
+                hex::decode_to_slice(pin, &mut sha256)
+                    .with_context(|| format!("The pin of {addr} isn't a hex SHA-256"))?;
+                (Some(sha256), host)
+            }
+            None => (None, url),
+        };
+        // IPv6 addresses are in brackets, the colons in them don't start a port
+        let (name, host) = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => {
+                port.parse::<u16>()
+                    .with_context(|| format!("{port} in {addr} isn't a port"))?;
+                (name, host.to_owned())
+            }
+            _ => (host, format!("{host}:{TLS_PORT}")),
+        };
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        ensure!(!name.is_empty(), "{addr} has no host");
+        Ok(Self {
+            host,
+            tls_name: Some(name.to_owned()),
+            pinned_certificate,
+        })
+    }
+}
+
+/// Connector for mesh peers reached over TLS. Their certificates must chain to one in the PEM
+/// file `ca`, or to one of the webpki roots without it.
+pub fn tls_connector(ca: Option<&Path>) -> anyhow::Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let pem = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+            for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                roots.add(cert.with_context(|| format!("Parsing {}", path.display()))?)?;
+            }
+            ensure!(!roots.is_empty(), "{} holds no certificate", path.display());
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Accepts only the certificate with the pinned SHA-256, whoever issued it and whatever name
+/// it's for. It must still sign the handshake.
+#[derive(Debug)]
+struct PinnedCertificate {
+    sha256: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertificate {
+    fn connector(sha256: [u8; 32]) -> TlsConnector {
+        let verifier = Self {
+            sha256,
+            algorithms: default_provider().signature_verification_algorithms,
+        };
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.sha256 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(CertificateError::ApplicationVerificationFailure.into())
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
 
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Application level liveness check of a mesh link, see `--mesh-ping-interval`
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    /// Time the peer has to answer a ping
+    pub timeout: Duration,
+}
+
+#[derive(Clone)]
 pub struct MeshClient {
-    addr: SocketAddr,
+    /// `host:port`, resolved again on every connection attempt to follow DNS changes
+    host: String,
+    /// Connector and the name the certificate must be valid for, for peers reached over TLS
+    tls: Option<(TlsConnector, ServerName<'static>)>,
     secret_key: SecretKey,
-    meshkey: String,
+    /// Sent in the ClientInfo, peers trusting our key accept us without it
+    meshkey: Option<String>,
+    heartbeat: Heartbeat,
     command_sender: Sender<ServiceCommand>,
 }
 
 impl MeshClient {
-    pub async fn new(
-        addr_or_host: &str,
+    /// Client for the mesh peer at `addr`, see [`MeshPeerAddr`]. Peers reached over TLS are
+    /// verified by `tls` unless their certificate is pinned.
+    pub fn new(
+        addr: &str,
         secret_key: SecretKey,
-        meshkey: String,
+        meshkey: Option<String>,
+        heartbeat: Heartbeat,
         command_sender: Sender<ServiceCommand>,
+        tls: &TlsConnector,
     ) -> anyhow::Result<Self> {
-        if let Some(addr) = lookup_host(addr_or_host).await?.next() {
-            debug!("mesh peer {addr_or_host} is in fact: {addr}");
-            Ok(Self {
-                addr,
-                secret_key,
-                meshkey,
-                command_sender,
-            })
-        } else {
-            bail!("Failed to resolve {addr_or_host}");
-        }
+        let peer: MeshPeerAddr = addr.parse()?;
+        let tls = match &peer.tls_name {
+            Some(name) => {
+                let name = ServerName::try_from(name.as_str())
+                    .with_context(|| format!("{name} can't be verified by TLS"))?
+                    .to_owned();
+                let connector = match peer.pinned_certificate {
+                    Some(sha256) => PinnedCertificate::connector(sha256),
+                    None => tls.clone(),
+                };
+                Some((connector, name))
+            }
+            None => None,
+        };
+        Ok(Self {
+            host: peer.host,
+            tls,
+            secret_key,
+            meshkey,
+            heartbeat,
+            command_sender,
+        })
+    }
+
+    /// Host the client connects to, as given to [`new`](Self::new)
+    pub fn host(&self) -> &str {
+        &self.host
     }
 
-    pub async fn start(self) -> anyhow::Result<(Sender<WriteLoopCommands>, PublicKey)> {
-        let stream = TcpStream::connect(self.addr).await?;
-        let (sender, receiver) = channel(1);
+    /// Connects and runs a mesh link in the background, can be called again once it broke
+    pub async fn start(
+        &self,
+        connect_timeout: Duration,
+        handshake_timeout: Duration,
+    ) -> anyhow::Result<MeshLink> {
+        let host = &self.host;
+        let connect = async {
+            let addrs = interleave_families(resolve(host).await?);
+            ensure!(!addrs.is_empty(), "Failed to resolve {host}");
+            debug!("mesh peer {host} is in fact: {addrs:?}");
+            connect_happy_eyeballs(&addrs).await
+        };
+        let stream = timeout(connect_timeout, connect)
+            .await
+            .map_err(|_| anyhow!("Connecting to {host} timed out after {connect_timeout:?}"))??;
+        let addr = stream.peer_addr()?;
+        debug!("connected to mesh peer {host} at {addr}");
+        let (sender, receiver) = write_lanes(DATA_LANE_SIZE);
         let (mesh_peer_pk_sender, mesh_peer_pk_receiver) = tokio::sync::oneshot::channel();
-        spawn(self.run(stream, sender.clone(), receiver, mesh_peer_pk_sender));
-        let mesh_peer_pk = mesh_peer_pk_receiver.await?;
-        Ok((sender, mesh_peer_pk))
+        let task = match &self.tls {
+            Some((connector, name)) => {
+                let stream = timeout(handshake_timeout, connector.connect(name.clone(), stream))
+                    .await
+                    .map_err(|_| {
+                        anyhow!("TLS handshake with {host} timed out after {handshake_timeout:?}")
+                    })?
+                    .with_context(|| format!("TLS handshake with {host}"))?;
+                let run =
+                    self.clone()
+                        .run(stream, addr, sender.clone(), receiver, mesh_peer_pk_sender);
+                spawn(run)
+            }
+            None => {
+                let run =
+                    self.clone()
+                        .run(stream, addr, sender.clone(), receiver, mesh_peer_pk_sender);
+                spawn(run)
+            }
+        };
+        let public_key = match timeout(handshake_timeout, mesh_peer_pk_receiver).await {
+            Ok(Ok(mesh_peer_pk)) => mesh_peer_pk,
+            // The link stopped before the key exchange, its error tells why
+            Ok(Err(_)) => {
+                task.await??;
+                bail!("Mesh handshake with {host} failed");
+            }
+            Err(_) => {
+                // Don't leave the handshake running next to the next attempt
+                task.abort();
+                bail!("Mesh handshake with {host} timed out after {handshake_timeout:?}");
+            }
+        };
+        Ok(MeshLink {
+            sink: sender,
+            public_key,
+            task,
+        })
     }
 
-    pub async fn run(
+    /// Runs the mesh connection over an already established `stream`, a TCP or a TLS stream
+    pub async fn run<S: AsyncRead + AsyncWrite + Send + 'static>(
         self,
-        stream: TcpStream,
-        sender: Sender<WriteLoopCommands>,
-        receiver: Receiver<WriteLoopCommands>,
+        stream: S,
+        server_addr: SocketAddr,
+        sender: ClientSink,
+        receiver: WriteLanes,
         mesh_peer_pk_sender: tokio::sync::oneshot::Sender<PublicKey>,
     ) -> anyhow::Result<()> {
         // TODO: handle closing of the mesh_peer_pk_sender when there is some error?
         // Maybe this is already handled by the receiver returning result?
-        let server_addr = stream.peer_addr()?;
-        let (mut r, mut w) = stream.into_split();
+        let (mut r, mut w) = split(stream);
 
-        let leftovers = connect_http(&mut r, &mut w).await?;
+        let leftovers = connect_http(&mut r, &mut w, Transport::Derp).await?;
         let reader = Cursor::new(leftovers).chain(r);
         let mut derp_reader = DerpReader::new(reader);
 
         let mesh_peer_pk = exchange_keys(
             &mut derp_reader,
             &mut w,
-            self.secret_key,
-            Some(&self.meshkey),
+            &self.secret_key,
+            self.meshkey.as_deref(),
+            None,
+            false,
         )
         .await?;
 
@@ -88,7 +346,10 @@ impl MeshClient {
             .send(mesh_peer_pk)
             .map_err(|e| anyhow!("{e}"))?;
 
-        read_server_info(&mut derp_reader).await?;
+        let server_info =
+            read_server_info(&mut derp_reader, &self.secret_key, mesh_peer_pk).await?;
+        // Relays that don't tell their version predate the ttl
+        let version = server_info.version.unwrap_or(FORWARD_TTL_VERSION - 1);
 
         write_watch_conns(&mut w).await?;
 
@@ -98,20 +359,52 @@ impl MeshClient {
             server_addr
         );
 
-        spawn(write_loop(receiver, w));
-
-        if let Err(e) = self.read_loop(derp_reader, sender).await {
-            warn!("[{mesh_peer_pk:?}] read loop failed: {e}");
-            return Err(e);
+        let command_sender = self.command_sender.clone();
+        let heartbeat = self.heartbeat;
+        let (pongs, pong_receiver) = watch::channel([0; 8]);
+        let read_loop = self.read_loop(derp_reader, mesh_peer_pk, version, sender.clone(), pongs);
+        let pinging = async {
+            if version >= FrameType::Ping.min_version() {
+                keep_pinging(
+                    sender.clone(),
+                    pong_receiver,
+                    heartbeat,
+                    mesh_peer_pk,
+                    command_sender.clone(),
+                )
+                .await
+            } else {
+                // Relays that old don't answer pings
+                pending().await
+            }
+        };
+        // Whichever fails first breaks the link, the service reconnects it
+        let result = select! {
+            result = read_loop => result.context("Read loop failed"),
+            result = write_loop(receiver, w, version) => result.context("Write loop failed"),
+            result = pinging => result,
+        };
+        if let Err(e) = &result {
+            warn!("[{}] {e:#}", mesh_peer_pk.log_display());
         }
+        command_sender
+            .send(ServiceCommand::PeerGone(
+                mesh_peer_pk,
+                PeerGoneReason::MeshConnBroke,
+                sender,
+            ))
+            .await?;
 
-        Ok(())
+        result
     }
 
     async fn read_loop<T: AsyncRead + Unpin>(
         self,
         mut reader: DerpReader<T>,
-        sender: Sender<WriteLoopCommands>,
+        mesh_peer_pk: PublicKey,
+        version: u32,
+        sender: ClientSink,
+        pongs: watch::Sender<[u8; 8]>,
     ) -> anyhow::Result<()> {
         loop {
             let message = reader.get_next_message().await?;
@@ -128,81 +421,309 @@ impl MeshClient {
                     self.command_sender
                         .send(ServiceCommand::PeerPresent(
                             peer_present.public_key,
+                            mesh_peer_pk,
                             sender.clone(),
                         ))
                         .await
                         .unwrap();
                 }
 
+                FrameType::PeerGone => {
+                    let peer_gone = Frame::<PeerGone>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    let reason = peer_gone.reason.unwrap_or(PeerGoneReason::Disconnected);
+                    trace!("Got peer gone for {} ({reason:?})", peer_gone.public_key);
+                    self.command_sender
+                        .send(ServiceCommand::PeerGone(
+                            peer_gone.public_key,
+                            reason,
+                            sender.clone(),
+                        ))
+                        .await?;
+                }
+
                 FrameType::ForwardPacket => {
-                    let forward_packet =
-                        Frame::<ForwardPacket>::decode(&mut message.buffer.as_slice())
-                            .map_err(|_| anyhow!("Decode error"))?
-                            .inner
-                            .into_inner();
+                    let forward_packet = ForwardPacket::decode_from(version, &message.buffer)?;
                     self.command_sender
                         .send(ServiceCommand::SendPacket {
                             source: forward_packet.source,
                             target: forward_packet.target,
-                            payload: forward_packet.payload,
+                            ttl: forward_packet.ttl,
+                            payload: forward_packet.payload.into(),
                         })
                         .await?;
                 }
 
-                _ => todo!(),
+                FrameType::Pong => {
+                    let pong = Frame::<Pong>::decode(&mut message.buffer.as_slice())
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .inner
+                        .into_inner();
+                    pongs.send_replace(pong.data);
+                }
+
+                FrameType::RosterComplete => {
+                    trace!(
+                        "Mesh peer {} sent its whole roster",
+                        mesh_peer_pk.log_display()
+                    );
+                    self.command_sender
+                        .send(ServiceCommand::RosterComplete(mesh_peer_pk, sender.clone()))
+                        .await?;
+                }
+
+                // E.g. the frames of a newer protocol, they don't concern the link
+                frame_type => debug!(
+                    "Skipping {frame_type:?} frame from mesh peer {}",
+                    mesh_peer_pk.log_display()
+                ),
+            }
+        }
+    }
+}
+
+/// Pings the mesh peer every `heartbeat.interval`, only returns once a ping went unanswered.
+/// The service is told the round trip time of each answered ping, to pick the fastest relay.
+async fn keep_pinging(
+    sink: ClientSink,
+    mut pongs: watch::Receiver<[u8; 8]>,
+    heartbeat: Heartbeat,
+    mesh_peer_pk: PublicKey,
+    command_sender: Sender<ServiceCommand>,
+) -> anyhow::Result<()> {
+    loop {
+        sleep(heartbeat.interval).await;
+        let sent = Instant::now();
+        let data: [u8; 8] = rand::random();
+        let mut ping = Vec::new();
+        Ping { data }.frame().encode(&mut ping)?;
+        sink.send(WriteLoopCommands::Frame(ping))
+            .await
+            .map_err(|_| anyhow!("Write loop stopped"))?;
+        match timeout(heartbeat.timeout, pongs.wait_for(|pong| *pong == data)).await {
+            Ok(Ok(_)) => {
+                let rtt = sent.elapsed();
+                trace!("Got pong from mesh peer after {rtt:?}");
+                command_sender
+                    .send(ServiceCommand::MeshRtt(mesh_peer_pk, rtt))
+                    .await?;
+            }
+            Ok(Err(_)) => bail!("Read loop stopped"),
+            Err(_) => bail!("No pong within {:?}", heartbeat.timeout),
+        }
+    }
+}
+
+/// A connected mesh link, see [`MeshClient::start`]
+pub struct MeshLink {
+    pub sink: ClientSink,
+    /// Key of the relay at the other end
+    pub public_key: PublicKey,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl MeshLink {
+    /// Waits until the link broke, the service was told about it by then
+    pub async fn closed(self) -> anyhow::Result<()> {
+        self.task.await?
+    }
+}
+
+/// Alternates between IPv6 and IPv4 addresses, starting with IPv6, so a broken address family
+/// only delays connecting by one attempt
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut interleaved = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connects to the first of `addrs` that answers. The next address is tried when the previous
+/// attempt failed or didn't finish within [`CONNECTION_ATTEMPT_DELAY`], pending attempts carry
+/// on in parallel.
+async fn connect_happy_eyeballs(addrs: &[SocketAddr]) -> anyhow::Result<TcpStream> {
+    let mut addrs = addrs.iter().copied().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+        }
+        let finished = if addrs.peek().is_some() {
+            match timeout(CONNECTION_ATTEMPT_DELAY, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+        // Dropping the set aborts the attempts still pending
+        let Some(finished) = finished else {
+            return Err(last_error.unwrap_or_else(|| anyhow!("No address to connect to")));
+        };
+        match finished? {
+            (_, Ok(stream)) => return Ok(stream),
+            (addr, Err(e)) => {
+                debug!("Failed to connect to {addr}: {e}");
+                last_error = Some(anyhow!("Failed to connect to {addr}: {e}"));
             }
         }
     }
 }
 
-async fn write_loop(mut r: Receiver<WriteLoopCommands>, mut writer: OwnedWriteHalf) {
+/// Writes to a mesh peer speaking protocol `version`
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut r: WriteLanes,
+    mut writer: W,
+    version: u32,
+) -> anyhow::Result<()> {
     loop {
         match r.recv().await {
+            Some(WriteLoopCommands::SendPacket {
+                source,
+                target,
+                ttl,
+                payload,
+            }) => {
+                let forward_packet = ForwardPacket::new(source, target, ttl, payload.into());
+                write_forward_packet(&mut writer, &forward_packet, version).await?;
+            }
             Some(WriteLoopCommands::PeerPresent(pk)) => {
-                write_peer_present(&mut writer, &pk).await.unwrap();
+                write_peer_present(&mut writer, &pk).await?;
+            }
+            Some(WriteLoopCommands::PeerGone(pk, reason)) => {
+                write_peer_gone(&mut writer, &pk, reason.for_version(version)).await?;
+            }
+            Some(WriteLoopCommands::Frame(frame)) => {
+                writer.write_all(&frame).await?;
+            }
+            // Older relays don't prune their roster
+            Some(WriteLoopCommands::RosterComplete)
+                if version < FrameType::RosterComplete.min_version() => {}
+            Some(WriteLoopCommands::RosterComplete) => {
+                let mut frame = Vec::new();
+                RosterComplete::default().frame().encode(&mut frame)?;
+                writer.write_all(&frame).await?;
             }
             Some(x) => todo!("{x:?}"),
-            None => todo!(),
+            // The link broke and the service forgot about it
+            None => return Ok(()),
         }
     }
 }
 
-async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
-    reader: &mut R,
-    writer: &mut W,
-    // server_keepalives: &DerpKeepaliveConfig,
-    // host: &str,
-) -> anyhow::Result<Vec<u8>> {
-    writer
-        .write_all(
-            format!(
-                // TODO: host header!
-                "GET /derp HTTP/1.1\r\n\
-                Connection: Upgrade\r\n\
-                Upgrade: WebSocket\r\n\
-                User-Agent: telio/{} {}\r\n\r\n",
-                env!("CARGO_PKG_VERSION"),
-                std::env::consts::OS,
-                // TODO: server_keepalives.tcp_keepalive,
-                // TODO: server_keepalives.derp_keepalive,
-            )
-            .as_bytes(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::duplex, net::TcpListener};
+
+    #[tokio::test]
+    async fn write_loop_returns_failed_writes() {
+        let (sink, lanes) = write_lanes(1);
+        let (writer, peer) = duplex(64);
+        drop(peer);
+        sink.send(WriteLoopCommands::PeerPresent(SecretKey::gen().public()))
+            .await
+            .unwrap();
+        let result = timeout(Duration::from_secs(5), write_loop(lanes, writer, 0)).await;
+        assert!(result.expect("write loop should stop").is_err());
+    }
+
+    #[test]
+    fn families_are_interleaved_starting_with_ipv6() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(
+            interleave_families(addrs.clone()),
+            vec![addrs[2], addrs[0], addrs[1]]
+        );
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_uses_the_reachable_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // TEST-NET-1 is never routed, the attempt either hangs or fails right away
+        let unreachable: SocketAddr = "192.0.2.1:9".parse().unwrap();
+
+        let stream = timeout(
+            Duration::from_secs(5),
+            connect_happy_eyeballs(&[unreachable, reachable]),
         )
-        .await?;
+        .await
+        .expect("reachable address should be used without waiting for the other")
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+    }
 
-    let mut data = [0_u8; MAX_TCP_PACKET_SIZE];
-    let data_len = reader.read(&mut data).await?;
+    #[tokio::test]
+    async fn connecting_gives_up_after_the_connect_timeout() {
+        let (command_sender, _commands) = tokio::sync::mpsc::channel(1);
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        };
+        // TEST-NET-1 is never routed, the attempt either hangs or fails right away
+        let mesh_client = MeshClient::new(
+            "192.0.2.1:9",
+            SecretKey::gen(),
+            None,
+            heartbeat,
+            command_sender,
+            &tls_connector(None).unwrap(),
+        )
+        .unwrap();
 
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut res = httparse::Response::new(&mut headers);
-    let res_len = match res.parse(&data)? {
-        Status::Partial => {
-            bail!("HTTP Response not full");
-        }
-        Status::Complete(len) => len,
-    };
-    Ok(data
-        .get(res_len..data_len)
-        .ok_or_else(|| anyhow!("Out of bounds index for data buffer"))?
-        .to_vec())
+        let result = timeout(
+            Duration::from_secs(5),
+            mesh_client.start(Duration::from_millis(200), Duration::from_secs(10)),
+        )
+        .await
+        .expect("connecting should give up after the connect timeout");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tls_mesh_peers_are_urls() {
+        let plain: MeshPeerAddr = "10.0.0.1:8765".parse().unwrap();
+        assert_eq!(plain.host, "10.0.0.1:8765");
+        assert_eq!(plain.tls_name, None);
+
+        let tls: MeshPeerAddr = "derps://derp.example.com".parse().unwrap();
+        assert_eq!(tls.host, "derp.example.com:443");
+        assert_eq!(tls.tls_name.as_deref(), Some("derp.example.com"));
+        assert_eq!(tls.pinned_certificate, None);
+
+        let pin = "ab".repeat(32);
+        let pinned: MeshPeerAddr = format!("https://{pin}@[::1]:8443/").parse().unwrap();
+        assert_eq!(pinned.host, "[::1]:8443");
+        assert_eq!(pinned.tls_name.as_deref(), Some("::1"));
+        assert_eq!(pinned.pinned_certificate, Some([0xab; 32]));
+
+        assert!("derps://abc@derp.example.com"
+            .parse::<MeshPeerAddr>()
+            .is_err());
+        assert!("derps://derp.example.com:https"
+            .parse::<MeshPeerAddr>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_fails_when_nothing_is_reachable() {
+        let refused = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        assert!(connect_happy_eyeballs(&[refused]).await.is_err());
+        assert!(connect_happy_eyeballs(&[]).await.is_err());
+    }
 }
@@ -1,16 +1,37 @@
 use anyhow::Context;
 use codec::{Decode, Encode, SizeWrapper};
+use std::{
+    fmt,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crypto_box::{
     aead::{Aead, AeadCore},
     PublicKey as BoxPublicKey, SalsaBox,
 };
-use serde::{Deserialize, Serialize};
+use rand::RngCore;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 
-use crate::crypto::{PublicKey, SecretKey};
+use crate::{
+    crypto::{PublicKey, SecretKey, KEY_SIZE},
+    inout::HEADER_SIZE,
+};
 
 /// 8 bytes of magic message prefix: `DERP🔑`
 const MAGIC: [u8; 8] = [0x44, 0x45, 0x52, 0x50, 0xF0, 0x9F, 0x94, 0x91];
+const RESUME_TOKEN_SIZE: usize = 16;
+/// Longest meshkey accepted in a ClientInfo, in bytes
+pub const MAX_MESHKEY_SIZE: usize = 256;
+/// Protocol version this implementation speaks, sent in ClientInfo and ServerInfo
+pub const PROTOCOL_VERSION: u32 = 6;
+/// Lowest protocol version whose ForwardPacket carries a ttl
+pub const FORWARD_TTL_VERSION: u32 = 3;
 
 #[derive(Debug, Decode, Encode, PartialEq)]
 pub enum FrameType {
@@ -48,7 +69,8 @@ pub enum FrameType {
     /// 32B pub key of peer that's connected
     #[tag(0x09)]
     PeerPresent,
-    /// 32B src pub key + 32B dst pub key + packet bytes
+    /// 32B src pub key + 32B dst pub key + 1B ttl + packet bytes,
+    /// without the ttl for relays before protocol version 3
     #[tag(0x0A)]
     ForwardPacket,
     /// WatchConns is how one DERP node in a regional mesh
@@ -76,14 +98,67 @@ pub enum FrameType {
     /// for communication with other peers through derp, they don't contain public_key
     #[tag(0x14)]
     ControlMessage,
+    /// Sent before the server closes the connection on purpose, the client should reconnect.
+    /// 4B ms to wait before reconnecting + 4B ms to keep trying for
+    #[tag(0x15)]
+    Restarting,
+    /// Privileged like WatchConns, and only honored with `--allow-mirroring`. Asks for copies,
+    /// as ForwardPackets, of every packet relayed from or to the given peer.
+    /// 32B pub key of the mirrored peer
+    #[tag(0x20)]
+    MirrorPackets,
+    /// Ends the PeerPresent flood answering a WatchConns, the roster sent so far is complete.
+    /// There's no payload.
+    #[tag(0x21)]
+    RosterComplete,
+    /// Only sent to clients that asked for it in their ClientInfo, tells what became of one of
+    /// their SendPackets.
+    /// 32B dest pub key + 1B status
+    #[tag(0x22)]
+    SendAck,
+    /// One payload for several destinations, the server relays it as a SendPacket to each of
+    /// them. Only sent to servers advertising a fanout limit in their ServerInfo.
+    /// 1B number of destinations + 32B pub key of each + packet bytes
+    #[tag(0x23)]
+    SendPackets,
 
     #[unknown]
     Unkonow(#[unknown] u8),
 }
 
 impl FrameType {
+    /// Allowed payload sizes of frames with a fixed layout, `None` for variable sized frames
+    pub fn expected_size(&self) -> Option<RangeInclusive<u32>> {
+        match self {
+            FrameType::KeepAlive | FrameType::WatchConns | FrameType::RosterComplete => Some(0..=0),
+            FrameType::NotePreferred => Some(1..=1),
+            FrameType::Ping | FrameType::Pong | FrameType::Restarting => Some(8..=8),
+            FrameType::PeerPresent | FrameType::ClosePeer | FrameType::MirrorPackets => {
+                Some(32..=32)
+            }
+            // The reason byte is optional
+            FrameType::PeerGone => Some(32..=33),
+            FrameType::SendAck => Some(33..=33),
+            _ => None,
+        }
+    }
+
+    /// Lowest protocol version that understands the frame type, peers speaking an older one
+    /// aren't sent it
+    pub fn min_version(&self) -> u32 {
+        match self {
+            FrameType::PeerGone | FrameType::Ping | FrameType::Pong | FrameType::ControlMessage => {
+                2
+            }
+            FrameType::MirrorPackets => 3,
+            FrameType::RosterComplete | FrameType::SendAck => 4,
+            FrameType::Restarting => 6,
+            _ => 1,
+        }
+    }
+
     pub fn get_frame_type(buf: &[u8]) -> Self {
-        if let Some(first_byte) = buf.get(0).copied() {
+        if let Some(first_byte) = buf.first().copied() {
             FrameType::decode(&mut vec![first_byte].as_slice()).unwrap_or(FrameType::Unkonow(0))
         } else {
             FrameType::Unkonow(0)
@@ -128,8 +203,128 @@ impl ServerKey {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ClientInfoPayload {
     pub version: u32,
-    #[serde(rename = "meshKey")]
+    #[serde(rename = "meshKey", deserialize_with = "deserialize_meshkey")]
     pub meshkey: String,
+    /// Seconds since the unix epoch when the client created the payload,
+    /// older clients don't send it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Token from the server info of a previous connection, to resume that session
+    #[serde(
+        rename = "resumeToken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resume_token: Option<ResumeToken>,
+    /// Asks for a SendAck after each SendPacket, for debugging delivery
+    #[serde(
+        rename = "sendAcks",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub send_acks: bool,
+}
+
+/// A meshkey that is refused before comparing it to ours
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MeshkeyError {
+    #[error("Meshkey of {0} bytes is over the {MAX_MESHKEY_SIZE} bytes limit")]
+    TooLong(usize),
+    #[error("Meshkey is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+/// Checks the length of a raw meshkey before its encoding, so oversized ones aren't scanned
+pub fn validate_meshkey(raw: &[u8]) -> Result<&str, MeshkeyError> {
+    if raw.len() > MAX_MESHKEY_SIZE {
+        return Err(MeshkeyError::TooLong(raw.len()));
+    }
+    std::str::from_utf8(raw).map_err(|_| MeshkeyError::InvalidUtf8)
+}
+
+/// Takes the meshkey as raw bytes, so it's validated before a string is allocated for it
+fn deserialize_meshkey<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    struct MeshkeyVisitor;
+
+    impl<'de> Visitor<'de> for MeshkeyVisitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a meshkey string")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<String, E> {
+            validate_meshkey(v).map(str::to_owned).map_err(E::custom)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+            self.visit_bytes(v.as_bytes())
+        }
+    }
+
+    deserializer.deserialize_bytes(MeshkeyVisitor)
+}
+
+/// Issued by the server after the handshake. Reconnecting with it within the token TTL
+/// replays the packets queued while the client was away. Serialized as 32 hex characters.
+#[derive(Clone, Copy, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub struct ResumeToken([u8; RESUME_TOKEN_SIZE]);
+
+impl ResumeToken {
+    pub fn gen() -> Self {
+        let mut token = [0; RESUME_TOKEN_SIZE];
+        rand::thread_rng().fill_bytes(&mut token);
+        ResumeToken(token)
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The token is a credential, keep it out of the logs
+        f.write_str("ResumeToken(..)")
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut token = [0; RESUME_TOKEN_SIZE];
+        hex::decode_to_slice(s, &mut token)?;
+        Ok(ResumeToken(token))
+    }
+}
+
+/// A ClientInfo that can't be trusted, as opposed to one that doesn't decode
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AuthError {
+    /// The ClientInfo was sealed for another server key, by another client than the one it
+    /// names, or altered on the way
+    #[error("ClientInfo doesn't decrypt with the server key")]
+    Decryption,
+    /// The ClientInfo carries a meshkey other than ours
+    #[error("Client {} tried to mesh with a wrong key", .0.log_display())]
+    WrongMeshkey(PublicKey),
+    /// The ClientInfo was already accepted, it was captured and sent again
+    #[error("ClientInfo of {} was replayed", .0.log_display())]
+    Replayed(PublicKey),
+}
+
+/// Returned when the ClientInfo timestamp is outside of the allowed clock skew
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ClockSkewError {
+    /// The payload was created too long ago
+    #[error("ClientInfo is stale by {0:?}")]
+    Stale(Duration),
+    /// The payload was created too far in the future
+    #[error("ClientInfo is future-dated by {0:?}")]
+    FutureDated(Duration),
 }
 
 #[derive(Clone, Decode, Encode)]
@@ -141,23 +336,29 @@ pub struct ClientInfo {
 
 impl ClientInfo {
     pub fn new(
-        secret_key: SecretKey,
+        secret_key: &SecretKey,
         server_key: PublicKey,
         meshkey: Option<&str>,
+        resume_token: Option<ResumeToken>,
+        send_acks: bool,
     ) -> anyhow::Result<Self> {
-        let secret_key = secret_key.into();
+        let secret_key = crypto_box::SecretKey::from(secret_key);
         let public_key = BoxPublicKey::from(&secret_key);
         let server_key = server_key.into();
 
+        if let Some(meshkey) = meshkey {
+            validate_meshkey(meshkey.as_bytes())?;
+        }
+
         let mut rng = rand_core::OsRng;
         let nonce = SalsaBox::generate_nonce(&mut rng);
-        let plain_text: Vec<u8> = if let Some(meshkey) = meshkey {
-            format!("{{\"version\": 2, \"meshKey\": \"{meshkey}\"}}")
-                .as_bytes()
-                .to_vec()
-        } else {
-            b"{\"version\": 2, \"meshKey\": \"\"}".to_vec()
-        };
+        let plain_text = serde_json::to_vec(&ClientInfoPayload {
+            version: PROTOCOL_VERSION,
+            meshkey: meshkey.unwrap_or_default().to_owned(),
+            timestamp: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+            resume_token,
+            send_acks,
+        })?;
 
         let b = SalsaBox::new(&server_key, &secret_key);
 
@@ -177,12 +378,14 @@ impl ClientInfo {
         })
     }
 
+    /// Decrypts the payload. It only opens with the secret key of the client named by
+    /// `public_key`, so a ClientInfo claiming another client's key is refused with
+    /// [`AuthError::Decryption`] and the key of the result is the authenticated one.
     pub fn complete(&self, sk: &SecretKey) -> anyhow::Result<CompleteClientInfo> {
         let b = SalsaBox::new(&self.public_key.into(), &sk.into());
-        let plain_text = b.decrypt(
-            self.nonce.clone().as_ref().try_into()?,
-            self.cipher_text.as_slice(),
-        )?;
+        let plain_text = b
+            .decrypt(self.nonce.as_ref().into(), self.cipher_text.as_slice())
+            .map_err(|_| AuthError::Decryption)?;
         let payload: ClientInfoPayload =
             serde_json::from_slice(&plain_text).with_context(|| "Client info parsing")?;
 
@@ -202,17 +405,110 @@ impl ClientInfo {
 }
 
 pub struct CompleteClientInfo {
+    /// Authenticated by the decryption, peers are routed by it
     pub public_key: PublicKey,
     pub nonce: [u8; 24],
     pub payload: ClientInfoPayload,
 }
 
-#[derive(Decode, Encode, Default)]
+impl CompleteClientInfo {
+    /// Checks that the payload timestamp is at most `max_skew` away from `now`,
+    /// payloads without a timestamp are accepted
+    pub fn validate_timestamp(
+        &self,
+        max_skew: Duration,
+        now: SystemTime,
+    ) -> Result<(), ClockSkewError> {
+        let Some(timestamp) = self.payload.timestamp else {
+            return Ok(());
+        };
+        let created = UNIX_EPOCH + Duration::from_secs(timestamp);
+        match now.duration_since(created) {
+            Ok(age) if age > max_skew => Err(ClockSkewError::Stale(age)),
+            Ok(_) => Ok(()),
+            Err(e) if e.duration() > max_skew => Err(ClockSkewError::FutureDated(e.duration())),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfoPayload {
+    #[serde(
+        rename = "resumeToken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resume_token: Option<ResumeToken>,
+    /// Region the server serves, for clients picking their home relay. Informational only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Largest payload the server forwards, larger ones are dropped
+    #[serde(
+        rename = "maxPacketSize",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_packet_size: Option<usize>,
+    /// Protocol version the server speaks, servers before version 3 don't send it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// The server sends a SendAck after each SendPacket, only when the client asked for it
+    #[serde(
+        rename = "sendAcks",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub send_acks: bool,
+    /// The server only relays presence and drops every packet sent through it
+    #[serde(
+        rename = "noForwarding",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub no_forwarding: bool,
+    /// Most destinations a single SendPackets may name, servers that don't take them leave it
+    /// out
+    #[serde(rename = "maxFanout", default, skip_serializing_if = "Option::is_none")]
+    pub max_fanout: Option<usize>,
+}
+
+#[derive(Decode, Encode)]
 pub struct ServerInfo {
-    data: Vec<u8>,
+    pub nonce: [u8; 24],
+    pub cipher_text: Vec<u8>,
 }
 
 impl ServerInfo {
+    pub fn new(
+        secret_key: &SecretKey,
+        client_key: PublicKey,
+        payload: &ServerInfoPayload,
+    ) -> anyhow::Result<Self> {
+        let b = SalsaBox::new(&client_key.into(), &secret_key.into());
+        let nonce = SalsaBox::generate_nonce(&mut rand_core::OsRng);
+        let cipher_text = b
+            .encrypt(&nonce, serde_json::to_vec(payload)?.as_slice())
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        let nonce: [u8; 24] = nonce
+            .to_vec()
+            .try_into()
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+
+        Ok(ServerInfo { nonce, cipher_text })
+    }
+
+    pub fn complete(
+        &self,
+        secret_key: &SecretKey,
+        server_key: PublicKey,
+    ) -> anyhow::Result<ServerInfoPayload> {
+        let b = SalsaBox::new(&server_key.into(), &secret_key.into());
+        let plain_text = b.decrypt(self.nonce.as_ref().into(), self.cipher_text.as_slice())?;
+        serde_json::from_slice(&plain_text).with_context(|| "Server info parsing")
+    }
+
     // This consume self
     pub fn frame(self) -> Frame<ServerInfo> {
         Frame {
@@ -228,24 +524,93 @@ pub struct SendPacket {
     pub payload: Vec<u8>,
 }
 
+impl SendPacket {
+    pub fn frame(self) -> Frame<SendPacket> {
+        Frame {
+            frame_type: FrameType::SendPacket,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+/// One payload for several destinations, see [`FrameType::SendPackets`]. Encoded by hand as
+/// the codec gives the targets no count.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendPackets {
+    pub targets: Vec<PublicKey>,
+    pub payload: Vec<u8>,
+}
+
+impl SendPackets {
+    /// Encodes the whole frame, at most 255 targets fit in it
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let count = u8::try_from(self.targets.len())?;
+        FrameType::SendPackets.encode(buf)?;
+        u32::try_from(1 + self.targets.len() * KEY_SIZE + self.payload.len())?.encode(buf)?;
+        count.encode(buf)?;
+        for target in &self.targets {
+            target.encode(buf)?;
+        }
+        buf.extend_from_slice(&self.payload);
+        Ok(())
+    }
+
+    /// Decodes a whole frame, header included
+    pub fn decode_from(buf: &[u8]) -> anyhow::Result<Self> {
+        let body = buf
+            .get(HEADER_SIZE..)
+            .ok_or_else(|| anyhow::anyhow!("Decode error"))?;
+        let (&count, rest) = body
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Decode error"))?;
+        let keys_size = usize::from(count) * KEY_SIZE;
+        anyhow::ensure!(rest.len() >= keys_size, "Decode error");
+        let (keys, payload) = rest.split_at(keys_size);
+        let targets = keys
+            .chunks_exact(KEY_SIZE)
+            .filter_map(|key| PublicKey::try_from(key).ok())
+            .collect();
+        Ok(SendPackets {
+            targets,
+            payload: payload.to_vec(),
+        })
+    }
+}
+
 #[derive(Debug, Decode, Encode)]
 pub struct RecvPacket {
-    pub target: PublicKey,
+    pub source: PublicKey,
     pub payload: Vec<u8>,
 }
 
+/// Mesh hops a packet may take after the relay its sender is connected to. A full mesh needs
+/// a single one, the counter only exists to break loops caused by misconfiguration.
+pub const MESH_TTL: u8 = 2;
+
 #[derive(Decode, Encode)]
 pub struct ForwardPacket {
     pub source: PublicKey,
     pub target: PublicKey,
+    /// Mesh hops left, a relay receiving it at 0 must not forward it to another relay. Only on
+    /// the wire for relays speaking [`FORWARD_TTL_VERSION`] or later.
+    pub ttl: u8,
     pub payload: Vec<u8>,
 }
 
+/// ForwardPacket of relays speaking a protocol version before [`FORWARD_TTL_VERSION`]
+#[derive(Decode, Encode)]
+struct UntimedForwardPacket {
+    source: PublicKey,
+    target: PublicKey,
+    payload: Vec<u8>,
+}
+
 impl ForwardPacket {
-    pub fn new(source: PublicKey, target: PublicKey, payload: Vec<u8>) -> Self {
+    pub fn new(source: PublicKey, target: PublicKey, ttl: u8, payload: Vec<u8>) -> Self {
         ForwardPacket {
             source,
             target,
+            ttl,
             payload,
         }
     }
@@ -256,6 +621,102 @@ impl ForwardPacket {
             inner: SizeWrapper::new(self),
         }
     }
+
+    /// Encodes the frame for a relay speaking protocol `version`, older relays get it without
+    /// the ttl
+    pub fn encode_for(self, version: u32, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        if version >= FORWARD_TTL_VERSION {
+            self.frame().encode(buf)?;
+        } else {
+            Frame {
+                frame_type: FrameType::ForwardPacket,
+                inner: SizeWrapper::new(UntimedForwardPacket {
+                    source: self.source,
+                    target: self.target,
+                    payload: self.payload,
+                }),
+            }
+            .encode(buf)?;
+        }
+        Ok(())
+    }
+
+    /// The frame [`encode_for`](Self::encode_for) gives up to the payload, which follows it on
+    /// the wire. Lets large payloads be written without copying them into the frame.
+    pub fn head_for(&self, version: u32) -> anyhow::Result<Vec<u8>> {
+        let with_ttl = version >= FORWARD_TTL_VERSION;
+        let head_size = HEADER_SIZE + 2 * KEY_SIZE + usize::from(with_ttl);
+        let mut head = Vec::with_capacity(head_size);
+        FrameType::ForwardPacket.encode(&mut head)?;
+        u32::try_from(head_size - HEADER_SIZE + self.payload.len())?.encode(&mut head)?;
+        self.source.encode(&mut head)?;
+        self.target.encode(&mut head)?;
+        if with_ttl {
+            self.ttl.encode(&mut head)?;
+        }
+        Ok(head)
+    }
+
+    /// Decodes the frame of a relay speaking protocol `version`. Older relays only forward
+    /// packets of their own clients, theirs have no hops left.
+    pub fn decode_from(version: u32, mut buf: &[u8]) -> anyhow::Result<Self> {
+        if version >= FORWARD_TTL_VERSION {
+            return Ok(Frame::<ForwardPacket>::decode(&mut buf)
+                .map_err(|_| anyhow::anyhow!("Decode error"))?
+                .inner
+                .into_inner());
+        }
+        let packet = Frame::<UntimedForwardPacket>::decode(&mut buf)
+            .map_err(|_| anyhow::anyhow!("Decode error"))?
+            .inner
+            .into_inner();
+        Ok(ForwardPacket::new(
+            packet.source,
+            packet.target,
+            0,
+            packet.payload,
+        ))
+    }
+}
+
+/// Whether the server is the client's home node
+#[derive(Debug, Decode, Encode)]
+pub struct NotePreferred {
+    pub preferred: u8,
+}
+
+impl NotePreferred {
+    pub fn new(preferred: bool) -> Self {
+        NotePreferred {
+            preferred: preferred.into(),
+        }
+    }
+
+    pub fn is_preferred(&self) -> bool {
+        self.preferred != 0
+    }
+
+    pub fn frame(self) -> Frame<NotePreferred> {
+        Frame {
+            frame_type: FrameType::NotePreferred,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+/// Peer whose relayed packets are copied to the sender
+#[derive(Debug, Decode, Encode)]
+pub struct MirrorPackets {
+    pub public_key: PublicKey,
+}
+
+impl MirrorPackets {
+    pub fn frame(self) -> Frame<MirrorPackets> {
+        Frame {
+            frame_type: FrameType::MirrorPackets,
+            inner: SizeWrapper::new(self),
+        }
+    }
 }
 
 #[derive(Debug, Decode, Encode)]
@@ -263,20 +724,287 @@ pub struct PeerPresent {
     pub public_key: PublicKey,
 }
 
+/// Why a peer is gone, sent as the optional last byte of PeerGone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Decode, Encode)]
+pub enum PeerGoneReason {
+    /// The peer closed its connection, or the connection failed
+    #[tag(0x00u8)]
+    Disconnected,
+    /// The server doesn't know the peer a packet was sent to
+    #[tag(0x01)]
+    NotHere,
+    /// The peer was disconnected because it didn't send anything for too long
+    #[tag(0x02)]
+    IdleTimeout,
+    /// The peer was disconnected because writing to it took too long
+    #[tag(0x03)]
+    WriteTimeout,
+    /// The peer was disconnected because the server went over its memory budget, and the
+    /// peer's connection held the most
+    #[tag(0x04)]
+    Evicted,
+    /// The peer was asked to reconnect after sending `--max-connection-bytes` over its
+    /// connection
+    #[tag(0x05)]
+    Recycled,
+    /// The mesh connection through which the peer was reachable broke
+    #[tag(0xF0)]
+    MeshConnBroke,
+
+    #[unknown]
+    Unknown(#[unknown] u8),
+}
+
+impl PeerGoneReason {
+    /// Lowest protocol version that knows the reason, peers speaking an older one are told
+    /// [`Disconnected`](Self::Disconnected) instead
+    pub fn min_version(&self) -> u32 {
+        match self {
+            PeerGoneReason::Evicted => 5,
+            PeerGoneReason::Recycled => 6,
+            _ => 1,
+        }
+    }
+
+    /// The reason as told to a peer speaking protocol `version`
+    pub fn for_version(self, version: u32) -> Self {
+        if self.min_version() > version {
+            PeerGoneReason::Disconnected
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Debug, Decode, Encode)]
+pub struct PeerGone {
+    pub public_key: PublicKey,
+    /// Older servers don't send a reason
+    pub reason: Option<PeerGoneReason>,
+}
+
+impl PeerGone {
+    pub fn new(public_key: PublicKey, reason: PeerGoneReason) -> Self {
+        PeerGone {
+            public_key,
+            reason: Some(reason),
+        }
+    }
+
+    pub fn frame(self) -> Frame<PeerGone> {
+        Frame {
+            frame_type: FrameType::PeerGone,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+#[derive(Debug, Default, Decode, Encode)]
+pub struct Ping {
+    pub data: [u8; 8],
+}
+
+impl Ping {
+    pub fn frame(self) -> Frame<Ping> {
+        Frame {
+            frame_type: FrameType::Ping,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+/// Echoes the data of a Ping
+#[derive(Debug, Default, Decode, Encode)]
+pub struct Pong {
+    pub data: [u8; 8],
+}
+
+impl Pong {
+    pub fn frame(self) -> Frame<Pong> {
+        Frame {
+            frame_type: FrameType::Pong,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
 #[derive(Default, Decode, Encode)]
 pub struct WatchConns {
     pub data: Vec<u8>,
 }
 
+#[derive(Default, Decode, Encode)]
+pub struct RosterComplete {
+    pub data: Vec<u8>,
+}
+
+impl RosterComplete {
+    pub fn frame(self) -> Frame<RosterComplete> {
+        Frame {
+            frame_type: FrameType::RosterComplete,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+/// What the server did with a SendPacket, see [`SendAck`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Decode, Encode)]
+pub enum SendStatus {
+    /// Queued for the connection of the target, or for the relay it's connected to
+    #[tag(0x00u8)]
+    Enqueued,
+    /// Not queued anywhere, the target won't get it
+    #[tag(0x01)]
+    Dropped,
+
+    #[unknown]
+    Unknown(#[unknown] u8),
+}
+
+#[derive(Debug, PartialEq, Eq, Decode, Encode)]
+pub struct SendAck {
+    pub target: PublicKey,
+    pub status: SendStatus,
+}
+
+impl SendAck {
+    pub fn frame(self) -> Frame<SendAck> {
+        Frame {
+            frame_type: FrameType::SendAck,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
+/// The server closes the connection right after, see [`FrameType::Restarting`]
+#[derive(Debug, Default, PartialEq, Eq, Decode, Encode)]
+pub struct Restarting {
+    /// Milliseconds to wait before reconnecting
+    pub reconnect_in: u32,
+    /// Milliseconds to keep trying to reconnect for
+    pub try_for: u32,
+}
+
+impl Restarting {
+    pub fn frame(self) -> Frame<Restarting> {
+        Frame {
+            frame_type: FrameType::Restarting,
+            inner: SizeWrapper::new(self),
+        }
+    }
+}
+
 #[derive(Decode)]
 pub struct Header {
     pub frame_type: FrameType,
     pub size: u32,
 }
 
+/// Encodes frames with fixed contents and checks their size on the wire, so a `codec` that
+/// encodes differently, e.g. after a bad upgrade, fails at startup instead of garbling traffic
+pub fn check_wire_format() -> anyhow::Result<()> {
+    let key = PublicKey::new([1; 32]);
+    let mut forward_packet = Vec::new();
+    ForwardPacket::new(key, key, MESH_TTL, vec![0; 4])
+        .encode_for(PROTOCOL_VERSION, &mut forward_packet)?;
+    let mut send_packets = Vec::new();
+    SendPackets {
+        targets: vec![key, key],
+        payload: vec![0; 4],
+    }
+    .encode_into(&mut send_packets)?;
+    check_frame_sizes(&[
+        (
+            FrameType::ServerKey,
+            encoded(ServerKey::new(key).frame())?,
+            45,
+        ),
+        (
+            FrameType::SendPacket,
+            encoded(
+                SendPacket {
+                    target: key,
+                    payload: vec![0; 4],
+                }
+                .frame(),
+            )?,
+            41,
+        ),
+        (FrameType::ForwardPacket, forward_packet, 74),
+        (FrameType::SendPackets, send_packets, 74),
+        (
+            FrameType::NotePreferred,
+            encoded(NotePreferred::new(true).frame())?,
+            6,
+        ),
+        (
+            FrameType::PeerGone,
+            encoded(PeerGone::new(key, PeerGoneReason::NotHere).frame())?,
+            38,
+        ),
+        (FrameType::Ping, encoded(Ping::default().frame())?, 13),
+        (
+            FrameType::Restarting,
+            encoded(Restarting::default().frame())?,
+            13,
+        ),
+        (
+            FrameType::RosterComplete,
+            encoded(RosterComplete::default().frame())?,
+            5,
+        ),
+    ])
+}
+
+fn encoded(frame: impl Encode) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    frame.encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// Checks each encoded frame is `size` bytes long and starts with a header giving its type and
+/// the length of the rest
+fn check_frame_sizes(frames: &[(FrameType, Vec<u8>, usize)]) -> anyhow::Result<()> {
+    for (frame_type, frame, size) in frames {
+        anyhow::ensure!(
+            frame.len() == *size,
+            "{frame_type:?} frames encode to {} bytes instead of {size}, the codec changed the \
+             wire format",
+            frame.len()
+        );
+        let header = Header::decode(&mut frame.as_slice())
+            .map_err(|_| anyhow::anyhow!("{frame_type:?} frames encode without a header"))?;
+        anyhow::ensure!(
+            header.frame_type == *frame_type && header.size as usize == size - HEADER_SIZE,
+            "{frame_type:?} frames encode with a header for a {:?} of {} bytes",
+            header.frame_type,
+            header.size
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn wire_format_check_passes() {
+        check_wire_format().unwrap();
+    }
+
+    #[test]
+    fn wire_format_check_catches_a_size_drift() {
+        let mut ping = encoded(Ping::default().frame()).unwrap();
+        check_frame_sizes(&[(FrameType::Ping, ping.clone(), 13)]).unwrap();
+
+        // As if the codec prefixed the data with its length
+        ping.insert(HEADER_SIZE, 8);
+        assert!(check_frame_sizes(&[(FrameType::Ping, ping.clone(), 13)]).is_err());
+        // The header disagrees even when the size is what's expected
+        assert!(check_frame_sizes(&[(FrameType::Ping, ping, 14)]).is_err());
+    }
+
     #[test]
     fn test_server_key_frame() {
         let data = &[
@@ -326,4 +1054,281 @@ mod tests {
         assert_eq!(decoded_client_info.nonce, client_info.nonce);
         assert_eq!(decoded_client_info.cipher_text, client_info.cipher_text);
     }
+
+    fn client_info_at(timestamp: Option<u64>) -> CompleteClientInfo {
+        CompleteClientInfo {
+            public_key: PublicKey::new([5; 32]),
+            nonce: [2; 24],
+            payload: ClientInfoPayload {
+                version: 2,
+                meshkey: String::new(),
+                timestamp,
+                resume_token: None,
+                send_acks: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_client_info_timestamp_in_window() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let max_skew = Duration::from_secs(30);
+
+        for timestamp in [None, Some(970), Some(1_000), Some(1_030)] {
+            assert_eq!(
+                client_info_at(timestamp).validate_timestamp(max_skew, now),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_info_timestamp_out_of_window() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let max_skew = Duration::from_secs(30);
+
+        assert_eq!(
+            client_info_at(Some(900)).validate_timestamp(max_skew, now),
+            Err(ClockSkewError::Stale(Duration::from_secs(100)))
+        );
+        assert_eq!(
+            client_info_at(Some(1_031)).validate_timestamp(max_skew, now),
+            Err(ClockSkewError::FutureDated(Duration::from_secs(31)))
+        );
+    }
+
+    #[test]
+    fn test_client_info_payload_timestamp_is_optional() {
+        let payload: ClientInfoPayload =
+            serde_json::from_str(r#"{"version": 2, "meshKey": ""}"#).unwrap();
+        assert_eq!(payload.timestamp, None);
+
+        let payload: ClientInfoPayload =
+            serde_json::from_str(r#"{"version": 2, "meshKey": "", "timestamp": 42}"#).unwrap();
+        assert_eq!(payload.timestamp, Some(42));
+    }
+
+    #[test]
+    fn test_client_info_meshkey_round_trip() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+
+        let client_info =
+            ClientInfo::new(&client_sk, server_sk.public(), Some("sécret"), None, false).unwrap();
+        let complete = client_info.complete(&server_sk).unwrap();
+        assert_eq!(complete.payload.meshkey, "sécret");
+    }
+
+    #[test]
+    fn test_client_info_claiming_another_key_is_refused() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+
+        let mut client_info =
+            ClientInfo::new(&client_sk, server_sk.public(), None, None, false).unwrap();
+        client_info.public_key = SecretKey::gen().public();
+        let err = client_info.complete(&server_sk).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&AuthError::Decryption));
+    }
+
+    #[test]
+    fn test_client_info_payload_refuses_oversized_meshkey() {
+        let meshkey = "k".repeat(MAX_MESHKEY_SIZE + 1);
+        let json = format!(r#"{{"version": 2, "meshKey": "{meshkey}"}}"#);
+
+        let err = serde_json::from_str::<ClientInfoPayload>(&json).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&MeshkeyError::TooLong(MAX_MESHKEY_SIZE + 1).to_string()));
+        assert!(ClientInfo::new(
+            &SecretKey::gen(),
+            SecretKey::gen().public(),
+            Some(&meshkey),
+            None,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_client_info_payload_refuses_invalid_utf8_meshkey() {
+        let json = b"{\"version\": 2, \"meshKey\": \"\xff\xfe\"}";
+
+        let err = serde_json::from_slice::<ClientInfoPayload>(json).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&MeshkeyError::InvalidUtf8.to_string()));
+    }
+
+    #[test]
+    fn test_peer_gone_frame() {
+        let mut data = vec![8, 0, 0, 0, 33];
+        data.extend([7; 32]);
+        data.push(0x02);
+        let peer_gone = PeerGone::new(PublicKey::new([7; 32]), PeerGoneReason::IdleTimeout);
+
+        let mut encoded_buf = Vec::new();
+        peer_gone.frame().encode(&mut encoded_buf).unwrap();
+        assert_eq!(encoded_buf, data);
+
+        let decoded = Frame::<PeerGone>::decode(&mut data.as_slice())
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded.public_key, PublicKey::new([7; 32]));
+        assert_eq!(decoded.reason, Some(PeerGoneReason::IdleTimeout));
+    }
+
+    #[test]
+    fn test_peer_gone_frame_without_reason() {
+        let mut data = vec![8, 0, 0, 0, 32];
+        data.extend([7; 32]);
+
+        let decoded = Frame::<PeerGone>::decode(&mut data.as_slice())
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded.public_key, PublicKey::new([7; 32]));
+        assert_eq!(decoded.reason, None);
+    }
+
+    #[test]
+    fn test_server_info_round_trip() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+        let payload = ServerInfoPayload {
+            resume_token: Some(ResumeToken::gen()),
+            region: None,
+            max_packet_size: Some(1000),
+            version: None,
+            send_acks: true,
+            no_forwarding: true,
+            max_fanout: Some(16),
+        };
+
+        let server_info = ServerInfo::new(&server_sk, client_sk.public(), &payload).unwrap();
+        let decoded = server_info
+            .complete(&client_sk, server_sk.public())
+            .unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn test_server_info_region_round_trip() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+        let payload = ServerInfoPayload {
+            resume_token: None,
+            region: Some("eu-central".to_owned()),
+            max_packet_size: None,
+            version: None,
+            send_acks: false,
+            no_forwarding: false,
+            max_fanout: None,
+        };
+
+        let server_info = ServerInfo::new(&server_sk, client_sk.public(), &payload).unwrap();
+        let decoded = server_info
+            .complete(&client_sk, server_sk.public())
+            .unwrap();
+        assert_eq!(decoded.region.as_deref(), Some("eu-central"));
+    }
+
+    #[test]
+    fn test_server_info_ignores_unknown_fields() {
+        let payload: ServerInfoPayload =
+            serde_json::from_str(r#"{"region": "eu-central", "fromTheFuture": 1}"#).unwrap();
+        assert_eq!(payload.region.as_deref(), Some("eu-central"));
+        assert_eq!(payload.resume_token, None);
+    }
+
+    #[test]
+    fn test_forward_packet_carries_ttl() {
+        let mut data = Vec::new();
+        ForwardPacket::new(
+            PublicKey::new([1; 32]),
+            PublicKey::new([2; 32]),
+            1,
+            b"hi".to_vec(),
+        )
+        .frame()
+        .encode(&mut data)
+        .unwrap();
+        let decoded = Frame::<ForwardPacket>::decode(&mut data.as_slice())
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded.source, PublicKey::new([1; 32]));
+        assert_eq!(decoded.target, PublicKey::new([2; 32]));
+        assert_eq!(decoded.ttl, 1);
+        assert_eq!(decoded.payload, b"hi");
+    }
+
+    #[test]
+    fn test_forward_packet_head_is_the_frame_up_to_the_payload() {
+        for version in [FORWARD_TTL_VERSION - 1, FORWARD_TTL_VERSION] {
+            let packet = ForwardPacket::new(
+                PublicKey::new([1; 32]),
+                PublicKey::new([2; 32]),
+                1,
+                b"hi".to_vec(),
+            );
+            let mut head = packet.head_for(version).unwrap();
+            let mut frame = Vec::new();
+            packet.encode_for(version, &mut frame).unwrap();
+            head.extend_from_slice(b"hi");
+            assert_eq!(head, frame);
+        }
+    }
+
+    #[test]
+    fn test_forward_packet_leaves_the_ttl_out_for_older_relays() {
+        let packet = || {
+            ForwardPacket::new(
+                PublicKey::new([1; 32]),
+                PublicKey::new([2; 32]),
+                1,
+                b"hi".to_vec(),
+            )
+        };
+        let (mut current, mut older) = (Vec::new(), Vec::new());
+        packet()
+            .encode_for(FORWARD_TTL_VERSION, &mut current)
+            .unwrap();
+        packet()
+            .encode_for(FORWARD_TTL_VERSION - 1, &mut older)
+            .unwrap();
+        assert_eq!(older.len(), current.len() - 1);
+
+        let decoded = ForwardPacket::decode_from(FORWARD_TTL_VERSION - 1, &older).unwrap();
+        assert_eq!(decoded.target, PublicKey::new([2; 32]));
+        assert_eq!(decoded.ttl, 0);
+        assert_eq!(decoded.payload, b"hi");
+        let decoded = ForwardPacket::decode_from(FORWARD_TTL_VERSION, &current).unwrap();
+        assert_eq!(decoded.ttl, 1);
+        assert_eq!(decoded.payload, b"hi");
+    }
+
+    #[test]
+    fn test_send_packets_round_trip() {
+        let packets = SendPackets {
+            targets: vec![PublicKey::new([1; 32]), PublicKey::new([2; 32])],
+            payload: b"hi".to_vec(),
+        };
+        let mut frame = Vec::new();
+        packets.encode_into(&mut frame).unwrap();
+        assert_eq!(SendPackets::decode_from(&frame).unwrap(), packets);
+
+        // A count naming more keys than the frame holds
+        frame[HEADER_SIZE] = 3;
+        assert!(SendPackets::decode_from(&frame).is_err());
+    }
+
+    #[test]
+    fn test_resume_token_is_hex() {
+        let token: ResumeToken = "000102030405060708090a0b0c0d0e0f".parse().unwrap();
+        assert_eq!(token.to_string(), "000102030405060708090a0b0c0d0e0f");
+        assert_eq!(format!("{token:?}"), "ResumeToken(..)");
+        assert!("0001".parse::<ResumeToken>().is_err());
+    }
 }
@@ -1,58 +1,526 @@
-use self::data::{
-    ClientInfo, ForwardPacket, Frame, FrameType, PeerPresent, ServerInfo, ServerKey, WatchConns,
+use self::{
+    data::{
+        AuthError, ClientInfo, CompleteClientInfo, ForwardPacket, Frame, FrameType, Header,
+        MirrorPackets, NotePreferred, PeerGone, PeerGoneReason, PeerPresent, Ping, ResumeToken,
+        SendPacket, SendPackets, ServerInfo, ServerInfoPayload, ServerKey, WatchConns,
+    },
+    websocket::{derive_accept_key, generate_key, Role, WebSocketIo},
 };
 
 use crate::{
-    crypto::{PublicKey, SecretKey},
-    inout::DerpReader,
+    crypto::{PublicKey, SecretKey, KEY_SIZE},
+    inout::{DerpReader, HEADER_SIZE},
+    FrameTrace, Timeouts,
 };
-use anyhow::{anyhow, ensure};
+use anyhow::{anyhow, bail, ensure};
 use codec::{Decode, Encode, SizeWrapper};
-use log::debug;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use httparse::Status;
+use log::{debug, trace, warn};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{self, Cursor, ErrorKind, IoSlice},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Semaphore,
+    task::spawn_blocking,
+    time::timeout,
+};
 
 pub mod data;
+pub mod websocket;
+
+/// How the client asks the server to upgrade the HTTP connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// `Upgrade: WebSocket` without a WebSocket handshake, frames are sent as they are after
+    /// the upgrade. What clients have always sent.
+    #[default]
+    Legacy,
+    /// `Upgrade: DERP`, frames are sent as they are after the upgrade
+    Derp,
+    /// A WebSocket connection, the frames go in binary messages. For clients behind proxies
+    /// that only pass WebSocket connections.
+    WebSocket,
+}
+
+impl Transport {
+    fn upgrade_header(self) -> &'static str {
+        match self {
+            Transport::Legacy | Transport::WebSocket => "WebSocket",
+            Transport::Derp => "DERP",
+        }
+    }
+}
+
+/// A connection past its HTTP upgrade, DERP frames are read from and written to it
+pub enum Upgraded<S> {
+    /// Frames as they are, the first bytes were read along with the upgrade
+    Plain(Cursor<Vec<u8>>, S),
+    WebSocket(WebSocketIo<S>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Upgraded<S> {
+    /// `stream` upgraded with `transport`, `leftovers` were read past the upgrade
+    pub async fn new(stream: S, leftovers: Vec<u8>, transport: Transport, role: Role) -> Self {
+        match transport {
+            Transport::Legacy | Transport::Derp => Upgraded::Plain(Cursor::new(leftovers), stream),
+            Transport::WebSocket => {
+                Upgraded::WebSocket(WebSocketIo::new(stream, leftovers, role).await)
+            }
+        }
+    }
+
+    /// Bytes read from the stream that weren't read from the connection yet
+    fn buffered(&self) -> usize {
+        match self {
+            Upgraded::Plain(leftovers, _) => {
+                leftovers.get_ref().len() - leftovers.position() as usize
+            }
+            Upgraded::WebSocket(websocket) => websocket.buffered(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Upgraded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upgraded::Plain(leftovers, _)
+                if leftovers.position() < leftovers.get_ref().len() as u64 =>
+            {
+                Pin::new(leftovers).poll_read(cx, buf)
+            }
+            Upgraded::Plain(_, stream) => Pin::new(stream).poll_read(cx, buf),
+            Upgraded::WebSocket(websocket) => Pin::new(websocket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Upgraded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Upgraded::Plain(_, stream) => Pin::new(stream).poll_write(cx, buf),
+            Upgraded::WebSocket(websocket) => Pin::new(websocket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upgraded::Plain(_, stream) => Pin::new(stream).poll_flush(cx),
+            Upgraded::WebSocket(websocket) => Pin::new(websocket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Upgraded::Plain(_, stream) => Pin::new(stream).poll_shutdown(cx),
+            Upgraded::WebSocket(websocket) => Pin::new(websocket).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Errors found while decoding frames
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ProtoError {
+    /// The frame length doesn't match the fixed size of its type
+    #[error("{frame_type:?} frame must be {expected:?} bytes long, got {actual}")]
+    FrameLengthMismatch {
+        frame_type: FrameType,
+        expected: RangeInclusive<u32>,
+        actual: u32,
+    },
+    /// The frame has the length of its type but its content doesn't decode
+    #[error("Malformed {0:?} frame")]
+    Malformed(FrameType),
+    /// A well formed frame of a type the receiver doesn't handle, e.g. one from a newer protocol
+    #[error("Unexpected {0:?} frame")]
+    UnexpectedFrame(FrameType),
+    /// The frame is longer than any the protocol has, it isn't buffered
+    #[error("{frame_type:?} frame of {size} bytes is too large")]
+    FrameTooLarge { frame_type: FrameType, size: u32 },
+}
+
+impl ProtoError {
+    /// Whether the frames after the erroneous one can still be read. All of these are about a
+    /// single frame whose end is known, [`DerpReader`] skips the rest of one with a wrong length.
+    ///
+    /// [`DerpReader`]: crate::inout::DerpReader
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ProtoError::UnexpectedFrame(_)
+                | ProtoError::Malformed(_)
+                | ProtoError::FrameLengthMismatch { .. }
+        )
+    }
+}
+
+/// Largest SendPacket payload that fits a frame read by [`DerpReader`]
+pub const MAX_PACKET_SIZE: usize = MAX_TCP_PACKET_SIZE - HEADER_SIZE - KEY_SIZE;
+
 const UPGRADE_MSG_SIZE: usize = 4096;
+/// Largest ClientInfo frame accepted, real ones are a few hundred bytes
+const MAX_CLIENT_INFO_SIZE: usize = 1024;
+/// Max TCP packet size is 65535
+const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Logs an encoded frame if frame tracing is enabled, `direction` is either "sent" or "received"
+pub fn trace_frame(frame_trace: FrameTrace, pk: &PublicKey, direction: &str, frame: &[u8]) {
+    if !frame_trace.frames {
+        return;
+    }
+    let ty = FrameType::get_frame_type(frame);
+    let body = frame.get(HEADER_SIZE..).unwrap_or_default();
+    let key_count = match ty {
+        FrameType::PeerPresent
+        | FrameType::PeerGone
+        | FrameType::ClosePeer
+        | FrameType::MirrorPackets => 1,
+        FrameType::SendPacket | FrameType::RecvPacket => 1,
+        FrameType::ForwardPacket => 2,
+        _ => 0,
+    };
+    let (keys, payload) = body.split_at((key_count * KEY_SIZE).min(body.len()));
+    let keys: Vec<String> = keys
+        .chunks_exact(KEY_SIZE)
+        .filter_map(|key| PublicKey::try_from(key).ok())
+        .map(|key| key.to_string())
+        .collect();
+
+    let mut line = format!(
+        "[{}] {direction} {ty:?} ({} bytes)",
+        pk.log_display(),
+        body.len()
+    );
+    if !keys.is_empty() {
+        line.push_str(&format!(" keys: {}", keys.join(", ")));
+    }
+    if frame_trace.payloads && !payload.is_empty() {
+        line.push_str(&format!(" payload: {}", hex::encode(payload)));
+    }
+    trace!("{line}");
+}
+
+/// What the server learns about a client during the handshake
+#[derive(Debug)]
+pub struct ClientHandshake {
+    pub public_key: PublicKey,
+    pub meshkey: Option<String>,
+    /// Token the client presented to resume a previous session
+    pub resume_token: Option<ResumeToken>,
+    /// Protocol version from the ClientInfo
+    pub version: u32,
+    /// Whether the client asked for a SendAck after each SendPacket
+    pub send_acks: bool,
+}
+
+/// Blocking threads ClientInfos are decrypted on, instead of the threads running connections
+#[derive(Debug, Clone)]
+pub struct DecryptionPool {
+    permits: Arc<Semaphore>,
+}
+
+impl DecryptionPool {
+    /// Decrypts at most `threads` ClientInfos at once
+    pub fn new(threads: NonZeroUsize) -> Self {
+        DecryptionPool {
+            permits: Arc::new(Semaphore::new(threads.get())),
+        }
+    }
+
+    async fn complete(
+        &self,
+        client_info: ClientInfo,
+        sk: &SecretKey,
+    ) -> anyhow::Result<CompleteClientInfo> {
+        let _permit = self.permits.acquire().await?;
+        let sk = sk.clone();
+        spawn_blocking(move || client_info.complete(&sk)).await?
+    }
+}
+
+/// Nonces of the ClientInfos accepted lately. A ClientInfo is accepted for as long as its
+/// timestamp is within the clock skew, anyone who captured it could replay it until then to
+/// take the place of its client, so its nonce is refused for that long.
+#[derive(Debug, Clone)]
+pub struct SeenNonces {
+    window: Duration,
+    seen: Arc<Mutex<NonceWindow>>,
+}
 
+#[derive(Debug, Default)]
+struct NonceWindow {
+    nonces: HashSet<[u8; 24]>,
+    /// When each nonce was seen, oldest first
+    by_age: VecDeque<(Instant, [u8; 24])>,
+}
+
+impl SeenNonces {
+    /// Remembers nonces for twice `max_clock_skew`, a ClientInfo future-dated by the skew when
+    /// it's first seen is accepted until it's stale by the skew
+    pub fn new(max_clock_skew: Duration) -> Self {
+        SeenNonces {
+            window: max_clock_skew * 2,
+            seen: Arc::default(),
+        }
+    }
+
+    /// Records the nonce of `client_info`, refuses it if it was seen within the window
+    fn check(&self, client_info: &CompleteClientInfo, now: Instant) -> Result<(), AuthError> {
+        let mut seen = self.seen.lock().unwrap();
+        while let Some(&(at, nonce)) = seen.by_age.front() {
+            if now.duration_since(at) < self.window {
+                break;
+            }
+            seen.by_age.pop_front();
+            seen.nonces.remove(&nonce);
+        }
+        if !seen.nonces.insert(client_info.nonce) {
+            return Err(AuthError::Replayed(client_info.public_key));
+        }
+        seen.by_age.push_back((now, client_info.nonce));
+        Ok(())
+    }
+}
+
+/// Runs the server side of the handshake, `server_info` is sent to the client once it's known.
+/// The upgrade request must complete within the handshake timeout, and the first frame after
+/// it must be a ClientInfo arriving within the ClientInfo timeout. The caller bounds the whole
+/// handshake by the handshake timeout. Handshakes over the slow
+/// handshake threshold are logged with the time each phase took. The ClientInfo is decrypted
+/// on `decryption_pool` if there's one. It may arrive together with the upgrade request, but
+/// nothing else may be sent before the ServerInfo. `sk` is presented to the client, a ClientInfo
+/// sealed for `previous_key` is accepted as well during a key rotation. A ClientInfo in
+/// `seen_nonces` is refused whichever key it was sealed for. Returns the upgraded connection.
 pub async fn handle_handshake<RW: AsyncWrite + AsyncRead + Unpin>(
-    mut rw: &mut RW,
+    rw: RW,
     sk: &SecretKey,
-) -> anyhow::Result<(PublicKey, Option<String>)> {
-    finalize_http_phase(&mut rw).await?;
+    previous_key: Option<&SecretKey>,
+    timeouts: &Timeouts,
+    max_clock_skew: Duration,
+    seen_nonces: &SeenNonces,
+    server_info: &ServerInfoPayload,
+    decryption_pool: Option<&DecryptionPool>,
+) -> anyhow::Result<(Upgraded<RW>, ClientHandshake)> {
+    let started = Instant::now();
+    let mut rw = rw;
+    let (pipelined, transport) = finalize_http_phase(&mut rw, timeouts.handshake_timeout).await?;
+    let mut rw = Upgraded::new(rw, pipelined, transport, Role::Server).await;
+    let http_phase = started.elapsed();
+
+    write_server_key(&mut rw, sk).await?;
+
+    let client_info_timeout = timeouts.client_info_timeout;
+    let (client, client_info_key) = timeout(
+        client_info_timeout,
+        read_client_info(
+            &mut rw,
+            sk,
+            previous_key,
+            max_clock_skew,
+            seen_nonces,
+            decryption_pool,
+        ),
+    )
+    .await
+    .map_err(|_| anyhow!("No ClientInfo within {client_info_timeout:?}"))??;
+    ensure!(
+        rw.buffered() == 0,
+        "Client sent frames before the ServerInfo"
+    );
+
+    let server_info = ServerInfoPayload {
+        send_acks: client.send_acks,
+        ..server_info.clone()
+    };
+    let client_info_read = started.elapsed() - http_phase;
+    // Sealed for the key the client knows us by
+    write_server_info(&mut rw, client_info_key, client.public_key, &server_info).await?;
 
-    write_server_key(&mut rw, &sk).await?;
+    let took = started.elapsed();
+    if timeouts
+        .slow_handshake_threshold
+        .is_some_and(|threshold| took > threshold)
+    {
+        let phases = [
+            ("HTTP phase", http_phase),
+            ("ClientInfo read", client_info_read),
+            ("ServerInfo write", took - http_phase - client_info_read),
+        ];
+        let slowest = phases
+            .iter()
+            .max_by_key(|(_, time)| *time)
+            .map_or("", |(phase, _)| *phase);
+        warn!(
+            "Slow handshake with {}: took {took:?}, mostly in the {slowest} ({:?} HTTP phase, \
+             {:?} ClientInfo read, {:?} ServerInfo write)",
+            client.public_key.log_display(),
+            phases[0].1,
+            phases[1].1,
+            phases[2].1
+        );
+    }
 
-    let (pk, meshkey) = read_client_info(&mut rw, &sk).await?;
+    Ok((rw, client))
+}
 
-    write_server_info(&mut rw).await?;
+/// An upgrade request that was refused, answered with `status` before closing
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}, answered with {status}")]
+struct HttpError {
+    status: &'static str,
+    reason: String,
+}
 
-    Ok((pk, meshkey))
+impl HttpError {
+    fn refuse(status: &'static str, reason: impl ToString) -> anyhow::Error {
+        Self {
+            status,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
 }
 
+/// Answers the upgrade request. Requests that are malformed or don't complete within
+/// `upgrade_timeout`, e.g. because they're sent a byte at a time, get an error status.
+/// Returns the bytes read past the request, the start of a pipelined ClientInfo, and what the
+/// connection was upgraded to.
 async fn finalize_http_phase<RW: AsyncWrite + AsyncRead + Unpin>(
     rw: &mut RW,
-) -> anyhow::Result<()> {
-    let mut buf = [0u8; UPGRADE_MSG_SIZE];
-    let n = rw.read(&mut buf).await?; // TODO: timeout
-    ensure!(n > 0, "empty initiall message");
-    ensure!(n < UPGRADE_MSG_SIZE, "initial message too big");
+    upgrade_timeout: Duration,
+) -> anyhow::Result<(Vec<u8>, Transport)> {
+    let result = timeout(upgrade_timeout, read_upgrade_request(rw))
+        .await
+        .unwrap_or_else(|_| {
+            Err(HttpError::refuse(
+                "408 Request Timeout",
+                format!("No complete upgrade request within {upgrade_timeout:?}"),
+            ))
+        });
+    let request = match result {
+        Ok(request) => request,
+        Err(e) => {
+            if let Some(http_error) = e.downcast_ref::<HttpError>() {
+                let response = format!("HTTP/1.1 {}\r\n\r\n", http_error.status);
+                // The connection is closed anyway, the client may already be gone
+                let _ = rw.write_all(response.as_bytes()).await;
+            }
+            return Err(e);
+        }
+    };
+    let (upgrade, transport) = match &request.websocket_key {
+        Some(_) => ("websocket", Transport::WebSocket),
+        None if request.upgrade.eq_ignore_ascii_case("websocket") => {
+            ("websocket", Transport::Legacy)
+        }
+        None => ("DERP", Transport::Derp),
+    };
+    let accept = request
+        .websocket_key
+        .map(|key| {
+            format!(
+                "Sec-WebSocket-Accept: {}\r\n",
+                derive_accept_key(key.as_bytes())
+            )
+        })
+        .unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: {upgrade}\r\nConnection: Upgrade\r\n\
+         {accept}\r\n"
+    );
+    rw.write_all(response.as_bytes()).await?;
 
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut req = httparse::Request::new(&mut headers);
-    let body_start = req.parse(&buf)?; // TODO: add context
-    ensure!(body_start.is_complete());
-    validate_headers(&headers)?;
-    let body_start = body_start.unwrap();
-    let _body = &buf[body_start..];
-    // TODO: do something with body?
-    rw.write(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+    Ok((request.pipelined, transport))
+}
 
+/// Answers the upgrade request with `status` instead of upgrading, for connections refused
+/// before their handshake. `retry_after` tells the client when to come back.
+pub async fn refuse_upgrade<RW: AsyncWrite + AsyncRead + Unpin>(
+    rw: &mut RW,
+    upgrade_timeout: Duration,
+    status: &str,
+    retry_after: Option<Duration>,
+) -> anyhow::Result<()> {
+    // Closing with the request unread could reset the connection before the answer arrives
+    let _ = timeout(upgrade_timeout, read_upgrade_request(rw)).await;
+    let retry_after = retry_after
+        .map(|after| format!("Retry-After: {}\r\n", after.as_secs()))
+        .unwrap_or_default();
+    let response = format!("HTTP/1.1 {status}\r\n{retry_after}\r\n");
+    rw.write_all(response.as_bytes()).await?;
     Ok(())
 }
 
+/// A valid upgrade request
+struct UpgradeRequest {
+    /// Value of the Upgrade header
+    upgrade: String,
+    /// Sent by clients speaking WebSocket, not by those only using its Upgrade header
+    websocket_key: Option<String>,
+    /// Bytes read past the request
+    pipelined: Vec<u8>,
+}
+
+/// Reads and validates the upgrade request
+async fn read_upgrade_request<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> anyhow::Result<UpgradeRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; UPGRADE_MSG_SIZE];
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        ensure!(n > 0, "connection closed during the upgrade request");
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(Status::Complete(len)) => {
+                validate_headers(&headers)
+                    .map_err(|e| HttpError::refuse("426 Upgrade Required", e))?;
+                let header = |name: &str| {
+                    headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case(name))
+                        .map(|h| String::from_utf8_lossy(h.value).trim().to_owned())
+                };
+                return Ok(UpgradeRequest {
+                    upgrade: header("Upgrade").unwrap_or_default(),
+                    websocket_key: header("Sec-WebSocket-Key"),
+                    pipelined: buf.split_off(len),
+                });
+            }
+            // Bytes past the request may fill the chunk, only the request itself is limited
+            Ok(Status::Partial) if buf.len() >= UPGRADE_MSG_SIZE => {
+                return Err(HttpError::refuse(
+                    "400 Bad Request",
+                    "initial message too big",
+                ));
+            }
+            Ok(Status::Partial) => continue,
+            Err(e) => return Err(HttpError::refuse("400 Bad Request", e)),
+        }
+    }
+}
+
 fn validate_headers(headers: &[httparse::Header]) -> anyhow::Result<()> {
     for h in headers {
-        if h.name == "Upgrade" {
+        if h.name.eq_ignore_ascii_case("Upgrade") {
             let value = std::str::from_utf8(h.value)?.to_ascii_lowercase();
             ensure!(
                 value == "websocket" || value == "derp",
@@ -60,7 +528,7 @@ fn validate_headers(headers: &[httparse::Header]) -> anyhow::Result<()> {
             );
         }
 
-        if h.name == "Connection" {
+        if h.name.eq_ignore_ascii_case("Connection") {
             let value = std::str::from_utf8(h.value)?.to_ascii_lowercase();
             ensure!(value == "upgrade", "Unexpected Connection value {value}");
         }
@@ -97,35 +565,85 @@ async fn read_server_key<R: AsyncRead + Unpin>(
     Ok(server_key.public_key)
 }
 
-async fn read_client_info<R: AsyncRead + Unpin>(
+/// Returns the handshake along with the key the ClientInfo was sealed for
+async fn read_client_info<'k, R: AsyncRead + Unpin>(
     reader: &mut R,
-    sk: &SecretKey,
-) -> anyhow::Result<(PublicKey, Option<String>)> {
-    // TODO use only one prealocated buffer for read / write
-    let mut buf = [0; 1024];
-    reader.read(&mut buf).await?;
-
-    let client_info = match FrameType::get_frame_type(&buf) {
-        FrameType::ClientInfo => {
-            Frame::<ClientInfo>::decode(&mut buf.as_slice()).map_err(|_| anyhow!("Decode error"))
+    sk: &'k SecretKey,
+    previous_key: Option<&'k SecretKey>,
+    max_clock_skew: Duration,
+    seen_nonces: &SeenNonces,
+    decryption_pool: Option<&DecryptionPool>,
+) -> anyhow::Result<(ClientHandshake, &'k SecretKey)> {
+    let mut buf = vec![0; HEADER_SIZE];
+    match reader.read_exact(&mut buf).await {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            bail!("connection closed before sending ClientInfo")
         }
-        ty => anyhow::bail!("Unexpected message: {ty:?}"),
-    }?;
+        result => result?,
+    };
+    let header = Header::decode(&mut buf.as_slice()).map_err(|_| anyhow!("Decode error"))?;
+    ensure!(
+        header.frame_type == FrameType::ClientInfo,
+        "Unexpected message: {:?}",
+        header.frame_type
+    );
+    let size = HEADER_SIZE + header.size as usize;
+    ensure!(
+        size <= MAX_CLIENT_INFO_SIZE,
+        "ClientInfo of {size} bytes is over the {MAX_CLIENT_INFO_SIZE} bytes limit"
+    );
+    buf.resize(size, 0);
+    reader.read_exact(&mut buf[HEADER_SIZE..]).await?;
+
+    let client_info = Frame::<ClientInfo>::decode(&mut buf.as_slice())
+        .map_err(|_| ProtoError::Malformed(FrameType::ClientInfo))?;
     let client_info = client_info.inner.into_inner();
-    debug!("Client public key: {:?}", client_info.public_key);
 
-    let complete_info = client_info.complete(sk)?;
+    // The claimed key is only trusted once the payload decrypts with it
+    let (complete_info, client_info_key) =
+        match open_client_info(client_info.clone(), sk, decryption_pool).await {
+            Err(e) if e.downcast_ref() == Some(&AuthError::Decryption) => match previous_key {
+                Some(previous_key) => (
+                    open_client_info(client_info, previous_key, decryption_pool).await?,
+                    previous_key,
+                ),
+                None => return Err(e),
+            },
+            opened => (opened?, sk),
+        };
+    debug!(
+        "Client public key: {}",
+        complete_info.public_key.log_display()
+    );
+    complete_info.validate_timestamp(max_clock_skew, SystemTime::now())?;
+    seen_nonces.check(&complete_info, Instant::now())?;
 
     debug!("client info: {:?}", complete_info.payload);
 
-    Ok((
-        complete_info.public_key,
-        if complete_info.payload.meshkey.is_empty() {
+    let handshake = ClientHandshake {
+        public_key: complete_info.public_key,
+        meshkey: if complete_info.payload.meshkey.is_empty() {
             None
         } else {
             Some(complete_info.payload.meshkey)
         },
-    ))
+        resume_token: complete_info.payload.resume_token,
+        version: complete_info.payload.version,
+        send_acks: complete_info.payload.send_acks,
+    };
+    Ok((handshake, client_info_key))
+}
+
+/// Decrypts `client_info` with `sk`, on `decryption_pool` if there's one
+async fn open_client_info(
+    client_info: ClientInfo,
+    sk: &SecretKey,
+    decryption_pool: Option<&DecryptionPool>,
+) -> anyhow::Result<CompleteClientInfo> {
+    match decryption_pool {
+        Some(pool) => pool.complete(client_info, sk).await,
+        None => client_info.complete(sk),
+    }
 }
 
 async fn write_client_info<W: AsyncWrite + Unpin>(
@@ -137,22 +655,34 @@ async fn write_client_info<W: AsyncWrite + Unpin>(
     writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
 }
 
-async fn write_server_info<W: AsyncWrite + Unpin>(writer: &mut W) -> anyhow::Result<()> {
+async fn write_server_info<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    sk: &SecretKey,
+    client_key: PublicKey,
+    payload: &ServerInfoPayload,
+) -> anyhow::Result<()> {
     let mut buf = Vec::new();
-    ServerInfo::default().frame().encode(&mut buf)?;
+    ServerInfo::new(sk, client_key, payload)?
+        .frame()
+        .encode(&mut buf)?;
     writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
 }
 
 pub async fn read_server_info<R: AsyncRead + Unpin>(
     derp_reader: &mut DerpReader<R>,
-) -> anyhow::Result<()> {
+    secret_key: &SecretKey,
+    server_key: PublicKey,
+) -> anyhow::Result<ServerInfoPayload> {
     let message = derp_reader.get_next_message().await?;
 
     if message.ty != FrameType::ServerInfo {
-        Err(anyhow::anyhow!("Invalid frame type {:?}", message.ty))
-    } else {
-        Ok(())
+        bail!("Invalid frame type {:?}", message.ty);
     }
+    Frame::<ServerInfo>::decode(&mut message.buffer.as_slice())
+        .map_err(|_| anyhow!("Decode error"))?
+        .inner
+        .into_inner()
+        .complete(secret_key, server_key)
 }
 
 pub async fn write_peer_present<W: AsyncWrite + Unpin>(
@@ -170,12 +700,79 @@ pub async fn write_peer_present<W: AsyncWrite + Unpin>(
     writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
 }
 
+pub async fn write_peer_gone<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    public_key: &PublicKey,
+    reason: PeerGoneReason,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    PeerGone::new(*public_key, reason)
+        .frame()
+        .encode(&mut buf)?;
+    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+}
+
+/// Writes the frame the way a relay speaking protocol `version` decodes it. The payload isn't
+/// copied, it's written along with the rest of the frame in vectored writes.
 pub async fn write_forward_packet<W: AsyncWrite + Unpin>(
     writer: &mut W,
-    forward_packet: ForwardPacket,
+    forward_packet: &ForwardPacket,
+    version: u32,
+) -> anyhow::Result<()> {
+    let head = forward_packet.head_for(version)?;
+    write_all_vectored(writer, &head, &forward_packet.payload)
+        .await
+        .map_err(|e| anyhow!("{e}"))
+}
+
+/// Writes `head` followed by `tail`, in single writes when the writer supports vectored ones
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    head: &[u8],
+    tail: &[u8],
+) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < head.len() + tail.len() {
+        let n = match head.get(written..) {
+            Some(rest) if !rest.is_empty() => {
+                writer
+                    .write_vectored(&[IoSlice::new(rest), IoSlice::new(tail)])
+                    .await?
+            }
+            _ => writer.write(&tail[written - head.len()..]).await?,
+        };
+        if n == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+pub async fn write_send_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    send_packet: SendPacket,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    send_packet.frame().encode(&mut buf)?;
+    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+}
+
+pub async fn write_send_packets<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    send_packets: SendPackets,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    send_packets.encode_into(&mut buf)?;
+    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+}
+
+pub async fn write_note_preferred<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    preferred: bool,
 ) -> anyhow::Result<()> {
     let mut buf = Vec::new();
-    forward_packet.frame().encode(&mut buf)?;
+    NotePreferred::new(preferred).frame().encode(&mut buf)?;
     writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
 }
 
@@ -189,20 +786,601 @@ pub async fn write_watch_conns<W: AsyncWrite + Unpin>(writer: &mut W) -> anyhow:
     writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
 }
 
+/// Sends a Ping with `data`, the server echoes it in a Pong
+pub async fn write_ping<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: [u8; 8],
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    Ping { data }.frame().encode(&mut buf)?;
+    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+}
+
+/// Asks for copies of the packets relayed from or to `peer`, needs mesh privileges
+pub async fn write_mirror_packets<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    peer: PublicKey,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    MirrorPackets { public_key: peer }
+        .frame()
+        .encode(&mut buf)?;
+    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+}
+
 /// Reads the server key and sends the initiation message via a writer to the DERP server
 /// Initiation message consists of:
 /// * `public key`
 /// * `nonce` - a random byte sequence generated by client
 /// * `ciphertext` - an initiation JSON encrypted with the secret key, using a generated nonce
+///
+/// Servers may send their ServerInfo right behind the key, in the same read. It stays buffered
+/// in `reader`, so the same reader must be passed on to [`read_server_info`].
 pub async fn exchange_keys<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     reader: &mut DerpReader<R>,
     mut writer: W,
-    secret_key: SecretKey,
+    secret_key: &SecretKey,
     meshkey: Option<&str>,
+    resume_token: Option<ResumeToken>,
+    send_acks: bool,
 ) -> anyhow::Result<PublicKey> {
     let server_key = read_server_key(reader).await?;
     debug!("server key: {server_key}");
-    let client_info = ClientInfo::new(secret_key, server_key, meshkey)?;
+    let client_info = ClientInfo::new(secret_key, server_key, meshkey, resume_token, send_acks)?;
     write_client_info(&mut writer, client_info).await?;
     Ok(server_key)
 }
+
+/// Sends the HTTP upgrade request to a DERP server and reads its response.
+/// Returns any bytes received after the response, they belong to the first frame.
+pub async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    transport: Transport,
+    // server_keepalives: &DerpKeepaliveConfig,
+    // host: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let websocket_key = (transport == Transport::WebSocket).then(generate_key);
+    let websocket_headers = websocket_key
+        .as_ref()
+        .map(|key| format!("Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n"))
+        .unwrap_or_default();
+    writer
+        .write_all(
+            format!(
+                // TODO: host header!
+                "GET /derp HTTP/1.1\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: {}\r\n\
+                {websocket_headers}\
+                User-Agent: telio/{} {}\r\n\r\n",
+                transport.upgrade_header(),
+                env!("CARGO_PKG_VERSION"),
+                std::env::consts::OS,
+                // TODO: server_keepalives.tcp_keepalive,
+                // TODO: server_keepalives.derp_keepalive,
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let mut data = [0_u8; MAX_TCP_PACKET_SIZE];
+    let data_len = reader.read(&mut data).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut res = httparse::Response::new(&mut headers);
+    let res_len = match res.parse(&data[..data_len])? {
+        Status::Partial => {
+            bail!("HTTP Response not full");
+        }
+        Status::Complete(len) => len,
+    };
+    match res.code {
+        Some(101) => {}
+        code => bail!(
+            "Server refused the {:?} upgrade: {} {}",
+            transport,
+            code.unwrap_or_default(),
+            res.reason.unwrap_or_default()
+        ),
+    }
+    if let Some(key) = websocket_key {
+        let accept = res
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Accept"));
+        ensure!(
+            accept.is_some_and(|h| h.value == derive_accept_key(key.as_bytes()).as_bytes()),
+            "Server didn't accept the WebSocket key"
+        );
+    }
+    Ok(data
+        .get(res_len..data_len)
+        .ok_or_else(|| anyhow!("Out of bounds index for data buffer"))?
+        .to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        faulty::FaultyStream,
+        inout::DerpReader,
+        proto::data::{ClientInfoPayload, FORWARD_TTL_VERSION, PROTOCOL_VERSION},
+        Config,
+    };
+    use clap::Parser;
+    use futures_util::{SinkExt, StreamExt};
+    use std::io::Cursor;
+    use tokio::{
+        io::{duplex, split},
+        time::sleep,
+    };
+    use tokio_tungstenite::{client_async, tungstenite::Message};
+
+    fn timeouts(handshake_timeout: &str, client_info_timeout: &str) -> Timeouts {
+        Config::parse_from([
+            "dersp",
+            "--listen-on",
+            "unused",
+            "--handshake-timeout",
+            handshake_timeout,
+            "--client-info-timeout",
+            client_info_timeout,
+        ])
+        .timeouts
+    }
+
+    #[tokio::test]
+    async fn closing_after_upgrade_is_reported() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        // Keep the read half alive so the server can still send its key
+        let (mut reader, mut writer) = split(client);
+        connect_http(&mut reader, &mut writer, Transport::Derp)
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "connection closed before sending ClientInfo"
+        );
+    }
+
+    async fn upgrade_then_send(frame: &[u8]) -> anyhow::Result<ClientHandshake> {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("30s", "100ms"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let (mut reader, mut writer) = split(client);
+        connect_http(&mut reader, &mut writer, Transport::Derp)
+            .await
+            .unwrap();
+        writer.write_all(frame).await.unwrap();
+
+        timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server should give up promptly")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn silence_after_upgrade_is_closed_promptly() {
+        let err = upgrade_then_send(&[]).await.unwrap_err();
+        assert_eq!(err.to_string(), "No ClientInfo within 100ms");
+    }
+
+    #[tokio::test]
+    async fn partial_client_info_is_closed_promptly() {
+        // A ClientInfo header announcing 100 bytes that never come
+        let err = upgrade_then_send(&[0x02, 0, 0, 0, 100]).await.unwrap_err();
+        assert_eq!(err.to_string(), "No ClientInfo within 100ms");
+    }
+
+    #[tokio::test]
+    async fn other_frame_before_client_info_is_rejected() {
+        // KeepAlive
+        let err = upgrade_then_send(&[0x06, 0, 0, 0, 0]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected message: KeepAlive");
+    }
+
+    #[tokio::test]
+    async fn oversized_client_info_is_rejected() {
+        let err = upgrade_then_send(&[0x02, 0, 1, 0, 0]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ClientInfo of 65541 bytes is over the 1024 bytes limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_succeeds_with_reads_split_at_awkward_boundaries() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                FaultyStream::new(server).split_reads(3),
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let (mut reader, mut writer) = split(client);
+        let leftovers = connect_http(&mut reader, &mut writer, Transport::Derp)
+            .await
+            .unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(reader));
+        let client_sk = SecretKey::gen();
+        exchange_keys(&mut reader, &mut writer, &client_sk, None, None, false)
+            .await
+            .unwrap();
+
+        let handshake = server.await.unwrap().unwrap();
+        assert_eq!(handshake.public_key, client_sk.public());
+    }
+
+    #[tokio::test]
+    async fn websocket_clients_get_frames_in_binary_messages() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server_key = sk.public();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        // A plain WebSocket client, it checks the Sec-WebSocket-Accept of the upgrade
+        let (mut websocket, _) = client_async("ws://localhost/derp", client).await.unwrap();
+        let mut expected = Vec::new();
+        ServerKey::new(server_key)
+            .frame()
+            .encode(&mut expected)
+            .unwrap();
+        let mut received = Vec::new();
+        while received.len() < expected.len() {
+            match websocket.next().await.unwrap().unwrap() {
+                Message::Binary(data) => received.extend(data),
+                message => panic!("Unexpected message {message:?}"),
+            }
+        }
+        assert_eq!(received, expected);
+
+        let client_sk = SecretKey::gen();
+        let mut client_info = Vec::new();
+        write_client_info(
+            &mut client_info,
+            ClientInfo::new(&client_sk, server_key, None, None, false).unwrap(),
+        )
+        .await
+        .unwrap();
+        websocket.send(Message::Binary(client_info)).await.unwrap();
+
+        let handshake = timeout(Duration::from_secs(5), server)
+            .await
+            .expect("handshake should complete")
+            .unwrap()
+            .unwrap();
+        assert_eq!(handshake.public_key, client_sk.public());
+    }
+
+    #[tokio::test]
+    async fn client_info_sent_with_the_upgrade_request_is_read() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server_key = sk.public();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        // A client that knows the server key upfront sends everything in one write
+        let client_sk = SecretKey::gen();
+        let mut pipelined = Vec::new();
+        connect_http(
+            &mut Cursor::new(b"HTTP/1.1 101 Switching Protocols\r\n\r\n"),
+            &mut pipelined,
+            Transport::Derp,
+        )
+        .await
+        .unwrap();
+        let client_info = ClientInfo::new(&client_sk, server_key, None, None, false).unwrap();
+        write_client_info(&mut pipelined, client_info)
+            .await
+            .unwrap();
+        let (_reader, mut writer) = split(client);
+        writer.write_all(&pipelined).await.unwrap();
+
+        let handshake = timeout(Duration::from_secs(5), server)
+            .await
+            .expect("handshake should complete")
+            .unwrap()
+            .unwrap();
+        assert_eq!(handshake.public_key, client_sk.public());
+    }
+
+    #[tokio::test]
+    async fn connection_broken_in_client_info_is_reported() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let (mut reader, mut writer) = split(client);
+        let upgrade_size = {
+            let mut request = Vec::new();
+            connect_http(
+                &mut Cursor::new(b"HTTP/1.1 101 Switching Protocols\r\n\r\n"),
+                &mut request,
+                Transport::Derp,
+            )
+            .await
+            .unwrap();
+            request.len()
+        };
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                // Breaks 10 bytes into the ClientInfo frame
+                FaultyStream::new(server).fail_reads_after(upgrade_size + 10),
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let leftovers = connect_http(&mut reader, &mut writer, Transport::Derp)
+            .await
+            .unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(reader));
+        exchange_keys(
+            &mut reader,
+            &mut writer,
+            &SecretKey::gen(),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn client_info_delayed_past_the_timeout_is_closed() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                // Every read is late, only the ClientInfo phase is short enough to notice
+                FaultyStream::new(server).delay_reads(Duration::from_millis(150)),
+                &sk,
+                None,
+                &timeouts("30s", "100ms"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let (mut reader, mut writer) = split(client);
+        let leftovers = connect_http(&mut reader, &mut writer, Transport::Derp)
+            .await
+            .unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(reader));
+        exchange_keys(
+            &mut reader,
+            &mut writer,
+            &SecretKey::gen(),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let err = timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server should give up promptly")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No ClientInfo within 100ms");
+    }
+
+    #[tokio::test]
+    async fn dribbled_upgrade_request_times_out_with_408() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("300ms", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let (mut reader, mut writer) = split(client);
+        for byte in b"GET /derp HTTP/1.1\r\nConn" {
+            writer.write_all(&[*byte]).await.unwrap();
+            sleep(Duration::from_millis(5)).await;
+        }
+
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), reader.read_to_string(&mut response))
+            .await
+            .expect("connection should be closed at the timeout")
+            .unwrap();
+        assert_eq!(response, "HTTP/1.1 408 Request Timeout\r\n\r\n");
+        assert_eq!(
+            server.await.unwrap().unwrap_err().to_string(),
+            "No complete upgrade request within 300ms, answered with 408 Request Timeout"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_upgrade_request_gets_400() {
+        let (client, server) = duplex(UPGRADE_MSG_SIZE);
+        let sk = SecretKey::gen();
+        let server = tokio::spawn(async move {
+            handle_handshake(
+                server,
+                &sk,
+                None,
+                &timeouts("30s", "30s"),
+                Duration::from_secs(30),
+                &SeenNonces::new(Duration::from_secs(30)),
+                &ServerInfoPayload::default(),
+                None,
+            )
+            .await
+            .map(|(_, handshake)| handshake)
+        });
+
+        let (mut reader, mut writer) = split(client);
+        writer
+            .write_all(b"\x16\x03\x01 not http\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        reader.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 400 Bad Request\r\n\r\n");
+        assert!(server.await.unwrap().is_err());
+    }
+    #[tokio::test]
+    async fn forward_packet_written_in_pieces_arrives_whole() {
+        for version in [FORWARD_TTL_VERSION - 1, FORWARD_TTL_VERSION] {
+            let packet = || {
+                ForwardPacket::new(
+                    PublicKey::new([1; 32]),
+                    PublicKey::new([2; 32]),
+                    1,
+                    (0..=255).collect(),
+                )
+            };
+            let mut expected = Vec::new();
+            packet().encode_for(version, &mut expected).unwrap();
+
+            // Takes a few bytes per write, the head and the payload are split arbitrarily
+            let (mut client, mut server) = duplex(7);
+            let reader = tokio::spawn(async move {
+                let mut received = Vec::new();
+                server.read_to_end(&mut received).await.unwrap();
+                received
+            });
+            write_forward_packet(&mut client, &packet(), version)
+                .await
+                .unwrap();
+            drop(client);
+            assert_eq!(reader.await.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn seen_nonces_are_forgotten_after_the_window() {
+        let seen = SeenNonces::new(Duration::from_secs(60));
+        let client_info = |nonce| CompleteClientInfo {
+            public_key: PublicKey::new([1; 32]),
+            nonce: [nonce; 24],
+            payload: ClientInfoPayload {
+                version: PROTOCOL_VERSION,
+                meshkey: String::new(),
+                timestamp: None,
+                resume_token: None,
+                send_acks: false,
+            },
+        };
+        let start = Instant::now();
+        for nonce in 0..10 {
+            seen.check(&client_info(nonce), start).unwrap();
+        }
+        let recent = start + Duration::from_secs(100);
+        seen.check(&client_info(10), recent).unwrap();
+        assert_eq!(
+            seen.check(&client_info(0), recent),
+            Err(AuthError::Replayed(PublicKey::new([1; 32])))
+        );
+
+        // Twice the skew later only the recent nonce is left, and still refused
+        let later = start + Duration::from_secs(120);
+        assert_eq!(
+            seen.check(&client_info(10), later),
+            Err(AuthError::Replayed(PublicKey::new([1; 32])))
+        );
+        assert_eq!(seen.seen.lock().unwrap().nonces.len(), 1);
+        seen.check(&client_info(0), later).unwrap();
+    }
+}
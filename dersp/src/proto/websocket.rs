@@ -0,0 +1,116 @@
+//! DERP over WebSocket (RFC 6455), for clients behind proxies that only pass WebSocket
+//! upgrades. The DERP frames are carried in binary messages, a frame may span several of them.
+
+use futures_util::{Sink, Stream};
+use std::{
+    io::{self, Cursor, ErrorKind},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+pub use tokio_tungstenite::tungstenite::{
+    handshake::{client::generate_key, derive_accept_key},
+    protocol::Role,
+};
+
+/// Reads and writes the bytes of a WebSocket connection, each write is sent as one binary
+/// message
+pub struct WebSocketIo<S> {
+    inner: WebSocketStream<S>,
+    /// What's left of the last binary message read
+    read: Cursor<Vec<u8>>,
+    /// Length of the write sent but not flushed yet, reported once it's flushed
+    unflushed: Option<usize>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketIo<S> {
+    /// Speaks WebSocket as `role` over `stream`, whose upgrade is done. `leftovers` were read
+    /// past the upgrade and are the start of the first message.
+    pub async fn new(stream: S, leftovers: Vec<u8>, role: Role) -> Self {
+        Self {
+            inner: WebSocketStream::from_partially_read(stream, leftovers, role, None).await,
+            read: Cursor::default(),
+            unflushed: None,
+        }
+    }
+
+    /// Bytes of the last message that weren't read yet
+    pub fn buffered(&self) -> usize {
+        self.read.get_ref().len() - self.read.position() as usize
+    }
+}
+
+fn io_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.buffered() > 0 {
+                let start = this.read.position() as usize;
+                let len = this.buffered().min(buf.remaining());
+                buf.put_slice(&this.read.get_ref()[start..start + len]);
+                this.read.set_position((start + len) as u64);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read = Cursor::new(data),
+                // Pings are answered by the WebSocket stream itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Text message on a DERP connection",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        // The write is only done once it left the WebSocket stream's buffer, or the last
+        // frame of a burst could sit there until the next write
+        if this.unflushed.is_none() {
+            let mut inner = Pin::new(&mut this.inner);
+            ready!(inner.as_mut().poll_ready(cx)).map_err(io_error)?;
+            inner
+                .start_send(Message::Binary(buf.to_vec()))
+                .map_err(io_error)?;
+            this.unflushed = Some(buf.len());
+        }
+        ready!(Pin::new(&mut this.inner).poll_flush(cx)).map_err(io_error)?;
+        Poll::Ready(Ok(this.unflushed.take().unwrap_or_default()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(io_error)
+    }
+}
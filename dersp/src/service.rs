@@ -1,65 +1,733 @@
+#[cfg(feature = "mesh")]
+use crate::mesh_client::{tls_connector, Heartbeat, MeshClient};
 use crate::{
-    client::{Client, WriteLoopCommands},
+    admin::AdminServer,
+    client::{Client, ClientSink, DerpClient, WriteLoopCommands},
+    config::FanoutOverflow,
     crypto::{PublicKey, SecretKey},
-    mesh_client::MeshClient,
-    proto::handle_handshake,
-    Config,
+    inout::BufferPool,
+    proto::{
+        data::{
+            AuthError, Frame, PeerGoneReason, ResumeToken, SendStatus, ServerInfoPayload, MESH_TTL,
+            PROTOCOL_VERSION,
+        },
+        handle_handshake, refuse_upgrade, ClientHandshake, DecryptionPool, ProtoError, SeenNonces,
+    },
+    Config, DestinationLimit, FrameRateLimit, FrameTrace, MaintenanceWindow, Timeouts,
 };
-use anyhow::{bail, ensure};
+use anyhow::{anyhow, bail, ensure};
+use bytes::Bytes;
+use codec::Encode;
 use log::{debug, info, trace, warn};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+    fmt,
+    future::pending,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    spawn,
+    io::{duplex, AsyncRead, AsyncWrite},
+    net::TcpListener,
+    select, spawn,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        RwLock,
+        broadcast,
+        mpsc::{channel, error::TrySendError, Receiver, Sender},
+        oneshot, RwLock, Semaphore,
     },
+    task::{JoinHandle, JoinSet},
+    time::{interval, sleep, timeout},
 };
 
+/// Buffer size of each direction of an in-process connection, fits the largest frame
+const IN_PROCESS_BUFFER_SIZE: usize = u16::MAX as usize;
+/// Time the whole self-test may take
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const SELF_TEST_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many packets are queued for a client that may resume, older ones are dropped first
+const RESUME_QUEUE_SIZE: usize = 64;
+/// Chunk size of the initial roster sent to a new watcher, see `Config::roster_chunk_size`
+#[cfg(not(feature = "mesh"))]
+const ROSTER_CHUNK_SIZE: usize = 256;
+/// Time a client has to make room for the stop on shutdown, stuck ones are left behind
+const SHUTDOWN_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+/// Repeated handshake failures from one IP within this window are logged as a single summary
+const HANDSHAKE_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Wait before reconnecting a mesh link that broke or failed to connect
+#[cfg(feature = "mesh")]
+const MESH_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often the memory held by the connections is checked against `--memory-budget`
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the bytes forwarded are sampled for [`DerpService::forwarding_rate`]
+const FORWARDING_RATE_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+/// Time the forwarding rate is averaged over
+const FORWARDING_RATE_WINDOW: Duration = Duration::from_secs(1);
+/// How often a draining service checks whether its clients are gone
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Events kept for subscribers, the ones lagging further behind miss the oldest
+const EVENT_CHANNEL_SIZE: usize = 1024;
+
+// Only implemented for `Arc<RwLock<DerpService>>`, callers don't need `Send` bounds on it
+#[allow(async_fn_in_trait)]
 pub trait Service {
+    /// Accepts connections until one of the accept loops fails
     async fn run(&self, listener: TcpListener) -> anyhow::Result<()>;
+
+    /// Like [`run`](Self::run) on a listener bound outside of tokio, e.g. passed by systemd
+    async fn run_on(&self, listener: std::net::TcpListener) -> anyhow::Result<()>;
+
+    /// Accepts connections in a spawned task, the handle stops it
+    async fn run_with_listener(&self, listener: TcpListener) -> ServiceHandle;
+}
+
+/// Lifecycle of a service running in the background, see [`Service::run_with_listener`]
+pub struct ServiceHandle {
+    service: Arc<RwLock<DerpService>>,
+    acceptors: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ServiceHandle {
+    /// Stops accepting connections and disconnects every client
+    pub async fn shutdown(&self) -> ShutdownSummary {
+        self.acceptors.abort();
+        self.service.write().await.shutdown().await
+    }
+
+    /// Stops accepting connections and gives the clients up to `timeout` to disconnect, e.g.
+    /// to reconnect to another process that took over the listener. Disconnects the rest.
+    pub async fn drain(&self, timeout: Duration) -> ShutdownSummary {
+        self.acceptors.abort();
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && !self.service.read().await.connected_peers().is_empty() {
+            sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        self.service.write().await.shutdown().await
+    }
+
+    /// Waits until the service stopped accepting connections, either after [`shutdown`] or
+    /// because an accept loop failed
+    ///
+    /// [`shutdown`]: Self::shutdown
+    pub async fn joined(&mut self) -> anyhow::Result<()> {
+        match (&mut self.acceptors).await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    sink: ClientSink,
+    /// Whether the peer is connected to us, as opposed to being reachable via a mesh peer
+    local: bool,
+    /// Relay a remote peer is connected to, `None` for local peers
+    via: Option<PublicKey>,
+    /// Token issued to a local peer during the handshake
+    resume_token: Option<ResumeToken>,
+    /// Whether a local peer told us we're its home node
+    preferred: bool,
+    /// Payload bytes forwarded to the peer
+    bytes_out: AtomicU64,
+    /// Payload bytes a local peer sent to others
+    bytes_in: AtomicU64,
+    /// When the peer connected, or was announced by its relay
+    since: SystemTime,
+    /// Protocol version from the ClientInfo of a local peer
+    version: Option<u32>,
+    /// Whether a local peer asked for a SendAck after each SendPacket
+    send_acks: bool,
+    /// Other relays that announced a remote peer, e.g. while it moves between them
+    alternatives: Vec<(PublicKey, ClientSink)>,
+}
+
+impl Peer {
+    /// A peer connected to us, `resume_token` was issued to it during the handshake
+    fn local(sink: ClientSink, resume_token: ResumeToken) -> Self {
+        Peer {
+            sink,
+            local: true,
+            via: None,
+            resume_token: Some(resume_token),
+            preferred: false,
+            bytes_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            since: SystemTime::now(),
+            version: None,
+            send_acks: false,
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// A peer of the relay `via`, reachable over the mesh link `sink`
+    fn remote(sink: ClientSink, via: PublicKey) -> Self {
+        Peer {
+            sink,
+            local: false,
+            via: Some(via),
+            resume_token: None,
+            preferred: false,
+            bytes_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            since: SystemTime::now(),
+            version: None,
+            send_acks: false,
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// Forgets the route to a remote peer through `sink`. Returns whether it was one of
+    /// several, the peer stays reachable through another relay then.
+    fn drop_route(&mut self, sink: &ClientSink) -> bool {
+        let routes = self.alternatives.len();
+        self.alternatives.retain(|(_, alt)| !alt.same_channel(sink));
+        if self.alternatives.len() < routes {
+            return true;
+        }
+        if !self.sink.same_channel(sink) {
+            return false;
+        }
+        match self.alternatives.pop() {
+            Some((via, alt)) => {
+                self.via = Some(via);
+                self.sink = alt;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Where a peer is connected, see [`DerpService::presence`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Presence {
+    /// Relay the peer is connected to, `None` when it's connected to us
+    pub via: Option<PublicKey>,
+    /// When the peer connected, or was announced by its relay
+    pub since: SystemTime,
+    /// Whether the peer told us we're its home node, always false for remote peers
+    pub preferred: bool,
+}
+
+/// Payload bytes of a local peer in each direction, see [`DerpService::asymmetric_peers`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTraffic {
+    pub peer: PublicKey,
+    /// Bytes the peer sent to others
+    pub sent: u64,
+    /// Bytes forwarded to the peer
+    pub received: u64,
+}
+
+impl PeerTraffic {
+    /// How many times the larger direction is the smaller one, a silent direction counts as
+    /// one byte
+    pub fn ratio(&self) -> u64 {
+        self.sent.max(self.received) / self.sent.min(self.received).max(1)
+    }
+}
+
+/// Samples of the bytes forwarded so far, covering the last [`FORWARDING_RATE_WINDOW`]
+#[derive(Debug, Default)]
+struct RateWindow {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    fn record(&mut self, now: Instant, total: u64) {
+        self.samples.push_back((now, total));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > FORWARDING_RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second between the oldest and the newest sample
+    fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((first_at, first)), Some((last_at, last))) if last_at > first_at => {
+                (last - first) as f64 / last_at.duration_since(*first_at).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// A disconnected peer that can still resume its session
+#[derive(Debug)]
+struct Resumable {
+    token: ResumeToken,
+    expires: Instant,
+    /// Packets sent to the peer while it was away, oldest first
+    queue: VecDeque<WriteLoopCommands>,
+    /// Whether the peer subscribed for peer changes
+    watcher: bool,
+    preferred: bool,
+}
+
+/// A mesh connection receiving copies of the packets relayed from or to `peer`
+#[derive(Debug)]
+struct Mirror {
+    peer: PublicKey,
+    sink: ClientSink,
+}
+
+/// Why a packet wasn't handed to the connection of its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The target isn't connected and has no session to resume
+    UnknownDestination,
+    /// The queue of a resumable target was full, its oldest packet was dropped
+    QueueOverflow,
+    /// The packet would have been forwarded to another relay after its TTL ran out
+    TtlExpired,
+    /// The payload is over `--max-packet-size`
+    Oversize,
+    /// The connection of the target had no room for it, it isn't keeping up
+    SlowDestination,
+    /// The server runs with `--no-forwarding`
+    ForwardingDisabled,
+}
+
+/// What happened in the service, see [`DerpService::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A client finished its handshake
+    Connect(PublicKey),
+    /// A client connected to this server is gone
+    Disconnect(PublicKey, PeerGoneReason),
+    /// A packet was handed to the connection of its target
+    Forward {
+        source: PublicKey,
+        target: PublicKey,
+    },
+    /// A packet was dropped
+    Drop {
+        source: PublicKey,
+        target: PublicKey,
+        reason: DropReason,
+    },
+}
+
+/// Dropped packets by [`DropReason`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketDrops {
+    pub unknown_destination: u64,
+    pub queue_overflow: u64,
+    pub ttl_expired: u64,
+    pub oversize: u64,
+    pub slow_destination: u64,
+    pub forwarding_disabled: u64,
+}
+
+impl PacketDrops {
+    fn record(&mut self, reason: DropReason) {
+        match reason {
+            DropReason::UnknownDestination => self.unknown_destination += 1,
+            DropReason::QueueOverflow => self.queue_overflow += 1,
+            DropReason::TtlExpired => self.ttl_expired += 1,
+            DropReason::Oversize => self.oversize += 1,
+            DropReason::SlowDestination => self.slow_destination += 1,
+            DropReason::ForwardingDisabled => self.forwarding_disabled += 1,
+        }
+    }
+}
+
+impl fmt::Display for PacketDrops {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} to unknown destinations, {} on queue overflow, {} with expired TTL, {} oversize, \
+             {} to slow destinations, {} with forwarding disabled",
+            self.unknown_destination,
+            self.queue_overflow,
+            self.ttl_expired,
+            self.oversize,
+            self.slow_destination,
+            self.forwarding_disabled
+        )
+    }
+}
+
+/// Build of the running server, e.g. `dersp 0.1.0 (1a2b3c4)`
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: option_env!("DERSP_GIT_HASH"),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Crate version, the same `--version` prints
+    pub version: &'static str,
+    /// Commit built from, when `DERSP_GIT_HASH` was set at build time
+    pub git_hash: Option<&'static str>,
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dersp {}", self.version)?;
+        if let Some(git_hash) = self.git_hash {
+            write!(f, " ({git_hash})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Aggregate counters logged when the service shuts down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Clients connected when the service shut down
+    pub connected_clients: usize,
+    /// Clients that completed the handshake since the service started
+    pub total_clients: u64,
+    /// Packets handed to the connection of their target
+    pub frames_forwarded: u64,
+    /// Packets that weren't forwarded
+    pub packets_dropped: PacketDrops,
+    /// Most clients connected at the same time
+    pub peak_concurrency: usize,
+    pub uptime: Duration,
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} clients connected ({} total, peak {}), {} frames forwarded, dropped {}, uptime {:?}",
+            self.connected_clients,
+            self.total_clients,
+            self.peak_concurrency,
+            self.frames_forwarded,
+            self.packets_dropped,
+            self.uptime,
+        )
+    }
+}
+
+/// Failed handshakes by cause
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeFailureCounts {
+    /// ClientInfos that don't decrypt or are replayed and wrong meshkeys, see [`AuthError`]
+    pub authentication: u64,
+    /// ClientInfos that don't decode
+    pub malformed: u64,
+    /// Everything else, e.g. bad upgrade requests and timeouts
+    pub other: u64,
+    /// Connections refused because their IP had too many authentication failures
+    pub refused: u64,
+}
+
+impl HandshakeFailureCounts {
+    /// Records `error`, returns whether it's an authentication failure
+    fn record(&mut self, error: &anyhow::Error) -> bool {
+        if error.downcast_ref::<AuthError>().is_some() {
+            self.authentication += 1;
+            return true;
+        }
+        if error.downcast_ref::<ProtoError>().is_some()
+            || error.downcast_ref::<serde_json::Error>().is_some()
+        {
+            self.malformed += 1;
+        } else {
+            self.other += 1;
+        }
+        false
+    }
+}
+
+/// A connection that hasn't completed its handshake yet, see [`DerpService::handshakes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InFlightHandshake {
+    pub peer_addr: SocketAddr,
+    pub started: SystemTime,
+}
+
+/// Connections in their handshake by address, dropping the sender cancels the handshake
+type InFlightHandshakes = Arc<Mutex<HashMap<SocketAddr, (SystemTime, oneshot::Sender<()>)>>>;
+
+/// Keeps a handshake listed in [`DerpService::handshakes`] until dropped
+struct HandshakeGuard {
+    handshakes: InFlightHandshakes,
+    peer_addr: SocketAddr,
+}
+
+impl HandshakeGuard {
+    /// Lists the handshake with `peer_addr`, the receiver resolves when it's cancelled
+    fn track(
+        handshakes: &InFlightHandshakes,
+        peer_addr: SocketAddr,
+    ) -> (Self, oneshot::Receiver<()>) {
+        let (cancel, cancelled) = oneshot::channel();
+        handshakes
+            .lock()
+            .unwrap()
+            .insert(peer_addr, (SystemTime::now(), cancel));
+        let guard = HandshakeGuard {
+            handshakes: handshakes.clone(),
+            peer_addr,
+        };
+        (guard, cancelled)
+    }
+}
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        self.handshakes.lock().unwrap().remove(&self.peer_addr);
+    }
+}
+
+/// Logs the first handshake failure from an IP, later ones within the window are only counted
+/// and summarized when the window ends, so a scanner can't flood the logs. With
+/// `max_auth_failures`, an IP with that many authentication failures within `auth_window` has
+/// its connections refused until that window ends.
+#[derive(Debug)]
+struct HandshakeFailures {
+    window: Duration,
+    max_auth_failures: Option<u64>,
+    auth_window: Duration,
+    by_ip: HashMap<IpAddr, FailureWindow>,
+    auth_failures: HashMap<IpAddr, FailureWindow>,
+    counts: HandshakeFailureCounts,
+}
+
+#[derive(Debug)]
+struct FailureWindow {
+    started: Instant,
+    failures: u64,
+}
+
+impl HandshakeFailures {
+    fn new(window: Duration, max_auth_failures: Option<u64>, auth_window: Duration) -> Self {
+        Self {
+            window,
+            max_auth_failures,
+            auth_window,
+            by_ip: HashMap::new(),
+            auth_failures: HashMap::new(),
+            counts: HandshakeFailureCounts::default(),
+        }
+    }
+
+    fn record(&mut self, addr: SocketAddr, error: &anyhow::Error) {
+        let now = Instant::now();
+        self.flush(now);
+        let auth_failure = self.counts.record(error);
+        match self.by_ip.entry(addr.ip()) {
+            Entry::Occupied(window) => window.into_mut().failures += 1,
+            Entry::Vacant(window) => {
+                warn!("Client {addr:?} failed: {error:?}");
+                window.insert(FailureWindow {
+                    started: now,
+                    failures: 1,
+                });
+            }
+        }
+        let Some(max_auth_failures) = self.max_auth_failures.filter(|_| auth_failure) else {
+            return;
+        };
+        let window = self
+            .auth_failures
+            .entry(addr.ip())
+            .or_insert(FailureWindow {
+                started: now,
+                failures: 0,
+            });
+        window.failures += 1;
+        if window.failures == max_auth_failures {
+            warn!(
+                "Refusing connections from {} for up to {:?}, {} authentication failures",
+                addr.ip(),
+                self.auth_window,
+                window.failures
+            );
+        }
+    }
+
+    /// Whether connections from `ip` are refused, counts the refusal if they are
+    fn refuse(&mut self, ip: IpAddr) -> bool {
+        self.flush(Instant::now());
+        let refused = self.max_auth_failures.is_some_and(|max_auth_failures| {
+            self.auth_failures
+                .get(&ip)
+                .is_some_and(|window| window.failures >= max_auth_failures)
+        });
+        if refused {
+            self.counts.refused += 1;
+        }
+        refused
+    }
+
+    /// Forgets the windows that ended, summarizing those with more than the logged failure
+    fn flush(&mut self, now: Instant) {
+        let window = self.window;
+        self.by_ip.retain(|ip, failures| {
+            if now.duration_since(failures.started) < window {
+                return true;
+            }
+            if failures.failures > 1 {
+                warn!(
+                    "{} handshake failures from {ip} in the last {window:?}",
+                    failures.failures
+                );
+            }
+            false
+        });
+        let auth_window = self.auth_window;
+        self.auth_failures
+            .retain(|_, failures| now.duration_since(failures.started) < auth_window);
+    }
 }
 
 #[derive(Debug)]
 pub struct DerpService {
-    peers_sinks: HashMap<PublicKey, Sender<WriteLoopCommands>>,
-    mesh: HashMap<PublicKey, Sender<WriteLoopCommands>>,
+    peers: HashMap<PublicKey, Peer>,
+    mesh: HashMap<PublicKey, ClientSink>,
+    resumable: HashMap<PublicKey, Resumable>,
     command_sender: Sender<ServiceCommand>,
     meshkey: Option<String>,
+    /// Relays allowed to mesh by their public key alone
+    trusted_relays: HashSet<PublicKey>,
+    allow_mirroring: bool,
+    mirrors: Vec<Mirror>,
+    max_watchers: Option<usize>,
+    roster_chunk_size: usize,
+    /// Limits the initial rosters sent at once, see `Config::max_roster_dumps`
+    roster_dumps: Option<Arc<Semaphore>>,
+    timeouts: Timeouts,
+    max_clock_skew: Duration,
+    /// ClientInfos accepted within the clock skew, refused if they're sent again
+    seen_nonces: SeenNonces,
+    resume_token_ttl: Duration,
+    region: Option<String>,
+    max_packet_size: usize,
+    no_forwarding: bool,
+    max_fanout: u8,
+    fanout_overflow: FanoutOverflow,
+    frame_trace: FrameTrace,
+    buffer_pool: Arc<BufferPool>,
+    strict_protocol: bool,
+    destination_limit: DestinationLimit,
+    frame_rate_limit: FrameRateLimit,
+    max_connection_bytes: Option<u64>,
+    /// Daily windows in which new connections are refused
+    maintenance_windows: Vec<MaintenanceWindow>,
+    acceptors: NonZeroUsize,
+    decryption_pool: Option<DecryptionPool>,
+    handshake_failures: Arc<Mutex<HandshakeFailures>>,
+    handshakes: InFlightHandshakes,
+    /// Presented to clients in the ServerKey frame of their handshake
+    server_key: SecretKey,
+    /// Key replaced by [`DerpService::begin_key_rotation`] and when it stops being accepted
+    previous_server_key: Option<(SecretKey, Instant)>,
+    started: Instant,
+    total_clients: u64,
+    frames_forwarded: AtomicU64,
+    /// Payload bytes of the frames forwarded
+    bytes_forwarded: AtomicU64,
+    forwarding_rate: Mutex<RateWindow>,
+    packets_dropped: PacketDrops,
+    peak_concurrency: usize,
+    /// Round trip times of the mesh links by relay, measured by their pings
+    mesh_rtts: HashMap<PublicKey, Duration>,
+    events: broadcast::Sender<Event>,
+    admin: Option<AdminServer>,
 }
 
 impl DerpService {
-    pub async fn add_new_client(
+    pub async fn add_new_client<S: AsyncRead + AsyncWrite + Send + 'static>(
         &mut self,
-        socket: TcpStream,
-        client_pk: PublicKey,
-        meshkey: Option<String>,
+        stream: S,
+        handshake: ClientHandshake,
+        issued_token: ResumeToken,
     ) -> anyhow::Result<()> {
+        let ClientHandshake {
+            public_key: client_pk,
+            meshkey,
+            resume_token,
+            version,
+            send_acks,
+        } = handshake;
         let can_mesh = match (&self.meshkey, &meshkey) {
+            _ if self.trusted_relays.contains(&client_pk) => true,
             (None, None) => false,
-            (None, Some(_)) => {
+            (None, Some(_)) if self.trusted_relays.is_empty() => {
                 bail!(
-                    "Client {client_pk:?} ({:?}) tried to mesh with a server that can't mesh",
-                    socket.peer_addr()
+                    "Client {} tried to mesh with a server that can't mesh",
+                    client_pk.log_display()
                 )
             }
+            (None, Some(_)) => return Err(AuthError::WrongMeshkey(client_pk).into()),
             (Some(_), None) => false,
             (Some(server_meshkey), Some(client_meshkey)) => {
-                ensure!(
-                    server_meshkey == client_meshkey,
-                    "Client {client_pk:?} ({:?}) tried to mesh with a wrong key",
-                    socket.peer_addr()
-                );
+                if server_meshkey != client_meshkey {
+                    return Err(AuthError::WrongMeshkey(client_pk).into());
+                }
                 true
             }
         };
-        let client = Client::new(socket, client_pk, can_mesh)?;
+        let client = Client::new(
+            stream,
+            client_pk,
+            can_mesh,
+            version,
+            self.timeouts,
+            self.frame_trace,
+            self.buffer_pool.clone(),
+            self.strict_protocol,
+            self.destination_limit,
+            self.frame_rate_limit,
+            send_acks,
+            self.max_connection_bytes,
+            self.max_fanout,
+        );
         let sink = client.run(self.command_sender.clone()).await?;
 
-        info!("will insert {client_pk:?} to peers (can mesh: {can_mesh})");
-        if let Some(old) = self.peers_sinks.insert(client_pk, sink) {
-            warn!("Newer client with {client_pk:?}: {old:?}");
+        let resumed = resume_token.and_then(|token| self.take_resumable(client_pk, token));
+
+        info!(
+            "will insert {} to peers (can mesh: {can_mesh}, version: {version})",
+            client_pk.log_display()
+        );
+        let peer = Peer {
+            preferred: resumed.as_ref().is_some_and(|resumed| resumed.preferred),
+            version: Some(version),
+            send_acks,
+            ..Peer::local(sink.clone(), issued_token)
+        };
+        if let Some(old) = self.peers.insert(client_pk, peer) {
+            warn!("Newer client with {}: {old:?}", client_pk.log_display());
+        }
+        self.total_clients += 1;
+        self.emit(Event::Connect(client_pk));
+        let concurrency = self.peers.values().filter(|peer| peer.local).count();
+        self.peak_concurrency = self.peak_concurrency.max(concurrency);
+
+        if let Some(resumed) = resumed {
+            info!(
+                "{} resumed its session, replaying {} packets",
+                client_pk.log_display(),
+                resumed.queue.len()
+            );
+            if resumed.watcher {
+                self.mesh.insert(client_pk, sink.clone());
+            }
+            spawn(async move {
+                for command in resumed.queue {
+                    if sink.send(command).await.is_err() {
+                        return;
+                    }
+                }
+            });
         }
 
         self.notify_all_mesh_peers(client_pk).await;
@@ -68,179 +736,4010 @@ impl DerpService {
     }
 
     pub async fn new(config: Config) -> anyhow::Result<Arc<RwLock<Self>>> {
+        #[cfg(feature = "mesh")]
         let meshkey = config.meshkey;
+        #[cfg(not(feature = "mesh"))]
+        let meshkey = None;
+        #[cfg(feature = "mesh")]
+        let trusted_relays: HashSet<_> = config.mesh_trusted_keys.into_iter().collect();
+        #[cfg(not(feature = "mesh"))]
+        let trusted_relays = HashSet::new();
+        #[cfg(feature = "mesh")]
+        let allow_mirroring = config.allow_mirroring;
+        #[cfg(not(feature = "mesh"))]
+        let allow_mirroring = false;
+        #[cfg(feature = "mesh")]
+        let max_watchers = config.max_watchers;
+        #[cfg(not(feature = "mesh"))]
+        let max_watchers = None;
+        #[cfg(feature = "mesh")]
+        let roster_chunk_size = config.roster_chunk_size.get();
+        #[cfg(not(feature = "mesh"))]
+        let roster_chunk_size = ROSTER_CHUNK_SIZE;
+        #[cfg(feature = "mesh")]
+        let roster_dumps = config
+            .max_roster_dumps
+            .map(|max| Arc::new(Semaphore::new(max.get())));
+        #[cfg(not(feature = "mesh"))]
+        let roster_dumps = None;
+        let timeouts = config.timeouts;
+        let admin_listener = match &config.admin_listen {
+            Some(addr) => Some(TcpListener::bind(addr.as_str()).await?),
+            None => None,
+        };
 
         let (s, r) = channel(1);
+        #[cfg(feature = "mesh")]
+        let service_sk = match &config.relay_key_file {
+            Some(path) => read_secret_key(path)?,
+            None => SecretKey::gen(),
+        };
+        #[cfg(not(feature = "mesh"))]
         let service_sk = SecretKey::gen();
         info!("Service public key: {}", service_sk.public());
 
         let ret = Arc::new(RwLock::new(Self {
-            peers_sinks: Default::default(),
+            peers: Default::default(),
             mesh: Default::default(),
+            resumable: Default::default(),
             command_sender: s.clone(),
             meshkey: meshkey.clone(),
+            trusted_relays,
+            allow_mirroring,
+            mirrors: Vec::new(),
+            max_watchers,
+            roster_chunk_size,
+            roster_dumps,
+            timeouts,
+            max_clock_skew: config.max_clock_skew,
+            seen_nonces: SeenNonces::new(config.max_clock_skew),
+            resume_token_ttl: config.resume_token_ttl,
+            region: config.region,
+            max_packet_size: config.max_packet_size,
+            no_forwarding: config.no_forwarding,
+            max_fanout: config.max_fanout,
+            fanout_overflow: config.fanout_overflow,
+            frame_trace: config.frame_trace,
+            buffer_pool: BufferPool::new(config.read_buffer_pool.get()),
+            strict_protocol: config.strict_protocol,
+            destination_limit: config.destination_limit,
+            frame_rate_limit: config.frame_rate_limit,
+            max_connection_bytes: config.max_connection_bytes,
+            maintenance_windows: config.maintenance_window,
+            acceptors: config.acceptors,
+            decryption_pool: config.crypto_threads.map(DecryptionPool::new),
+            handshake_failures: Arc::new(Mutex::new(HandshakeFailures::new(
+                HANDSHAKE_FAILURE_WINDOW,
+                config.max_auth_failures,
+                config.auth_failure_window,
+            ))),
+            handshakes: InFlightHandshakes::default(),
+            server_key: SecretKey::gen(),
+            previous_server_key: None,
+            started: Instant::now(),
+            total_clients: 0,
+            frames_forwarded: AtomicU64::new(0),
+            bytes_forwarded: AtomicU64::new(0),
+            forwarding_rate: Mutex::new(RateWindow::default()),
+            packets_dropped: PacketDrops::default(),
+            peak_concurrency: 0,
+            mesh_rtts: HashMap::new(),
+            events: broadcast::channel(EVENT_CHANNEL_SIZE).0,
+            admin: None,
         }));
+        if let Some(listener) = admin_listener {
+            ret.write().await.admin = Some(AdminServer::start(listener, Arc::downgrade(&ret))?);
+        }
         spawn(command_loop(r, ret.clone()));
-        if let Some(meshkey) = meshkey {
-            for addr in config.mesh_peers {
-                let mesh_client =
-                    MeshClient::new(&addr, service_sk, meshkey.clone(), s.clone()).await?;
-                match mesh_client.start().await {
-                    Ok((sender, mesh_peer_pk)) => {
-                        ret.write().await.mesh.insert(mesh_peer_pk, sender);
-                    }
-                    Err(e) => warn!("Failed to start peer client for {addr}: {e}"),
-                }
-            }
-        } else {
-            warn!(
-                "Can't peer without a meshkey, ignoring mesh peers: {:?}",
-                config.mesh_peers
-            );
+        spawn(sample_forwarding_rate(Arc::downgrade(&ret)));
+        if let Some(budget) = config.memory_budget {
+            spawn(enforce_memory_budget(Arc::downgrade(&ret), budget));
         }
+        #[cfg(feature = "mesh")]
+        Self::connect_mesh_peers(
+            &ret,
+            service_sk,
+            meshkey,
+            config.relay_key_file.is_some(),
+            unique_mesh_peers(config.mesh_peers, config.max_mesh_peers),
+            config.mesh_ca.as_deref(),
+            timeouts,
+            s,
+        )
+        .await?;
         Ok(ret)
     }
 
-    async fn notify_all_mesh_peers(&self, client_pk: PublicKey) {
-        trace!("Will notify all mesh about new client: {client_pk:?}");
-        let mesh = self.mesh.clone();
-        spawn(async move {
-            for (peer, sink) in mesh {
-                if let Err(e) = sink
-                    .send(WriteLoopCommands::PeerPresent(client_pk.clone()))
-                    .await
-                {
-                    warn!("Failed to notify mesh peer {peer} about client {client_pk:?}: {e}");
-                }
+    #[cfg(feature = "mesh")]
+    async fn connect_mesh_peers(
+        service: &Arc<RwLock<Self>>,
+        service_sk: SecretKey,
+        meshkey: Option<String>,
+        stable_key: bool,
+        mesh_peers: Vec<String>,
+        mesh_ca: Option<&std::path::Path>,
+        timeouts: Timeouts,
+        command_sender: Sender<ServiceCommand>,
+    ) -> anyhow::Result<()> {
+        let heartbeat = Heartbeat {
+            interval: timeouts.mesh_ping_interval,
+            timeout: timeouts.mesh_ping_timeout,
+        };
+        let tls = tls_connector(mesh_ca)?;
+        for peer in &mesh_peers {
+            let (addr, peer_meshkey) = parse_mesh_peer(peer);
+            let meshkey = peer_meshkey.map(str::to_owned).or_else(|| meshkey.clone());
+            // Without either, the peer can't tell us from a client
+            if meshkey.is_none() && !stable_key {
+                warn!("Can't peer with {addr} without a meshkey or a relay key, ignoring it");
+                continue;
             }
-        });
+            let mesh_client = MeshClient::new(
+                addr,
+                service_sk.clone(),
+                meshkey,
+                heartbeat,
+                command_sender.clone(),
+                &tls,
+            )?;
+            spawn(Self::maintain_mesh_link(
+                service.clone(),
+                mesh_client,
+                timeouts.mesh_connect_timeout,
+                timeouts.mesh_handshake_timeout,
+            ));
+        }
+        Ok(())
     }
-}
 
-// TODO: should this be RWLock instead of Mutex?
-impl Service for Arc<RwLock<DerpService>> {
-    async fn run(&self, listener: TcpListener) -> anyhow::Result<()> {
+    /// Keeps the link of `mesh_client` up until the service stops, reconnecting
+    /// [`MESH_RECONNECT_DELAY`] after it broke or failed to connect
+    #[cfg(feature = "mesh")]
+    async fn maintain_mesh_link(
+        service: Arc<RwLock<Self>>,
+        mesh_client: MeshClient,
+        connect_timeout: Duration,
+        handshake_timeout: Duration,
+    ) {
+        let host = mesh_client.host();
         loop {
-            // TODO: handle panic!
-            if let Ok((socket, peer_addr)) = listener.accept().await {
-                let service = self.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, peer_addr, service).await {
-                        warn!("Client {peer_addr:?} failed: {e:?}");
+            match mesh_client.start(connect_timeout, handshake_timeout).await {
+                Ok(link) => {
+                    Self::add_mesh_link(&service, link.public_key, link.sink.clone()).await;
+                    if let Err(e) = link.closed().await {
+                        debug!("Mesh link to {host} failed: {e}");
                     }
-                });
+                    info!("Mesh link to {host} broke, reconnecting in {MESH_RECONNECT_DELAY:?}");
+                }
+                Err(e) => warn!("Failed to start peer client for {host}: {e}"),
+            }
+            sleep(MESH_RECONNECT_DELAY).await;
+            if service.read().await.command_sender.is_closed() {
+                debug!("Service stopped, not reconnecting to {host}");
+                return;
             }
         }
     }
-}
 
-async fn handle_client(
-    mut socket: TcpStream,
-    peer_addr: SocketAddr,
-    service: Arc<RwLock<DerpService>>,
-) -> anyhow::Result<()> {
-    debug!("Got connection from: {peer_addr:?}");
-    let sk = SecretKey::gen();
-    let (client_pk, meshkey) = handle_handshake(&mut socket, &sk).await?;
+    /// Takes a (re)connected link to the relay `pk` into use and announces our peers over it
+    #[cfg(feature = "mesh")]
+    async fn add_mesh_link(service: &Arc<RwLock<Self>>, pk: PublicKey, sink: ClientSink) {
+        let mut this = service.write().await;
+        this.mesh.insert(pk, sink.clone());
+        let (chunk_size, dumps) = (this.roster_chunk_size, this.roster_dumps.clone());
+        notify_about_all_clients(Arc::downgrade(service), pk, sink, chunk_size, dumps);
+    }
 
-    service
-        .write()
-        .await
-        .add_new_client(socket, client_pk, meshkey)
-        .await?;
+    /// Called when the relay `relay` announced its whole roster over `sink`. Peers learned over
+    /// its previous links that it didn't announce again are gone, those it did moved to `sink`.
+    fn prune_mesh_roster(&mut self, relay: PublicKey, sink: &ClientSink) {
+        let mut stale = Vec::new();
+        for (pk, peer) in &self.peers {
+            if peer.via == Some(relay) && !peer.sink.same_channel(sink) {
+                stale.push((*pk, peer.sink.clone()));
+            }
+            for (via, alternative) in &peer.alternatives {
+                if *via == relay && !alternative.same_channel(sink) {
+                    stale.push((*pk, alternative.clone()));
+                }
+            }
+        }
+        if stale.is_empty() {
+            return;
+        }
+        debug!(
+            "{} didn't announce {} of its peers again, they're gone",
+            relay.log_display(),
+            stale.len()
+        );
+        for (pk, sink) in stale {
+            self.remove_peer(pk, PeerGoneReason::MeshConnBroke, &sink);
+        }
+    }
 
-    Ok(())
-}
+    /// Peers announced to the relay `relay`: all we know of but the relays and the peers we
+    /// learned from `relay` itself. The first `len` keys of it past `after`, in key order so
+    /// that a roster can be sent in chunks without holding the service or collecting every key
+    fn roster(&self, relay: PublicKey, after: Option<PublicKey>, len: usize) -> Vec<PublicKey> {
+        let mut chunk = BinaryHeap::with_capacity(len + 1);
+        for (pk, peer) in &self.peers {
+            if peer.via == Some(relay)
+                || self.mesh.contains_key(pk)
+                || after.is_some_and(|after| *pk <= after)
+            {
+                continue;
+            }
+            chunk.push(*pk);
+            if chunk.len() > len {
+                chunk.pop();
+            }
+        }
+        chunk.into_sorted_vec()
+    }
 
-async fn command_loop(
-    mut r: Receiver<ServiceCommand>,
-    service: Arc<RwLock<DerpService>>,
-) -> anyhow::Result<()> {
-    loop {
-        match r.recv().await {
-            Some(ServiceCommand::SendPacket {
-                source,
-                target,
-                payload,
-            }) => {
-                // TODO: to make this faster client/mesh_client should have direct access to
-                // the `peers_sinks`, instead of sending requests to service. This way clients
-                // communication will not put preasure on the services queue. With curren aproach,
-                // sink to serviced quickly will block whole service. After this change, it will
-                // only impact senders wanting to communicate with it.
-                debug!("send packet to {target:?}");
-                let sink = match service.read().await.peers_sinks.get(&target) {
-                    Some(sink) => sink.clone(),
-                    None => {
-                        continue;
-                    }
-                };
-                sink.send(WriteLoopCommands::SendPacket {
-                    source,
-                    target,
-                    payload,
-                })
-                .await?;
+    /// Sink of `source` if it's a local peer that asked for acks
+    fn ack_sink(&self, source: &PublicKey) -> Option<ClientSink> {
+        self.peers
+            .get(source)
+            .filter(|peer| peer.send_acks)
+            .map(|peer| peer.sink.clone())
+    }
+
+    /// Link packets to `peer` go out on. A remote peer announced by several relays is reached
+    /// through the one with the lowest measured round trip time.
+    fn route<'a>(&self, peer: &'a Peer) -> &'a ClientSink {
+        let Some(via) = peer.via else {
+            return &peer.sink;
+        };
+        let rtt = |relay: &PublicKey| self.mesh_rtts.get(relay).copied().unwrap_or(Duration::MAX);
+        let mut route = (rtt(&via), &peer.sink);
+        for (relay, sink) in &peer.alternatives {
+            if rtt(relay) < route.0 {
+                route = (rtt(relay), sink);
             }
-            Some(ServiceCommand::SubscribeForPeerChanges(mesh_peer_pk, mesh_sink)) => {
-                let current_peers: Vec<PublicKey> = {
-                    let mut service = service.write().await;
-                    if let Some(_old) = service.mesh.insert(mesh_peer_pk, mesh_sink.clone()) {
-                        warn!("Mesh peer for {mesh_peer_pk:?} overwriten");
-                    }
-                    let service = service.downgrade();
-                    service
-                        .peers_sinks
-                        .keys()
-                        // TODO: should we not send it:
-                        .filter(|pk| !service.mesh.contains_key(pk))
-                        .copied()
-                        .collect()
-                };
+        }
+        route.1
+    }
+
+    /// Whether a known destination among `targets` can't take another packet right now
+    fn any_queue_full(&self, targets: &[PublicKey]) -> bool {
+        targets
+            .iter()
+            .filter_map(|target| self.peers.get(target))
+            .any(|peer| self.route(peer).is_full())
+    }
+
+    /// Peers connected directly to this server
+    pub fn connected_peers(&self) -> Vec<PublicKey> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.local)
+            .map(|(pk, _)| *pk)
+            .collect()
+    }
 
-                notify_about_all_clients(mesh_peer_pk, mesh_sink, current_peers);
+    /// Bytes held for the connections of local peers, see [`MemoryUsage`]
+    ///
+    /// [`MemoryUsage`]: crate::client::MemoryUsage
+    pub fn memory_usage(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.local)
+            .map(|peer| peer.sink.memory().bytes())
+            .sum()
+    }
 
-                trace!("Peer {mesh_peer_pk:?} added to mesh");
+    /// Evicts the local peers holding the most memory until the others hold at most `budget`
+    /// bytes. Evicted connections report themselves gone with [`PeerGoneReason::Evicted`].
+    fn evict_over_budget(&self, budget: usize) {
+        let mut usage: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.local)
+            .map(|(pk, peer)| (*pk, peer.sink.memory().bytes(), peer.sink.memory()))
+            .collect();
+        let mut total: usize = usage.iter().map(|(_, bytes, _)| bytes).sum();
+        if total <= budget {
+            return;
+        }
+        usage.sort_by_key(|(_, bytes, _)| Reverse(*bytes));
+        for (pk, bytes, memory) in usage {
+            if total <= budget {
+                break;
             }
-            Some(ServiceCommand::PeerPresent(pk, sink)) => {
-                let mut service = service.write().await;
-                match service.peers_sinks.entry(pk) {
-                    std::collections::hash_map::Entry::Occupied(_) => {
-                        warn!("Ignoring already known peer: {pk:?}");
-                    }
-                    std::collections::hash_map::Entry::Vacant(e) => {
-                        info!("will insert {pk:?} to peers (via peer present)");
-                        e.insert(sink);
-                    }
-                }
+            // Still counted until its connection is gone
+            if memory.evict() {
+                warn!(
+                    "Evicting {}, it holds {bytes} bytes, {total} bytes are held against a \
+                     budget of {budget}",
+                    pk.log_display()
+                );
             }
-            Some(ServiceCommand::_Stop) => return Ok(()),
-            None => return Ok(()),
+            total -= bytes;
         }
     }
-}
 
-fn notify_about_all_clients(
-    mesh_peer_pk: PublicKey,
-    mesh_sink: Sender<WriteLoopCommands>,
-    clients_pk: Vec<PublicKey>,
-) {
-    spawn(async move {
-        for pk in clients_pk {
-            if let Err(e) = mesh_sink.send(WriteLoopCommands::PeerPresent(pk)).await {
-                warn!("Failed to notify mesh peer {mesh_peer_pk:?} about client {pk:?}: {e}");
+    /// Disconnects all clients and stops handling commands, returns the summary it logs
+    pub async fn shutdown(&mut self) -> ShutdownSummary {
+        let sinks: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.local)
+            .map(|(pk, peer)| (*pk, peer.sink.clone()))
+            .collect();
+        let summary = ShutdownSummary {
+            connected_clients: sinks.len(),
+            total_clients: self.total_clients,
+            frames_forwarded: self.frames_forwarded.load(Ordering::Relaxed),
+            packets_dropped: self.packets_dropped,
+            peak_concurrency: self.peak_concurrency,
+            uptime: self.uptime(),
+        };
+        info!("Shutting down: {summary}");
+        self.stop_admin();
+
+        // The command loop may be waiting for the lock we hold, don't wait for it here
+        let command_sender = self.command_sender.clone();
+        spawn(async move {
+            // A client with a full queue must not hold up stopping the others
+            let mut stops = JoinSet::new();
+            for (pk, sink) in sinks {
+                stops.spawn(async move {
+                    let stop = sink.send(WriteLoopCommands::Stop);
+                    if timeout(SHUTDOWN_STOP_TIMEOUT, stop).await.is_err() {
+                        debug!(
+                            "[{}] queue still full, not waiting to stop it",
+                            pk.log_display()
+                        );
+                    }
+                });
             }
-        }
-    });
-}
+            while stops.join_next().await.is_some() {}
+            let _ = command_sender.send(ServiceCommand::Stop).await;
+        });
 
-pub enum ServiceCommand {
-    _Stop,
-    SendPacket {
+        summary
+    }
+
+    /// Address of the admin API, see `Config::admin_listen`
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().map(AdminServer::addr)
+    }
+
+    /// Stops serving the admin API, e.g. to let another process bind its address
+    pub fn stop_admin(&mut self) {
+        self.admin = None;
+    }
+
+    /// Time since the service started
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The `n` local peers most bytes were forwarded to, most first
+    pub fn top_talkers(&self, n: usize) -> Vec<(PublicKey, u64)> {
+        let mut talkers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.local)
+            .map(|(pk, peer)| (*pk, peer.bytes_out.load(Ordering::Relaxed)))
+            .collect();
+        talkers.sort_by(|a, b| b.1.cmp(&a.1));
+        talkers.truncate(n);
+        talkers
+    }
+
+    /// Local peers that moved at least `min_bytes` one way and `ratio` times less the other,
+    /// e.g. ones that only send. Those may be misconfigured or scanning. Most lopsided first.
+    pub fn asymmetric_peers(&self, min_bytes: u64, ratio: u64) -> Vec<PeerTraffic> {
+        let mut asymmetric: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.local)
+            .map(|(pk, peer)| PeerTraffic {
+                peer: *pk,
+                sent: peer.bytes_in.load(Ordering::Relaxed),
+                received: peer.bytes_out.load(Ordering::Relaxed),
+            })
+            .filter(|traffic| {
+                traffic.sent.max(traffic.received) >= min_bytes && traffic.ratio() >= ratio
+            })
+            .collect();
+        asymmetric.sort_by_key(|traffic| Reverse(traffic.ratio()));
+        asymmetric
+    }
+
+    /// Payload bytes forwarded since the service started
+    pub fn bytes_forwarded(&self) -> u64 {
+        self.bytes_forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes forwarded per second, averaged over the last second
+    pub fn forwarding_rate(&self) -> f64 {
+        self.forwarding_rate.lock().unwrap().rate()
+    }
+
+    /// Packets dropped since the service started
+    pub fn packets_dropped(&self) -> PacketDrops {
+        self.packets_dropped
+    }
+
+    /// Counts a packet handed to the connection of `target`
+    fn record_forward(&self, target: &PublicKey, bytes: usize) {
+        self.frames_forwarded.fetch_add(1, Ordering::Relaxed);
+        self.bytes_forwarded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(peer) = self.peers.get(target) {
+            peer.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn record_drop(&mut self, source: PublicKey, target: PublicKey, reason: DropReason) {
+        self.packets_dropped.record(reason);
+        self.emit(Event::Drop {
+            source,
+            target,
+            reason,
+        });
+    }
+
+    /// Events from now on. Subscribers that fall behind miss the oldest events, they're told
+    /// how many with [`broadcast::error::RecvError::Lagged`], the service never waits for them.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: Event) {
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Time left of the maintenance window `now` is in, `None` outside of them
+    pub fn maintenance_remaining(&self, now: SystemTime) -> Option<Duration> {
+        self.maintenance_windows
+            .iter()
+            .filter_map(|window| window.remaining(now))
+            .max()
+    }
+
+    /// Handshakes failed since the service started
+    pub fn handshake_failures(&self) -> HandshakeFailureCounts {
+        self.handshake_failures.lock().unwrap().counts
+    }
+
+    /// Connections accepted that haven't completed their handshake yet, oldest first.
+    /// In-process connections aren't listed.
+    pub fn handshakes(&self) -> Vec<InFlightHandshake> {
+        let mut handshakes: Vec<_> = self
+            .handshakes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer_addr, (started, _))| InFlightHandshake {
+                peer_addr: *peer_addr,
+                started: *started,
+            })
+            .collect();
+        handshakes.sort_by_key(|handshake| handshake.started);
+        handshakes
+    }
+
+    /// Closes the connection from `peer_addr` if it's still in its handshake, e.g. a slowloris
+    /// one. Returns whether there was such a connection.
+    pub fn cancel_handshake(&self, peer_addr: SocketAddr) -> bool {
+        let cancelled = self.handshakes.lock().unwrap().remove(&peer_addr);
+        if cancelled.is_some() {
+            info!("Cancelled the handshake with {peer_addr}");
+        }
+        cancelled.is_some()
+    }
+
+    /// Public key clients handshake with
+    pub fn server_key(&self) -> PublicKey {
+        self.server_key.public()
+    }
+
+    /// Presents `new_key` to clients from now on. ClientInfos sealed for the current key are
+    /// still accepted for `grace`, clients that knew it can connect meanwhile without
+    /// learning the new one first.
+    pub fn begin_key_rotation(&mut self, new_key: SecretKey, grace: Duration) {
+        let old_key = std::mem::replace(&mut self.server_key, new_key);
+        info!(
+            "Rotating the server key to {}, {} is accepted for {grace:?}",
+            self.server_key.public(),
+            old_key.public()
+        );
+        self.previous_server_key = Some((old_key, Instant::now() + grace));
+    }
+
+    /// The key replaced by the last key rotation while its grace period lasts
+    fn previous_server_key(&self) -> Option<&SecretKey> {
+        self.previous_server_key
+            .as_ref()
+            .filter(|(_, until)| Instant::now() < *until)
+            .map(|(key, _)| key)
+    }
+
+    /// Local peers subscribed for peer changes, as opposed to our own mesh links
+    fn watcher_count(&self) -> usize {
+        self.mesh
+            .keys()
+            .filter(|pk| self.peers.get(pk).is_some_and(|peer| peer.local))
+            .count()
+    }
+
+    /// Queues `frame` to the local peer `pk`, after the packets already queued to it
+    pub fn send_to<T: Encode>(&self, pk: &PublicKey, frame: Frame<T>) -> anyhow::Result<()> {
+        let peer = match self.peers.get(pk) {
+            Some(peer) if peer.local => peer,
+            _ => bail!("Peer {} isn't connected", pk.log_display()),
+        };
+        let mut buffer = Vec::new();
+        frame.encode(&mut buffer)?;
+        peer.sink
+            .try_send(WriteLoopCommands::Frame(buffer))
+            .map_err(|e| anyhow!("Sending to {}: {e}", pk.log_display()))
+    }
+
+    /// Whether `pk` is connected to us or a mesh peer, `None` when it's unknown
+    pub fn presence(&self, pk: &PublicKey) -> Option<Presence> {
+        self.peers.get(pk).map(|peer| Presence {
+            via: peer.via,
+            since: peer.since,
+            preferred: peer.preferred,
+        })
+    }
+
+    /// Number of local peers by the protocol version they connected with, to see which versions
+    /// are still in use
+    pub fn protocol_versions(&self) -> BTreeMap<u32, usize> {
+        let mut versions = BTreeMap::new();
+        for version in self.peers.values().filter_map(|peer| peer.version) {
+            *versions.entry(version).or_default() += 1;
+        }
+        versions
+    }
+
+    /// Local peers for which we're the home node
+    pub fn preferred_peers(&self) -> Vec<PublicKey> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.local && peer.preferred)
+            .map(|(pk, _)| *pk)
+            .collect()
+    }
+
+    fn note_preferred(&mut self, pk: PublicKey, preferred: bool, sink: &ClientSink) {
+        match self.peers.get_mut(&pk) {
+            Some(peer) if peer.local && peer.sink.same_channel(sink) => peer.preferred = preferred,
+            _ => trace!(
+                "Ignoring preference of unknown or replaced peer {}",
+                pk.log_display()
+            ),
+        }
+    }
+
+    /// Takes the session of `pk` if `token` resumes it, either a parked one or the one of a
+    /// connection that didn't notice it's broken yet
+    fn take_resumable(&mut self, pk: PublicKey, token: ResumeToken) -> Option<Resumable> {
+        match self.resumable.get(&pk) {
+            Some(parked) if parked.token == token && parked.expires > Instant::now() => {
+                return self.resumable.remove(&pk);
+            }
+            _ => {}
+        }
+        match self.peers.get(&pk) {
+            Some(peer) if peer.local && peer.resume_token == Some(token) => Some(Resumable {
+                token,
+                expires: Instant::now(),
+                queue: VecDeque::new(),
+                watcher: self
+                    .mesh
+                    .get(&pk)
+                    .is_some_and(|mesh_sink| mesh_sink.same_channel(&peer.sink)),
+                preferred: peer.preferred,
+            }),
+            _ => {
+                debug!(
+                    "{} presented an unknown or expired resume token",
+                    pk.log_display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Keeps the session of a disconnected peer around until its resume token expires
+    fn park(&mut self, pk: PublicKey, token: ResumeToken, watcher: bool, preferred: bool) {
+        let now = Instant::now();
+        self.resumable.retain(|_, parked| parked.expires > now);
+        self.resumable.insert(
+            pk,
+            Resumable {
+                token,
+                expires: now + self.resume_token_ttl,
+                queue: VecDeque::new(),
+                watcher,
+                preferred,
+            },
+        );
+    }
+
+    /// Queues a packet for a peer that may still resume its session
+    fn queue_for_resumable(
+        &mut self,
         source: PublicKey,
         target: PublicKey,
-        payload: Vec<u8>,
-    },
-    SubscribeForPeerChanges(PublicKey, Sender<WriteLoopCommands>),
-    PeerPresent(PublicKey, Sender<WriteLoopCommands>),
+        ttl: u8,
+        payload: Bytes,
+    ) -> bool {
+        let Some(parked) = self.resumable.get_mut(&target) else {
+            return false;
+        };
+        if parked.expires <= Instant::now() {
+            self.resumable.remove(&target);
+            return false;
+        }
+        let overflow = if parked.queue.len() == RESUME_QUEUE_SIZE {
+            parked.queue.pop_front()
+        } else {
+            None
+        };
+        parked.queue.push_back(WriteLoopCommands::SendPacket {
+            source,
+            target,
+            ttl,
+            payload,
+        });
+        if let Some(WriteLoopCommands::SendPacket { source, target, .. }) = overflow {
+            self.record_drop(source, target, DropReason::QueueOverflow);
+        }
+        true
+    }
+
+    async fn notify_all_mesh_peers(&self, client_pk: PublicKey) {
+        trace!(
+            "Will notify all mesh about new client: {}",
+            client_pk.log_display()
+        );
+        self.notify_watchers(WriteLoopCommands::PeerPresent(client_pk));
+    }
+
+    fn notify_watchers(&self, command: WriteLoopCommands) {
+        let mesh = self.mesh.clone();
+        spawn(async move {
+            for (peer, sink) in mesh {
+                if let Err(e) = sink.send(command.clone()).await {
+                    warn!("Failed to notify mesh peer {peer} with {command:?}: {e}");
+                }
+            }
+        });
+    }
+
+    /// Copies packets from `peer` to `observer`, if mirroring is allowed
+    fn add_mirror(&mut self, observer: PublicKey, peer: PublicKey, sink: ClientSink) {
+        if !self.allow_mirroring {
+            warn!(
+                "Refusing to mirror {} to {}, mirroring is off",
+                peer.log_display(),
+                observer.log_display()
+            );
+            return;
+        }
+        info!(
+            "mirroring {} to {}",
+            peer.log_display(),
+            observer.log_display()
+        );
+        self.mirrors.push(Mirror { peer, sink });
+    }
+
+    /// Sinks of the observers of packets from `source` to `target`
+    fn mirrors_of(&self, source: PublicKey, target: PublicKey) -> Vec<ClientSink> {
+        self.mirrors
+            .iter()
+            .filter(|mirror| mirror.peer == source || mirror.peer == target)
+            .map(|mirror| mirror.sink.clone())
+            .collect()
+    }
+
+    /// Removes `pk` if it's reachable through `sink`. When `sink` belongs to a mesh peer,
+    /// every peer learned through that mesh peer is gone too.
+    fn remove_peer(&mut self, pk: PublicKey, reason: PeerGoneReason, sink: &ClientSink) {
+        let rerouted = self
+            .peers
+            .get_mut(&pk)
+            .is_some_and(|peer| !peer.local && peer.drop_route(sink));
+        match self.peers.get(&pk) {
+            Some(_) if rerouted => {
+                debug!("{} is still behind another relay", pk.log_display());
+            }
+            Some(peer) if peer.sink.same_channel(sink) => {
+                let peer = self.peers.remove(&pk).expect("peer was just found");
+                info!("removed {} from peers ({reason:?})", pk.log_display());
+                if peer.local {
+                    self.notify_watchers(WriteLoopCommands::PeerGone(pk, reason));
+                    self.emit(Event::Disconnect(pk, reason));
+                }
+                if let Some(token) = peer.resume_token {
+                    let watcher = self
+                        .mesh
+                        .get(&pk)
+                        .is_some_and(|mesh_sink| mesh_sink.same_channel(sink));
+                    self.park(pk, token, watcher, peer.preferred);
+                }
+            }
+            _ => trace!(
+                "Ignoring peer gone for unknown or replaced peer {}",
+                pk.log_display()
+            ),
+        }
+
+        if self
+            .mesh
+            .get(&pk)
+            .is_some_and(|mesh_sink| mesh_sink.same_channel(sink))
+        {
+            self.mesh.remove(&pk);
+            self.mesh_rtts.remove(&pk);
+        }
+        self.mirrors
+            .retain(|mirror| !mirror.sink.same_channel(sink));
+
+        let via_mesh: Vec<PublicKey> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| !peer.local)
+            // Peers another relay announced too stay, through that relay
+            .filter_map(|(pk, peer)| {
+                (!peer.drop_route(sink) && peer.sink.same_channel(sink)).then_some(*pk)
+            })
+            .collect();
+        for pk in via_mesh {
+            debug!(
+                "removed {} from peers ({:?})",
+                pk.log_display(),
+                PeerGoneReason::MeshConnBroke
+            );
+            self.peers.remove(&pk);
+        }
+    }
+}
+
+// TODO: should this be RWLock instead of Mutex?
+impl Service for Arc<RwLock<DerpService>> {
+    async fn run(&self, listener: TcpListener) -> anyhow::Result<()> {
+        self.run_with_listener(listener).await.joined().await
+    }
+
+    async fn run_on(&self, listener: std::net::TcpListener) -> anyhow::Result<()> {
+        listener.set_nonblocking(true)?;
+        self.run(TcpListener::from_std(listener)?).await
+    }
+
+    async fn run_with_listener(&self, listener: TcpListener) -> ServiceHandle {
+        ServiceHandle {
+            service: self.clone(),
+            acceptors: spawn(run_acceptors(self.clone(), listener)),
+        }
+    }
+}
+
+async fn run_acceptors(
+    service: Arc<RwLock<DerpService>>,
+    listener: TcpListener,
+) -> anyhow::Result<()> {
+    let listener = Arc::new(listener);
+    let (acceptor_count, handshake_failures) = {
+        let service = service.read().await;
+        (service.acceptors.get(), service.handshake_failures.clone())
+    };
+    // Dropping the set, e.g. when this task is aborted, stops every accept loop
+    let mut acceptors = JoinSet::new();
+    for _ in 0..acceptor_count {
+        acceptors.spawn(accept_loop(
+            listener.clone(),
+            service.clone(),
+            handshake_failures.clone(),
+        ));
+    }
+    acceptors.spawn(summarize_handshake_failures(handshake_failures));
+    // Accept loops only stop by panicking
+    while let Some(result) = acceptors.join_next().await {
+        result?;
+    }
+    Ok(())
+}
+
+/// Tells the sender of a packet to `target` what became of it, `acks` is the sender's sink if
+/// it asked for acks
+fn send_ack(acks: Option<ClientSink>, target: PublicKey, status: SendStatus) {
+    if let Some(sink) = acks {
+        // Acks are for debugging, losing one is fine
+        if sink
+            .try_send(WriteLoopCommands::SendAck(target, status))
+            .is_err()
+        {
+            trace!("dropping ack for packet to {}", target.log_display());
+        }
+    }
+}
+
+/// Dual-stack listeners report IPv4 clients as `::ffff:a.b.c.d`, use the plain IPv4 form so
+/// the same client always has the same address
+fn canonical_peer_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+async fn accept_loop(
+    listener: Arc<TcpListener>,
+    service: Arc<RwLock<DerpService>>,
+    handshake_failures: Arc<Mutex<HandshakeFailures>>,
+) {
+    loop {
+        if let Ok((socket, peer_addr)) = listener.accept().await {
+            let peer_addr = canonical_peer_addr(peer_addr);
+            if handshake_failures.lock().unwrap().refuse(peer_addr.ip()) {
+                debug!("Refused connection from {peer_addr:?}, too many authentication failures");
+                continue;
+            }
+            debug!("Got connection from: {peer_addr:?}");
+            let service = service.clone();
+            let handshake_failures = handshake_failures.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(socket, Some(peer_addr), service).await {
+                    handshake_failures.lock().unwrap().record(peer_addr, &e);
+                }
+            });
+        }
+    }
+}
+
+/// Evicts the connections holding the most memory whenever all of them together hold more
+/// than `budget` bytes, until the service is gone
+async fn enforce_memory_budget(service: Weak<RwLock<DerpService>>, budget: usize) {
+    let mut ticks = interval(MEMORY_CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let Some(service) = service.upgrade() else {
+            return;
+        };
+        let service = service.read().await;
+        service.evict_over_budget(budget);
+    }
+}
+
+/// Samples the bytes forwarded for the forwarding rate until the service is gone
+async fn sample_forwarding_rate(service: Weak<RwLock<DerpService>>) {
+    let mut ticks = interval(FORWARDING_RATE_SAMPLE_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let Some(service) = service.upgrade() else {
+            return;
+        };
+        let service = service.read().await;
+        let total = service.bytes_forwarded.load(Ordering::Relaxed);
+        service
+            .forwarding_rate
+            .lock()
+            .unwrap()
+            .record(Instant::now(), total);
+    }
+}
+
+/// Logs the summaries of failure windows that ended even if no new failure comes in
+async fn summarize_handshake_failures(handshake_failures: Arc<Mutex<HandshakeFailures>>) {
+    let period = handshake_failures.lock().unwrap().window;
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        handshake_failures.lock().unwrap().flush(Instant::now());
+    }
+}
+
+/// Reads a secret key, hex or base64, surrounding whitespace is ignored
+#[cfg(feature = "mesh")]
+fn read_secret_key(path: &std::path::Path) -> anyhow::Result<SecretKey> {
+    let key = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Reading relay key from {}: {e}", path.display()))?;
+    key.trim()
+        .parse()
+        .map_err(|e| anyhow!("Parsing relay key from {}: {e}", path.display()))
+}
+
+/// Connects a client to `service` through an in-memory stream, no port is bound
+pub async fn connect_in_process(
+    service: Arc<RwLock<DerpService>>,
+    secret_key: SecretKey,
+) -> anyhow::Result<DerpClient> {
+    let (client_stream, server_stream) = duplex(IN_PROCESS_BUFFER_SIZE);
+    spawn(async move {
+        if let Err(e) = handle_client(server_stream, None, service).await {
+            warn!("In-process client failed: {e:?}");
+        }
+    });
+    DerpClient::connect_stream(client_stream, secret_key).await
+}
+
+/// Runs two in-process clients through the handshake and relays a packet between them,
+/// without binding `config.listen_on` or connecting to mesh peers
+pub async fn self_test(#[allow(unused_mut)] mut config: Config) -> anyhow::Result<()> {
+    #[cfg(feature = "mesh")]
+    config.mesh_peers.clear();
+    let service = DerpService::new(config).await?;
+
+    let result = async {
+        let sender = connect_in_process(service.clone(), SecretKey::gen()).await?;
+        let receiver = connect_in_process(service.clone(), SecretKey::gen()).await?;
+        // The client is done with the handshake slightly before the service registers it
+        while !service
+            .read()
+            .await
+            .connected_peers()
+            .contains(&receiver.public_key())
+        {
+            sleep(SELF_TEST_POLL_INTERVAL).await;
+        }
+
+        let payload = rand::random::<[u8; 32]>().to_vec();
+        sender
+            .send_packet(receiver.public_key(), payload.clone())
+            .await?;
+        let (source, received) = receiver.recv_packet().await?;
+        ensure!(
+            source == sender.public_key() && received == payload,
+            "Relayed packet came back altered"
+        );
+        Ok(())
+    };
+    let result = timeout(SELF_TEST_TIMEOUT, result)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Self-test timed out after {SELF_TEST_TIMEOUT:?}")));
+    service.write().await.shutdown().await;
+    result
+}
+
+/// Runs the handshake and hands the client to `service`. Connections with a `peer_addr` are
+/// listed in [`DerpService::handshakes`] until the handshake completes.
+async fn handle_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    mut stream: S,
+    peer_addr: Option<SocketAddr>,
+    service: Arc<RwLock<DerpService>>,
+) -> anyhow::Result<()> {
+    let resume_token = ResumeToken::gen();
+    let (
+        sk,
+        previous_key,
+        timeouts,
+        max_clock_skew,
+        seen_nonces,
+        server_info,
+        handshakes,
+        decryption_pool,
+        maintenance,
+    ) = {
+        let service = service.read().await;
+        let server_info = ServerInfoPayload {
+            resume_token: Some(resume_token),
+            region: service.region.clone(),
+            max_packet_size: Some(service.max_packet_size),
+            version: Some(PROTOCOL_VERSION),
+            no_forwarding: service.no_forwarding,
+            max_fanout: Some(service.max_fanout.into()),
+            // Granted during the handshake to the clients asking for them
+            send_acks: false,
+        };
+        (
+            service.server_key.clone(),
+            service.previous_server_key().cloned(),
+            service.timeouts,
+            service.max_clock_skew,
+            service.seen_nonces.clone(),
+            server_info,
+            service.handshakes.clone(),
+            service.decryption_pool.clone(),
+            service.maintenance_remaining(SystemTime::now()),
+        )
+    };
+    if let Some(remaining) = maintenance {
+        debug!("Refused connection from {peer_addr:?}, in a maintenance window for {remaining:?}");
+        let unavailable = "503 Service Unavailable";
+        return refuse_upgrade(
+            &mut stream,
+            timeouts.handshake_timeout,
+            unavailable,
+            maintenance,
+        )
+        .await;
+    }
+    let (tracked, cancelled) = match peer_addr {
+        Some(peer_addr) => {
+            let (guard, cancelled) = HandshakeGuard::track(&handshakes, peer_addr);
+            (Some(guard), Some(cancelled))
+        }
+        None => (None, None),
+    };
+    // The upgrade and the ClientInfo have their own timeouts within this one
+    let handshake_timeout = timeouts.handshake_timeout;
+    let handshake = timeout(
+        handshake_timeout,
+        handle_handshake(
+            stream,
+            &sk,
+            previous_key.as_ref(),
+            &timeouts,
+            max_clock_skew,
+            &seen_nonces,
+            &server_info,
+            decryption_pool.as_ref(),
+        ),
+    );
+    let (stream, handshake) = select! {
+        handshake = handshake => handshake
+            .map_err(|_| anyhow!("Handshake timed out after {handshake_timeout:?}"))??,
+        // Resolves once the sender is dropped by `cancel_handshake`
+        _ = async move {
+            match cancelled {
+                Some(cancelled) => {
+                    let _ = cancelled.await;
+                }
+                None => pending().await,
+            }
+        } => bail!("Handshake cancelled"),
+    };
+    drop(tracked);
+
+    service
+        .write()
+        .await
+        .add_new_client(stream, handshake, resume_token)
+        .await?;
+
+    Ok(())
+}
+
+/// Hands a packet from `source` to the connection of `target`. Packets for a target that may
+/// resume are queued, those that can't be handed over right away are dropped.
+async fn relay_packet(
+    service: &RwLock<DerpService>,
+    events: &broadcast::Sender<Event>,
+    source: PublicKey,
+    target: PublicKey,
+    ttl: u8,
+    payload: Bytes,
+) {
+    // TODO: to make this faster client/mesh_client should have direct access to
+    // the `peers_sinks`, instead of sending requests to service. This way clients
+    // communication will not put preasure on the services queue.
+    debug!("send packet to {}", target.log_display());
+    let (route, mirrors, acks) = {
+        let service = service.read().await;
+        if let Some(source) = service.peers.get(&source).filter(|peer| peer.local) {
+            source
+                .bytes_in
+                .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        }
+        let route = service
+            .peers
+            .get(&target)
+            .map(|peer| (service.route(peer).clone(), peer.local));
+        (
+            route,
+            service.mirrors_of(source, target),
+            service.ack_sink(&source),
+        )
+    };
+    for mirror in mirrors {
+        let copy = WriteLoopCommands::SendPacket {
+            source,
+            target,
+            ttl: 0,
+            payload: payload.clone(),
+        };
+        // A slow observer loses copies rather than holding up the relay
+        if mirror.try_send(copy).is_err() {
+            trace!(
+                "dropping mirrored packet from {} to {}",
+                source.log_display(),
+                target.log_display()
+            );
+        }
+    }
+    let Some((sink, local)) = route else {
+        let mut service = service.write().await;
+        if service.queue_for_resumable(source, target, ttl, payload) {
+            trace!("queued packet for resumable {}", target.log_display());
+            send_ack(acks, target, SendStatus::Enqueued);
+            return;
+        }
+        service.record_drop(source, target, DropReason::UnknownDestination);
+        send_ack(acks, target, SendStatus::Dropped);
+        if let Some(source) = service.peers.get(&source) {
+            let sink = source.sink.clone();
+            spawn(async move {
+                let _ = sink
+                    .send(WriteLoopCommands::PeerGone(target, PeerGoneReason::NotHere))
+                    .await;
+            });
+        }
+        return;
+    };
+    // Forwarding to another relay takes a hop
+    let ttl = match (local, ttl) {
+        (true, ttl) => ttl,
+        (false, 0) => {
+            debug!(
+                "dropping packet to {}, its TTL expired",
+                target.log_display()
+            );
+            service
+                .write()
+                .await
+                .record_drop(source, target, DropReason::TtlExpired);
+            send_ack(acks, target, SendStatus::Dropped);
+            return;
+        }
+        (false, ttl) => ttl - 1,
+    };
+    // Waiting for room would stall every other packet behind a slow target
+    let len = payload.len();
+    let packet = WriteLoopCommands::SendPacket {
+        source,
+        target,
+        ttl,
+        payload,
+    };
+    let reason = match sink.try_send(packet) {
+        Ok(()) => {
+            service.read().await.record_forward(&target, len);
+            send_ack(acks, target, SendStatus::Enqueued);
+            let _ = events.send(Event::Forward { source, target });
+            return;
+        }
+        Err(TrySendError::Full(_)) => DropReason::SlowDestination,
+        // Gone, it's reported as such shortly
+        Err(TrySendError::Closed(_)) => DropReason::UnknownDestination,
+    };
+    trace!("dropping packet to {}: {reason:?}", target.log_display());
+    service.write().await.record_drop(source, target, reason);
+    send_ack(acks, target, SendStatus::Dropped);
+}
+
+async fn command_loop(
+    mut r: Receiver<ServiceCommand>,
+    service: Arc<RwLock<DerpService>>,
+) -> anyhow::Result<()> {
+    let (max_packet_size, no_forwarding, fanout_overflow, events) = {
+        let service = service.read().await;
+        (
+            service.max_packet_size,
+            service.no_forwarding,
+            service.fanout_overflow,
+            service.events.clone(),
+        )
+    };
+    loop {
+        match r.recv().await {
+            Some(ServiceCommand::SendPacket { source, target, .. }) if no_forwarding => {
+                trace!(
+                    "dropping packet to {}, forwarding is disabled",
+                    target.log_display()
+                );
+                let mut service = service.write().await;
+                service.record_drop(source, target, DropReason::ForwardingDisabled);
+                send_ack(service.ack_sink(&source), target, SendStatus::Dropped);
+            }
+            Some(ServiceCommand::SendPacket {
+                source,
+                target,
+                payload,
+                ..
+            }) if payload.len() > max_packet_size => {
+                debug!("dropping packet of {} bytes", payload.len());
+                let mut service = service.write().await;
+                service.record_drop(source, target, DropReason::Oversize);
+                send_ack(service.ack_sink(&source), target, SendStatus::Dropped);
+            }
+            Some(ServiceCommand::SendPacket {
+                source,
+                target,
+                ttl,
+                payload,
+            }) => relay_packet(&service, &events, source, target, ttl, payload).await,
+            Some(ServiceCommand::SendPackets {
+                source,
+                targets,
+                payload,
+            }) => {
+                let reason = if no_forwarding {
+                    Some(DropReason::ForwardingDisabled)
+                } else if payload.len() > max_packet_size {
+                    Some(DropReason::Oversize)
+                } else if fanout_overflow == FanoutOverflow::DropAll
+                    && service.read().await.any_queue_full(&targets)
+                {
+                    Some(DropReason::SlowDestination)
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    trace!(
+                        "dropping packet to {} destinations: {reason:?}",
+                        targets.len()
+                    );
+                    let mut service = service.write().await;
+                    let acks = service.ack_sink(&source);
+                    for target in targets {
+                        service.record_drop(source, target, reason);
+                        send_ack(acks.clone(), target, SendStatus::Dropped);
+                    }
+                    continue;
+                }
+                for target in targets {
+                    let payload = payload.clone();
+                    relay_packet(&service, &events, source, target, MESH_TTL, payload).await;
+                }
+            }
+            Some(ServiceCommand::SubscribeForPeerChanges(mesh_peer_pk, mesh_sink)) => {
+                let (chunk_size, dumps) = {
+                    let mut service = service.write().await;
+                    let at_limit = service
+                        .max_watchers
+                        .is_some_and(|max| service.watcher_count() >= max);
+                    if at_limit && !service.mesh.contains_key(&mesh_peer_pk) {
+                        warn!(
+                            "Refusing WatchConns from {}, too many watchers",
+                            mesh_peer_pk.log_display()
+                        );
+                        continue;
+                    }
+                    if let Some(_old) = service.mesh.insert(mesh_peer_pk, mesh_sink.clone()) {
+                        warn!("Mesh peer for {} overwriten", mesh_peer_pk.log_display());
+                    }
+                    (service.roster_chunk_size, service.roster_dumps.clone())
+                };
+
+                notify_about_all_clients(
+                    Arc::downgrade(&service),
+                    mesh_peer_pk,
+                    mesh_sink,
+                    chunk_size,
+                    dumps,
+                );
+
+                trace!("Peer {} added to mesh", mesh_peer_pk.log_display());
+            }
+            Some(ServiceCommand::PeerPresent(pk, relay, sink)) => {
+                let mut service = service.write().await;
+                match service.peers.entry(pk) {
+                    // Announced again after the relay reconnected
+                    Entry::Occupied(mut e) if e.get().via == Some(relay) => {
+                        trace!(
+                            "{} is still behind {}",
+                            pk.log_display(),
+                            relay.log_display()
+                        );
+                        e.get_mut().sink = sink;
+                    }
+                    Entry::Occupied(mut e) if !e.get().local => {
+                        trace!(
+                            "{} is also behind {}",
+                            pk.log_display(),
+                            relay.log_display()
+                        );
+                        let alternatives = &mut e.get_mut().alternatives;
+                        alternatives.retain(|(via, _)| *via != relay);
+                        alternatives.push((relay, sink));
+                    }
+                    Entry::Occupied(_) => {
+                        warn!("Ignoring already known peer: {}", pk.log_display());
+                    }
+                    Entry::Vacant(e) => {
+                        info!(
+                            "will insert {} to peers (via peer present)",
+                            pk.log_display()
+                        );
+                        e.insert(Peer::remote(sink, relay));
+                    }
+                }
+            }
+            Some(ServiceCommand::PeerGone(pk, reason, sink)) => {
+                service.write().await.remove_peer(pk, reason, &sink);
+            }
+            Some(ServiceCommand::RosterComplete(relay, sink)) => {
+                service.write().await.prune_mesh_roster(relay, &sink);
+            }
+            Some(ServiceCommand::NotePreferred(pk, preferred, sink)) => {
+                service.write().await.note_preferred(pk, preferred, &sink);
+            }
+            Some(ServiceCommand::MirrorPackets(observer, peer, sink)) => {
+                service.write().await.add_mirror(observer, peer, sink);
+            }
+            Some(ServiceCommand::MeshRtt(relay, rtt)) => {
+                service.write().await.mesh_rtts.insert(relay, rtt);
+            }
+            Some(ServiceCommand::Stop) => return Ok(()),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// `mesh_peers` without duplicates, at most `max` of them
+#[cfg(feature = "mesh")]
+fn unique_mesh_peers(mesh_peers: Vec<String>, max: usize) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    for peer in mesh_peers {
+        let (addr, _) = parse_mesh_peer(&peer);
+        if !seen.insert(addr.to_owned()) {
+            warn!("Mesh peer {addr} is listed more than once, connecting to it once");
+        } else if unique.len() == max {
+            warn!("Over {max} mesh peers, ignoring {addr}");
+        } else {
+            unique.push(peer);
+        }
+    }
+    unique
+}
+
+/// Splits a `--mesh-peers` entry, `addr` or `addr=meshkey`, into the address and the meshkey
+/// presented to that peer instead of `--meshkey`
+#[cfg(feature = "mesh")]
+fn parse_mesh_peer(peer: &str) -> (&str, Option<&str>) {
+    match peer.split_once('=') {
+        Some((addr, meshkey)) => (addr, Some(meshkey)),
+        None => (peer, None),
+    }
+}
+
+/// Sends a PeerPresent for each peer of the roster of `mesh_peer_pk`, followed by a
+/// RosterComplete. Waits for a permit of `dumps` first, if given.
+fn notify_about_all_clients(
+    service: Weak<RwLock<DerpService>>,
+    mesh_peer_pk: PublicKey,
+    mesh_sink: ClientSink,
+    chunk_size: usize,
+    dumps: Option<Arc<Semaphore>>,
+) {
+    // Only a chunk of keys is held at a time, the service is read again for the next one
+    spawn(async move {
+        let _permit = match dumps {
+            Some(dumps) => dumps.acquire_owned().await.ok(),
+            None => None,
+        };
+        let mut after = None;
+        loop {
+            let Some(service) = service.upgrade() else {
+                return;
+            };
+            let chunk = service.read().await.roster(mesh_peer_pk, after, chunk_size);
+            drop(service);
+            for pk in &chunk {
+                if let Err(e) = mesh_sink.send(WriteLoopCommands::PeerPresent(*pk)).await {
+                    warn!(
+                        "Failed to notify mesh peer {} about client {}: {e}",
+                        mesh_peer_pk.log_display(),
+                        pk.log_display()
+                    );
+                    return;
+                }
+            }
+            match chunk.last() {
+                Some(last) if chunk.len() == chunk_size => after = Some(*last),
+                _ => break,
+            }
+        }
+        if let Err(e) = mesh_sink.send(WriteLoopCommands::RosterComplete).await {
+            warn!(
+                "Failed to tell mesh peer {} the roster is complete: {e}",
+                mesh_peer_pk.log_display()
+            );
+        }
+    });
+}
+
+pub enum ServiceCommand {
+    Stop,
+    SendPacket {
+        source: PublicKey,
+        target: PublicKey,
+        /// Mesh hops left, see [`MESH_TTL`](crate::proto::data::MESH_TTL)
+        ttl: u8,
+        payload: Bytes,
+    },
+    /// One packet from a local peer for several destinations, see
+    /// [`FanoutOverflow`](crate::config::FanoutOverflow)
+    SendPackets {
+        source: PublicKey,
+        targets: Vec<PublicKey>,
+        /// Shared by the destinations, none of them gets a copy
+        payload: Bytes,
+    },
+    SubscribeForPeerChanges(PublicKey, ClientSink),
+    /// The peer is connected to the relay with the second key, reachable through the sink
+    PeerPresent(PublicKey, PublicKey, ClientSink),
+    /// The peer reachable through the sink is gone
+    PeerGone(PublicKey, PeerGoneReason, ClientSink),
+    /// The relay announced its whole roster through the sink
+    RosterComplete(PublicKey, ClientSink),
+    /// Whether the peer reachable through the sink prefers us as its home node
+    NotePreferred(PublicKey, bool, ClientSink),
+    /// The observer wants copies of the packets from or to the second key through the sink
+    MirrorPackets(PublicKey, PublicKey, ClientSink),
+    /// A ping over the mesh link to the relay was answered after the duration
+    MeshRtt(PublicKey, Duration),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{write_lanes, WriteLanes},
+        crypto::TestKeys,
+        faulty::FaultyStream,
+        inout::DerpReader,
+        listener::bind,
+        proto::{
+            connect_http,
+            data::{ClientInfo, FrameType, Ping, SendAck},
+            exchange_keys, read_server_info, Transport,
+        },
+        test_utils::{capture_logs, count_logs, start_service, wait_for_log, wait_for_peer},
+    };
+    use clap::Parser;
+    use codec::{Encode, SizeWrapper};
+    use std::{io::Cursor, time::UNIX_EPOCH};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{tcp::OwnedWriteHalf, TcpStream},
+        task::yield_now,
+        time::sleep,
+    };
+
+    async fn connect(
+        addr: SocketAddr,
+    ) -> (
+        DerpReader<impl AsyncRead + Unpin>,
+        OwnedWriteHalf,
+        PublicKey,
+    ) {
+        connect_with_meshkey(addr, None).await
+    }
+
+    async fn connect_with_meshkey(
+        addr: SocketAddr,
+        meshkey: Option<&str>,
+    ) -> (
+        DerpReader<impl AsyncRead + Unpin>,
+        OwnedWriteHalf,
+        PublicKey,
+    ) {
+        connect_as(addr, SecretKey::gen(), meshkey).await
+    }
+
+    async fn connect_as(
+        addr: SocketAddr,
+        sk: SecretKey,
+        meshkey: Option<&str>,
+    ) -> (
+        DerpReader<impl AsyncRead + Unpin>,
+        OwnedWriteHalf,
+        PublicKey,
+    ) {
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        let leftovers = connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(r));
+        let server_key = exchange_keys(&mut reader, &mut w, &sk, meshkey, None, false)
+            .await
+            .unwrap();
+        read_server_info(&mut reader, &sk, server_key)
+            .await
+            .unwrap();
+        (reader, w, sk.public())
+    }
+
+    async fn queued_packets(service: &Arc<RwLock<DerpService>>, pk: PublicKey) -> Option<usize> {
+        let service = service.read().await;
+        service.resumable.get(&pk).map(|parked| parked.queue.len())
+    }
+
+    async fn wait_until(
+        service: &Arc<RwLock<DerpService>>,
+        condition: impl Fn(&DerpService) -> bool,
+    ) {
+        timeout(Duration::from_secs(5), async {
+            while !condition(&*service.read().await) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition should be met");
+    }
+
+    async fn add_watcher(service: &Arc<RwLock<DerpService>>) -> WriteLanes {
+        let (sink, watcher) = write_lanes(16);
+        service
+            .write()
+            .await
+            .mesh
+            .insert(SecretKey::gen().public(), sink);
+        watcher
+    }
+
+    async fn next_command(watcher: &mut WriteLanes) -> WriteLoopCommands {
+        timeout(Duration::from_secs(5), watcher.recv())
+            .await
+            .expect("watcher should be notified")
+            .expect("watcher channel should be open")
+    }
+
+    #[tokio::test]
+    async fn clean_close_is_reported_as_disconnected() {
+        let (service, addr) = start_service(&[]).await;
+        let mut watcher = add_watcher(&service).await;
+
+        let (reader, writer, pk) = connect(addr).await;
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerPresent(present) if present == pk
+        ));
+
+        drop((reader, writer));
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerGone(gone, PeerGoneReason::Disconnected) if gone == pk
+        ));
+        assert!(!service.read().await.peers.contains_key(&pk));
+    }
+
+    #[tokio::test]
+    async fn closed_client_is_reported_as_disconnected() {
+        let (service, addr) = start_service(&[]).await;
+        let mut watcher = add_watcher(&service).await;
+
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let pk = client.public_key();
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerPresent(present) if present == pk
+        ));
+
+        client.close().await.unwrap();
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerGone(gone, PeerGoneReason::Disconnected) if gone == pk
+        ));
+        assert!(!service.read().await.peers.contains_key(&pk));
+    }
+
+    #[tokio::test]
+    async fn idle_client_is_reported_as_idle_timeout() {
+        let (service, addr) = start_service(&["--idle-timeout", "100ms"]).await;
+        let mut watcher = add_watcher(&service).await;
+
+        let (_reader, _writer, pk) = connect(addr).await;
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerPresent(present) if present == pk
+        ));
+
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerGone(gone, PeerGoneReason::IdleTimeout) if gone == pk
+        ));
+        assert!(!service.read().await.peers.contains_key(&pk));
+    }
+
+    #[tokio::test]
+    async fn client_sending_but_not_reading_is_reaped() {
+        // The write timeout alone would keep the client around for an hour
+        let (service, addr) =
+            start_service(&["--liveness-timeout", "500ms", "--write-timeout", "1h"]).await;
+        let mut watcher = add_watcher(&service).await;
+        let (_reader, mut writer, pk) = connect(addr).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, pk).await;
+
+        // Junk keeps the server reading, the packets fill the socket buffers as nothing reads them
+        let junk = spawn(async move {
+            while writer.write_all(&UNKNOWN_FRAME).await.is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let flood = spawn(async move {
+            while sender.send_packet(pk, vec![0; 60_000]).await.is_ok() {
+                yield_now().await;
+            }
+        });
+
+        let reason = timeout(Duration::from_secs(10), async {
+            loop {
+                if let WriteLoopCommands::PeerGone(gone, reason) = next_command(&mut watcher).await
+                {
+                    if gone == pk {
+                        return reason;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("client should be reaped");
+        assert_eq!(reason, PeerGoneReason::WriteTimeout);
+        junk.abort();
+        flood.abort();
+    }
+
+    #[tokio::test]
+    async fn connection_holding_the_most_is_evicted_over_the_memory_budget() {
+        let (service, addr) = start_service(&["--memory-budget", "100000"]).await;
+        let mut watcher = add_watcher(&service).await;
+        // Neither reads, packets to the greedy one pile up once the socket buffers are full
+        let (_greedy_reader, _greedy_writer, greedy) = connect(addr).await;
+        let (_light_reader, _light_writer, light) = connect(addr).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, greedy).await;
+        wait_for_peer(&service, light).await;
+
+        let flood = spawn(async move {
+            while sender.send_packet(greedy, vec![0; 60_000]).await.is_ok() {
+                yield_now().await;
+            }
+        });
+
+        let reason = timeout(Duration::from_secs(10), async {
+            loop {
+                if let WriteLoopCommands::PeerGone(gone, reason) = next_command(&mut watcher).await
+                {
+                    if gone == greedy {
+                        return reason;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("greedy client should be evicted");
+        assert_eq!(reason, PeerGoneReason::Evicted);
+        assert!(service.read().await.peers.contains_key(&light));
+        flood.abort();
+    }
+
+    #[tokio::test]
+    async fn self_test_passes() {
+        let config = Config::parse_from(["dersp", "--listen-on", "unused", "--self-test"]);
+        self_test(config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_with_pong() {
+        let (_service, addr) = start_service(&[]).await;
+        let (mut reader, mut w, _pk) = connect(addr).await;
+
+        let mut ping = Vec::new();
+        Ping { data: [3; 8] }.frame().encode(&mut ping).unwrap();
+        w.write_all(&ping).await.unwrap();
+
+        let pong = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("ping should be answered")
+            .unwrap();
+        assert_eq!(pong.ty, FrameType::Pong);
+        assert_eq!(pong.buffer[5..], [3; 8]);
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_ahead_of_a_packet_backlog() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let receiver = Arc::new(DerpClient::connect(&addr, SecretKey::gen()).await.unwrap());
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let target = receiver.public_key();
+        wait_for_peer(&service, target).await;
+
+        // Until the receiver reads, packets fill its inbound queue, the socket buffers and then
+        // the lane to it
+        let flood = spawn(async move {
+            while sender.send_packet(target, vec![0; 1000]).await.is_ok() {
+                yield_now().await;
+            }
+        });
+        wait_until(&service, |service| service.memory_usage() > 0).await;
+
+        let drain = spawn({
+            let receiver = receiver.clone();
+            async move { while receiver.recv_packet().await.is_ok() {} }
+        });
+        timeout(Duration::from_secs(5), receiver.ping())
+            .await
+            .expect("pong should overtake the backlog")
+            .unwrap();
+        flood.abort();
+        drain.abort();
+    }
+
+    #[tokio::test]
+    async fn slow_target_does_not_hold_up_other_packets() {
+        let (service, addr) = start_service(&[]).await;
+        // Never reads, its socket buffers and then its queue fill up
+        let (_slow_reader, _slow_writer, slow) = connect(addr).await;
+        let addr = addr.to_string();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let sender = Arc::new(DerpClient::connect(&addr, SecretKey::gen()).await.unwrap());
+        wait_for_peer(&service, slow).await;
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        let flood = spawn({
+            let sender = sender.clone();
+            async move {
+                while sender.send_packet(slow, vec![0; 60_000]).await.is_ok() {
+                    yield_now().await;
+                }
+            }
+        });
+        wait_until(&service, |service| {
+            service.packets_dropped().slow_destination > 0
+        })
+        .await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        let (source, payload) = timeout(Duration::from_secs(1), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed past the slow target")
+            .unwrap();
+        assert_eq!(source, sender.public_key());
+        assert_eq!(payload, b"hello");
+        flood.abort();
+
+        // A dropped packet isn't counted as forwarded
+        let (forwarded, dropped) = {
+            let service = service.read().await;
+            (
+                service.frames_forwarded.load(Ordering::Relaxed),
+                service.packets_dropped().slow_destination,
+            )
+        };
+        sender.send_packet(slow, vec![0; 60_000]).await.unwrap();
+        wait_until(&service, |service| {
+            service.packets_dropped().slow_destination > dropped
+        })
+        .await;
+        assert_eq!(
+            service
+                .read()
+                .await
+                .frames_forwarded
+                .load(Ordering::Relaxed),
+            forwarded
+        );
+    }
+
+    /// Frame of a type from the future, with a 2 bytes payload
+    const UNKNOWN_FRAME: [u8; 7] = [0x7F, 0, 0, 0, 2, 1, 2];
+
+    #[tokio::test]
+    async fn unknown_frame_is_skipped_by_default() {
+        let (_service, addr) = start_service(&[]).await;
+        let (mut reader, mut w, _pk) = connect(addr).await;
+
+        w.write_all(&UNKNOWN_FRAME).await.unwrap();
+        let mut ping = Vec::new();
+        Ping { data: [3; 8] }.frame().encode(&mut ping).unwrap();
+        w.write_all(&ping).await.unwrap();
+
+        let pong = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("ping should be answered")
+            .unwrap();
+        assert_eq!(pong.ty, FrameType::Pong);
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_is_skipped_by_default() {
+        let (_service, addr) = start_service(&[]).await;
+        let (mut reader, mut w, _pk) = connect(addr).await;
+
+        // KeepAlive, then a SendPacket too short for its target key
+        w.write_all(&[0x06, 0, 0, 0, 0]).await.unwrap();
+        w.write_all(&[0x04, 0, 0, 0, 4, 1, 2, 3, 4]).await.unwrap();
+        let mut ping = Vec::new();
+        Ping { data: [3; 8] }.frame().encode(&mut ping).unwrap();
+        w.write_all(&ping).await.unwrap();
+
+        let pong = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("ping should be answered")
+            .unwrap();
+        assert_eq!(pong.ty, FrameType::Pong);
+    }
+
+    #[tokio::test]
+    async fn unknown_frame_disconnects_with_strict_protocol() {
+        let (service, addr) = start_service(&["--strict-protocol"]).await;
+        let (mut reader, mut w, pk) = connect(addr).await;
+        wait_for_peer(&service, pk).await;
+
+        w.write_all(&UNKNOWN_FRAME).await.unwrap();
+        let closed = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("client should be disconnected");
+        assert!(closed.is_err());
+        wait_until(&service, |service| !service.peers.contains_key(&pk)).await;
+    }
+
+    #[tokio::test]
+    async fn frame_is_sent_to_a_connected_peer() {
+        let (service, addr) = start_service(&[]).await;
+        let (mut reader, _w, pk) = connect(addr).await;
+        wait_for_peer(&service, pk).await;
+
+        let health = Frame {
+            frame_type: FrameType::ControlMessage,
+            inner: SizeWrapper::new(b"health: going down for maintenance".to_vec()),
+        };
+        service.read().await.send_to(&pk, health).unwrap();
+
+        let received = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("frame should be delivered")
+            .unwrap();
+        assert_eq!(received.ty, FrameType::ControlMessage);
+        assert_eq!(received.buffer[5..], *b"health: going down for maintenance");
+    }
+
+    #[tokio::test]
+    async fn frame_to_an_unknown_peer_is_an_error() {
+        let (service, _addr) = start_service(&[]).await;
+        let frame = Ping { data: [1; 8] }.frame();
+        assert!(service
+            .read()
+            .await
+            .send_to(&SecretKey::gen().public(), frame)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_is_recycled_past_max_connection_bytes() {
+        let (service, addr) = start_service(&["--max-connection-bytes", "1000"]).await;
+        let mut watcher = add_watcher(&service).await;
+        let addr = addr.to_string();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+        wait_for_peer(&service, sender.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), vec![0; 600])
+            .await
+            .unwrap();
+        receiver.recv_packet().await.unwrap();
+        assert!(service
+            .read()
+            .await
+            .connected_peers()
+            .contains(&sender.public_key()));
+
+        // The packet crossing the limit is still relayed
+        sender
+            .send_packet(receiver.public_key(), vec![0; 600])
+            .await
+            .unwrap();
+        receiver.recv_packet().await.unwrap();
+        let reason = timeout(Duration::from_secs(5), async {
+            loop {
+                if let WriteLoopCommands::PeerGone(gone, reason) = next_command(&mut watcher).await
+                {
+                    if gone == sender.public_key() {
+                        return reason;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("sender should be recycled");
+        assert_eq!(reason, PeerGoneReason::Recycled);
+    }
+
+    #[tokio::test]
+    async fn maintenance_window_refuses_only_new_connections() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+        wait_for_peer(&service, sender.public_key()).await;
+
+        // From a minute ago to an hour from now
+        const DAY_MINUTES: u64 = 24 * 60;
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let now = since_epoch.as_secs() / 60 % DAY_MINUTES;
+        let (start, end) = (
+            (now + DAY_MINUTES - 1) % DAY_MINUTES,
+            (now + 60) % DAY_MINUTES,
+        );
+        let window = format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        );
+        service.write().await.maintenance_windows = vec![window.parse().unwrap()];
+
+        let err = DerpClient::connect(&addr, SecretKey::gen())
+            .await
+            .err()
+            .expect("new connections should be refused");
+        assert_eq!(
+            err.to_string(),
+            "Server refused the Derp upgrade: 503 Service Unavailable"
+        );
+
+        sender
+            .send_packet(receiver.public_key(), b"still relayed".to_vec())
+            .await
+            .unwrap();
+        let (_, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("connected clients should keep their traffic")
+            .unwrap();
+        assert_eq!(payload, b"still relayed");
+    }
+
+    #[tokio::test]
+    async fn one_frame_reaches_every_destination() {
+        let (service, addr) = start_service(&["--max-fanout", "3"]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+            wait_for_peer(&service, receiver.public_key()).await;
+            receivers.push(receiver);
+        }
+        wait_for_peer(&service, sender.public_key()).await;
+        assert_eq!(sender.max_fanout(), Some(3));
+
+        let targets: Vec<_> = receivers.iter().map(DerpClient::public_key).collect();
+        sender
+            .send_packets(targets.clone(), b"to all".to_vec())
+            .await
+            .unwrap();
+        for receiver in &receivers {
+            let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("every destination should get the packet")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(payload, b"to all");
+        }
+
+        let too_many = [targets, vec![sender.public_key()]].concat();
+        assert!(sender.send_packets(too_many, b"x".to_vec()).await.is_err());
+    }
+
+    /// A sender, two destinations that read and one whose queue is full
+    async fn fanout_with_a_full_destination(
+        args: &[&str],
+    ) -> (
+        Arc<RwLock<DerpService>>,
+        DerpClient,
+        [DerpClient; 2],
+        WriteLanes,
+    ) {
+        let (service, addr) = start_service(args).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let a = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let b = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&sender, &a, &b] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+        let full = SecretKey::gen().public();
+        let (sink, lanes) = write_lanes(1);
+        sink.try_send(WriteLoopCommands::SendPacket {
+            source: sender.public_key(),
+            target: full,
+            ttl: 0,
+            payload: Bytes::from_static(b"queued"),
+        })
+        .unwrap();
+        service
+            .write()
+            .await
+            .peers
+            .insert(full, Peer::local(sink, ResumeToken::gen()));
+
+        let targets = vec![full, a.public_key(), b.public_key()];
+        sender
+            .send_packets(targets, b"to all".to_vec())
+            .await
+            .unwrap();
+        (service, sender, [a, b], lanes)
+    }
+
+    #[tokio::test]
+    async fn full_destination_is_skipped_in_a_fanout() {
+        let (service, sender, receivers, _lanes) = fanout_with_a_full_destination(&[]).await;
+        for receiver in &receivers {
+            let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("healthy destinations should get the packet")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(payload, b"to all");
+        }
+        assert_eq!(service.read().await.packets_dropped().slow_destination, 1);
+    }
+
+    #[tokio::test]
+    async fn full_destination_drops_the_whole_fanout() {
+        let (service, sender, receivers, _lanes) =
+            fanout_with_a_full_destination(&["--fanout-overflow", "drop-all"]).await;
+        wait_until(&service, |service| {
+            service.packets_dropped().slow_destination == 3
+        })
+        .await;
+
+        // Packets sent after the fanout only arrive if it was dropped
+        for receiver in &receivers {
+            sender
+                .send_packet(receiver.public_key(), b"after".to_vec())
+                .await
+                .unwrap();
+            let (_, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(payload, b"after");
+        }
+    }
+
+    #[tokio::test]
+    async fn fanout_destinations_share_the_payload() {
+        let (service, addr) = start_service(&[]).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, sender.public_key()).await;
+        let mut destinations = Vec::new();
+        for _ in 0..2 {
+            let pk = SecretKey::gen().public();
+            let (sink, lanes) = write_lanes(1);
+            service
+                .write()
+                .await
+                .peers
+                .insert(pk, Peer::local(sink, ResumeToken::gen()));
+            destinations.push((pk, lanes));
+        }
+
+        let targets = destinations.iter().map(|(pk, _)| *pk).collect();
+        sender
+            .send_packets(targets, b"to all".to_vec())
+            .await
+            .unwrap();
+        let mut payloads = Vec::new();
+        for (_, lanes) in &mut destinations {
+            match next_command(lanes).await {
+                WriteLoopCommands::SendPacket { payload, .. } => payloads.push(payload),
+                command => panic!("unexpected command: {command:?}"),
+            }
+        }
+        assert_eq!(payloads[0][..], b"to all"[..]);
+        assert_eq!(payloads[0].as_ptr(), payloads[1].as_ptr());
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_rate_limit_are_throttled() {
+        const PINGS: u8 = 30;
+
+        let (_service, addr) = start_service(&["--max-frames-per-sec", "20"]).await;
+        let (mut reader, mut w, _pk) = connect(addr).await;
+
+        let mut pings = Vec::new();
+        for i in 0..PINGS {
+            Ping { data: [i; 8] }.frame().encode(&mut pings).unwrap();
+        }
+        let start = Instant::now();
+        w.write_all(&pings).await.unwrap();
+        for i in 0..PINGS {
+            let pong = timeout(Duration::from_secs(5), reader.get_next_message())
+                .await
+                .expect("pings should be answered")
+                .unwrap();
+            assert_eq!(pong.buffer[5..], [i; 8]);
+        }
+        // The first 20 are a burst, the other 10 come in at 20 per second
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_rate_limit_disconnect_when_asked() {
+        let (service, addr) = start_service(&[
+            "--max-frames-per-sec",
+            "10",
+            "--frame-rate-action",
+            "disconnect",
+        ])
+        .await;
+        let (mut reader, mut w, pk) = connect(addr).await;
+        wait_for_peer(&service, pk).await;
+
+        // KeepAlive frames: type 0x06, no payload
+        w.write_all(&[0x06, 0, 0, 0, 0].repeat(50)).await.unwrap();
+        let closed = timeout(Duration::from_secs(5), reader.get_next_message())
+            .await
+            .expect("client should be disconnected");
+        assert!(closed.is_err());
+        wait_until(&service, |service| !service.peers.contains_key(&pk)).await;
+    }
+
+    #[tokio::test]
+    async fn in_process_clients_relay_packets() {
+        let config = Config::parse_from(["dersp", "--listen-on", "unused"]);
+        let service = DerpService::new(config).await.unwrap();
+        let sender = connect_in_process(service.clone(), SecretKey::gen())
+            .await
+            .unwrap();
+        let receiver = connect_in_process(service.clone(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+        assert_eq!(source, sender.public_key());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn in_process_clients_relay_in_both_directions() {
+        let config = Config::parse_from(["dersp", "--listen-on", "unused"]);
+        let service = DerpService::new(config).await.unwrap();
+        let a = connect_in_process(service.clone(), SecretKey::gen())
+            .await
+            .unwrap();
+        let b = connect_in_process(service.clone(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, a.public_key()).await;
+        wait_for_peer(&service, b.public_key()).await;
+
+        a.send_packet(b.public_key(), b"from a".to_vec())
+            .await
+            .unwrap();
+        b.send_packet(a.public_key(), b"from b".to_vec())
+            .await
+            .unwrap();
+
+        for (receiver, sender, expected) in [(&b, &a, b"from a"), (&a, &b, b"from b")] {
+            let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("packet should be relayed")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(payload, expected);
+        }
+    }
+
+    /// Connects like [`connect_in_process`], with the service seeing the client's bytes in
+    /// reads of at most `max` bytes
+    async fn connect_with_split_reads(service: Arc<RwLock<DerpService>>, max: usize) -> DerpClient {
+        let (client_stream, server_stream) = duplex(IN_PROCESS_BUFFER_SIZE);
+        spawn(handle_client(
+            FaultyStream::new(server_stream).split_reads(max),
+            None,
+            service,
+        ));
+        DerpClient::connect_stream(client_stream, SecretKey::gen())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_and_relay_work_with_reads_split_at_awkward_boundaries() {
+        let config = Config::parse_from(["dersp", "--listen-on", "unused"]);
+        let service = DerpService::new(config).await.unwrap();
+        // 1 byte splits every header, 7 bytes never lines up with a frame or key boundary
+        let sender = connect_with_split_reads(service.clone(), 1).await;
+        let receiver = connect_with_split_reads(service.clone(), 7).await;
+        wait_for_peer(&service, sender.public_key()).await;
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        for payload in [b"hello".to_vec(), vec![7; 1000]] {
+            sender
+                .send_packet(receiver.public_key(), payload.clone())
+                .await
+                .unwrap();
+            let (source, received) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("packet should be relayed")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(received, payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_traced_with_trace_frames() {
+        capture_logs();
+        let (service, addr) = start_service(&["--trace-frames"]).await;
+        let (_reader, mut writer, pk) = connect(addr).await;
+        wait_for_peer(&service, pk).await;
+
+        // KeepAlive frame: type 0x06, no payload
+        writer.write_all(&[0x06, 0, 0, 0, 0]).await.unwrap();
+        wait_for_log(&format!(
+            "[{}] received KeepAlive (0 bytes)",
+            pk.log_display()
+        ))
+        .await;
+    }
+
+    #[tokio::test]
+    async fn resumed_client_receives_queued_packets() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let receiver_sk = SecretKey::gen();
+        let receiver_pk = receiver_sk.public();
+        let receiver = DerpClient::connect(&addr, receiver_sk.clone())
+            .await
+            .unwrap();
+        let token = receiver
+            .resume_token()
+            .expect("server should issue a resume token");
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, receiver_pk).await;
+        wait_for_peer(&service, sender.public_key()).await;
+
+        drop(receiver);
+        timeout(Duration::from_secs(5), async {
+            while queued_packets(&service, receiver_pk).await.is_none() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("disconnected client should be resumable");
+
+        sender
+            .send_packet(receiver_pk, b"while away".to_vec())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while queued_packets(&service, receiver_pk).await != Some(1) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("packet should be queued");
+
+        let receiver = DerpClient::resume(&addr, receiver_sk, token).await.unwrap();
+        let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("queued packet should be replayed")
+            .unwrap();
+        assert_eq!(source, sender.public_key());
+        assert_eq!(payload, b"while away");
+        assert!(!service.read().await.resumable.contains_key(&receiver_pk));
+    }
+
+    #[tokio::test]
+    async fn clients_connect_through_any_acceptor() {
+        let (service, addr) = start_service(&["--acceptors", "4"]).await;
+        let mut clients = Vec::new();
+        for _ in 0..16 {
+            clients.push(
+                DerpClient::connect(&addr.to_string(), SecretKey::gen())
+                    .await
+                    .unwrap(),
+            );
+        }
+        for client in &clients {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+        assert_eq!(service.read().await.connected_peers().len(), clients.len());
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_addr_is_canonical() {
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:4000".parse().unwrap();
+        let plain: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(canonical_peer_addr(mapped), plain);
+        assert_eq!(canonical_peer_addr(plain), plain);
+
+        let v6: SocketAddr = "[::1]:4000".parse().unwrap();
+        assert_eq!(canonical_peer_addr(v6), v6);
+    }
+
+    #[tokio::test]
+    async fn mapped_and_plain_ipv4_share_a_failure_bucket() {
+        // Dual-stack, IPv4 clients are accepted as ::ffff:127.0.0.1
+        let config =
+            Config::parse_from(["dersp", "--listen-on", "[::]:0", "--max-auth-failures", "2"]);
+        let listener = bind(&config).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = DerpService::new(config).await.unwrap();
+        let runner = service.clone();
+        spawn(async move { runner.run(listener).await });
+
+        let plain = SocketAddr::from(([127, 0, 0, 1], port));
+        let mapped: SocketAddr = format!("[::ffff:127.0.0.1]:{port}").parse().unwrap();
+        send_undecryptable_client_info(plain).await;
+        send_undecryptable_client_info(mapped).await;
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 2
+        })
+        .await;
+        {
+            let service = service.read().await;
+            let failures = service.handshake_failures.lock().unwrap();
+            assert_eq!(failures.by_ip.len(), 1);
+            assert_eq!(failures.auth_failures.len(), 1);
+            assert_eq!(failures.auth_failures[&plain.ip()].failures, 2);
+        }
+
+        // Either form is refused now
+        for addr in [plain, mapped] {
+            let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+            assert!(connect_http(&mut r, &mut w, Transport::Derp).await.is_err());
+        }
+        assert_eq!(service.read().await.handshake_failures().refused, 2);
+    }
+
+    #[tokio::test]
+    async fn preferred_flag_follows_the_client() {
+        let (service, addr) = start_service(&[]).await;
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let pk = client.public_key();
+        wait_for_peer(&service, pk).await;
+        assert!(service.read().await.preferred_peers().is_empty());
+
+        client.set_preferred(true).await.unwrap();
+        wait_until(&service, |service| service.preferred_peers() == vec![pk]).await;
+
+        client.set_preferred(false).await.unwrap();
+        wait_until(&service, |service| service.preferred_peers().is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn local_peers_are_counted_by_protocol_version() {
+        let (service, addr) = start_service(&[]).await;
+        let (_reader, _writer, current) = connect(addr).await;
+        wait_for_peer(&service, current).await;
+
+        // Older clients only differ in the version of their ClientInfo
+        let mut old_clients = Vec::new();
+        for _ in 0..2 {
+            let (client_stream, server_stream) = duplex(1024);
+            let pk = SecretKey::gen().public();
+            let handshake = ClientHandshake {
+                public_key: pk,
+                meshkey: None,
+                resume_token: None,
+                version: 1,
+                send_acks: false,
+            };
+            service
+                .write()
+                .await
+                .add_new_client(server_stream, handshake, ResumeToken::gen())
+                .await
+                .unwrap();
+            old_clients.push((client_stream, pk));
+        }
+        assert_eq!(
+            service.read().await.protocol_versions(),
+            BTreeMap::from([(1, 2), (PROTOCOL_VERSION, 1)])
+        );
+
+        let (client_stream, gone) = old_clients.pop().unwrap();
+        drop(client_stream);
+        wait_until(&service, |service| !service.peers.contains_key(&gone)).await;
+        assert_eq!(
+            service.read().await.protocol_versions(),
+            BTreeMap::from([(1, 1), (PROTOCOL_VERSION, 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn presence_of_a_connected_peer_is_reported() {
+        let (service, addr) = start_service(&[]).await;
+        let before = SystemTime::now();
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let pk = client.public_key();
+        wait_for_peer(&service, pk).await;
+        let after = SystemTime::now();
+        client.set_preferred(true).await.unwrap();
+        wait_until(&service, |service| service.preferred_peers() == vec![pk]).await;
+
+        let presence = service.read().await.presence(&pk).unwrap();
+        assert_eq!(presence.via, None);
+        assert!(presence.preferred);
+        assert!(before <= presence.since && presence.since <= after);
+        assert_eq!(
+            service.read().await.presence(&SecretKey::gen().public()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn stuck_handshake_is_listed_and_can_be_cancelled() {
+        let (service, addr) = start_service(&[]).await;
+        // Never sends its upgrade request
+        let mut stuck = TcpStream::connect(addr).await.unwrap();
+        let peer_addr = stuck.local_addr().unwrap();
+        wait_until(&service, |service| {
+            service
+                .handshakes()
+                .iter()
+                .any(|handshake| handshake.peer_addr == peer_addr)
+        })
+        .await;
+        assert!(service.read().await.connected_peers().is_empty());
+
+        assert!(service.read().await.cancel_handshake(peer_addr));
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), stuck.read(&mut buf))
+            .await
+            .expect("cancelling should close the connection");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(service.read().await.handshakes().is_empty());
+        assert!(!service.read().await.cancel_handshake(peer_addr));
+    }
+
+    #[tokio::test]
+    async fn slow_handshake_is_logged_with_its_slowest_phase() {
+        capture_logs();
+        let (_service, addr) = start_service(&["--slow-handshake-threshold", "100ms"]).await;
+        let sk = SecretKey::gen();
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        let leftovers = connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(r));
+        // The server waits for the ClientInfo meanwhile
+        sleep(Duration::from_millis(200)).await;
+        let server_key = exchange_keys(&mut reader, &mut w, &sk, None, None, false)
+            .await
+            .unwrap();
+        read_server_info(&mut reader, &sk, server_key)
+            .await
+            .unwrap();
+
+        let needle = format!("Slow handshake with {}", sk.public().log_display());
+        let line = wait_for_log(&needle).await;
+        assert!(line.contains("mostly in the ClientInfo read"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn clients_connect_with_crypto_threads() {
+        let (service, addr) = start_service(&["--crypto-threads", "1"]).await;
+        let addr = addr.to_string();
+        // More handshakes than threads, they wait their turn
+        let mut clients = Vec::new();
+        for _ in 0..4 {
+            clients.push(DerpClient::connect(&addr, SecretKey::gen()));
+        }
+        let mut clients = futures_util::future::try_join_all(clients).await.unwrap();
+        let receiver = clients.pop().unwrap();
+        let sender = clients.pop().unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        let (source, payload) = receiver.recv_packet().await.unwrap();
+        assert_eq!(source, sender.public_key());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn acks_tell_enqueued_from_dropped_packets() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect_with_acks(&addr, SecretKey::gen())
+            .await
+            .unwrap();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, sender.public_key()).await;
+        wait_for_peer(&service, receiver.public_key()).await;
+        let absent = SecretKey::gen().public();
+
+        for target in [receiver.public_key(), absent] {
+            sender.send_packet(target, b"hello".to_vec()).await.unwrap();
+        }
+        let mut acks = Vec::new();
+        for _ in 0..2 {
+            let ack = timeout(Duration::from_secs(5), sender.recv_ack())
+                .await
+                .expect("packets should be acked")
+                .unwrap();
+            acks.push(ack);
+        }
+        assert_eq!(
+            acks,
+            [
+                SendAck {
+                    target: receiver.public_key(),
+                    status: SendStatus::Enqueued
+                },
+                SendAck {
+                    target: absent,
+                    status: SendStatus::Dropped
+                },
+            ]
+        );
+        // Only those asking for acks get them
+        assert!(receiver.recv_ack().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_clients_connect() {
+        let (service, addr) = start_service(&[]).await;
+        let mut events = service.read().await.subscribe();
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("connect should be reported")
+            .unwrap();
+        assert_eq!(event, Event::Connect(client.public_key()));
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_counts() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, sender.public_key()).await;
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("packet should be relayed")
+            .unwrap();
+
+        let summary = service.write().await.shutdown().await;
+        assert_eq!(summary.connected_clients, 2);
+        assert_eq!(summary.total_clients, 2);
+        assert_eq!(summary.peak_concurrency, 2);
+        assert_eq!(summary.frames_forwarded, 1);
+        assert!(summary.uptime > Duration::ZERO);
+
+        let closed = timeout(Duration::from_secs(5), receiver.recv_packet())
+            .await
+            .expect("shutdown should disconnect clients");
+        assert!(closed.is_err());
+    }
+
+    #[tokio::test]
+    async fn build_info_and_uptime_are_reported() {
+        let (service, _addr) = start_service(&[]).await;
+        sleep(Duration::from_millis(10)).await;
+
+        assert!(service.read().await.uptime() >= Duration::from_millis(10));
+        assert_eq!(BUILD_INFO.version, env!("CARGO_PKG_VERSION"));
+        assert!(BUILD_INFO
+            .to_string()
+            .starts_with(&format!("dersp {}", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_not_held_up_by_stalled_clients() {
+        let (service, addr) = start_service(&[]).await;
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+        // Clients whose write loops never take anything from their full queues
+        let mut stalled = Vec::new();
+        {
+            let mut service = service.write().await;
+            for _ in 0..4 {
+                let (sink, lanes) = write_lanes(1);
+                sink.try_send(WriteLoopCommands::Frame(Vec::new())).unwrap();
+                service.peers.insert(
+                    SecretKey::gen().public(),
+                    Peer::local(sink, ResumeToken::gen()),
+                );
+                stalled.push(lanes);
+            }
+        }
+        let command_sender = service.read().await.command_sender.clone();
+
+        let start = Instant::now();
+        let summary = service.write().await.shutdown().await;
+        assert_eq!(summary.connected_clients, 5);
+        let closed = timeout(Duration::from_secs(1), client.recv_packet())
+            .await
+            .expect("shutdown should disconnect responsive clients right away");
+        assert!(closed.is_err());
+        timeout(2 * SHUTDOWN_STOP_TIMEOUT, command_sender.closed())
+            .await
+            .expect("shutdown should finish despite stalled clients");
+        assert!(start.elapsed() >= SHUTDOWN_STOP_TIMEOUT);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn watchers_past_the_limit_are_refused() {
+        use crate::proto::write_watch_conns;
+
+        capture_logs();
+        let (service, addr) = start_service(&["--meshkey", "secret", "--max-watchers", "1"]).await;
+        // The readers aren't needed, the connections stay open with their write halves
+        let (_, mut first_writer, first) = connect_with_meshkey(addr, Some("secret")).await;
+        let (_, mut second_writer, second) = connect_with_meshkey(addr, Some("secret")).await;
+        wait_for_peer(&service, first).await;
+        wait_for_peer(&service, second).await;
+
+        write_watch_conns(&mut first_writer).await.unwrap();
+        wait_until(&service, |service| service.mesh.contains_key(&first)).await;
+
+        write_watch_conns(&mut second_writer).await.unwrap();
+        wait_for_log(&format!("Refusing WatchConns from {second:?}")).await;
+        assert!(!service.read().await.mesh.contains_key(&second));
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn only_trusted_relay_keys_get_mesh_privileges() {
+        use crate::proto::write_watch_conns;
+
+        capture_logs();
+        let relay_sk = SecretKey::gen();
+        let (service, addr) =
+            start_service(&["--mesh-trusted-keys", &relay_sk.public().to_string()]).await;
+        let (_, mut trusted_writer, trusted) = connect_as(addr, relay_sk, None).await;
+        let (_, mut untrusted_writer, untrusted) = connect(addr).await;
+        wait_for_peer(&service, trusted).await;
+        wait_for_peer(&service, untrusted).await;
+
+        write_watch_conns(&mut trusted_writer).await.unwrap();
+        wait_until(&service, |service| service.mesh.contains_key(&trusted)).await;
+
+        write_watch_conns(&mut untrusted_writer).await.unwrap();
+        wait_for_log(&format!(
+            "[{}] Refusing WatchConns without mesh privileges",
+            untrusted.log_display()
+        ))
+        .await;
+        assert!(!service.read().await.mesh.contains_key(&untrusted));
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn listed_peers_include_other_clients() {
+        let (service, addr) = start_service(&["--meshkey", "secret"]).await;
+        let addr = addr.to_string();
+        let other = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let watcher = DerpClient::connect_with_meshkey(&addr, SecretKey::gen(), "secret")
+            .await
+            .unwrap();
+        wait_for_peer(&service, other.public_key()).await;
+        wait_for_peer(&service, watcher.public_key()).await;
+
+        let peers = timeout(Duration::from_secs(5), watcher.list_peers())
+            .await
+            .expect("roster should complete")
+            .unwrap();
+        assert!(peers.contains(&other.public_key()));
+
+        // Asking again gets a fresh roster
+        drop(other);
+        wait_until(&service, |service| service.peers.len() == 1).await;
+        let peers = timeout(Duration::from_secs(5), watcher.list_peers())
+            .await
+            .expect("roster should complete")
+            .unwrap();
+        // Watchers are relays, they aren't part of the roster
+        assert!(peers.is_empty());
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn only_presence_is_relayed_without_forwarding() {
+        let (service, addr) = start_service(&["--no-forwarding", "--meshkey", "secret"]).await;
+        let addr = addr.to_string();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let watcher = DerpClient::connect_with_meshkey(&addr, SecretKey::gen(), "secret")
+            .await
+            .unwrap();
+        assert!(!sender.forwards_packets());
+        wait_for_peer(&service, receiver.public_key()).await;
+        wait_for_peer(&service, sender.public_key()).await;
+
+        sender
+            .send_packet(receiver.public_key(), b"hello".to_vec())
+            .await
+            .unwrap();
+        wait_until(&service, |service| {
+            service.packets_dropped().forwarding_disabled == 1
+        })
+        .await;
+        assert!(timeout(Duration::from_millis(100), receiver.recv_packet())
+            .await
+            .is_err());
+
+        let peers = timeout(Duration::from_secs(5), watcher.list_peers())
+            .await
+            .expect("roster should complete")
+            .unwrap();
+        assert!(peers.contains(&receiver.public_key()));
+        assert!(peers.contains(&sender.public_key()));
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn mirror_receives_copies_of_matching_packets_only() {
+        use crate::proto::{data::ForwardPacket, write_mirror_packets};
+
+        let (service, addr) = start_service(&["--meshkey", "secret", "--allow-mirroring"]).await;
+        let addr_str = addr.to_string();
+        let watched = DerpClient::connect(&addr_str, SecretKey::gen())
+            .await
+            .unwrap();
+        let other = DerpClient::connect(&addr_str, SecretKey::gen())
+            .await
+            .unwrap();
+        let receiver = DerpClient::connect(&addr_str, SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        let (mut observer, mut observer_writer, _) =
+            connect_with_meshkey(addr, Some("secret")).await;
+        write_mirror_packets(&mut observer_writer, watched.public_key())
+            .await
+            .unwrap();
+        wait_until(&service, |service| !service.mirrors.is_empty()).await;
+
+        other
+            .send_packet(receiver.public_key(), b"unwatched".to_vec())
+            .await
+            .unwrap();
+        let (_, payload) = receiver.recv_packet().await.unwrap();
+        assert_eq!(payload, b"unwatched");
+        watched
+            .send_packet(receiver.public_key(), b"watched".to_vec())
+            .await
+            .unwrap();
+
+        let copy = timeout(Duration::from_secs(5), observer.get_next_message())
+            .await
+            .expect("observer should get a copy")
+            .unwrap();
+        assert_eq!(copy.ty, FrameType::ForwardPacket);
+        let copy = ForwardPacket::decode_from(PROTOCOL_VERSION, &copy.buffer).unwrap();
+        assert_eq!(copy.source, watched.public_key());
+        assert_eq!(copy.target, receiver.public_key());
+        assert_eq!(copy.payload, b"watched");
+    }
+
+    #[tokio::test]
+    async fn sender_over_the_destination_limit_is_flagged_and_dropped() {
+        capture_logs();
+        let (service, addr) = start_service(&[
+            "--max-destinations",
+            "2",
+            "--max-destinations-action",
+            "drop",
+        ])
+        .await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+            wait_for_peer(&service, receiver.public_key()).await;
+            receivers.push(receiver);
+        }
+
+        for receiver in &receivers {
+            sender
+                .send_packet(receiver.public_key(), b"scan".to_vec())
+                .await
+                .unwrap();
+        }
+        wait_for_log(&format!(
+            "[{}] sent to over 2 destinations",
+            sender.public_key().log_display()
+        ))
+        .await;
+        // Destinations seen before the limit are still reachable
+        sender
+            .send_packet(receivers[0].public_key(), b"known".to_vec())
+            .await
+            .unwrap();
+
+        for receiver in &receivers[..2] {
+            let (_, payload) = receiver.recv_packet().await.unwrap();
+            assert_eq!(payload, b"scan");
+        }
+        let (_, payload) = receivers[0].recv_packet().await.unwrap();
+        assert_eq!(payload, b"known");
+        assert!(
+            timeout(Duration::from_millis(200), receivers[2].recv_packet())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn packets_to_unknown_destinations_are_counted() {
+        let (service, addr) = start_service(&[]).await;
+        let sender = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, sender.public_key()).await;
+
+        sender
+            .send_packet(SecretKey::gen().public(), b"nobody".to_vec())
+            .await
+            .unwrap();
+        wait_until(&service, |service| {
+            service.packets_dropped().unknown_destination == 1
+        })
+        .await;
+        assert_eq!(service.read().await.packets_dropped().queue_overflow, 0);
+    }
+
+    #[tokio::test]
+    async fn overflowing_resume_queue_is_counted() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let receiver_sk = SecretKey::gen();
+        let receiver_pk = receiver_sk.public();
+        let receiver = DerpClient::connect(&addr, receiver_sk).await.unwrap();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, receiver_pk).await;
+        wait_for_peer(&service, sender.public_key()).await;
+
+        drop(receiver);
+        wait_until(&service, |service| {
+            service.resumable.contains_key(&receiver_pk)
+        })
+        .await;
+
+        for _ in 0..RESUME_QUEUE_SIZE + 2 {
+            sender
+                .send_packet(receiver_pk, b"while away".to_vec())
+                .await
+                .unwrap();
+        }
+        wait_until(&service, |service| {
+            service.packets_dropped().queue_overflow == 2
+        })
+        .await;
+        assert_eq!(
+            queued_packets(&service, receiver_pk).await,
+            Some(RESUME_QUEUE_SIZE)
+        );
+        assert_eq!(
+            service.read().await.packets_dropped().unknown_destination,
+            0
+        );
+    }
+
+    #[test]
+    fn repeated_handshake_failures_are_collapsed() {
+        capture_logs();
+        let window = Duration::from_millis(50);
+        let mut failures = HandshakeFailures::new(window, None, window);
+        let scanner: IpAddr = "192.0.2.7".parse().unwrap();
+        let client: IpAddr = "192.0.2.8".parse().unwrap();
+
+        for port in 0..20 {
+            failures.record(
+                SocketAddr::new(scanner, 4000 + port),
+                &anyhow!("bad upgrade"),
+            );
+        }
+        failures.record(SocketAddr::new(client, 4000), &anyhow!("bad upgrade"));
+        assert_eq!(count_logs("Client 192.0.2.7:"), 1);
+        assert_eq!(count_logs("Client 192.0.2.8:"), 1);
+
+        std::thread::sleep(window);
+        failures.flush(Instant::now());
+        assert_eq!(count_logs("20 handshake failures from 192.0.2.7"), 1);
+        assert_eq!(count_logs("handshake failures from 192.0.2.8"), 0);
+        assert!(failures.by_ip.is_empty());
+    }
+
+    /// Completes the upgrade, then sends a ClientInfo sealed for another server key
+    async fn send_undecryptable_client_info(addr: SocketAddr) {
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        let leftovers = connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(r));
+        let server_key = reader.get_next_message().await.unwrap();
+        assert_eq!(server_key.ty, FrameType::ServerKey);
+
+        let client_info = ClientInfo::new(
+            &SecretKey::gen(),
+            SecretKey::gen().public(),
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        client_info.frame().encode(&mut buf).unwrap();
+        w.write_all(&buf).await.unwrap();
+        // The server closes the connection once it gave up on it
+        let _ = reader.get_next_message().await;
+    }
+
+    /// Seals the ClientInfo for `server_key` whatever key the server presents, like a client
+    /// that knew the key from before, and returns the ServerInfo
+    async fn handshake_with_key(
+        addr: SocketAddr,
+        server_key: PublicKey,
+    ) -> anyhow::Result<ServerInfoPayload> {
+        let sk = SecretKey::gen();
+        let client_info = ClientInfo::new(&sk, server_key, None, None, false)?;
+        send_client_info(addr, &sk, server_key, client_info).await
+    }
+
+    /// Sends `client_info` of the client `sk` as is, e.g. a second time, and returns the
+    /// ServerInfo
+    async fn send_client_info(
+        addr: SocketAddr,
+        sk: &SecretKey,
+        server_key: PublicKey,
+        client_info: ClientInfo,
+    ) -> anyhow::Result<ServerInfoPayload> {
+        let (mut r, mut w) = TcpStream::connect(addr).await?.into_split();
+        let leftovers = connect_http(&mut r, &mut w, Transport::Derp).await?;
+        let mut reader = DerpReader::new(Cursor::new(leftovers).chain(r));
+        let presented = reader.get_next_message().await?;
+        assert_eq!(presented.ty, FrameType::ServerKey);
+
+        let mut buf = Vec::new();
+        client_info.frame().encode(&mut buf)?;
+        w.write_all(&buf).await?;
+        read_server_info(&mut reader, sk, server_key).await
+    }
+
+    #[tokio::test]
+    async fn old_and_new_server_key_are_accepted_during_a_key_rotation() {
+        let (service, addr) = start_service(&[]).await;
+        let old_key = service.read().await.server_key();
+        let new_key = SecretKey::gen();
+        service
+            .write()
+            .await
+            .begin_key_rotation(new_key.clone(), Duration::from_secs(60));
+        assert_eq!(service.read().await.server_key(), new_key.public());
+
+        handshake_with_key(addr, old_key)
+            .await
+            .expect("the old key should be accepted during the grace period");
+        handshake_with_key(addr, new_key.public())
+            .await
+            .expect("the new key should be accepted");
+        // Clients taking the key from the ServerKey frame get the new one
+        DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn old_server_key_is_refused_after_the_grace_period() {
+        let (service, addr) = start_service(&[]).await;
+        let old_key = service.read().await.server_key();
+        service
+            .write()
+            .await
+            .begin_key_rotation(SecretKey::gen(), Duration::ZERO);
+
+        assert!(handshake_with_key(addr, old_key).await.is_err());
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 1
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn replayed_client_info_is_refused_for_either_key() {
+        let (service, addr) = start_service(&[]).await;
+        let old_key = service.read().await.server_key();
+        service
+            .write()
+            .await
+            .begin_key_rotation(SecretKey::gen(), Duration::from_secs(60));
+        let new_key = service.read().await.server_key();
+
+        for server_key in [old_key, new_key] {
+            let sk = SecretKey::gen();
+            let client_info = ClientInfo::new(&sk, server_key, None, None, false).unwrap();
+            send_client_info(addr, &sk, server_key, client_info.clone())
+                .await
+                .expect("the first ClientInfo should be accepted");
+            assert!(send_client_info(addr, &sk, server_key, client_info)
+                .await
+                .is_err());
+        }
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 2
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn undecryptable_client_info_counts_as_authentication_failure() {
+        let (service, addr) = start_service(&[]).await;
+
+        send_undecryptable_client_info(addr).await;
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 1
+        })
+        .await;
+        assert_eq!(
+            service.read().await.handshake_failures(),
+            HandshakeFailureCounts {
+                authentication: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_authentication_failures_refuse_the_ip() {
+        let (service, addr) = start_service(&["--max-auth-failures", "2"]).await;
+        for _ in 0..2 {
+            send_undecryptable_client_info(addr).await;
+        }
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 2
+        })
+        .await;
+
+        // Closed without an answer to the upgrade request
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        assert!(connect_http(&mut r, &mut w, Transport::Derp).await.is_err());
+        assert_eq!(service.read().await.handshake_failures().refused, 1);
+    }
+
+    #[tokio::test]
+    async fn authentication_failures_are_unlimited_by_default() {
+        let (service, addr) = start_service(&[]).await;
+        for _ in 0..20 {
+            send_undecryptable_client_info(addr).await;
+        }
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 20
+        })
+        .await;
+
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+        assert_eq!(service.read().await.handshake_failures().refused, 0);
+    }
+
+    #[tokio::test]
+    async fn refused_ip_is_accepted_after_the_auth_failure_window() {
+        let (service, addr) =
+            start_service(&["--max-auth-failures", "1", "--auth-failure-window", "300ms"]).await;
+        send_undecryptable_client_info(addr).await;
+        wait_until(&service, |service| {
+            service.handshake_failures().authentication == 1
+        })
+        .await;
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        assert!(connect_http(&mut r, &mut w, Transport::Derp).await.is_err());
+
+        sleep(Duration::from_millis(400)).await;
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout_bounds_the_whole_handshake() {
+        let (_service, addr) = start_service(&[
+            "--handshake-timeout",
+            "300ms",
+            "--client-info-timeout",
+            "10s",
+        ])
+        .await;
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        connect_http(&mut r, &mut w, Transport::Derp).await.unwrap();
+
+        // No ClientInfo follows, the server gives up long before the ClientInfo timeout
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), r.read_to_end(&mut rest))
+            .await
+            .expect("server should close the connection")
+            .unwrap();
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn new_watcher_receives_the_whole_roster() {
+        use std::collections::HashSet;
+
+        const PEERS: usize = 5000;
+
+        let (service, _addr) = start_service(&["--roster-chunk-size", "100"]).await;
+        let (peer_sink, _peer_receiver) = write_lanes(1);
+        let relay = SecretKey::gen().public();
+        let mut keys = TestKeys::default();
+        let mut roster = HashSet::new();
+        {
+            let mut service = service.write().await;
+            for _ in 0..PEERS {
+                let pk = keys.next_key().public();
+                roster.insert(pk);
+                service
+                    .peers
+                    .insert(pk, Peer::remote(peer_sink.clone(), relay));
+            }
+        }
+
+        // The watcher only has a few frames queued, the rest waits for it to read
+        let (watcher_sink, mut watcher) = write_lanes(1);
+        let command_sender = service.read().await.command_sender.clone();
+        command_sender
+            .send(ServiceCommand::SubscribeForPeerChanges(
+                SecretKey::gen().public(),
+                watcher_sink,
+            ))
+            .await
+            .unwrap();
+
+        let mut received = HashSet::new();
+        while received.len() < PEERS {
+            match next_command(&mut watcher).await {
+                WriteLoopCommands::PeerPresent(pk) => assert!(received.insert(pk)),
+                command => panic!("unexpected command: {command:?}"),
+            }
+        }
+        assert_eq!(received, roster);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn roster_dumps_take_turns() {
+        const PEERS: usize = 100;
+        const WATCHERS: usize = 5;
+
+        let (service, _addr) = start_service(&["--max-roster-dumps", "1"]).await;
+        let (peer_sink, _peer_receiver) = write_lanes(1);
+        {
+            let mut service = service.write().await;
+            let mut keys = TestKeys::default();
+            for _ in 0..PEERS {
+                service.peers.insert(
+                    keys.next_key().public(),
+                    Peer::local(peer_sink.clone(), ResumeToken::gen()),
+                );
+            }
+        }
+
+        // Nobody reads yet, the first roster fills its watcher's queue and holds the permit
+        let command_sender = service.read().await.command_sender.clone();
+        let mut watchers = Vec::new();
+        for _ in 0..WATCHERS {
+            let (watcher_sink, watcher) = write_lanes(1);
+            command_sender
+                .send(ServiceCommand::SubscribeForPeerChanges(
+                    SecretKey::gen().public(),
+                    watcher_sink,
+                ))
+                .await
+                .unwrap();
+            watchers.push(watcher);
+        }
+        sleep(Duration::from_millis(100)).await;
+        let mut started = 0;
+        for watcher in &mut watchers {
+            if let Ok(first) = timeout(Duration::from_millis(50), watcher.recv()).await {
+                assert!(matches!(first, Some(WriteLoopCommands::PeerPresent(_))));
+                started += 1;
+            }
+        }
+        assert_eq!(started, 1, "only one roster should be sent at a time");
+
+        // Reading lets the rosters go through one after the other
+        let mut rosters = Vec::new();
+        for mut watcher in watchers {
+            rosters.push(spawn(async move {
+                let mut received = 0;
+                loop {
+                    match next_command(&mut watcher).await {
+                        WriteLoopCommands::PeerPresent(_) => received += 1,
+                        WriteLoopCommands::RosterComplete => return received,
+                        command => panic!("unexpected command: {command:?}"),
+                    }
+                }
+            }));
+        }
+        let mut received = Vec::new();
+        for roster in rosters {
+            received.push(roster.await.unwrap());
+        }
+        // The frame taken to see which roster started isn't counted
+        received.sort_unstable();
+        assert_eq!(received[0], PEERS - 1);
+        assert!(received[1..].iter().all(|&count| count == PEERS));
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn roster_complete_prunes_peers_the_relay_did_not_announce_again() {
+        let (service, _addr) = start_service(&[]).await;
+        let relay = SecretKey::gen().public();
+        let (old_link, _old_receiver) = write_lanes(16);
+        let (stays, leaves) = (SecretKey::gen().public(), SecretKey::gen().public());
+        {
+            let mut service = service.write().await;
+            for pk in [stays, leaves] {
+                service
+                    .peers
+                    .insert(pk, Peer::remote(old_link.clone(), relay));
+            }
+        }
+
+        // The relay reconnected and announces one of its peers again
+        let (new_link, mut new_receiver) = write_lanes(16);
+        let command_sender = service.read().await.command_sender.clone();
+        command_sender
+            .send(ServiceCommand::SubscribeForPeerChanges(
+                relay,
+                new_link.clone(),
+            ))
+            .await
+            .unwrap();
+        command_sender
+            .send(ServiceCommand::PeerPresent(stays, relay, new_link.clone()))
+            .await
+            .unwrap();
+        wait_until(&service, |service| {
+            service.peers[&stays].sink.same_channel(&new_link)
+        })
+        .await;
+        // Its peers aren't announced back to it
+        assert!(matches!(
+            next_command(&mut new_receiver).await,
+            WriteLoopCommands::RosterComplete
+        ));
+        // The rest of its roster may still come
+        sleep(Duration::from_millis(100)).await;
+        assert!(service.read().await.peers.contains_key(&leaves));
+
+        command_sender
+            .send(ServiceCommand::RosterComplete(relay, new_link.clone()))
+            .await
+            .unwrap();
+        wait_until(&service, |service| !service.peers.contains_key(&leaves)).await;
+        assert!(service.read().await.peers[&stays]
+            .sink
+            .same_channel(&new_link));
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn roster_complete_follows_the_initial_roster_once() {
+        use std::collections::HashSet;
+
+        let (service, addr) = start_service(&["--roster-chunk-size", "2"]).await;
+        let (peer_sink, _peer_receiver) = write_lanes(1);
+        let mut roster = HashSet::new();
+        {
+            let mut service = service.write().await;
+            for _ in 0..5 {
+                let pk = SecretKey::gen().public();
+                roster.insert(pk);
+                service
+                    .peers
+                    .insert(pk, Peer::local(peer_sink.clone(), ResumeToken::gen()));
+            }
+        }
+
+        let (watcher_sink, mut watcher) = write_lanes(16);
+        let command_sender = service.read().await.command_sender.clone();
+        command_sender
+            .send(ServiceCommand::SubscribeForPeerChanges(
+                SecretKey::gen().public(),
+                watcher_sink,
+            ))
+            .await
+            .unwrap();
+
+        let mut received = HashSet::new();
+        loop {
+            match next_command(&mut watcher).await {
+                WriteLoopCommands::PeerPresent(pk) => assert!(received.insert(pk)),
+                WriteLoopCommands::RosterComplete => break,
+                command => panic!("unexpected command: {command:?}"),
+            }
+        }
+        assert_eq!(received, roster);
+
+        // Live updates come without another marker
+        let (_reader, _writer, pk) = connect(addr).await;
+        assert!(matches!(
+            next_command(&mut watcher).await,
+            WriteLoopCommands::PeerPresent(present) if present == pk
+        ));
+        assert!(timeout(Duration::from_millis(200), watcher.recv())
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn mesh_peers_are_reached_by_hostname() {
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let by_name = format!("localhost:{}", b_addr.port());
+        let (a, _a_addr) = start_service(&["--meshkey", "secret", "--mesh-peers", &by_name]).await;
+
+        wait_until(&a, |a| a.mesh.len() == 1).await;
+        wait_until(&b, |b| b.mesh.len() == 1).await;
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn packets_are_forwarded_both_ways_between_meshed_relays() {
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let b_addr = b_addr.to_string();
+        let (a, a_addr) = start_service(&["--meshkey", "secret", "--mesh-peers", &b_addr]).await;
+
+        let on_a = DerpClient::connect(&a_addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let on_b = DerpClient::connect(&b_addr, SecretKey::gen())
+            .await
+            .unwrap();
+        wait_until(&a, |a| a.peers.contains_key(&on_b.public_key())).await;
+        wait_until(&b, |b| b.peers.contains_key(&on_a.public_key())).await;
+
+        on_a.send_packet(on_b.public_key(), b"from a".to_vec())
+            .await
+            .unwrap();
+        on_b.send_packet(on_a.public_key(), b"from b".to_vec())
+            .await
+            .unwrap();
+        for (receiver, sender, expected) in [(&on_b, &on_a, b"from a"), (&on_a, &on_b, b"from b")] {
+            let (source, payload) = timeout(Duration::from_secs(5), receiver.recv_packet())
+                .await
+                .expect("packet should be forwarded over the mesh")
+                .unwrap();
+            assert_eq!(source, sender.public_key());
+            assert_eq!(payload, expected);
+        }
+        assert_eq!(a.read().await.packets_dropped().ttl_expired, 0);
+    }
+
+    #[tokio::test]
+    async fn packets_take_the_relay_with_the_lowest_rtt() {
+        let (service, addr) = start_service(&[]).await;
+        let command_sender = service.read().await.command_sender.clone();
+        let mut keys = TestKeys::default();
+        let (slow_relay, fast_relay) = (keys.next_key().public(), keys.next_key().public());
+        let (to_slow, mut slow) = write_lanes(1);
+        let (to_fast, mut fast) = write_lanes(1);
+        // Announced by both relays, the slower one first
+        let target = keys.next_key().public();
+        for command in [
+            ServiceCommand::PeerPresent(target, slow_relay, to_slow),
+            ServiceCommand::PeerPresent(target, fast_relay, to_fast.clone()),
+            ServiceCommand::MeshRtt(slow_relay, Duration::from_millis(80)),
+            ServiceCommand::MeshRtt(fast_relay, Duration::from_millis(5)),
+        ] {
+            command_sender.send(command).await.unwrap();
+        }
+        wait_until(&service, |service| service.mesh_rtts.len() == 2).await;
+
+        let sender = DerpClient::connect(&addr.to_string(), keys.next_key())
+            .await
+            .unwrap();
+        sender.send_packet(target, b"fast".to_vec()).await.unwrap();
+        match next_command(&mut fast).await {
+            WriteLoopCommands::SendPacket { payload, .. } => assert_eq!(payload[..], b"fast"[..]),
+            command => panic!("unexpected command: {command:?}"),
+        }
+
+        // The slower relay still has it once the faster one lost it
+        command_sender
+            .send(ServiceCommand::PeerGone(
+                target,
+                PeerGoneReason::Disconnected,
+                to_fast,
+            ))
+            .await
+            .unwrap();
+        wait_until(&service, |service| {
+            service.presence(&target).and_then(|presence| presence.via) == Some(slow_relay)
+        })
+        .await;
+        sender.send_packet(target, b"slow".to_vec()).await.unwrap();
+        match next_command(&mut slow).await {
+            WriteLoopCommands::SendPacket { payload, .. } => assert_eq!(payload[..], b"slow"[..]),
+            command => panic!("unexpected command: {command:?}"),
+        }
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn looping_packet_is_dropped_when_its_ttl_expires() {
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let b_addr = b_addr.to_string();
+        let (a, a_addr) = start_service(&["--meshkey", "secret", "--mesh-peers", &b_addr]).await;
+        wait_until(&a, |a| !a.mesh.is_empty()).await;
+        wait_until(&b, |b| !b.mesh.is_empty()).await;
+
+        // Both relays wrongly believe the target is connected to the other one
+        let target = SecretKey::gen().public();
+        {
+            let mut a = a.write().await;
+            let (b_pk, to_b) = a.mesh.iter().next().unwrap();
+            let peer = Peer::remote(to_b.clone(), *b_pk);
+            a.peers.insert(target, peer);
+        }
+        {
+            let mut b = b.write().await;
+            let (a_pk, to_a) = b.mesh.iter().next().unwrap();
+            let peer = Peer::remote(to_a.clone(), *a_pk);
+            b.peers.insert(target, peer);
+        }
+
+        let sender = DerpClient::connect(&a_addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        sender.send_packet(target, b"loop".to_vec()).await.unwrap();
+        // A hands it to B, B back to A, where it has no hops left
+        wait_until(&a, |a| a.packets_dropped().ttl_expired == 1).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(a.read().await.packets_dropped().ttl_expired, 1);
+        assert_eq!(b.read().await.packets_dropped().ttl_expired, 0);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn roster_converges_after_the_mesh_link_flaps() {
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let b_addr = b_addr.to_string();
+        let (a, a_addr) = start_service(&["--meshkey", "secret", "--mesh-peers", &b_addr]).await;
+        let a_addr = a_addr.to_string();
+
+        let stays = DerpClient::connect(&a_addr, SecretKey::gen())
+            .await
+            .unwrap();
+        let leaves = DerpClient::connect(&a_addr, SecretKey::gen())
+            .await
+            .unwrap();
+        let leaves_pk = leaves.public_key();
+        let on_b = DerpClient::connect(&b_addr, SecretKey::gen())
+            .await
+            .unwrap();
+        wait_until(&b, |b| {
+            b.peers.contains_key(&stays.public_key()) && b.peers.contains_key(&leaves_pk)
+        })
+        .await;
+        wait_until(&a, |a| {
+            !a.mesh.is_empty() && a.peers.contains_key(&on_b.public_key())
+        })
+        .await;
+
+        // B drops the link without noticing, it keeps believing A's clients are behind it
+        let to_a = b.read().await.mesh.values().next().unwrap().clone();
+        to_a.send(WriteLoopCommands::Stop).await.unwrap();
+        wait_until(&a, |a| a.mesh.is_empty()).await;
+        drop(leaves);
+        wait_until(&a, |a| !a.peers.contains_key(&leaves_pk)).await;
+        let joins = DerpClient::connect(&b_addr, SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&b, joins.public_key()).await;
+
+        // A reconnects, both sides announce their clients again
+        wait_until(&a, |a| {
+            a.peers.contains_key(&on_b.public_key()) && a.peers.contains_key(&joins.public_key())
+        })
+        .await;
+        wait_until(&b, |b| !b.peers.contains_key(&leaves_pk)).await;
+        assert!(!a.read().await.peers.contains_key(&leaves_pk));
+
+        on_b.send_packet(stays.public_key(), b"after the flap".to_vec())
+            .await
+            .unwrap();
+        let (source, payload) = timeout(Duration::from_secs(5), stays.recv_packet())
+            .await
+            .expect("packet should be forwarded over the new link")
+            .unwrap();
+        assert_eq!(source, on_b.public_key());
+        assert_eq!(payload, b"after the flap");
+    }
+
+    /// Relays connections to `to`, the first one stops being relayed once the sender fires
+    /// while both of its sockets stay open
+    #[cfg(feature = "mesh")]
+    async fn freezable_proxy(to: SocketAddr) -> (SocketAddr, tokio::sync::oneshot::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (freeze, frozen) = tokio::sync::oneshot::channel::<()>();
+        spawn(async move {
+            let mut frozen = Some(frozen);
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                let mut outbound = TcpStream::connect(to).await.unwrap();
+                let frozen = frozen.take();
+                spawn(async move {
+                    let relay = tokio::io::copy_bidirectional(&mut inbound, &mut outbound);
+                    let Some(frozen) = frozen else {
+                        let _ = relay.await;
+                        return;
+                    };
+                    tokio::select! {
+                        _ = relay => return,
+                        _ = frozen => {}
+                    }
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        (addr, freeze)
+    }
+
+    /// Terminates TLS in front of `to` with a self-signed certificate for localhost. Returns the
+    /// address to connect to and the certificate, DER encoded.
+    #[cfg(feature = "mesh")]
+    async fn tls_proxy(to: SocketAddr) -> (SocketAddr, Vec<u8>) {
+        use tokio_rustls::{
+            rustls::{
+                pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+                ServerConfig,
+            },
+            TlsAcceptor,
+        };
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(der.clone())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der())),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            loop {
+                let (inbound, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                spawn(async move {
+                    let Ok(mut inbound) = acceptor.accept(inbound).await else {
+                        return;
+                    };
+                    let mut outbound = TcpStream::connect(to).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        });
+        (addr, der)
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn relays_mesh_over_tls() {
+        use sha2::{Digest, Sha256};
+
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let (b_tls, b_cert) = tls_proxy(b_addr).await;
+        let (c, c_addr) = start_service(&["--meshkey", "secret"]).await;
+        let (c_tls, c_cert) = tls_proxy(c_addr).await;
+
+        // B is trusted through the CA file, C by its pinned certificate
+        let ca = std::env::temp_dir().join(format!("dersp-mesh-ca-{}.pem", b_tls.port()));
+        let base64 = base64::encode(&b_cert);
+        let lines: Vec<_> = base64
+            .as_bytes()
+            .chunks(64)
+            .map(String::from_utf8_lossy)
+            .collect();
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            lines.join("\n")
+        );
+        std::fs::write(&ca, pem).unwrap();
+        let c_pin = hex::encode(Sha256::digest(&c_cert));
+        let (a, _a_addr) = start_service(&[
+            "--meshkey",
+            "secret",
+            "--mesh-ca",
+            ca.to_str().unwrap(),
+            "--mesh-peers",
+            &format!("derps://localhost:{}", b_tls.port()),
+            "--mesh-peers",
+            &format!("derps://{c_pin}@localhost:{}", c_tls.port()),
+        ])
+        .await;
+
+        wait_until(&a, |service| service.mesh.len() == 2).await;
+        wait_until(&b, |service| service.mesh.len() == 1).await;
+        wait_until(&c, |service| service.mesh.len() == 1).await;
+        std::fs::remove_file(ca).unwrap();
+
+        // A certificate other than the pinned one is refused
+        let b_pin = hex::encode(Sha256::digest(&b_cert));
+        let (command_sender, _commands) = tokio::sync::mpsc::channel(1);
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        };
+        let mismatched = MeshClient::new(
+            &format!("derps://{b_pin}@localhost:{}", c_tls.port()),
+            SecretKey::gen(),
+            Some("secret".to_owned()),
+            heartbeat,
+            command_sender,
+            &tls_connector(None).unwrap(),
+        )
+        .unwrap();
+        let err = mismatched
+            .start(Duration::from_secs(5), Duration::from_secs(5))
+            .await
+            .err()
+            .expect("the pinned certificate should be required");
+        assert!(format!("{err:#}").contains("TLS handshake"), "{err:#}");
+    }
+
+    /// Number of connections accepted by `listener` until nothing connects for a while
+    #[cfg(feature = "mesh")]
+    async fn count_connections(listener: &TcpListener) -> usize {
+        let mut connections = Vec::new();
+        while let Ok(connection) = timeout(Duration::from_millis(300), listener.accept()).await {
+            connections.push(connection.unwrap());
+        }
+        connections.len()
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn duplicate_mesh_peers_are_connected_once() {
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap().to_string();
+        let (_service, _addr) = start_service(&[
+            "--meshkey",
+            "secret",
+            "--mesh-peers",
+            &addr,
+            "--mesh-peers",
+            &addr,
+            "--mesh-peers",
+            &addr,
+        ])
+        .await;
+
+        assert_eq!(count_connections(&peer).await, 1);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn each_mesh_peer_is_presented_its_own_meshkey() {
+        let (b, b_addr) = start_service(&["--meshkey", "b-secret"]).await;
+        let (c, c_addr) = start_service(&["--meshkey", "c-secret"]).await;
+        // B gets the global meshkey, C its own
+        let (_a, _a_addr) = start_service(&[
+            "--meshkey",
+            "b-secret",
+            "--mesh-peers",
+            &b_addr.to_string(),
+            "--mesh-peers",
+            &format!("{c_addr}=c-secret"),
+        ])
+        .await;
+
+        wait_until(&b, |service| service.mesh.len() == 1).await;
+        wait_until(&c, |service| service.mesh.len() == 1).await;
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn mesh_peers_over_the_limit_are_ignored() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_service, _addr) = start_service(&[
+            "--meshkey",
+            "secret",
+            "--max-mesh-peers",
+            "1",
+            "--mesh-peers",
+            &first.local_addr().unwrap().to_string(),
+            "--mesh-peers",
+            &second.local_addr().unwrap().to_string(),
+        ])
+        .await;
+
+        assert_eq!(count_connections(&first).await, 1);
+        assert_eq!(count_connections(&second).await, 0);
+    }
+
+    #[cfg(feature = "mesh")]
+    #[tokio::test]
+    async fn unresponsive_mesh_link_is_reconnected() {
+        let (b, b_addr) = start_service(&["--meshkey", "secret"]).await;
+        let (proxy_addr, freeze) = freezable_proxy(b_addr).await;
+        let (a, _a_addr) = start_service(&[
+            "--meshkey",
+            "secret",
+            "--mesh-peers",
+            &proxy_addr.to_string(),
+            "--mesh-ping-interval",
+            "100ms",
+            "--mesh-ping-timeout",
+            "300ms",
+        ])
+        .await;
+        wait_until(&a, |a| !a.mesh.is_empty()).await;
+        wait_until(&b, |b| !b.mesh.is_empty()).await;
+        // Pings are answered while the link works
+        sleep(Duration::from_millis(500)).await;
+        assert!(!a.read().await.mesh.is_empty());
+
+        freeze.send(()).unwrap();
+        wait_until(&a, |a| a.mesh.is_empty()).await;
+        wait_until(&a, |a| !a.mesh.is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn runs_on_a_std_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = DerpService::new(Config::parse_from(["dersp", "--systemd-socket"]))
+            .await
+            .unwrap();
+        let runner = service.clone();
+        spawn(async move { runner.run_on(listener).await });
+
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+    }
+
+    #[tokio::test]
+    async fn handle_shuts_the_service_down() {
+        let config = Config::parse_from(["dersp", "--listen-on", "127.0.0.1:0"]);
+        let listener = bind(&config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = DerpService::new(config).await.unwrap();
+        let mut handle = service.run_with_listener(listener).await;
+
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+
+        let summary = handle.shutdown().await;
+        assert_eq!(summary.connected_clients, 1);
+        timeout(Duration::from_secs(5), handle.joined())
+            .await
+            .expect("service should stop")
+            .unwrap();
+
+        let closed = timeout(Duration::from_secs(5), client.recv_packet())
+            .await
+            .expect("shutdown should disconnect clients");
+        assert!(closed.is_err());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_the_clients_to_leave() {
+        let config = Config::parse_from(["dersp", "--listen-on", "127.0.0.1:0"]);
+        let listener = bind(&config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = DerpService::new(config).await.unwrap();
+        let handle = service.run_with_listener(listener).await;
+
+        let leaving = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        let staying = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, leaving.public_key()).await;
+        wait_for_peer(&service, staying.public_key()).await;
+
+        spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            leaving.close().await.unwrap();
+        });
+        let start = Instant::now();
+        let summary = handle.drain(Duration::from_secs(1)).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(summary.connected_clients, 1);
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        let closed = timeout(Duration::from_secs(5), staying.recv_packet())
+            .await
+            .expect("drain should disconnect the clients left");
+        assert!(closed.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_returns_once_the_clients_are_gone() {
+        let config = Config::parse_from(["dersp", "--listen-on", "127.0.0.1:0"]);
+        let listener = bind(&config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = DerpService::new(config).await.unwrap();
+        let handle = service.run_with_listener(listener).await;
+
+        let client = DerpClient::connect(&addr.to_string(), SecretKey::gen())
+            .await
+            .unwrap();
+        wait_for_peer(&service, client.public_key()).await;
+        client.close().await.unwrap();
+
+        let summary = timeout(
+            Duration::from_secs(5),
+            handle.drain(Duration::from_secs(60)),
+        )
+        .await
+        .expect("drain should not wait once the clients are gone");
+        assert_eq!(summary.connected_clients, 0);
+    }
+
+    #[tokio::test]
+    async fn top_talkers_are_ordered_by_bytes() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let busy = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let quiet = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&sender, &busy, &quiet] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+
+        for (receiver, packets) in [(&quiet, 1), (&busy, 3)] {
+            for _ in 0..packets {
+                sender
+                    .send_packet(receiver.public_key(), vec![0; 100])
+                    .await
+                    .unwrap();
+                timeout(Duration::from_secs(5), receiver.recv_packet())
+                    .await
+                    .expect("packet should be relayed")
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(
+            service.read().await.top_talkers(2),
+            vec![(busy.public_key(), 300), (quiet.public_key(), 100)]
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarding_rate_follows_the_current_throughput() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let sender = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let receiver = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        wait_for_peer(&service, sender.public_key()).await;
+        wait_for_peer(&service, receiver.public_key()).await;
+
+        // 20 kB/s for a bit longer than the averaging window
+        for _ in 0..24 {
+            sender
+                .send_packet(receiver.public_key(), vec![0; 1000])
+                .await
+                .unwrap();
+            receiver.recv_packet().await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+        {
+            let service = service.read().await;
+            assert_eq!(service.bytes_forwarded(), 24_000);
+            let rate = service.forwarding_rate();
+            assert!((10_000.0..=30_000.0).contains(&rate), "rate {rate}");
+        }
+
+        // Bytes dropped at a full destination aren't forwarded
+        let full = SecretKey::gen().public();
+        let (sink, _lanes) = write_lanes(1);
+        sink.try_send(WriteLoopCommands::SendPacket {
+            source: sender.public_key(),
+            target: full,
+            ttl: 0,
+            payload: Bytes::from_static(b"queued"),
+        })
+        .unwrap();
+        service
+            .write()
+            .await
+            .peers
+            .insert(full, Peer::local(sink, ResumeToken::gen()));
+        sender.send_packet(full, vec![0; 1000]).await.unwrap();
+        wait_until(&service, |service| {
+            service.packets_dropped().slow_destination == 1
+        })
+        .await;
+        assert_eq!(service.read().await.bytes_forwarded(), 24_000);
+
+        // Nothing forwarded for a whole window
+        sleep(FORWARDING_RATE_WINDOW + FORWARDING_RATE_SAMPLE_INTERVAL * 2).await;
+        assert_eq!(service.read().await.forwarding_rate(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn send_only_peers_are_asymmetric() {
+        let (service, addr) = start_service(&[]).await;
+        let addr = addr.to_string();
+        let send_only = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let a = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        let b = DerpClient::connect(&addr, SecretKey::gen()).await.unwrap();
+        for client in [&send_only, &a, &b] {
+            wait_for_peer(&service, client.public_key()).await;
+        }
+
+        // `a` gets twice what it sends, the others only go one way or are even
+        for (sender, receiver) in [(&send_only, &a), (&a, &b), (&b, &a)] {
+            for _ in 0..4 {
+                sender
+                    .send_packet(receiver.public_key(), vec![0; 100])
+                    .await
+                    .unwrap();
+                timeout(Duration::from_secs(5), receiver.recv_packet())
+                    .await
+                    .expect("packet should be relayed")
+                    .unwrap();
+            }
+        }
+
+        let asymmetric = service.read().await.asymmetric_peers(100, 10);
+        assert_eq!(
+            asymmetric,
+            vec![PeerTraffic {
+                peer: send_only.public_key(),
+                sent: 400,
+                received: 0,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn oversize_packets_are_dropped() {
+        use crate::proto::{data::SendPacket, write_send_packet};
+
+        let (service, addr) = start_service(&["--max-packet-size", "10"]).await;
+        let (_reader, mut writer, pk) = connect(addr).await;
+        wait_for_peer(&service, pk).await;
+
+        let packet = SendPacket {
+            target: pk,
+            payload: vec![0; 11],
+        };
+        write_send_packet(&mut writer, packet).await.unwrap();
+        wait_until(&service, |service| service.packets_dropped().oversize == 1).await;
+    }
 }
@@ -0,0 +1,94 @@
+use crate::{
+    crypto::PublicKey,
+    listener::bind,
+    service::{DerpService, Service},
+    Config,
+};
+use clap::Parser;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
+use tokio::{spawn, sync::RwLock, time::sleep};
+
+/// Starts a service listening on a random local port, `args` are appended to the command line
+pub async fn start_service(args: &[&str]) -> (Arc<RwLock<DerpService>>, SocketAddr) {
+    let config = Config::parse_from(
+        ["dersp", "--listen-on", "127.0.0.1:0"]
+            .iter()
+            .chain(args.iter()),
+    );
+    let listener = bind(&config).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = DerpService::new(config).await.unwrap();
+    let runner = service.clone();
+    spawn(async move { runner.run(listener).await });
+    (service, addr)
+}
+
+/// The client learns the handshake is done slightly before the service registers it
+pub async fn wait_for_peer(service: &Arc<RwLock<DerpService>>, pk: PublicKey) {
+    for _ in 0..500 {
+        if service.read().await.connected_peers().contains(&pk) {
+            return;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{pk:?} never got connected");
+}
+
+static CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Captures every log line from now on, lines of all tests running in parallel end up together
+pub fn capture_logs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+/// Number of captured lines containing `needle`, needs [`capture_logs`]
+pub fn count_logs(needle: &str) -> usize {
+    CAPTURED_LOGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.contains(needle))
+        .count()
+}
+
+/// Waits until a line was logged that contains `needle`, needs [`capture_logs`]
+pub async fn wait_for_log(needle: &str) -> String {
+    for _ in 0..500 {
+        if let Some(line) = CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|line| line.contains(needle))
+        {
+            return line.clone();
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{needle:?} was never logged");
+}